@@ -0,0 +1,325 @@
+use bureau::storage::{self, mem, DataPath};
+use bureau::{Config, Db};
+use clap::{Parser, ValueEnum};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Runs a fixed workload against a database in the same process and prints throughput and
+/// latencies as a single JSON object, so that runs can be compared.
+#[derive(Parser, Debug)]
+struct Args {
+    /// Requests to make in total. Ignored if duration is given.
+    #[arg(long, default_value_t = 100_000)]
+    requests: usize,
+
+    /// Seconds to run for instead of a fixed number of requests.
+    #[arg(long)]
+    duration: Option<u64>,
+
+    /// Share of reads among requests, from 0 to 1.
+    #[arg(long, default_value_t = 0.5)]
+    read_ratio: f64,
+
+    /// Number of distinct keys.
+    #[arg(long, default_value_t = 10_000)]
+    keys: usize,
+
+    /// Which keys requests go to.
+    #[arg(long, value_enum, default_value_t = Distribution::Uniform)]
+    distribution: Distribution,
+
+    /// Key length in bytes, either a number or a range like 8-32 to pick from uniformly.
+    #[arg(long, default_value = "8-32")]
+    key_size: SizeRange,
+
+    /// Value length in bytes, either a number or a range like 16-512 to pick from uniformly.
+    #[arg(long, default_value = "16-512")]
+    value_size: SizeRange,
+
+    /// Number of clients making requests at the same time.
+    #[arg(long, default_value_t = 8)]
+    concurrency: usize,
+
+    /// Directory to keep the data in. Data is kept in memory if not given.
+    #[arg(long)]
+    data_dir: Option<String>,
+
+    /// Seed of the workload, the same seed gives the same sequence of requests per client.
+    #[arg(long, default_value_t = 1)]
+    seed: u64,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Distribution {
+    /// Every key is equally likely.
+    Uniform,
+    /// 80% of requests go to 20% of the keys.
+    Skewed,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct SizeRange {
+    min: usize,
+    max: usize,
+}
+
+impl FromStr for SizeRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |n: &str| {
+            n.trim()
+                .parse::<usize>()
+                .map_err(|_| format!("invalid size: {}", n))
+        };
+        let (min, max) = match s.split_once('-') {
+            Some((min, max)) => (parse(min)?, parse(max)?),
+            None => (parse(s)?, parse(s)?),
+        };
+        if min == 0 || min > max {
+            return Err(format!("invalid size range: {}", s));
+        }
+
+        Ok(SizeRange { min, max })
+    }
+}
+
+/// Small xorshift generator, the workload doesn't need anything better.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // Zero state would only produce zeroes.
+        Rng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn size(&mut self, range: SizeRange) -> usize {
+        range.min + self.below(range.max - range.min + 1)
+    }
+
+    fn chance(&mut self, ratio: f64) -> bool {
+        (self.next() as f64 / u64::MAX as f64) < ratio
+    }
+}
+
+/// Workload of a single client.
+struct Workload {
+    args: Arc<Args>,
+    rng: Rng,
+}
+
+impl Workload {
+    fn new(args: Arc<Args>, client: usize) -> Self {
+        let rng = Rng::new(args.seed.wrapping_add(client as u64));
+        Workload { args, rng }
+    }
+
+    fn key_idx(&mut self) -> usize {
+        let keys = self.args.keys;
+        match self.args.distribution {
+            Distribution::Uniform => self.rng.below(keys),
+            Distribution::Skewed => {
+                let hot = (keys / 5).max(1);
+                match self.rng.chance(0.8) {
+                    true => self.rng.below(hot),
+                    false => hot + self.rng.below((keys - hot).max(1)),
+                }
+                .min(keys - 1)
+            }
+        }
+    }
+
+    /// Keys are numbered, the length of each key is fixed by its number.
+    fn key(&self, idx: usize) -> String {
+        let len = Rng::new(idx as u64).size(self.args.key_size);
+        format!("{:0>len$}", idx, len = len)
+    }
+
+    fn value(&mut self) -> String {
+        "v".repeat(self.rng.size(self.args.value_size))
+    }
+}
+
+#[derive(Default)]
+struct Latencies(Vec<Duration>);
+
+impl Latencies {
+    fn percentile(&self, p: f64) -> u128 {
+        if self.0.is_empty() {
+            return 0;
+        }
+        let idx = ((self.0.len() - 1) as f64 * p).round() as usize;
+        self.0[idx].as_micros()
+    }
+
+    fn merge(&mut self, other: Latencies) {
+        self.0.extend(other.0);
+    }
+}
+
+impl fmt::Display for Latencies {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            r#"{{"count":{},"p50_us":{},"p90_us":{},"p99_us":{},"max_us":{}}}"#,
+            self.0.len(),
+            self.percentile(0.5),
+            self.percentile(0.9),
+            self.percentile(0.99),
+            self.percentile(1.0),
+        )
+    }
+}
+
+struct Report {
+    elapsed: Duration,
+    reads: Latencies,
+    writes: Latencies,
+    errors: usize,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total = self.reads.0.len() + self.writes.0.len();
+        write!(
+            f,
+            r#"{{"requests":{},"errors":{},"elapsed_ms":{},"throughput_rps":{:.1},"reads":{},"writes":{}}}"#,
+            total,
+            self.errors,
+            self.elapsed.as_millis(),
+            total as f64 / self.elapsed.as_secs_f64(),
+            self.reads,
+            self.writes,
+        )
+    }
+}
+
+async fn run(args: Args, db: Db) -> Report {
+    let args = Arc::new(args);
+    let issued = Arc::new(AtomicUsize::new(0));
+    let deadline = args
+        .duration
+        .map(|secs| Instant::now() + Duration::from_secs(secs));
+    let start = Instant::now();
+
+    let mut clients = Vec::new();
+    for client in 0..args.concurrency.max(1) {
+        let mut workload = Workload::new(args.clone(), client);
+        let (db, issued) = (db.clone(), issued.clone());
+        clients.push(tokio::spawn(async move {
+            let (mut reads, mut writes, mut errors) =
+                (Latencies::default(), Latencies::default(), 0);
+            loop {
+                let more = match deadline {
+                    Some(deadline) => Instant::now() < deadline,
+                    None => issued.fetch_add(1, Ordering::Relaxed) < workload.args.requests,
+                };
+                if !more {
+                    break;
+                }
+
+                let idx = workload.key_idx();
+                let key = workload.key(idx);
+                let started = Instant::now();
+                if workload.rng.chance(workload.args.read_ratio) {
+                    errors += db.get(key).await.is_err() as usize;
+                    reads.0.push(started.elapsed());
+                } else {
+                    let value = workload.value();
+                    errors += db.set(key, value).await.is_err() as usize;
+                    writes.0.push(started.elapsed());
+                }
+            }
+
+            (reads, writes, errors)
+        }));
+    }
+
+    let mut report = Report {
+        elapsed: Duration::ZERO,
+        reads: Latencies::default(),
+        writes: Latencies::default(),
+        errors: 0,
+    };
+    for client in clients {
+        let (reads, writes, errors) = client.await.expect("client failed");
+        report.reads.merge(reads);
+        report.writes.merge(writes);
+        report.errors += errors;
+    }
+    report.elapsed = start.elapsed();
+    report.reads.0.sort();
+    report.writes.0.sort();
+
+    report
+}
+
+#[tokio::main]
+async fn main() -> bureau::Result<()> {
+    let args = Args::parse();
+    if !(0.0..=1.0).contains(&args.read_ratio) || args.keys == 0 {
+        return Err(bureau::BureauError::InvalidInput(
+            "read ratio must be within 0 and 1 and there must be some keys".to_string(),
+        ));
+    }
+
+    let db = match &args.data_dir {
+        Some(dir) => Db::open(
+            storage::new(DataPath::Is(dir.clone())),
+            storage::wal::new(DataPath::Is(dir.clone())),
+            Config::default(),
+        ),
+        None => Db::open(mem::new(), mem::new_wal(), Config::default()),
+    };
+
+    let report = run(args, db.clone()).await;
+    db.close().await?;
+    println!("{}", report);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_tiny_workload() {
+        let args = Args::parse_from([
+            "bench",
+            "--requests",
+            "200",
+            "--keys",
+            "50",
+            "--distribution",
+            "skewed",
+            "--key-size",
+            "4",
+            "--concurrency",
+            "3",
+        ]);
+        let db = Db::open(mem::new(), mem::new_wal(), Config::default());
+
+        let report = run(args, db).await;
+        assert_eq!(report.reads.0.len() + report.writes.0.len(), 200);
+        assert_eq!(report.errors, 0);
+        assert!(report.reads.percentile(0.5) <= report.reads.percentile(1.0));
+        assert!(report
+            .to_string()
+            .starts_with(r#"{"requests":200,"errors":0,"#));
+    }
+}
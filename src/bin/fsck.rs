@@ -0,0 +1,39 @@
+use bureau::fsck;
+use bureau::storage::{self, DataPath};
+use bureau::Storage;
+use clap::Parser;
+
+/// Checks that every table in the data directory can be read, reports damaged ones.
+#[derive(Parser)]
+struct Args {
+    /// Directory tables are kept in.
+    data_dir: String,
+
+    /// Directory to move damaged tables to, tables with ids out of order are only reported.
+    /// Tables are only reported if not given.
+    #[arg(long)]
+    quarantine: Option<String>,
+}
+
+fn main() -> bureau::Result<()> {
+    let args = Args::parse();
+
+    let stor = storage::new(DataPath::Is(args.data_dir));
+    let report = fsck::fsck(&stor)?;
+    print!("{}", report);
+
+    if let Some(dir) = args.quarantine {
+        let quarantine = storage::new(DataPath::Is(dir));
+        quarantine.bootstrap()?;
+        // Tables whose only problem is their id are fine to read, they stay.
+        for table_id in report.damaged() {
+            fsck::quarantine(&stor, &quarantine, &table_id)?;
+            println!("table {} quarantined", table_id);
+        }
+    }
+
+    match report.is_ok() {
+        true => Ok(()),
+        false => Err(format!("{} damaged tables", report.problems.len()))?,
+    }
+}
@@ -0,0 +1,19 @@
+use bureau::engine::SsTable;
+use clap::Parser;
+use std::fs;
+
+#[derive(Parser)]
+struct Args {
+    /// Path to the table file to inspect.
+    file: String,
+}
+
+fn main() -> bureau::Result<()> {
+    let args = Args::parse();
+
+    let file = fs::File::open(&args.file)?;
+    let report = SsTable::inspect(&file)?;
+    print!("{}", report);
+
+    Ok(())
+}
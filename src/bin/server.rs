@@ -1,213 +1,49 @@
-use bureau::engine::{Command, Engine};
-use bureau::{storage, storage::DataPath};
-use bytes::Bytes;
-use futures::SinkExt;
+use bureau::{server::ServerBuilder, storage, storage::DataPath, Config};
 use std::env;
-use std::error::Error;
 use tokio::net::TcpListener;
-use tokio::sync::{mpsc, oneshot};
-use tokio_stream::StreamExt;
-use tokio_util::codec::{Framed, LinesCodec};
-use tracing::{error, info, warn};
+use tokio::signal::unix::{signal, SignalKind};
 use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
-enum Request {
-    Get { key: String },
-    Set { key: String, value: String },
-}
-
-enum Response {
-    Get { key: String, value: Bytes },
-    Set { key: String, value: Bytes },
-    Error { msg: String },
-}
-
 #[tokio::main]
-async fn main() -> bureau::Result<(), Box<dyn Error>> {
+async fn main() -> bureau::Result<()> {
     // TODO: Assemble config from env here.
     tracing_subscriber::registry()
         .with(EnvFilter::from_default_env())
         .with(fmt::Layer::default())
         .init();
 
-    let addr = env::args()
-        .nth(1)
-        .unwrap_or_else(|| "127.0.0.1:12650".to_string());
-
-    let listener = TcpListener::bind(&addr).await?;
-    info!("Listening on: {}", addr);
-
-    let (req_tx, req_rx) = mpsc::channel(64);
-    let stor = storage::new(DataPath::Default);
-    let engine = Engine::new(req_rx);
-
-    let engine_handle = tokio::spawn(async move {
-        engine.run(stor).await;
-        tracing::error!("engine exited");
-    });
-
-    let network_loop_handle = tokio::spawn(async move {
-        loop {
-            let req_tx = req_tx.clone();
-
-            match listener.accept().await {
-                Ok((socket, _)) => {
-                    tokio::spawn(async move {
-                        let mut lines = Framed::new(socket, LinesCodec::new());
-
-                        if let Some(result) = lines.next().await {
-                            match result {
-                                Ok(line) => match Request::parse(&line) {
-                                    Ok(request) => {
-                                        let response = handle_request(request, req_tx).await;
-                                        let serialized = response.serialize();
-
-                                        if let Err(e) = lines.send(&serialized).await {
-                                            warn!("error on sending response; error = {:?}", e);
-                                        }
-                                    }
-                                    Err(e) => {
-                                        let response = Response::Error {
-                                            msg: format!("could not parse command: {}", e),
-                                        };
-                                        let serialized = response.serialize();
-
-                                        if let Err(e) = lines.send(serialized.as_str()).await {
-                                            warn!("error on sending response; error = {:?}", e);
-                                        }
-                                    }
-                                },
-                                Err(e) => {
-                                    error!("error on decoding from socket; error = {:?}", e);
-                                }
-                            }
-                        }
-                    });
-                }
-                Err(e) => error!("error accepting socket; error = {:?}", e),
-            }
-        }
-    });
+    // Every argument is an address to listen on, e.g. both IPv4 and IPv6 ones.
+    let mut addrs: Vec<String> = env::args().skip(1).collect();
+    if addrs.is_empty() {
+        addrs.push("127.0.0.1:12650".to_string());
+    }
 
-    tokio::select! {
-        res = engine_handle => {
-            tracing::error!("engine handle down: {res:?}");
-            res?;
-        },
-        res = network_loop_handle => {
-            tracing::error!("network loop down: {res:?}");
-            res?;
-        }
+    // Level 0 is pushed down once there are this many tables, deeper levels are kept within
+    // their sizes, so the number of tables stays bounded under a steady write load.
+    let config = Config {
+        compaction_max_tables: Some(8),
+        ..Default::default()
     };
-
-    Ok(())
-}
-
-async fn handle_request(request: Request, req_tx: mpsc::Sender<Command>) -> Response {
-    match request {
-        Request::Get { key } => {
-            let (resp_tx, resp_rx) = oneshot::channel();
-
-            let cmd = Command::Get {
-                key: Bytes::from(key.clone()),
-                responder: resp_tx,
-            };
-
-            if let Err(e) = req_tx.send(cmd).await {
-                return Response::Error { msg: e.to_string() };
-            }
-
-            let resp = resp_rx.await;
-
-            // TODO: Log error instead of sending it to client;
-            if resp.is_err() {
-                return Response::Error {
-                    msg: resp.err().unwrap().to_string(),
-                };
-            }
-
-            let resp = resp.unwrap();
-
-            match resp {
-                Ok(option) => match option {
-                    Some(value) => Response::Get {
-                        key,
-                        value: value.clone(),
-                    },
-                    None => Response::Error {
-                        msg: "no value for given key".to_string(),
-                    },
-                },
-                Err(e) => Response::Error { msg: e.to_string() },
-            }
-        }
-        Request::Set { key, value } => {
-            let (resp_tx, resp_rx) = oneshot::channel();
-
-            let cmd = Command::Set {
-                key: Bytes::from(key.clone()),
-                value: Bytes::from(value.clone()),
-                responder: Some(resp_tx),
-            };
-
-            if let Err(e) = req_tx.send(cmd).await {
-                return Response::Error { msg: e.to_string() };
-            }
-
-            let resp = resp_rx.await.unwrap(); // TODO: Remove unwrap();
-
-            match resp {
-                Ok(_) => Response::Set {
-                    key,
-                    value: Bytes::from(value),
-                },
-                Err(e) => Response::Error { msg: e.to_string() },
-            }
-        }
+    let data_path = config.data_path.to_string_lossy().into_owned();
+    let stor = storage::new(DataPath::Is(data_path.clone()));
+    let wal_stor = storage::wal::new(DataPath::Is(data_path));
+
+    let mut server = ServerBuilder::new(stor, wal_stor)
+        .config(config)
+        .shutdown(shutdown_signal());
+    for addr in addrs {
+        server = server.listener(TcpListener::bind(&addr).await?);
     }
-}
 
-impl Request {
-    fn parse(input: &str) -> bureau::Result<Request> {
-        let mut parts = input.splitn(3, ' ');
-        match parts.next() {
-            Some("GET") => {
-                let key = parts.next().ok_or("GET must be followed by a key")?;
-                if parts.next().is_some() {
-                    Err("GET's key must not be followed by anything")?
-                }
-                Ok(Request::Get {
-                    key: key.to_string(),
-                })
-            }
-            Some("SET") => {
-                let key = match parts.next() {
-                    Some(key) => key,
-                    None => Err("SET must be followed by a key")?,
-                };
-                let value = match parts.next() {
-                    Some(value) => value,
-                    None => Err("SET needs a value")?,
-                };
-                Ok(Request::Set {
-                    key: key.to_string(),
-                    value: value.to_string(),
-                })
-            }
-            Some(cmd) => Err(format!("unknown command: {}", cmd))?,
-            None => Err("empty input")?,
-        }
-    }
+    server.run().await
 }
 
-impl Response {
-    fn serialize(&self) -> String {
-        match *self {
-            Response::Get { ref key, ref value } => format!("{:?} = {:?}", key, value),
-            Response::Set { ref key, ref value } => {
-                format!("set {} = `{:?}`", key, value)
-            }
-            Response::Error { ref msg } => format!("error: {}", msg),
-        }
+/// Resolves on either Ctrl-C or SIGTERM.
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("could not listen for SIGTERM");
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
     }
 }
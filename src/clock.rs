@@ -0,0 +1,49 @@
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Source of the wall clock time that ends up in data: table ids, sequence numbers of writes and
+/// expiration times. Timers and timeouts that never leave the process use tokio time instead.
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> SystemTime;
+
+    /// Time since the epoch, zero if the clock is before it.
+    fn since_epoch(&self) -> Duration {
+        self.now().duration_since(UNIX_EPOCH).unwrap_or_default()
+    }
+}
+
+/// The clock of the system. This is what the server runs with.
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Clock that only moves when told to. Exists for test. Clones share the time, so a clone given
+/// to the engine can be moved from the test.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<SystemTime>>,
+}
+
+impl ManualClock {
+    pub fn new(now: SystemTime) -> Self {
+        ManualClock {
+            now: Arc::new(Mutex::new(now)),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap()
+    }
+}
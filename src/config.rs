@@ -0,0 +1,409 @@
+use crate::engine::memtable::SsTableSize;
+use crate::{engine, protocol};
+use crate::{Clock, SystemClock};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Database tunables. Default values are the ones the server runs with.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Directory the server keeps tables and the log in.
+    pub data_path: PathBuf,
+
+    /// Longest key and longest value the engine takes, in bytes, longer ones are refused as
+    /// invalid input. They can only be set lower than the defaults, since memtable estimates
+    /// the size of its entries with them (see Config::validate).
+    pub max_key_size: u32,
+    pub max_value_size: u32,
+
+    /// Bounds of how many tables are allowed to be in the process of writing to disk at the same
+    /// time. The actual limit grows towards the max under a burst of writes and goes back to the
+    /// min when writes calm down. Grow the max to make DB more tolerant to high amount of writes,
+    /// it will consume more memory in return. Once the limit is reached, writes are suspended
+    /// until the tables are on disk.
+    pub dispatcher_buffer_min: usize,
+    pub dispatcher_buffer_max: usize,
+
+    /// Commands that may wait for the engine before whoever sends them has to wait too. Server
+    /// connections and Db handles send to the same queue. A longer queue absorbs bursts of
+    /// requests, but requests wait longer in it and every one holds its key and value in memory.
+    /// Zero is taken as one.
+    pub engine_queue_len: usize,
+
+    /// Commands that may wait for the dispatcher, sent by the engine and by compaction once it is
+    /// done. Engine waits on it to read from disk and to hand over tables, so a longer queue lets
+    /// it get further ahead of the disk. Zero is taken as one.
+    pub dispatcher_queue_len: usize,
+
+    /// Size of a memtable, which is also the size of tables it is flushed to. Can be changed between
+    /// restarts, the log written with a bigger memtable is recovered into several tables.
+    pub memtable_size: SsTableSize,
+
+    /// Percentage of the memtable capacity after which the engine swaps the table and sends it to
+    /// disk in the background, instead of waiting for it to get full. Flushing a bit earlier keeps
+    /// the engine from stalling on the dispatcher right when the table can't take any more writes.
+    /// None means the table is only flushed once it is full.
+    pub flush_watermark: Option<u8>,
+
+    /// How many full memtables may be on their way to disk while the next one takes writes. They
+    /// are kept in memory and serve reads until newer ones push them out. Allowing a few of them
+    /// smooths out bursts of writes the dispatcher can't keep up with, at the cost of a memtable
+    /// worth of memory each. Zero makes the engine wait for the dispatcher every time memtable
+    /// gets full.
+    pub immutable_memtables: usize,
+
+    /// How long writes may stay in memtable before it is flushed no matter how full it is. Keeps
+    /// the log short and recovery quick when writes are rare. Memtable is checked once per
+    /// interval, so a write may wait for up to two intervals. None means memtable is only
+    /// flushed once it is full (or reaches the watermark).
+    pub flush_interval: Option<Duration>,
+
+    /// How long the dispatcher remembers that a key was not found on disk, so repeated reads of
+    /// a missing key don't go through all the tables. Costs memory for every remembered key.
+    /// None disables caching of missed reads.
+    pub negative_cache_ttl: Option<Duration>,
+
+    /// How many values read from disk the dispatcher keeps in its cache. Each of them may take up
+    /// to max_value_size of memory. Zero disables caching of values.
+    pub cache_capacity: usize,
+
+    /// How many times a key has to be read before its value is let into the cache. Keeps values
+    /// read only once or twice from taking room in a cache that is not full yet. A full cache
+    /// takes a value only if its key is read more often than the least read cached key anyway,
+    /// so this matters once the cache is full only if it is the higher bar. Reads are counted by
+    /// the sketch and decay over time (see cache_sketch), so a high threshold may keep keys that
+    /// are read steadily but rarely out for good. Zero takes any value while there is room.
+    pub cache_min_reads: usize,
+
+    /// Dimensions of the sketch the cache estimates read frequencies with. A wider sketch gives
+    /// more accurate estimates for workloads with many distinct keys (see SketchSize).
+    pub cache_sketch: SketchSize,
+
+    /// File to keep keys of cached values in between restarts. Keys are written on shutdown and
+    /// their values are read back to the cache on start, so that the cache is not cold. Hints
+    /// are skipped if tables changed in between. None disables it.
+    pub cache_hints: Option<PathBuf>,
+
+    /// How many decoded table blocks the dispatcher keeps for lookups, least recently used ones
+    /// are dropped first. Each takes up to 4KB of memory. Lookups of neighbouring keys, or of
+    /// keys a bloom filter lets through by mistake, find their block there instead of reading
+    /// the table again. Scans read blocks around it. Zero disables it.
+    pub block_cache_capacity: usize,
+
+    /// Recompute checksums of cached values on every read from the cache, for environments where
+    /// memory can't be trusted either. A corrupted value is dropped from the cache and read from
+    /// disk again. Blocks read from disk are verified regardless of this setting.
+    pub verify_on_read: bool,
+
+    /// Check every table built from memtable or by compaction before it is written (see
+    /// SsTable::validate). A flush of an invalid table stops the server, a compaction that
+    /// produced one fails and leaves its inputs as they are. Always on in debug builds.
+    pub validate_tables: bool,
+
+    /// Put a hash index to every table built from memtable or by compaction. A point read then
+    /// goes from the bloom filter straight to the block, the table index is not read. It takes
+    /// 4 bytes per slot, at least two slots per key, and longer to build a table. Tables written
+    /// without it are read through the table index as before.
+    pub hash_index: bool,
+
+    /// Compress blocks of every table built from memtable or by compaction with LZ4. A block is
+    /// only kept compressed if it gets shorter, tables take less disk but every block read is
+    /// decompressed. Only takes effect with the compression feature, tables with compressed
+    /// blocks can't be read without it.
+    pub compression: bool,
+
+    /// False positive rate the bloom filter of every table built from memtable or by compaction
+    /// is sized for, from 0 to 1 exclusive. Filters are sized for the keys of their table, a lower
+    /// rate takes more bytes per key (about 1.2 bytes at 0.01, 1.8 at 0.001) and saves lookups of
+    /// keys a table doesn't have from reading its index and a block. Tables keep the len of their
+    /// filter, so tables built with different rates are read alike.
+    pub bloom_fp_rate: f64,
+
+    /// Give the OS hints about how tables are read (posix_fadvise, only on file system storage):
+    /// tables that are looked up are read ahead, tables read over a range by scans and compaction
+    /// are dropped from the page cache once read, so that they don't push out the hot ones.
+    /// Hints given are counted in engine stats.
+    pub os_cache_advice: bool,
+
+    /// Bytes per second compaction may read and write, so that a big merge doesn't take the whole
+    /// disk bandwidth from flushes and reads. Compaction sleeps once it is over the budget, which
+    /// makes it take longer. None means compaction runs as fast as the disk allows.
+    pub compaction_io_budget: Option<u64>,
+
+    /// Number of tables above which the dispatcher compacts on its own. Tables are kept in levels:
+    /// flushed tables go to level 0 and may overlap, once over the limit all of them are merged
+    /// into the non-overlapping tables of level 1. Every deeper level may take ten times more
+    /// bytes than the one above, a level over its size is pushed down a table at a time. More
+    /// tables make reads go through more of them, compacting makes every value written again.
+    /// None means no limit on the number of tables.
+    pub compaction_max_tables: Option<usize>,
+
+    /// Bytes taken by all the tables above which the dispatcher compacts on its own, the same way
+    /// as for compaction_max_tables. Merging only drops overwritten and deleted values, so it
+    /// can't always get under the limit, compaction then stops once level 0 is empty and no
+    /// level is over its size. None means no limit on the size. With both limits unset tables
+    /// are only compacted on request.
+    pub compaction_max_bytes: Option<u64>,
+
+    /// Free disk space, in bytes, below which the engine refuses writes while still serving
+    /// reads, so that tables already in memory can still be put to disk. Space is checked on the
+    /// disk of the log. Writes are taken again once there is enough space. Zero disables the
+    /// check.
+    pub min_free_space: u64,
+
+    /// UNSAFE: don't keep the write ahead log at all. Writes get to memtable only, so every write
+    /// that is not in a table yet is lost on a crash, and there is nothing to recover on start.
+    /// Sync does nothing then. Meant only for data that can be rebuilt, like a cache, where write
+    /// throughput matters more than durability. A clean shutdown still puts memtable to disk.
+    pub unsafe_no_wal: bool,
+
+    /// When appends to the write ahead log are put to disk, beyond the explicit Sync. Trades
+    /// durability of the latest writes for write throughput (see SyncPolicy).
+    pub wal_sync: SyncPolicy,
+
+    /// Bytes after which the write ahead log starts a new generation, even if memtable is not
+    /// full yet. Generations are removed once every write they hold is in a table on disk, so
+    /// smaller ones give disk space back sooner. None starts a new generation only when
+    /// memtable is swapped.
+    pub wal_segment_size: Option<u64>,
+
+    /// How many ids of the latest applied requests the engine remembers, so that a retried
+    /// request carrying an id is not applied twice. Ids are forgotten on restart. Zero disables
+    /// deduplication.
+    pub dedupe_window: usize,
+
+    /// How long the server lets connections finish their requests once it is asked to shut down.
+    /// Connections still open after that are closed.
+    pub drain_timeout: Duration,
+
+    /// Most connections the server keeps open at once, across all listeners. A client that
+    /// connects past it is answered with an error and disconnected. None means no limit.
+    pub max_connections: Option<usize>,
+
+    /// How long a read made after a given write waits for the server to see the write (see
+    /// protocol AFTER). A read that waited for longer is answered with an error instead.
+    pub read_after_timeout: Duration,
+
+    /// Most keys and most bytes of keys a single KEYS or SCAN response lists, whatever limit the
+    /// request asks for, so that a scan can't make the server build a response of any size. SCAN
+    /// counts the bytes of values too. A response cut short by them, or by the limit, carries the
+    /// key to continue the scan from. A response lists at least one key if there is one, however
+    /// long it is.
+    pub max_scan_keys: usize,
+    pub max_scan_bytes: usize,
+
+    /// Longest request line the server reads, in bytes. A longer one is refused before it is
+    /// read in full, so that a client can't make the server buffer an endless line. None means
+    /// the longest request that can hold a full MSET or MGET of keys and values within
+    /// max_key_size and max_value_size (see protocol::max_request_len).
+    pub max_request_len: Option<usize>,
+
+    /// Options set on every accepted client socket.
+    pub socket: SocketConfig,
+
+    /// Where table ids, sequence numbers of writes and expiration times take the time from.
+    /// Tests put a ManualClock here to move time without sleeping.
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            data_path: PathBuf::from(engine::DATA_PATH),
+            max_key_size: engine::MAX_KEY_SIZE,
+            max_value_size: engine::MAX_VALUE_SIZE,
+            dispatcher_buffer_min: 2,
+            dispatcher_buffer_max: 32,
+            engine_queue_len: 64,
+            dispatcher_queue_len: 64,
+            memtable_size: SsTableSize::Default,
+            flush_watermark: None,
+            immutable_memtables: 0,
+            flush_interval: None,
+            negative_cache_ttl: None,
+            cache_capacity: 100,
+            cache_min_reads: 0,
+            cache_sketch: SketchSize::default(),
+            cache_hints: None,
+            block_cache_capacity: 0,
+            verify_on_read: false,
+            validate_tables: false,
+            hash_index: false,
+            compression: false,
+            bloom_fp_rate: engine::BLOOM_FP_RATE,
+            os_cache_advice: false,
+            compaction_io_budget: None,
+            compaction_max_tables: None,
+            compaction_max_bytes: None,
+            min_free_space: 64 * 1024 * 1024, // 64MB.
+            dedupe_window: 1024,
+            unsafe_no_wal: false,
+            wal_sync: SyncPolicy::Never,
+            wal_segment_size: None,
+            drain_timeout: Duration::from_secs(5),
+            max_connections: None,
+            read_after_timeout: Duration::from_secs(1),
+            max_scan_keys: protocol::MAX_KEYS_LIMIT,
+            max_scan_bytes: 64 * 1024, // 64KB.
+            max_request_len: None,
+            socket: SocketConfig::default(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+impl Config {
+    /// Longest request line the server reads, see max_request_len.
+    pub fn request_len_limit(&self) -> usize {
+        self.max_request_len
+            .unwrap_or_else(|| protocol::max_request_len(self.max_key_size, self.max_value_size))
+    }
+
+    /// Checks the settings that can't be taken as they are. Engine refuses to start with a config
+    /// that doesn't pass.
+    pub fn validate(&self) -> crate::Result<()> {
+        if !(1..=engine::MAX_KEY_SIZE).contains(&self.max_key_size) {
+            return Err(crate::Error::InvalidInput(format!(
+                "max_key_size must be from 1 to {}",
+                engine::MAX_KEY_SIZE
+            )));
+        }
+
+        if !(1..=engine::MAX_VALUE_SIZE).contains(&self.max_value_size) {
+            return Err(crate::Error::InvalidInput(format!(
+                "max_value_size must be from 1 to {}",
+                engine::MAX_VALUE_SIZE
+            )));
+        }
+
+        if self
+            .flush_watermark
+            .is_some_and(|percent| !(1..=100).contains(&percent))
+        {
+            return Err(crate::Error::InvalidInput(
+                "flush_watermark must be from 1 to 100".into(),
+            ));
+        }
+
+        if !(self.bloom_fp_rate > 0.0 && self.bloom_fp_rate < 1.0) {
+            return Err(crate::Error::InvalidInput(
+                "bloom_fp_rate must be between 0 and 1".into(),
+            ));
+        }
+
+        if self.wal_segment_size == Some(0) {
+            return Err(crate::Error::InvalidInput(
+                "wal_segment_size must be positive".into(),
+            ));
+        }
+
+        if self.wal_sync == SyncPolicy::EveryMs(0) {
+            return Err(crate::Error::InvalidInput(
+                "wal_sync interval must be positive".into(),
+            ));
+        }
+
+        if self.cache_sketch.depth == 0 || self.cache_sketch.width == 0 {
+            return Err(crate::Error::InvalidInput(
+                "cache_sketch depth and width must be positive".into(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// How often the write ahead log is put to disk. A write is acknowledged once it is appended to
+/// the log, but an append only gets to the OS, and is lost if the machine goes down before the
+/// OS writes it out. A crash of the process alone loses nothing. Sync puts everything appended
+/// so far to disk whatever the policy is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncPolicy {
+    /// Every append is put to disk before the write is acknowledged. Nothing acknowledged is
+    /// lost, at the cost of a disk flush per write.
+    Always,
+    /// Appends are put to disk in the background every given number of milliseconds, so a crash
+    /// loses writes of the last interval at most. Writes don't wait for the disk.
+    EveryMs(u64),
+    /// The OS decides when appends get to disk. Fastest, but how many writes a crash loses is
+    /// up to the OS. Log is still put to disk on every rotation.
+    Never,
+}
+
+/// Dimensions of the count-min sketch of the dispatcher cache. Sketch holds depth × width
+/// counters of usize, which is 128KB on 64 bit targets with the default 4 × 4096. Every
+/// estimate is the smallest of `depth` counters, so depth makes a badly overestimated key less
+/// likely, and width makes every overestimate smaller.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SketchSize {
+    pub depth: usize,
+    pub width: usize,
+}
+
+impl SketchSize {
+    /// Sizes the sketch for a cache of the given capacity. Estimates are off by no more than
+    /// `error` times the average number of reads per cached key, with ~98% probability.
+    pub fn for_capacity(capacity: usize, error: f64) -> Self {
+        assert!(error > 0.0, "error bound must be positive");
+
+        SketchSize {
+            depth: 4,
+            width: (std::f64::consts::E * capacity.max(1) as f64 / error).ceil() as usize,
+        }
+    }
+}
+
+impl Default for SketchSize {
+    fn default() -> Self {
+        SketchSize {
+            depth: 4,
+            width: 4096,
+        }
+    }
+}
+
+/// Options of client sockets.
+#[derive(Debug, Clone)]
+pub struct SocketConfig {
+    /// Disable Nagle's algorithm, so that short responses are sent right away instead of waiting
+    /// to be coalesced with more data.
+    pub nodelay: bool,
+
+    /// TCP keepalive probing of idle connections, so that the server notices clients that are
+    /// gone without closing their connections. None disables keepalive, which also suits
+    /// sockets that don't support it.
+    pub keepalive: Option<Keepalive>,
+}
+
+impl Default for SocketConfig {
+    fn default() -> Self {
+        SocketConfig {
+            nodelay: true,
+            keepalive: Some(Keepalive::default()),
+        }
+    }
+}
+
+/// TCP keepalive timings.
+#[derive(Debug, Clone)]
+pub struct Keepalive {
+    /// How long a connection stays idle before the first probe is sent.
+    pub time: Duration,
+
+    /// Time between probes when a probe is not answered.
+    pub interval: Duration,
+
+    /// How many unanswered probes it takes to drop the connection.
+    pub retries: u32,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Keepalive {
+            time: Duration::from_secs(30),
+            interval: Duration::from_secs(30),
+            retries: 3,
+        }
+    }
+}
@@ -0,0 +1,253 @@
+use crate::engine::{Command, Engine};
+use crate::{Config, Responder, WalStorage};
+use bytes::Bytes;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+/// Handle to a database running in the same process, for services that embed it instead of
+/// talking to a server. Engine runs in a background task and takes the same commands the server
+/// sends it, there is no protocol in between. Handle can be cloned, all the clones talk to the
+/// same engine, which stops once every clone is dropped or close is called.
+#[derive(Debug, Clone)]
+pub struct Db {
+    req_tx: mpsc::Sender<Command>,
+}
+
+impl Db {
+    /// Starts the engine over the given storages. Has to be called within a tokio runtime.
+    pub fn open<T: crate::AsyncStorage, W: WalStorage>(
+        storage: T,
+        wal_storage: W,
+        config: Config,
+    ) -> Self {
+        let (req_tx, req_rx) = mpsc::channel(config.engine_queue_len.max(1));
+        let engine = Engine::new(req_rx, config);
+        tokio::spawn(engine.run(storage, wal_storage));
+
+        Db { req_tx }
+    }
+
+    pub async fn get(&self, key: impl Into<Bytes>) -> crate::Result<Option<Bytes>> {
+        self.send(|responder| Command::Get {
+            key: key.into(),
+            fill_cache: true,
+            responder,
+        })
+        .await
+    }
+
+    /// Returns the sequence number the value was put at.
+    pub async fn set(&self, key: impl Into<Bytes>, value: impl Into<Bytes>) -> crate::Result<u64> {
+        self.send(|responder| Command::Set {
+            key: key.into(),
+            value: value.into(),
+            request_id: None,
+            responder: Some(responder),
+        })
+        .await
+    }
+
+    /// Same as set for a value that expires in ttl (see engine::Command::SetEx).
+    pub async fn set_ex(
+        &self,
+        key: impl Into<Bytes>,
+        value: impl Into<Bytes>,
+        ttl: Duration,
+    ) -> crate::Result<u64> {
+        self.send(|responder| Command::SetEx {
+            key: key.into(),
+            value: value.into(),
+            ttl,
+            responder,
+        })
+        .await
+    }
+
+    /// Sets several keys in one go (see engine::Command::SetBatch). Returns the sequence number
+    /// of the last write.
+    pub async fn set_batch(&self, pairs: Vec<(Bytes, Bytes)>) -> crate::Result<u64> {
+        self.send(|responder| Command::SetBatch { pairs, responder })
+            .await
+    }
+
+    /// Sets the key to new if its current value is the expected one, None expects no value.
+    /// Returns whether the value was swapped.
+    pub async fn cas(
+        &self,
+        key: impl Into<Bytes>,
+        expected: Option<Bytes>,
+        new: impl Into<Bytes>,
+    ) -> crate::Result<bool> {
+        self.send(|responder| Command::Cas {
+            key: key.into(),
+            expected,
+            new: new.into(),
+            responder,
+        })
+        .await
+    }
+
+    /// Returns the sequence number of the delete.
+    pub async fn delete(&self, key: impl Into<Bytes>) -> crate::Result<u64> {
+        self.send(|responder| Command::Delete {
+            key: key.into(),
+            responder,
+        })
+        .await
+    }
+
+    /// Returns the length of the new value (see engine::Command::Append).
+    pub async fn append(
+        &self,
+        key: impl Into<Bytes>,
+        suffix: impl Into<Bytes>,
+    ) -> crate::Result<u64> {
+        self.send(|responder| Command::Append {
+            key: key.into(),
+            suffix: suffix.into(),
+            responder,
+        })
+        .await
+    }
+
+    /// Lists up to limit first keys within [start, end) in order.
+    pub async fn keys(
+        &self,
+        start: impl Into<Bytes>,
+        end: impl Into<Bytes>,
+        limit: usize,
+    ) -> crate::Result<Vec<Bytes>> {
+        self.send(|responder| Command::ScanKeys {
+            start: start.into(),
+            end: end.into(),
+            limit,
+            responder,
+        })
+        .await
+    }
+
+    /// Lists up to limit first keys within [start, end) in order along with their values.
+    pub async fn scan(
+        &self,
+        start: impl Into<Bytes>,
+        end: impl Into<Bytes>,
+        limit: usize,
+    ) -> crate::Result<Vec<(Bytes, Bytes)>> {
+        self.send(|responder| Command::Scan {
+            start: start.into(),
+            end: end.into(),
+            limit,
+            responder,
+        })
+        .await
+    }
+
+    /// Puts everything written so far to disk (see engine::Command::Sync).
+    pub async fn sync(&self) -> crate::Result<()> {
+        self.send(|responder| Command::Sync { responder }).await
+    }
+
+    /// Stops the engine once memtable is on disk. Clones of the handle get an error after that.
+    pub async fn close(self) -> crate::Result<()> {
+        self.send(|responder| Command::Shutdown { responder }).await
+    }
+
+    async fn send<R>(&self, cmd: impl FnOnce(Responder<R>) -> Command) -> crate::Result<R> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.req_tx
+            .send(cmd(resp_tx))
+            .await
+            .map_err(|_| "engine is gone")?;
+
+        resp_rx.await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::mem;
+    use crate::Storage;
+
+    #[tokio::test]
+    async fn test_db() {
+        let stor = mem::new();
+        let wal_stor = mem::new_wal();
+        let db = Db::open(stor.clone(), wal_stor.clone(), Config::default());
+
+        assert_eq!(db.get("foo").await.unwrap(), None);
+        let seq = db.set("foo", "bar").await.unwrap();
+        assert!(seq > 0);
+        assert_eq!(db.get("foo").await.unwrap(), Some(Bytes::from("bar")));
+
+        assert_eq!(db.append("foo", "baz").await.unwrap(), 6);
+        assert_eq!(db.append("qux", "1").await.unwrap(), 1);
+        db.set("zzz", "1").await.unwrap();
+        db.set_batch(vec![(Bytes::from("zzy"), Bytes::from("1"))])
+            .await
+            .unwrap();
+        assert_eq!(db.get("zzy").await.unwrap(), Some(Bytes::from("1")));
+        assert!(!db.cas("zzy", None, "2").await.unwrap());
+        assert!(db.cas("zzy", Some(Bytes::from("1")), "2").await.unwrap());
+
+        assert_eq!(
+            db.keys("a", "z", 10).await.unwrap(),
+            vec![Bytes::from("foo"), Bytes::from("qux")]
+        );
+        db.delete("qux").await.unwrap();
+        assert_eq!(db.get("qux").await.unwrap(), None);
+        assert_eq!(
+            db.keys("a", "z", 10).await.unwrap(),
+            vec![Bytes::from("foo")]
+        );
+        assert_eq!(
+            db.scan("a", "zzz", 10).await.unwrap(),
+            vec![
+                (Bytes::from("foo"), Bytes::from("barbaz")),
+                (Bytes::from("zzy"), Bytes::from("2"))
+            ]
+        );
+        assert_eq!(db.scan("a", "zzz", 1).await.unwrap().len(), 1);
+        assert!(matches!(
+            db.set("", "bar").await,
+            Err(crate::Error::InvalidInput(_))
+        ));
+
+        db.sync().await.unwrap();
+        let other = db.clone();
+        db.close().await.unwrap();
+        assert_eq!(stor.list_entries().unwrap().len(), 1, "memtable is flushed");
+        assert!(other.get("foo").await.is_err(), "engine is stopped");
+
+        let db = Db::open(stor, wal_stor, Config::default());
+        assert_eq!(db.get("foo").await.unwrap(), Some(Bytes::from("barbaz")));
+    }
+
+    #[tokio::test]
+    async fn test_engine_queue_len() {
+        let config = Config {
+            engine_queue_len: 2,
+            ..Default::default()
+        };
+        let db = Db::open(mem::new(), mem::new_wal(), config);
+        assert_eq!(db.req_tx.max_capacity(), 2);
+
+        // Engine doesn't get to run on this thread until the test yields, so the queue fills up.
+        let get = || {
+            let (responder, _) = oneshot::channel();
+            Command::Get {
+                key: Bytes::from("foo"),
+                fill_cache: true,
+                responder,
+            }
+        };
+        db.req_tx.try_send(get()).unwrap();
+        db.req_tx.try_send(get()).unwrap();
+        assert!(matches!(
+            db.req_tx.try_send(get()),
+            Err(mpsc::error::TrySendError::Full(_))
+        ));
+
+        assert_eq!(db.get("foo").await.unwrap(), None, "senders wait instead");
+    }
+}
@@ -0,0 +1,65 @@
+use std::collections::{HashMap, VecDeque};
+
+/// Remembers ids of the latest applied requests, so that a retried request is acknowledged
+/// without being applied once again. Along with an id the sequence number the request was
+/// applied at is kept, so the retry gets the same answer. Only the last `capacity` ids are kept,
+/// the oldest one is forgotten first. Ids are not logged, so they are forgotten on restart too.
+#[derive(Debug)]
+pub struct Dedupe {
+    capacity: usize,
+    order: VecDeque<u64>,
+    seen: HashMap<u64, u64>,
+}
+
+impl Dedupe {
+    pub fn new(capacity: usize) -> Self {
+        Dedupe {
+            capacity,
+            order: VecDeque::with_capacity(capacity),
+            seen: HashMap::with_capacity(capacity),
+        }
+    }
+
+    /// Returns the sequence number the request with the id was applied at, if it is remembered.
+    pub fn get(&self, id: u64) -> Option<u64> {
+        self.seen.get(&id).copied()
+    }
+
+    /// Remembers the id of an applied request. Does nothing if the capacity is zero.
+    pub fn record(&mut self, id: u64, seq: u64) {
+        if self.capacity == 0 || self.seen.insert(id, seq).is_some() {
+            return;
+        }
+
+        self.order.push_back(id);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window() {
+        let mut dedupe = Dedupe::new(2);
+        dedupe.record(1, 10);
+        dedupe.record(2, 20);
+        dedupe.record(2, 20);
+        assert_eq!(dedupe.get(1), Some(10));
+        assert_eq!(dedupe.get(2), Some(20));
+
+        dedupe.record(3, 30);
+        assert_eq!(dedupe.get(1), None, "oldest id is forgotten");
+        assert_eq!(dedupe.get(2), Some(20));
+        assert_eq!(dedupe.get(3), Some(30));
+
+        let mut dedupe = Dedupe::new(0);
+        dedupe.record(1, 10);
+        assert_eq!(dedupe.get(1), None);
+    }
+}
@@ -0,0 +1,121 @@
+use crate::{Advice, StorageEntry};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// How a table is read, which decides the hints given about it.
+#[derive(Debug, Clone, Copy)]
+pub enum Access {
+    /// A few blocks of the table are read, it is likely to be read again soon.
+    Lookup,
+    /// The table is read through once, its pages should not push out the ones lookups need.
+    Scan,
+}
+
+/// Hint counters since the start (see Config::os_cache_advice).
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct AdviceStats {
+    pub will_need: u64,
+    pub sequential: u64,
+    pub dont_need: u64,
+    /// Hints the storage refused, they are not counted above.
+    pub failed: u64,
+}
+
+/// Gives storage hints about how tables are read. Clones share the counters, so that reads
+/// made off the dispatcher task, like compaction, are counted too.
+#[derive(Debug, Clone, Default)]
+pub struct Advisor {
+    enabled: bool,
+    counters: Arc<Counters>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    will_need: AtomicU64,
+    sequential: AtomicU64,
+    dont_need: AtomicU64,
+    failed: AtomicU64,
+}
+
+impl Advisor {
+    pub fn new(enabled: bool) -> Self {
+        Advisor {
+            enabled,
+            ..Default::default()
+        }
+    }
+
+    /// Called before the table is read.
+    pub fn before<E: StorageEntry>(&self, blob: &E, access: Access) {
+        match access {
+            Access::Lookup => self.advise(blob, Advice::WillNeed),
+            Access::Scan => self.advise(blob, Advice::Sequential),
+        }
+    }
+
+    /// Called once the table is read.
+    pub fn after<E: StorageEntry>(&self, blob: &E, access: Access) {
+        match access {
+            Access::Lookup => {}
+            Access::Scan => self.advise(blob, Advice::DontNeed),
+        }
+    }
+
+    pub fn stats(&self) -> AdviceStats {
+        AdviceStats {
+            will_need: self.counters.will_need.load(Ordering::Relaxed),
+            sequential: self.counters.sequential.load(Ordering::Relaxed),
+            dont_need: self.counters.dont_need.load(Ordering::Relaxed),
+            failed: self.counters.failed.load(Ordering::Relaxed),
+        }
+    }
+
+    /// A hint that fails is only counted, reads go on as they would without it.
+    fn advise<E: StorageEntry>(&self, blob: &E, advice: Advice) {
+        if !self.enabled {
+            return;
+        }
+
+        let counter = match blob.advise(advice) {
+            Ok(()) => match advice {
+                Advice::WillNeed => &self.counters.will_need,
+                Advice::Sequential => &self.counters.sequential,
+                Advice::DontNeed => &self.counters.dont_need,
+            },
+            Err(e) => {
+                tracing::debug!("could not advise {:?}: {}", advice, e);
+                &self.counters.failed
+            }
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_advisor() {
+        let blob = vec![0u8; 16];
+        let advisor = Advisor::new(true);
+        advisor.before(&blob, Access::Lookup);
+        advisor.after(&blob, Access::Lookup);
+        let clone = advisor.clone();
+        clone.before(&blob, Access::Scan);
+        clone.after(&blob, Access::Scan);
+        assert_eq!(
+            advisor.stats(),
+            AdviceStats {
+                will_need: 1,
+                sequential: 1,
+                dont_need: 1,
+                failed: 0,
+            }
+        );
+
+        let disabled = Advisor::new(false);
+        disabled.before(&blob, Access::Scan);
+        assert_eq!(disabled.stats(), AdviceStats::default());
+    }
+}
@@ -0,0 +1,103 @@
+use std::time::Duration;
+
+/// Weight of the latest persist in the average persist latency, out of 8.
+const LATENCY_WEIGHT: u32 = 2;
+
+/// Limit of tables that are allowed to wait for their turn to be persisted while the engine
+/// keeps on writing. The limit adapts to write pressure within given bounds: it doubles every
+/// time tables keep coming while one is persisted or a persist takes much longer than usual, and
+/// goes down by one every time the dispatcher catches up with the queue. So it grows quickly
+/// under a burst of writes and slowly returns to the lower bound when writes calm down.
+#[derive(Debug)]
+pub struct BufferLimit {
+    min: usize,
+    max: usize,
+    current: usize,
+    avg_latency: Option<Duration>,
+}
+
+impl BufferLimit {
+    pub fn new(min: usize, max: usize) -> Self {
+        let min = min.max(1);
+        let max = max.max(min);
+
+        BufferLimit {
+            min,
+            max,
+            current: min,
+            avg_latency: None,
+        }
+    }
+
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    /// Adjusts the limit after a table is persisted. Queue depth is the number of commands that
+    /// came in while the table was persisted.
+    pub fn on_persist(&mut self, latency: Duration, queue_depth: usize) {
+        let slow = match self.avg_latency {
+            Some(avg) => latency > avg * 2,
+            None => false,
+        };
+
+        self.avg_latency = Some(match self.avg_latency {
+            Some(avg) => (avg * (8 - LATENCY_WEIGHT) + latency * LATENCY_WEIGHT) / 8,
+            None => latency,
+        });
+
+        if queue_depth > 0 || slow {
+            self.current = (self.current * 2).min(self.max);
+        } else {
+            self.current = self.current.saturating_sub(1).max(self.min);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limit_adapts() {
+        let latency = Duration::from_millis(10);
+        let mut limit = BufferLimit::new(2, 32);
+        assert_eq!(limit.current(), 2);
+
+        // Write burst: tables keep queueing up while they are persisted.
+        let mut prev = limit.current();
+        for _ in 0..4 {
+            limit.on_persist(latency, 3);
+            assert!(limit.current() > prev);
+            prev = limit.current();
+        }
+        limit.on_persist(latency, 3);
+        assert_eq!(limit.current(), 32, "limit must not exceed the upper bound");
+
+        // Idle: the queue is drained every time.
+        limit.on_persist(latency, 0);
+        assert_eq!(limit.current(), 31);
+        for _ in 0..100 {
+            limit.on_persist(latency, 0);
+        }
+        assert_eq!(
+            limit.current(),
+            2,
+            "limit must not go below the lower bound"
+        );
+
+        // Persist got a lot slower than usual with no queue yet.
+        limit.on_persist(latency * 5, 0);
+        assert_eq!(limit.current(), 4);
+    }
+
+    #[test]
+    fn test_limit_bounds() {
+        let limit = BufferLimit::new(0, 0);
+        assert_eq!(limit.current(), 1);
+
+        let mut limit = BufferLimit::new(8, 4);
+        limit.on_persist(Duration::from_millis(1), 10);
+        assert_eq!(limit.current(), 8);
+    }
+}
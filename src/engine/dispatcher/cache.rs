@@ -0,0 +1,525 @@
+use crate::SketchSize;
+use bytes::Bytes;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::warn;
+use uuid::Uuid;
+
+/// Once this many reads per counter in a row are recorded, all the counters are halved so that
+/// frequencies reflect recent reads rather than reads since the start.
+const CMS_RESET_FACTOR: usize = 8;
+
+/// How many missing keys are remembered at most.
+const MISSING_CAPACITY: usize = 1000;
+
+/// Cache of values that live on disk. Dispatcher checks it before going through the tables.
+/// Read frequencies are estimated with a count-min sketch, a new value gets into a full cache
+/// only if its key is read more often than the least frequent cached key, which is evicted then.
+/// Each cached value remembers its generation, which is the id of the table it was read from.
+/// Among equally frequent keys the one from the newest table is evicted first since it is the
+/// cheapest to read again.
+///
+/// Besides that a key has to be read at least min_reads times before its value is taken even
+/// while the cache has room, so that values read once don't fill it up. Once the cache is full the
+/// frequency of the least read cached key is usually the higher bar.
+///
+/// Optionally cache also remembers keys that were not found on disk for a short time (ttl).
+///
+/// Every cached value keeps the checksum it had when it was read from disk. With verification on,
+/// the checksum is recomputed on every hit and a value that doesn't match is dropped, so the read
+/// goes to disk instead. Such failures are counted.
+///
+/// Cache must never return a value shadowed by a newer one: memtable is checked before the cache,
+/// and when a memtable gets to disk the cached values for its keys are dropped (see invalidate).
+#[derive(Debug)]
+pub struct Cache {
+    capacity: usize,
+    min_reads: usize,
+    items: HashMap<Bytes, Item>,
+    sketch: Sketch,
+    missing_ttl: Option<Duration>,
+    missing: HashMap<Bytes, Instant>,
+    verify: bool,
+    stats: CacheStats,
+}
+
+/// Cache counters since the start, to see how well the cache works for the load.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CacheStats {
+    /// Reads answered by the cache, including keys known to be missing.
+    pub hits: u64,
+    /// Reads that had to go to disk.
+    pub misses: u64,
+    /// Values read from disk and offered to the cache.
+    pub candidates: u64,
+    /// Offered values the cache took.
+    pub insertions: u64,
+    /// Values pushed out by more frequently read ones.
+    pub evictions: u64,
+    /// Cached values found corrupted (see Config::verify_on_read).
+    pub verify_failures: u64,
+    /// Number of values in the cache right now.
+    pub size: usize,
+}
+
+#[derive(Debug)]
+struct Item {
+    value: Bytes,
+    generation: Uuid,
+    checksum: u32,
+}
+
+impl Cache {
+    pub fn new(
+        capacity: usize,
+        min_reads: usize,
+        sketch: SketchSize,
+        missing_ttl: Option<Duration>,
+        verify: bool,
+    ) -> Self {
+        Cache {
+            capacity,
+            min_reads,
+            items: HashMap::with_capacity(capacity),
+            sketch: Sketch::new(sketch),
+            missing_ttl,
+            missing: HashMap::new(),
+            verify,
+            stats: CacheStats::default(),
+        }
+    }
+
+    /// Records a read of the key and returns the cached result of the read if there is one.
+    /// Some(None) means the key is known to be missing.
+    pub fn check(&mut self, key: &Bytes) -> Option<Option<Bytes>> {
+        self.sketch.increment(key);
+        let cached = self.lookup(key);
+        match cached {
+            Some(_) => self.stats.hits += 1,
+            None => self.stats.misses += 1,
+        }
+
+        cached
+    }
+
+    /// Same as check, but the read is neither recorded nor counted in stats, so that one-off
+    /// reads don't make keys look hot.
+    pub fn peek(&mut self, key: &Bytes) -> Option<Option<Bytes>> {
+        self.lookup(key)
+    }
+
+    fn lookup(&mut self, key: &Bytes) -> Option<Option<Bytes>> {
+        if let Some(item) = self.items.get(key) {
+            if !self.verify || crc32fast::hash(&item.value) == item.checksum {
+                return Some(Some(item.value.clone()));
+            }
+
+            self.stats.verify_failures += 1;
+            warn!(
+                "checksum mismatch in cached value of {:?}, dropping it ({} failures so far)",
+                key, self.stats.verify_failures
+            );
+            self.items.remove(key);
+            return None;
+        }
+
+        match self.missing.get(key) {
+            Some(expires) if *expires > Instant::now() => Some(None),
+            Some(_) => {
+                self.missing.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Remembers that the key was not found in any table. Does nothing unless missing keys
+    /// caching is on.
+    pub fn remember_missing(&mut self, key: Bytes) {
+        let Some(ttl) = self.missing_ttl else {
+            return;
+        };
+
+        let now = Instant::now();
+        if self.missing.len() >= MISSING_CAPACITY {
+            self.missing.retain(|_, expires| *expires > now);
+        }
+        if self.missing.len() >= MISSING_CAPACITY {
+            // Drop the one that expires first.
+            let oldest = self
+                .missing
+                .iter()
+                .min_by_key(|(_, expires)| **expires)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.missing.remove(&oldest);
+            }
+        }
+
+        self.missing.insert(key, now + ttl);
+    }
+
+    /// Offers a value read from a table to the cache. Returns true if it was taken.
+    pub fn try_insert(&mut self, key: Bytes, value: Bytes, generation: Uuid) -> bool {
+        self.stats.candidates += 1;
+        if self.sketch.estimate(&key) < self.min_reads {
+            return false;
+        }
+
+        self.admit(key, value, generation)
+    }
+
+    /// Same as try_insert for a value that was cached before a restart. Reads of its key are
+    /// not recorded yet, so it is not held to min_reads.
+    pub fn restore(&mut self, key: Bytes, value: Bytes, generation: Uuid) -> bool {
+        self.stats.candidates += 1;
+        self.admit(key, value, generation)
+    }
+
+    fn admit(&mut self, key: Bytes, value: Bytes, generation: Uuid) -> bool {
+        if self.capacity == 0 {
+            return false;
+        }
+
+        if self.items.len() >= self.capacity && !self.items.contains_key(&key) {
+            let victim = self
+                .items
+                .iter()
+                .map(|(k, item)| (k, self.sketch.estimate(k), item.generation))
+                .min_by(|a, b| a.1.cmp(&b.1).then(b.2.cmp(&a.2)))
+                .map(|(k, freq, _)| (k.clone(), freq));
+
+            match victim {
+                Some((victim, freq)) if freq < self.sketch.estimate(&key) => {
+                    self.items.remove(&victim);
+                    self.stats.evictions += 1;
+                }
+                _ => return false,
+            }
+        }
+
+        let checksum = crc32fast::hash(&value);
+        self.items.insert(
+            key,
+            Item {
+                value,
+                generation,
+                checksum,
+            },
+        );
+        self.stats.insertions += 1;
+
+        true
+    }
+
+    /// Drops cached values for the keys of a table that is being created, since the table
+    /// holds newer values for them.
+    pub fn invalidate<V>(&mut self, table: &BTreeMap<Bytes, V>) {
+        self.items.retain(|key, _| !table.contains_key(key));
+        self.missing.retain(|key, _| !table.contains_key(key));
+    }
+
+    /// Moves values read from compacted tables to the table they were merged into. Compaction
+    /// keeps the newest value of every key, so cached values stay valid, only their generation
+    /// changes.
+    pub fn rebase(&mut self, inputs: &[Uuid], output: Uuid) {
+        for item in self.items.values_mut() {
+            if inputs.contains(&item.generation) {
+                item.generation = output;
+            }
+        }
+    }
+
+    /// Keys of the cached values, the most frequently read ones first.
+    pub fn hot_keys(&self) -> Vec<Bytes> {
+        let mut keys: Vec<(&Bytes, usize)> = self
+            .items
+            .keys()
+            .map(|key| (key, self.sketch.estimate(key)))
+            .collect();
+        keys.sort_by_key(|(_, freq)| std::cmp::Reverse(*freq));
+
+        keys.into_iter().map(|(key, _)| key.clone()).collect()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            size: self.items.len(),
+            ..self.stats.clone()
+        }
+    }
+}
+
+/// Count-min sketch to estimate how often keys are read. Every row (bucket) hashes keys with its
+/// own seed.
+#[derive(Debug)]
+struct Sketch {
+    counters: Vec<usize>,
+    depth: usize,
+    width: usize,
+    additions: usize,
+}
+
+impl Sketch {
+    fn new(size: SketchSize) -> Self {
+        assert!(
+            size.depth > 0 && size.width > 0,
+            "sketch must have at least one counter"
+        );
+
+        Sketch {
+            counters: vec![0; size.depth * size.width],
+            depth: size.depth,
+            width: size.width,
+            additions: 0,
+        }
+    }
+
+    fn increment(&mut self, key: &Bytes) {
+        for bucket in 0..self.depth {
+            let pos = self.position(bucket, key);
+            self.counters[pos] = self.counters[pos].saturating_add(1);
+        }
+
+        self.additions += 1;
+        if self.additions >= self.width * CMS_RESET_FACTOR {
+            self.counters.iter_mut().for_each(|counter| *counter /= 2);
+            self.additions = 0;
+        }
+    }
+
+    fn estimate(&self, key: &Bytes) -> usize {
+        (0..self.depth)
+            .map(|bucket| self.counters[self.position(bucket, key)])
+            .min()
+            .unwrap_or(0)
+    }
+
+    fn position(&self, bucket: usize, key: &Bytes) -> usize {
+        let mut hasher = DefaultHasher::new();
+        bucket.hash(&mut hasher);
+        key.hash(&mut hasher);
+
+        bucket * self.width + (hasher.finish() as usize % self.width)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generation(n: u128) -> Uuid {
+        Uuid::from_u128(n)
+    }
+
+    #[test]
+    fn test_check_and_insert() {
+        let mut cache = Cache::new(2, 0, SketchSize::default(), None, false);
+        let (foo, bar, baz) = (Bytes::from("foo"), Bytes::from("bar"), Bytes::from("baz"));
+
+        assert!(cache.check(&foo).is_none());
+        assert!(cache.try_insert(foo.clone(), Bytes::from("1"), generation(1)));
+        assert!(cache.try_insert(bar.clone(), Bytes::from("2"), generation(1)));
+        assert_eq!(cache.check(&foo), Some(Some(Bytes::from("1"))));
+
+        // Cache is full and baz was never read, so it is not more frequent than anything cached.
+        assert!(!cache.try_insert(baz.clone(), Bytes::from("3"), generation(1)));
+
+        for _ in 0..3 {
+            cache.check(&baz);
+        }
+        assert!(cache.try_insert(baz.clone(), Bytes::from("3"), generation(1)));
+        assert_eq!(cache.stats().size, 2);
+        assert!(cache.check(&bar).is_none(), "least frequent key is evicted");
+        assert_eq!(cache.check(&foo), Some(Some(Bytes::from("1"))));
+    }
+
+    #[test]
+    fn test_capacity_and_min_reads() {
+        let keys: Vec<Bytes> = (0..20).map(|i| Bytes::from(format!("key-{i}"))).collect();
+        let fill = |cache: &mut Cache| {
+            for key in &keys {
+                cache.check(key);
+                cache.check(key);
+                cache.try_insert(key.clone(), Bytes::from("v"), generation(1));
+            }
+        };
+
+        let mut small = Cache::new(5, 0, SketchSize::default(), None, false);
+        fill(&mut small);
+        let mut large = Cache::new(20, 0, SketchSize::default(), None, false);
+        fill(&mut large);
+        assert_eq!((small.stats().size, large.stats().size), (5, 20));
+
+        // Every key was read twice, not enough for a threshold of three.
+        let mut strict = Cache::new(20, 3, SketchSize::default(), None, false);
+        fill(&mut strict);
+        assert_eq!(strict.stats().size, 0);
+
+        let foo = Bytes::from("foo");
+        strict.check(&foo);
+        strict.check(&foo);
+        assert!(!strict.try_insert(foo.clone(), Bytes::from("1"), generation(1)));
+        strict.check(&foo);
+        assert!(strict.try_insert(foo.clone(), Bytes::from("1"), generation(1)));
+        assert!(
+            strict.restore(Bytes::from("bar"), Bytes::from("2"), generation(1)),
+            "restored values skip the threshold"
+        );
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut cache = Cache::new(1, 0, SketchSize::default(), None, false);
+        let (foo, bar) = (Bytes::from("foo"), Bytes::from("bar"));
+
+        cache.check(&foo);
+        cache.try_insert(foo.clone(), Bytes::from("1"), generation(1));
+        cache.check(&foo);
+        cache.check(&foo);
+
+        // Not read as often as foo, refused.
+        cache.check(&bar);
+        cache.try_insert(bar.clone(), Bytes::from("2"), generation(1));
+
+        for _ in 0..3 {
+            cache.check(&bar);
+        }
+        cache.try_insert(bar.clone(), Bytes::from("2"), generation(1));
+
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 2,
+                misses: 5,
+                candidates: 3,
+                insertions: 2,
+                evictions: 1,
+                verify_failures: 0,
+                size: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_sketch_width() {
+        // Every key is read 3 times, total overestimate tells how much keys collide.
+        let keys: Vec<Bytes> = (0..1000)
+            .map(|n| Bytes::from(format!("key-{}", n)))
+            .collect();
+        let overestimate = |width| {
+            let mut sketch = Sketch::new(SketchSize { depth: 4, width });
+            for _ in 0..3 {
+                keys.iter().for_each(|key| sketch.increment(key));
+            }
+            keys.iter()
+                .map(|key| sketch.estimate(key) - 3)
+                .sum::<usize>()
+        };
+
+        let narrow = overestimate(512);
+        let wide = overestimate(SketchSize::for_capacity(1000, 0.1).width);
+        assert!(narrow > 0);
+        assert!(
+            wide < narrow,
+            "wide sketch overestimates by {}, narrow one by {}",
+            wide,
+            narrow
+        );
+    }
+
+    #[test]
+    fn test_invalidate() {
+        let mut cache = Cache::new(10, 0, SketchSize::default(), None, false);
+        cache.try_insert(Bytes::from("foo"), Bytes::from("old"), generation(1));
+        cache.try_insert(Bytes::from("bar"), Bytes::from("old"), generation(1));
+
+        let mut table = BTreeMap::new();
+        table.insert(Bytes::from("foo"), Bytes::from("new"));
+        cache.invalidate(&table);
+
+        assert!(cache.check(&Bytes::from("foo")).is_none());
+        assert_eq!(
+            cache.check(&Bytes::from("bar")),
+            Some(Some(Bytes::from("old")))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_missing() {
+        let key = Bytes::from("foo");
+
+        let mut cache = Cache::new(10, 0, SketchSize::default(), None, false);
+        cache.remember_missing(key.clone());
+        assert!(
+            cache.check(&key).is_none(),
+            "missing keys are not cached by default"
+        );
+
+        let mut cache = Cache::new(
+            10,
+            0,
+            SketchSize::default(),
+            Some(Duration::from_millis(50)),
+            false,
+        );
+        cache.remember_missing(key.clone());
+        assert_eq!(cache.check(&key), Some(None));
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(cache.check(&key).is_none(), "missing key expires");
+
+        cache.remember_missing(key.clone());
+        let mut table = BTreeMap::new();
+        table.insert(key.clone(), Bytes::from("bar"));
+        cache.invalidate(&table);
+        assert!(
+            cache.check(&key).is_none(),
+            "written key is no longer missing"
+        );
+    }
+
+    #[test]
+    fn test_verify_on_read() {
+        let key = Bytes::from("foo");
+        let corrupt = |cache: &mut Cache| {
+            cache.items.get_mut(&key).unwrap().value = Bytes::from("fOo");
+        };
+
+        let mut cache = Cache::new(10, 0, SketchSize::default(), None, false);
+        cache.try_insert(key.clone(), Bytes::from("foo"), generation(1));
+        corrupt(&mut cache);
+        assert_eq!(
+            cache.check(&key),
+            Some(Some(Bytes::from("fOo"))),
+            "values are not verified by default"
+        );
+        assert_eq!(cache.stats().verify_failures, 0);
+
+        let mut cache = Cache::new(10, 0, SketchSize::default(), None, true);
+        cache.try_insert(key.clone(), Bytes::from("foo"), generation(1));
+        assert_eq!(cache.check(&key), Some(Some(Bytes::from("foo"))));
+
+        corrupt(&mut cache);
+        assert!(cache.check(&key).is_none(), "corrupted value is not served");
+        assert_eq!(cache.stats().verify_failures, 1);
+        assert_eq!(cache.stats().size, 0, "corrupted value is dropped");
+    }
+
+    #[test]
+    fn test_rebase() {
+        let mut cache = Cache::new(10, 0, SketchSize::default(), None, false);
+        cache.try_insert(Bytes::from("foo"), Bytes::from("1"), generation(1));
+        cache.try_insert(Bytes::from("bar"), Bytes::from("2"), generation(2));
+        cache.try_insert(Bytes::from("baz"), Bytes::from("3"), generation(3));
+
+        cache.rebase(&[generation(2), generation(1)], generation(4));
+
+        assert_eq!(cache.items[&Bytes::from("foo")].generation, generation(4));
+        assert_eq!(cache.items[&Bytes::from("bar")].generation, generation(4));
+        assert_eq!(cache.items[&Bytes::from("baz")].generation, generation(3));
+    }
+}
@@ -1 +1,717 @@
+use super::advice::{Access, Advisor};
+use super::index::{Entry, Index};
+use crate::engine::memtable::{MemTable, ProbeResult, SsTableSize, Value, SSTABLE_BYTESIZE};
+use crate::engine::sstable::{Format, SsTable, MAX_LEVEL};
+use crate::Clock;
+use crate::Result;
+use crate::StorageEntry;
+use bytes::Bytes;
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio::time::Instant;
+use uuid::Uuid;
 
+/// Bytes level 1 may take, every deeper level may take LEVEL_SIZE_RATIO times more than the one
+/// above. Once a level is over, its tables are pushed down one at a time.
+const LEVEL1_MAX_BYTES: u64 = 10 * SSTABLE_BYTESIZE as u64;
+const LEVEL_SIZE_RATIO: u64 = 10;
+
+/// Tables picked for a compaction along with where its output goes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Plan {
+    /// Tables to merge, from the newest to the oldest.
+    pub inputs: Vec<Uuid>,
+    /// Level the output tables are put to.
+    pub level: u8,
+    /// There are no tables below the output level, so tombstones have nothing left to hide.
+    pub bottom: bool,
+}
+
+/// Picks the tables to compact next with the leveled policy. Tables of level 0 overlap, so with
+/// flush_level0 set all of them go down to level 1 at once, along with the level 1 tables they
+/// overlap. Otherwise the oldest table of the first level over its size (see LEVEL1_MAX_BYTES)
+/// goes one level down, along with the tables of that level it overlaps. Damaged tables can't
+/// be merged, they are left out. None if there is nothing to do.
+pub fn pick(index: &Index, flush_level0: bool) -> Option<Plan> {
+    let level0: Vec<&Entry> = index.level(0).filter(|entry| !entry.damaged).collect();
+    if flush_level0 && !level0.is_empty() {
+        return Some(push_down(index, &level0, 0));
+    }
+
+    let deepest = index.deepest()?.min(MAX_LEVEL - 1);
+    (1..=deepest)
+        .find(|level| index.level_size(*level) > level_max_bytes(*level))
+        .and_then(|level| oldest(index, level).map(|entry| push_down(index, &[entry], level)))
+}
+
+/// Same as pick with level 0 flushed, but when no level is over its size the oldest table of the
+/// shallowest level that has tables below it is pushed down anyway. Tables only go down, so
+/// compacting again and again comes to the point where there is nothing to do.
+pub fn pick_any(index: &Index) -> Option<Plan> {
+    pick(index, true).or_else(|| {
+        let deepest = index.deepest()?;
+        (1..deepest)
+            .find_map(|level| oldest(index, level).map(|entry| push_down(index, &[entry], level)))
+    })
+}
+
+fn level_max_bytes(level: u8) -> u64 {
+    LEVEL1_MAX_BYTES.saturating_mul(LEVEL_SIZE_RATIO.saturating_pow(level as u32 - 1))
+}
+
+fn oldest(index: &Index, level: u8) -> Option<&Entry> {
+    index.level(level).min_by_key(|entry| entry.id)
+}
+
+/// Plan merging the tables of the level with the tables of the next level they overlap.
+fn push_down(index: &Index, tables: &[&Entry], level: u8) -> Plan {
+    let first_key = tables.iter().map(|entry| &entry.first_key).min();
+    let last_key = tables.iter().map(|entry| &entry.last_key).max();
+    let (Some(first_key), Some(last_key)) = (first_key, last_key) else {
+        panic!("Nothing to push down");
+    };
+
+    let mut inputs: Vec<Uuid> = tables.iter().map(|entry| entry.id).collect();
+    inputs.extend(
+        index
+            .overlapping(level + 1, first_key, last_key)
+            .iter()
+            .map(|entry| entry.id),
+    );
+
+    Plan {
+        inputs,
+        level: level + 1,
+        bottom: index.deepest() <= Some(level + 1),
+    }
+}
+
+/// Merges the tables of the plan and persists the result, returns index entries of the new
+/// tables. It is meant to be run outside of the dispatcher loop, so it only reads tables and
+/// writes new ones, index stays untouched.
+///
+/// For a key present in several inputs the value from the newest one wins. Output of level 0 is
+/// a single table whose id directly follows the newest input id, so that it takes the place of
+/// its inputs among the level 0 tables and tables flushed while compaction was running still
+/// shadow it. Output of a deeper level is split into tables of the default size with new ids,
+/// tables of a level are read in the order of their keys.
+///
+/// The last table written holds the ids of the inputs, so a compaction whose outputs are all on
+/// disk is done even if a crash stops the inputs from being deleted (see Dispatcher::init).
+///
+/// With validation on the merged tables are checked before they are written and nothing is
+/// written if one is invalid. Merged tables are laid out the way format says. With a
+/// throttle, bytes read from every input and bytes written are taken from its budget and merge
+/// sleeps whenever it is spent. Inputs are read through once, the advisor is told so.
+///
+/// Values expired by the clock time are dropped. A tombstone is kept in place of each of them,
+/// as of every deleted key, since the key might have values in tables below the merged ones,
+/// unless the output goes to the bottom level.
+pub async fn merge<T: crate::AsyncStorage>(
+    storage: &T,
+    plan: &Plan,
+    validate: bool,
+    format: Format,
+    mut throttle: Option<Throttle>,
+    advisor: Advisor,
+    clock: &dyn Clock,
+) -> Result<Vec<Entry>> {
+    assert!(!plan.inputs.is_empty(), "Nothing to merge");
+    let now = clock.since_epoch().as_millis() as u64;
+
+    // Go from the oldest to the newest so that newer values overwrite older ones. Values keep
+    // their sequence numbers, so older versions are gone but the kept ones are read as of the
+    // same writes. Decoding and building a table is heavy, so it is kept off the runtime threads.
+    let mut merged = BTreeMap::new();
+    for table_id in plan.inputs.iter().rev() {
+        let blob = Metered::new(storage.open(table_id).await?);
+        let advisor = advisor.clone();
+        let (entries, bytes_read) = tokio::task::spawn_blocking(move || -> Result<_> {
+            advisor.before(&blob, Access::Scan);
+            let entries = SsTable::decode(&blob);
+            advisor.after(&blob, Access::Scan);
+            Ok((entries?, blob.bytes_read.get()))
+        })
+        .await??;
+        for (key, (value, seq, flags)) in entries {
+            merged.insert(key, (value.expire(now), seq, flags));
+        }
+
+        if let Some(throttle) = throttle.as_mut() {
+            throttle.consume(bytes_read).await;
+        }
+    }
+
+    let mut outputs = Vec::new();
+    let tables = split(merged, plan.level > 0, plan.bottom);
+    let last = tables.len().saturating_sub(1);
+    for (num, data) in tables.into_iter().enumerate() {
+        let id = match plan.level {
+            0 => next_id(&plan.inputs[0]),
+            _ => SsTable::generate_id(clock),
+        };
+        let level = plan.level;
+        let (first_key, last_key) = match (data.iter().next(), data.iter().last()) {
+            (Some((first, _)), Some((last, _))) => (first.clone(), last.clone()),
+            _ => unreachable!("split tables are never empty"),
+        };
+        let replaces = match num == last {
+            true => plan.inputs.clone(),
+            false => Vec::new(),
+        };
+        let encoded = tokio::task::spawn_blocking(move || -> Result<Vec<u8>> {
+            let mut table = SsTable::build_with_format(data, id, format);
+            table.level = level;
+            table.replaces = replaces;
+            if validate {
+                table.validate()?;
+            }
+
+            Ok(table.encode())
+        })
+        .await??;
+
+        if let Some(throttle) = throttle.as_mut() {
+            throttle.consume(encoded.len() as u64).await;
+        }
+        storage.write(&id, &encoded).await?;
+
+        outputs.push(Entry {
+            id,
+            size: encoded.len() as u64,
+            level,
+            first_key,
+            last_key,
+            damaged: false,
+        });
+    }
+
+    Ok(outputs)
+}
+
+/// Puts merged entries to tables, in order of their keys. With limit_size a table is only filled
+/// up to the default size and the next one is started, otherwise all of them go to a single
+/// table. With drop_tombstones set deleted keys are left out.
+fn split(
+    merged: BTreeMap<Bytes, (Value, u64, u8)>,
+    limit_size: bool,
+    drop_tombstones: bool,
+) -> Vec<MemTable> {
+    let mut tables = Vec::new();
+    let mut table = MemTable::new(SsTableSize::Default);
+    for (key, (value, seq, flags)) in merged {
+        if drop_tombstones && value.is_tombstone() {
+            continue;
+        }
+
+        let size = match limit_size {
+            true => match table.probe(&key, &value) {
+                ProbeResult::Available(size) => Some(size),
+                ProbeResult::Full => {
+                    tables.push(std::mem::replace(
+                        &mut table,
+                        MemTable::new(SsTableSize::Default),
+                    ));
+                    None
+                }
+            },
+            false => None,
+        };
+        table.insert_typed(key, value, flags, seq, size);
+    }
+
+    if !table.is_empty() {
+        tables.push(table);
+    }
+
+    tables
+}
+
+/// Token bucket limiting bytes per second. Bucket holds up to a second worth of bytes. Taking
+/// more than there is leaves it in debt, which is paid off by sleeping, so that a single big
+/// read or write is not refused but the ones after it wait longer.
+#[derive(Debug)]
+pub struct Throttle {
+    rate: u64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Throttle {
+    pub fn new(rate: u64) -> Self {
+        assert!(rate > 0, "I/O budget must be positive");
+
+        Throttle {
+            rate,
+            tokens: rate as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Takes bytes from the budget, sleeps until the budget is back if it went into debt.
+    pub async fn consume(&mut self, bytes: u64) {
+        let now = Instant::now();
+        let refill = now.duration_since(self.updated).as_secs_f64() * self.rate as f64;
+        self.tokens = (self.tokens + refill).min(self.rate as f64) - bytes as f64;
+        self.updated = now;
+
+        if self.tokens < 0.0 {
+            let debt = Duration::from_secs_f64(-self.tokens / self.rate as f64);
+            tokio::time::sleep(debt).await;
+        }
+    }
+}
+
+/// Table entry that counts bytes read from it.
+struct Metered<E> {
+    inner: E,
+    bytes_read: Cell<u64>,
+}
+
+impl<E> Metered<E> {
+    fn new(inner: E) -> Self {
+        Metered {
+            inner,
+            bytes_read: Cell::new(0),
+        }
+    }
+}
+
+impl<E: StorageEntry> StorageEntry for Metered<E> {
+    fn read_at(&self, data: &mut Vec<u8>, position: u64) -> std::io::Result<()> {
+        let res = self.inner.read_at(data, position);
+        self.bytes_read
+            .set(self.bytes_read.get() + data.len() as u64);
+        res
+    }
+
+    fn size(&self) -> std::io::Result<u64> {
+        self.inner.size()
+    }
+
+    fn advise(&self, advice: crate::Advice) -> std::io::Result<()> {
+        self.inner.advise(advice)
+    }
+}
+
+/// Returns an id that sorts right after the given one.
+fn next_id(id: &Uuid) -> Uuid {
+    Uuid::from_u128(id.as_u128() + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::sstable::Placement;
+    use crate::storage::mem;
+    use crate::Storage;
+    use crate::{ManualClock, SystemClock};
+    use std::time::UNIX_EPOCH;
+
+    /// Plan rewriting the tables to a single level 0 table.
+    fn level0(inputs: &[Uuid]) -> Plan {
+        Plan {
+            inputs: inputs.to_vec(),
+            level: 0,
+            bottom: false,
+        }
+    }
+
+    fn write_table<T: Storage>(
+        storage: &T,
+        seq: u64,
+        pairs: &[(&'static str, &'static str)],
+    ) -> Uuid {
+        let mut mt = MemTable::new(SsTableSize::Default);
+        for (key, value) in pairs {
+            mt.insert(Bytes::from(*key), Bytes::from(*value), seq, None);
+        }
+
+        let table = SsTable::build(mt);
+        storage.write(&table.id, &table.encode()).unwrap();
+        table.id
+    }
+
+    #[tokio::test]
+    async fn test_merge() {
+        let stor = mem::new();
+        let older = write_table(&stor, 1, &[("a", "old"), ("b", "old")]);
+        let newer = write_table(&stor, 2, &[("b", "new"), ("c", "new")]);
+
+        let output = merge(
+            &stor,
+            &level0(&[newer, older]),
+            true,
+            Format::default(),
+            None,
+            Advisor::default(),
+            &SystemClock,
+        )
+        .await
+        .unwrap()
+        .remove(0);
+        let (output, size) = (output.id, output.size);
+        assert!(output > newer, "output should sort after its newest input");
+
+        let blob = stor.open(&output).unwrap();
+        assert_eq!(blob.len() as u64, size);
+        let merged = SsTable::decode(&blob).unwrap();
+        assert_eq!(merged.len(), 3);
+        assert_eq!(
+            merged.get(&Bytes::from("a")),
+            Some(&(Value::Live(Bytes::from("old")), 1, 0))
+        );
+        assert_eq!(
+            merged.get(&Bytes::from("b")),
+            Some(&(Value::Live(Bytes::from("new")), 2, 0))
+        );
+        assert_eq!(
+            merged.get(&Bytes::from("c")),
+            Some(&(Value::Live(Bytes::from("new")), 2, 0))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_merge_tombstones() {
+        let stor = mem::new();
+        let older = write_table(&stor, 1, &[("a", "old"), ("b", "old")]);
+        let mut mt = MemTable::new(SsTableSize::Default);
+        mt.insert_typed(Bytes::from("a"), Value::Tombstone, 0, 2, None);
+        let newer = SsTable::build(mt);
+        stor.write(&newer.id, &newer.encode()).unwrap();
+
+        let output = merge(
+            &stor,
+            &level0(&[newer.id, older]),
+            true,
+            Format::default(),
+            None,
+            Advisor::default(),
+            &SystemClock,
+        )
+        .await
+        .unwrap()
+        .remove(0)
+        .id;
+
+        // Tombstone stays, the key might have values in tables older than the merged ones.
+        let blob = stor.open(&output).unwrap();
+        assert_eq!(
+            SsTable::lookup(&blob, &Bytes::from("a"), 0, None).unwrap(),
+            Some(Value::Tombstone)
+        );
+        let merged = SsTable::decode(&blob).unwrap();
+        assert_eq!(
+            merged.get(&Bytes::from("a")),
+            Some(&(Value::Tombstone, 2, 0))
+        );
+        assert_eq!(
+            merged.get(&Bytes::from("b")),
+            Some(&(Value::Live(Bytes::from("old")), 1, 0))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_merge_expired() {
+        let stor = mem::new();
+        let older = write_table(&stor, 1, &[("a", "old"), ("b", "old")]);
+        let mut mt = MemTable::new(SsTableSize::Default);
+        for (key, expires_at) in [("a", 1000), ("b", 2000)] {
+            let value = Value::Expiring {
+                value: Bytes::from("new"),
+                expires_at,
+            };
+            mt.insert_typed(Bytes::from(key), value, 0, 2, None);
+        }
+        let newer = SsTable::build(mt);
+        stor.write(&newer.id, &newer.encode()).unwrap();
+
+        let clock = ManualClock::new(UNIX_EPOCH + Duration::from_millis(1000));
+        let output = merge(
+            &stor,
+            &level0(&[newer.id, older]),
+            true,
+            Format::default(),
+            None,
+            Advisor::default(),
+            &clock,
+        )
+        .await
+        .unwrap()
+        .remove(0)
+        .id;
+
+        // Expired value is dropped, its tombstone still hides the older values.
+        let merged = SsTable::decode(&stor.open(&output).unwrap()).unwrap();
+        assert_eq!(
+            merged.get(&Bytes::from("a")),
+            Some(&(Value::Tombstone, 2, 0))
+        );
+        assert_eq!(
+            merged.get(&Bytes::from("b")),
+            Some(&(
+                Value::Expiring {
+                    value: Bytes::from("new"),
+                    expires_at: 2000
+                },
+                2,
+                0
+            ))
+        );
+    }
+
+    /// Writes a full table of distinct keys prefixed with the given one.
+    fn write_full_table<T: Storage>(storage: &T, prefix: &str) -> Uuid {
+        let mut mt = MemTable::new(SsTableSize::Default);
+        let value = Value::Live(Bytes::from(vec![b'v'; 100]));
+        for i in 0.. {
+            let key = Bytes::from(format!("{}-{:05}", prefix, i));
+            match mt.probe(&key, &value) {
+                ProbeResult::Available(size) => {
+                    mt.insert_typed(key, value.clone(), 0, 1, Some(size))
+                }
+                ProbeResult::Full => break,
+            }
+        }
+
+        let table = SsTable::build(mt);
+        storage.write(&table.id, &table.encode()).unwrap();
+        table.id
+    }
+
+    #[tokio::test]
+    async fn test_merge_throttled() {
+        let stor = mem::new();
+        let older = write_full_table(&stor, "a");
+        let newer = write_full_table(&stor, "b");
+        let input_len = stor.open(&older).unwrap().len() + stor.open(&newer).unwrap().len();
+
+        // Inputs are read and the output is written at about twice the budget, bucket starts
+        // full with a second worth of it, so the merge takes about a second.
+        let started = std::time::Instant::now();
+        let throttle = Throttle::new(input_len as u64);
+        let output = merge(
+            &stor,
+            &level0(&[newer, older]),
+            true,
+            Format::default(),
+            Some(throttle),
+            Advisor::default(),
+            &SystemClock,
+        )
+        .await
+        .unwrap()
+        .remove(0)
+        .id;
+        let elapsed = started.elapsed();
+        assert!(
+            elapsed >= Duration::from_millis(500),
+            "merge took {:?} only",
+            elapsed
+        );
+
+        let merged = SsTable::decode(&stor.open(&output).unwrap()).unwrap();
+        let expected = SsTable::decode(&stor.open(&older).unwrap()).unwrap().len()
+            + SsTable::decode(&stor.open(&newer).unwrap()).unwrap().len();
+        assert_eq!(merged.len(), expected);
+    }
+
+    #[tokio::test]
+    async fn test_merge_split() {
+        let stor = mem::new();
+        let older = write_full_table(&stor, "a");
+        let newer = write_full_table(&stor, "b");
+        let plan = Plan {
+            inputs: vec![newer, older],
+            level: 1,
+            bottom: false,
+        };
+
+        let outputs = merge(
+            &stor,
+            &plan,
+            true,
+            Format::default(),
+            None,
+            Advisor::default(),
+            &SystemClock,
+        )
+        .await
+        .unwrap();
+        assert!(outputs.len() > 1, "two full tables don't fit one");
+
+        let mut merged = 0;
+        for (num, output) in outputs.iter().enumerate() {
+            let blob = stor.open(&output.id).unwrap();
+            // Only the last output tells that the inputs are replaced.
+            let replaces = match num == outputs.len() - 1 {
+                true => vec![newer, older],
+                false => Vec::new(),
+            };
+            assert_eq!(
+                SsTable::placement(&blob).unwrap(),
+                Placement {
+                    level: 1,
+                    first_key: output.first_key.clone(),
+                    last_key: output.last_key.clone(),
+                    replaces,
+                }
+            );
+            if num > 0 {
+                assert!(
+                    outputs[num - 1].last_key < output.first_key,
+                    "tables overlap"
+                );
+            }
+            merged += SsTable::decode(&blob).unwrap().len();
+        }
+
+        let expected = SsTable::decode(&stor.open(&older).unwrap()).unwrap().len()
+            + SsTable::decode(&stor.open(&newer).unwrap()).unwrap().len();
+        assert_eq!(merged, expected);
+    }
+
+    #[tokio::test]
+    async fn test_merge_bottom() {
+        let stor = mem::new();
+        let older = write_table(&stor, 1, &[("a", "old"), ("b", "old")]);
+        let mut mt = MemTable::new(SsTableSize::Default);
+        mt.insert_typed(Bytes::from("a"), Value::Tombstone, 0, 2, None);
+        let newer = SsTable::build(mt);
+        stor.write(&newer.id, &newer.encode()).unwrap();
+
+        // Nothing below the bottom level for a tombstone to hide.
+        let plan = Plan {
+            inputs: vec![newer.id, older],
+            level: 1,
+            bottom: true,
+        };
+        let outputs = merge(
+            &stor,
+            &plan,
+            true,
+            Format::default(),
+            None,
+            Advisor::default(),
+            &SystemClock,
+        )
+        .await
+        .unwrap();
+        assert_eq!(outputs.len(), 1);
+        let merged = SsTable::decode(&stor.open(&outputs[0].id).unwrap()).unwrap();
+        assert_eq!(merged.keys().collect::<Vec<_>>(), vec![&Bytes::from("b")]);
+
+        // Tables that only hold deleted keys leave nothing behind.
+        let plan = Plan {
+            inputs: vec![newer.id],
+            level: 1,
+            bottom: true,
+        };
+        let outputs = merge(
+            &stor,
+            &plan,
+            true,
+            Format::default(),
+            None,
+            Advisor::default(),
+            &SystemClock,
+        )
+        .await
+        .unwrap();
+        assert!(outputs.is_empty());
+    }
+
+    #[test]
+    fn test_pick() {
+        let entry = |id: u128, size: u64, level: u8, keys: (&'static str, &'static str)| Entry {
+            id: Uuid::from_u128(id),
+            size,
+            level,
+            first_key: Bytes::from(keys.0),
+            last_key: Bytes::from(keys.1),
+            damaged: false,
+        };
+        let ids =
+            |ids: &[u128]| -> Vec<Uuid> { ids.iter().map(|id| Uuid::from_u128(*id)).collect() };
+
+        // Level 0 goes down along with the level 1 tables it overlaps.
+        let index = Index::init(vec![
+            entry(5, 10, 0, ("c", "f")),
+            entry(4, 10, 0, ("a", "b")),
+            entry(1, 10, 1, ("a", "b")),
+            entry(2, 10, 1, ("d", "e")),
+            entry(3, 10, 1, ("x", "z")),
+        ]);
+        assert_eq!(
+            pick(&index, true),
+            Some(Plan {
+                inputs: ids(&[5, 4, 1, 2]),
+                level: 1,
+                bottom: true,
+            })
+        );
+        assert_eq!(pick(&index, false), None, "level 1 is within its size");
+
+        // The oldest table of a level over its size goes down.
+        let table_size = SSTABLE_BYTESIZE as u64;
+        let mut entries = vec![
+            entry(100, table_size, 2, ("a", "c")),
+            entry(101, table_size, 2, ("m", "p")),
+            entry(102, table_size, 3, ("a", "z")),
+        ];
+        for i in 0..11 {
+            let keys = ["b", "d", "f", "h", "j", "l", "n", "q", "r", "t", "o"];
+            entries.push(entry(
+                50 - i,
+                table_size,
+                1,
+                (keys[i as usize], keys[i as usize]),
+            ));
+        }
+        let index = Index::init(entries);
+        assert_eq!(
+            pick(&index, false),
+            Some(Plan {
+                inputs: ids(&[40, 101]),
+                level: 2,
+                bottom: false,
+            })
+        );
+
+        // Levels within their sizes are only pushed down when asked to.
+        let index = Index::init(vec![
+            entry(2, 10, 1, ("a", "c")),
+            entry(1, 10, 1, ("d", "f")),
+            entry(3, 10, 2, ("a", "z")),
+        ]);
+        assert_eq!(pick(&index, true), None);
+        assert_eq!(
+            pick_any(&index),
+            Some(Plan {
+                inputs: ids(&[1, 3]),
+                level: 2,
+                bottom: true,
+            })
+        );
+        let index = Index::init(vec![entry(3, 10, 2, ("a", "z"))]);
+        assert_eq!(pick_any(&index), None);
+
+        // Damaged tables are left out.
+        let damaged = Entry {
+            damaged: true,
+            ..entry(6, 10, 0, ("", ""))
+        };
+        let index = Index::init(vec![damaged.clone(), entry(5, 10, 0, ("a", "b"))]);
+        assert_eq!(
+            pick_any(&index),
+            Some(Plan {
+                inputs: ids(&[5]),
+                level: 1,
+                bottom: true,
+            })
+        );
+        let index = Index::init(vec![damaged]);
+        assert_eq!(pick_any(&index), None);
+    }
+}
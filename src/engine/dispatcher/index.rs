@@ -1,40 +1,129 @@
+use bytes::Bytes;
 use uuid::Uuid;
 
 /// Index holding all the SSTables. Index is being updated by Dispatcher
 /// in runtime and initialized from disk at the start of the database.
+///
+/// Tables are kept in the order they are read in. Tables of level 0 are flushed from memtable
+/// and may overlap, they go first, from the newest to the oldest. Tables of every deeper level
+/// don't overlap each other and hold older values than the levels above, they follow level by
+/// level, ordered by their keys. Tables of a deeper level only overlap when a crash left tables
+/// behind a compaction, overlapping ones go from the newest to the oldest then, the same way
+/// tables of level 0 do.
 #[derive(Debug, Clone)]
 pub struct Index {
     pub entries: Vec<Entry>,
 }
 
 /// An entry in the LSM index representing a single SSTable.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Entry {
     pub id: Uuid,
+    /// Size of the table in bytes, so that compaction can tell how full a level is.
+    pub size: u64,
+    pub level: u8,
+    /// Range of keys the table holds, compaction picks the tables of the next level that
+    /// overlap it.
+    pub first_key: Bytes,
+    pub last_key: Bytes,
+    /// The table could not be read at start, so its keys are unknown and it is taken as level 0.
+    /// Reads of it fail, compaction leaves it be (see fsck to find out what is damaged).
+    pub damaged: bool,
+}
+
+impl Entry {
+    fn overlaps(&self, first_key: &Bytes, last_key: &Bytes) -> bool {
+        self.first_key <= *last_key && self.last_key >= *first_key
+    }
 }
 
 /// Holds an ordered list of SSTables present on disk and ready for requests.
 impl Index {
     // TODO: Consider renaming it to new.
-    pub fn init(entries: &mut [Uuid]) -> Self {
-        // Seem to be not necessary here but tables set will not be too huge and index only needs
-        // to be initialized once the database starts so it's fine if we end up doing extra work.
-        entries.sort();
-        entries.reverse();
-
-        Self {
-            entries: entries
-                .iter()
-                .map(|table_id| Entry { id: *table_id })
-                .collect(),
-        }
+    pub fn init(entries: Vec<Entry>) -> Self {
+        let mut index = Self { entries };
+        index.sort();
+        index
     }
 
-    pub fn prepend(&mut self, id: Uuid) {
-        let old = self.entries.clone();
-        self.entries = Vec::new();
-        self.entries.push(Entry { id });
-        self.entries.extend(old);
+    /// Puts a table flushed from memtable in front of the others. Panics if the table is already
+    /// in the index, a second entry would shadow the first one.
+    pub fn prepend(&mut self, entry: Entry) {
+        assert!(
+            self.entries.iter().all(|e| e.id != entry.id),
+            "table {} is already in the index",
+            entry.id
+        );
+        assert_eq!(entry.level, 0, "only level 0 tables are prepended");
+        self.entries.insert(0, entry);
+    }
+
+    /// Bytes taken by all the tables.
+    pub fn total_size(&self) -> u64 {
+        self.entries.iter().map(|entry| entry.size).sum()
+    }
+
+    /// Removes the inputs of a compaction from the index and puts its outputs in their place.
+    pub fn replace(&mut self, inputs: &[Uuid], outputs: Vec<Entry>) {
+        self.entries.retain(|entry| !inputs.contains(&entry.id));
+        self.entries.extend(outputs);
+        self.sort();
+    }
+
+    /// Id of the newest table, tables of deeper levels may be newer than the ones above.
+    pub fn newest(&self) -> Option<Uuid> {
+        self.entries.iter().map(|entry| entry.id).max()
+    }
+
+    /// Tables of the level in the order they are read.
+    pub fn level(&self, level: u8) -> impl Iterator<Item = &Entry> {
+        self.entries
+            .iter()
+            .filter(move |entry| entry.level == level)
+    }
+
+    /// Bytes taken by the tables of the level.
+    pub fn level_size(&self, level: u8) -> u64 {
+        self.level(level).map(|entry| entry.size).sum()
+    }
+
+    /// Deepest level that holds tables.
+    pub fn deepest(&self) -> Option<u8> {
+        self.entries.iter().map(|entry| entry.level).max()
+    }
+
+    /// Tables of the level holding keys within [first_key, last_key].
+    pub fn overlapping(&self, level: u8, first_key: &Bytes, last_key: &Bytes) -> Vec<&Entry> {
+        self.level(level)
+            .filter(|entry| entry.overlaps(first_key, last_key))
+            .collect()
+    }
+
+    fn sort(&mut self) {
+        self.entries.sort_by(|a, b| {
+            a.level.cmp(&b.level).then_with(|| match a.level {
+                0 => b.id.cmp(&a.id),
+                _ => a.first_key.cmp(&b.first_key),
+            })
+        });
+
+        let mut start = 0;
+        while start < self.entries.len() {
+            let level = self.entries[start].level;
+            let mut last_key = self.entries[start].last_key.clone();
+            let mut end = start + 1;
+            while level > 0
+                && end < self.entries.len()
+                && self.entries[end].level == level
+                && self.entries[end].first_key <= last_key
+            {
+                last_key = last_key.max(self.entries[end].last_key.clone());
+                end += 1;
+            }
+
+            self.entries[start..end].sort_by_key(|entry| std::cmp::Reverse(entry.id));
+            start = end;
+        }
     }
 }
 
@@ -42,44 +131,116 @@ impl Index {
 mod tests {
     use super::*;
 
+    fn entry(id: &str, size: u64, level: u8, keys: (&'static str, &'static str)) -> Entry {
+        Entry {
+            id: Uuid::parse_str(id).unwrap(),
+            size,
+            level,
+            first_key: Bytes::from(keys.0),
+            last_key: Bytes::from(keys.1),
+            damaged: false,
+        }
+    }
+
     #[test]
     fn test_prepend() {
-        let mut idx = Index {
-            entries: vec![
-                Entry {
-                    id: Uuid::parse_str("01923000-1551-71d1-96b0-4063addc3fcd").unwrap(),
-                },
-                Entry {
-                    id: Uuid::parse_str("01922ffe-ff42-7a24-99af-69793801e519").unwrap(),
-                },
-            ],
-        };
+        let mut idx = Index::init(vec![
+            entry("01923000-1551-71d1-96b0-4063addc3fcd", 10, 0, ("a", "z")),
+            entry("01922ffe-ff42-7a24-99af-69793801e519", 20, 1, ("a", "z")),
+        ]);
 
         let to_prepend = "01923001-1551-71d1-96b0-4063addc3fcd";
 
-        idx.prepend(Uuid::parse_str(to_prepend).unwrap());
+        idx.prepend(entry(to_prepend, 30, 0, ("a", "z")));
 
-        assert_eq!(idx.entries[0].id.to_string(), to_prepend)
+        assert_eq!(idx.entries[0].id.to_string(), to_prepend);
+        assert_eq!(idx.entries[0].size, 30);
+        assert_eq!(idx.newest(), Some(idx.entries[0].id));
     }
 
     #[test]
-    fn test_init() {
-        let mut ids: Vec<Uuid> = vec![
-            Uuid::parse_str("01923000-9809-722f-b567-64f172b54f56").unwrap(),
-            Uuid::parse_str("01923000-4db5-71c9-8586-0554d2c9f956").unwrap(),
-            Uuid::parse_str("01923000-d486-705e-b6fe-f1dcf9cb01ae").unwrap(),
-            Uuid::parse_str("01922ffe-ff42-7a24-99af-69793801e519").unwrap(),
-            Uuid::parse_str("01923000-1551-71d1-96b0-4063addc3fcd").unwrap(),
-        ];
+    #[should_panic(expected = "already in the index")]
+    fn test_prepend_existing() {
+        let id = "01923000-1551-71d1-96b0-4063addc3fcd";
+        let mut idx = Index::init(vec![entry(id, 10, 0, ("a", "z"))]);
+        idx.prepend(entry(id, 20, 0, ("a", "z")));
+    }
 
-        let index = Index::init(&mut ids);
+    #[test]
+    fn test_replace() {
+        let mut idx = Index::init(vec![
+            entry("01923000-d486-705e-b6fe-f1dcf9cb01ae", 10, 0, ("a", "m")),
+            entry("01923000-9809-722f-b567-64f172b54f56", 10, 0, ("c", "z")),
+            entry("01923000-4db5-71c9-8586-0554d2c9f956", 10, 1, ("a", "f")),
+            entry("01923000-1551-71d1-96b0-4063addc3fcd", 10, 1, ("x", "z")),
+            entry("01922ffe-ff42-7a24-99af-69793801e519", 10, 2, ("a", "z")),
+        ]);
+        let inputs = [idx.entries[1].id, idx.entries[3].id];
         assert_eq!(
-            index.entries[0].id.to_string(),
-            "01923000-d486-705e-b6fe-f1dcf9cb01ae"
+            idx.overlapping(1, &Bytes::from("c"), &Bytes::from("z")),
+            vec![&idx.entries[2], &idx.entries[3]]
+        );
+
+        // Outputs go to level 1 between the tables left there, a newer id doesn't put them
+        // ahead of level 0.
+        idx.replace(
+            &inputs,
+            vec![
+                entry("01923001-0000-7000-8000-000000000002", 20, 1, ("w", "z")),
+                entry("01923001-0000-7000-8000-000000000001", 20, 1, ("g", "v")),
+            ],
         );
+
+        let got: Vec<(String, u8)> = idx
+            .entries
+            .iter()
+            .map(|e| (e.id.to_string(), e.level))
+            .collect();
         assert_eq!(
-            index.entries[4].id.to_string(),
-            "01922ffe-ff42-7a24-99af-69793801e519"
+            got,
+            vec![
+                ("01923000-d486-705e-b6fe-f1dcf9cb01ae".to_string(), 0),
+                ("01923000-4db5-71c9-8586-0554d2c9f956".to_string(), 1),
+                ("01923001-0000-7000-8000-000000000001".to_string(), 1),
+                ("01923001-0000-7000-8000-000000000002".to_string(), 1),
+                ("01922ffe-ff42-7a24-99af-69793801e519".to_string(), 2),
+            ]
         );
+        assert_eq!(idx.level_size(1), 50);
+        assert_eq!(idx.deepest(), Some(2));
+        assert_eq!(
+            idx.newest().unwrap().to_string(),
+            "01923001-0000-7000-8000-000000000002"
+        );
+    }
+
+    #[test]
+    fn test_init() {
+        let entries = vec![
+            entry("01923000-9809-722f-b567-64f172b54f56", 1, 0, ("a", "z")),
+            entry("01923000-4db5-71c9-8586-0554d2c9f956", 2, 1, ("n", "z")),
+            entry("01923000-d486-705e-b6fe-f1dcf9cb01ae", 3, 0, ("a", "z")),
+            entry("01922ffe-ff42-7a24-99af-69793801e519", 4, 0, ("a", "z")),
+            entry("01923000-1551-71d1-96b0-4063addc3fcd", 5, 1, ("a", "m")),
+        ];
+
+        let index = Index::init(entries);
+        let got: Vec<u64> = index.entries.iter().map(|e| e.size).collect();
+        assert_eq!(got, vec![3, 1, 4, 5, 2]);
+    }
+
+    #[test]
+    fn test_init_overlapping() {
+        // A compaction output at level 1 along with an input a crash left behind.
+        let entries = vec![
+            entry("01923000-1551-71d1-96b0-4063addc3fcd", 1, 1, ("a", "f")),
+            entry("01923000-4db5-71c9-8586-0554d2c9f956", 2, 1, ("c", "k")),
+            entry("01923001-0000-7000-8000-000000000001", 3, 1, ("a", "m")),
+            entry("01923000-9809-722f-b567-64f172b54f56", 4, 1, ("n", "z")),
+        ];
+
+        let index = Index::init(entries);
+        let got: Vec<u64> = index.entries.iter().map(|e| e.size).collect();
+        assert_eq!(got, vec![3, 2, 1, 4]);
     }
 }
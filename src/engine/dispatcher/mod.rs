@@ -1,118 +1,1878 @@
+mod advice;
+mod buffer;
+mod cache;
 mod compaction;
 mod index;
+mod warmup;
 
-use crate::engine::memtable::MemTable;
-use crate::engine::sstable::SsTable;
+use crate::engine::memtable::{MemTable, Value};
+pub use crate::engine::sstable::cache::BlockCacheStats;
+use crate::engine::sstable::cache::{BlockCache, TableCache};
+use crate::engine::sstable::{Format, Placement, SsTable};
+use crate::engine::{CompactionReport, FlushReport, RangeCount};
 use crate::Responder;
-use crate::Storage;
+use crate::{Clock, Config};
+pub use advice::AdviceStats;
+use advice::{Access, Advisor};
+use buffer::BufferLimit;
 use bytes::Bytes;
-use index::Index;
-use tokio::sync::mpsc;
+use cache::Cache;
+pub use cache::CacheStats;
+use compaction::Plan;
+use index::{Entry, Index};
+use std::collections::{BTreeMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time;
 use uuid::Uuid;
 
+/// How often the dispatcher checks if tables went over the compaction limits
+/// (see Config::compaction_max_tables).
+const COMPACTION_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long to wait before writing a table again when the disk is full.
+const NO_SPACE_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
 pub enum Command {
+    /// Unless fill_cache is set, the read is served by the cache if it can be, but doesn't
+    /// count towards key frequencies and the value read from disk is not offered to the cache.
     Get {
         key: Bytes,
+        fill_cache: bool,
+        responder: Responder<Option<Bytes>>,
+    },
+    /// Reads the newest value of the key put no later than the given sequence number. Cache
+    /// only holds the latest values, so it is not used.
+    GetAt {
+        key: Bytes,
+        seq: u64,
         responder: Responder<Option<Bytes>>,
     },
+    /// Reads the value along with its flags. Cache doesn't keep flags, so tables are always read.
+    GetTyped {
+        key: Bytes,
+        responder: Responder<Option<(Bytes, u8)>>,
+    },
     CreateTable {
         data: MemTable,
         responder: Responder<()>,
     },
-    ReplaceTables(((Uuid, Uuid), Uuid)), // TODO: To be used by a compaction thread.
+    /// Same as CreateTable, but always responds once the table is on disk, no matter how full
+    /// the buffer is.
+    Flush {
+        data: MemTable,
+        responder: Responder<FlushReport>,
+    },
+    /// Pushes tables one level down in the background, the way compaction over the limits does,
+    /// see compaction::pick_any. Responds once the merged tables replaced their inputs in the
+    /// index.
+    Compact {
+        responder: Responder<CompactionReport>,
+    },
+    /// Rewrites a table with the bloom filter built anew from its keys, for a table whose filter
+    /// is damaged or no longer fits its keys. Rewritten table gets a new id and replaces the old
+    /// one in the background, the same way compaction output does. Responds with the new id.
+    RebuildBloom {
+        table_id: Uuid,
+        responder: Responder<Uuid>,
+    },
+    /// Adds up keys within [start, end) in all the tables to the count made in memtable.
+    CountRange {
+        start: Bytes,
+        end: Bytes,
+        memtable_count: u64,
+        responder: Responder<RangeCount>,
+    },
+    /// Merges keys within [start, end) in all the tables with the ones found in memtable and
+    /// returns up to limit first of them.
+    ScanKeys {
+        start: Bytes,
+        end: Bytes,
+        limit: usize,
+        /// Whether the key is live along with every key, deleted keys hide the keys of tables.
+        memtable_keys: Vec<(Bytes, bool)>,
+        responder: Responder<Vec<Bytes>>,
+    },
+    /// Same as ScanKeys along with the values of the keys.
+    Scan {
+        start: Bytes,
+        end: Bytes,
+        limit: usize,
+        /// Deleted keys come with tombstones, they hide the keys of tables.
+        memtable_entries: Vec<(Bytes, Value)>,
+        responder: Responder<Vec<(Bytes, Bytes)>>,
+    },
+    /// Reports counters of the value and the block caches along with the hints given to the
+    /// OS cache and the number of tables in use.
+    Stats {
+        responder: Responder<(CacheStats, BlockCacheStats, AdviceStats, usize)>,
+    },
+    /// Puts tables storage to disk (see Storage::sync). Tables sent before it are created first.
+    Sync { responder: Responder<()> },
+    /// Reports the id of the newest table in the index.
+    NewestTable { responder: Responder<Option<Uuid>> },
+    /// Sent by the compaction task once merged tables are persisted. Swaps the inputs with
+    /// the outputs in the index in a single step, input tables are removed from storage after.
+    ReplaceTables {
+        inputs: Vec<Uuid>,
+        outputs: Vec<Entry>,
+        responder: Responder<()>,
+    },
 }
 
 /// Dispatcher is managing SSTables on disk, syncronizing access and modification.
-/// Dispatcher holds receiver to get commands from Engine, sstables buffer, that tells
+/// Dispatcher holds receiver to get commands from Engine, sstables buffer limit, that tells
 /// how many sstables are allowed to be in the process of saving it to disk at the same time.
 /// The lower this number, the lower memory bureau will consume under pressure. But all
-/// the reads and writes will be suspended while buffer is full.
-/// Dispatcher is also managing index which is a vector of all the tables ids persisted to disk.
+/// the reads and writes will be suspended while buffer is full. The limit adapts to the write
+/// pressure within the bounds given in config (see BufferLimit).
+/// Dispatcher is also managing index which is a vector of all the tables ids persisted to disk
+/// along with their levels, a cache of frequently read values and a cache of recently read blocks.
+/// Compaction runs in a separate task that only reads existing tables and writes a new one, then
+/// reports back to dispatcher through its own channel, so reads are not held by the merge.
 #[derive(Debug)]
-pub struct Dispatcher<T: Storage> {
+pub struct Dispatcher<T: crate::AsyncStorage> {
     cmd_rx: mpsc::Receiver<Command>,
+    cmd_tx: mpsc::WeakSender<Command>,
     storage: T,
     index: Index,
-    sst_buf_size: usize,
-    sst_buf: usize,
+    cache: Cache,
+    /// Blocks read by lookups, None if it is off (see Config::block_cache_capacity).
+    blocks: Option<BlockCache>,
+    buf_limit: BufferLimit,
+    compaction: Option<JoinHandle<()>>,
+    /// Check tables before they are written (see Config::validate_tables).
+    validate_tables: bool,
+    /// How new tables are laid out (see Config::hash_index and Config::compression).
+    format: Format,
+    /// Bytes per second a compaction may read and write (see Config::compaction_io_budget).
+    compaction_io_budget: Option<u64>,
+    /// Limits that start compaction once tables go over them (see Config::compaction_max_tables).
+    compaction_max_tables: Option<usize>,
+    compaction_max_bytes: Option<u64>,
+    /// Time new table ids are made of.
+    clock: Arc<dyn Clock>,
+    /// Where keys of cached values are kept between restarts (see Config::cache_hints).
+    cache_hints: Option<PathBuf>,
+    /// Hints to the OS cache (see Config::os_cache_advice).
+    advisor: Advisor,
 }
 
-impl<T: Storage> Dispatcher<T> {
-    pub fn init(
+impl<T: crate::AsyncStorage> Dispatcher<T> {
+    pub async fn init(
         cmd_rx: mpsc::Receiver<Command>,
-        sst_buf_size: usize,
+        cmd_tx: mpsc::WeakSender<Command>,
         storage: T,
+        config: &Config,
     ) -> std::result::Result<Self, anyhow::Error> {
-        let mut entries = storage.list_entries()?;
-        let index = Index::init(&mut entries);
+        let advisor = Advisor::new(config.os_cache_advice);
+        let mut entries = Vec::new();
+        let mut replaced = Vec::new();
+        for (id, size) in storage.list_entries_with_meta().await? {
+            let hints = (advisor.clone(), Access::Lookup);
+            let (placement, damaged) =
+                match read_table(&storage, &id, hints, SsTable::placement).await {
+                    Ok(placement) => (placement, false),
+                    Err(e) => {
+                        tracing::error!("could not read table {}, taking it as level 0: {}", id, e);
+                        let placement = Placement {
+                            level: 0,
+                            first_key: Bytes::new(),
+                            last_key: Bytes::new(),
+                            replaces: Vec::new(),
+                        };
+                        (placement, true)
+                    }
+                };
+            replaced.push(placement.replaces);
+            entries.push(Entry {
+                id,
+                size,
+                level: placement.level,
+                first_key: placement.first_key,
+                last_key: placement.last_key,
+                damaged,
+            });
+        }
+
+        // Inputs of a compaction whose outputs are all on disk, a crash stopped them from being
+        // deleted. They are deleted the way the compaction does it, from the oldest to the newest
+        // up to the first failure, and the ones deleted are left out.
+        let mut present: HashSet<Uuid> = entries.iter().map(|entry| entry.id).collect();
+        for inputs in replaced {
+            for id in inputs.iter().rev() {
+                if !present.contains(id) {
+                    continue;
+                }
+                tracing::warn!("deleting table {} left behind by compaction", id);
+                if let Err(e) = storage.delete(id).await {
+                    tracing::error!("could not delete compacted table {}: {}", id, e);
+                    break;
+                }
+                present.remove(id);
+            }
+        }
+        entries.retain(|entry| present.contains(&entry.id));
+        let index = Index::init(entries);
+        if let Some(newest) = index.newest() {
+            SsTable::observe_id(&newest);
+        }
 
-        Ok(Dispatcher {
+        let mut dispatcher = Dispatcher {
             cmd_rx,
+            cmd_tx,
             storage,
             index,
-            sst_buf_size,
-            sst_buf: 0,
-        })
+            cache: Cache::new(
+                config.cache_capacity,
+                config.cache_min_reads,
+                config.cache_sketch,
+                config.negative_cache_ttl,
+                config.verify_on_read,
+            ),
+            blocks: (config.block_cache_capacity > 0)
+                .then(|| BlockCache::new(config.block_cache_capacity)),
+            buf_limit: BufferLimit::new(config.dispatcher_buffer_min, config.dispatcher_buffer_max),
+            compaction: None,
+            validate_tables: cfg!(debug_assertions) || config.validate_tables,
+            format: Format {
+                hash_index: config.hash_index,
+                compression: config.compression,
+                bloom_fp_rate: config.bloom_fp_rate,
+            },
+            compaction_io_budget: config.compaction_io_budget,
+            compaction_max_tables: config.compaction_max_tables,
+            compaction_max_bytes: config.compaction_max_bytes,
+            clock: config.clock.clone(),
+            cache_hints: config.cache_hints.clone(),
+            advisor,
+        };
+        dispatcher.warm_up().await;
+
+        Ok(dispatcher)
     }
 
     pub async fn run(mut self) {
-        while let Some(cmd) = self.cmd_rx.recv().await {
-            match cmd {
-                Command::Get { key, responder } => {
-                    // Defaults to Ok(None) which will be returned if none was found after all tables are checked.
-                    let mut response: Result<Option<Bytes>, _> = Ok(None);
+        // Compaction that is over the limits is picked up right after a table is created, the
+        // timer picks it up again once that compaction is done.
+        let mut compaction_timer = (self.compaction_max_tables.is_some()
+            || self.compaction_max_bytes.is_some())
+        .then(|| time::interval(COMPACTION_CHECK_INTERVAL));
 
-                    for entry in self.index.entries.iter() {
-                        let blob = self.storage.open(&entry.id).unwrap(); // TODO: Log error and send response to engine.
+        loop {
+            let cmd = tokio::select! {
+                cmd = self.cmd_rx.recv() => match cmd {
+                    Some(cmd) => cmd,
+                    None => break,
+                },
+                _ = super::tick(&mut compaction_timer) => {
+                    self.compact_over_limits();
+                    continue;
+                }
+            };
 
-                        match SsTable::lookup(&blob, &key) {
-                            Ok(Some(value)) => {
-                                response = Ok(Some(value));
-                                break;
-                            }
-                            Ok(None) => {
-                                // Go check the next table.
-                                continue;
+            match cmd {
+                Command::Get {
+                    key,
+                    fill_cache,
+                    responder,
+                } => {
+                    let cached = match fill_cache {
+                        true => self.cache.check(&key),
+                        false => self.cache.peek(&key),
+                    };
+                    if let Some(cached) = cached {
+                        responder.send(Ok(cached)).ok();
+                        continue;
+                    }
+
+                    let response = match self.lookup(&key).await {
+                        Ok(Some((value, table_id))) => {
+                            // Cache doesn't know when values expire, expiring ones are read
+                            // from disk every time.
+                            if let (true, Value::Live(value)) = (fill_cache, &value) {
+                                self.cache.try_insert(key, value.clone(), table_id);
                             }
-                            Err(e) => {
-                                response = Err(e);
-                                break;
+                            Ok(value.live())
+                        }
+                        Ok(None) => {
+                            if fill_cache {
+                                self.cache.remember_missing(key);
                             }
+                            Ok(None)
                         }
-                    }
+                        Err(e) => Err(e),
+                    };
 
                     responder.send(response).ok();
                 }
+                Command::GetAt {
+                    key,
+                    seq,
+                    responder,
+                } => {
+                    responder.send(self.get_at(&key, seq).await).ok();
+                }
+                Command::GetTyped { key, responder } => {
+                    responder.send(self.get_typed(&key).await).ok();
+                }
                 Command::CreateTable { data, responder } => {
-                    self.sst_buf += 1;
-                    if self.sst_buf < self.sst_buf_size {
+                    // Tables are persisted one by one, so everything queued behind this one is
+                    // counted as in flight. Reads are counted too, which errs on the side of waiting.
+                    let in_flight = 1 + self.cmd_rx.len();
+                    let started = Instant::now();
+                    if in_flight < self.buf_limit.current() {
                         responder.send(Ok(())).ok(); // If buffer isnt full ack immediately to free engine thread.
-                        let id = self.persist_table(data);
-                        self.index.prepend(id);
-                        self.sst_buf -= 1;
+                        self.create_table(data).await;
                     } else {
-                        let id = self.persist_table(data);
-                        self.index.prepend(id);
-                        self.sst_buf -= 1;
+                        self.create_table(data).await;
                         responder.send(Ok(())).ok(); // If buffer is full, ack only when the table is on disk.
                     }
+                    self.buf_limit
+                        .on_persist(started.elapsed(), self.cmd_rx.len());
+                }
+                Command::Flush { data, responder } => {
+                    let report = self.create_table(data).await;
+                    responder.send(Ok(report)).ok();
+                }
+                Command::Compact { responder } => {
+                    self.compact(responder);
+                }
+                Command::RebuildBloom {
+                    table_id,
+                    responder,
+                } => {
+                    self.rebuild_bloom(table_id, responder);
+                }
+                Command::CountRange {
+                    start,
+                    end,
+                    memtable_count,
+                    responder,
+                } => {
+                    responder
+                        .send(self.count_range(&start, &end, memtable_count).await)
+                        .ok();
+                }
+                Command::ScanKeys {
+                    start,
+                    end,
+                    limit,
+                    memtable_keys,
+                    responder,
+                } => {
+                    responder
+                        .send(self.scan_keys(start, end, limit, memtable_keys).await)
+                        .ok();
+                }
+                Command::Scan {
+                    start,
+                    end,
+                    limit,
+                    memtable_entries,
+                    responder,
+                } => {
+                    responder
+                        .send(self.scan(start, end, limit, memtable_entries).await)
+                        .ok();
+                }
+                Command::Stats { responder } => {
+                    let blocks = self.blocks.as_ref().map(BlockCache::stats);
+                    responder
+                        .send(Ok((
+                            self.cache.stats(),
+                            blocks.unwrap_or_default(),
+                            self.advisor.stats(),
+                            self.index.entries.len(),
+                        )))
+                        .ok();
+                }
+                Command::Sync { responder } => {
+                    let res = self.storage.sync().await.map_err(crate::Error::from);
+                    if let Err(e) = &res {
+                        tracing::error!("could not sync tables storage: {}", e);
+                    }
+                    responder.send(res).ok();
                 }
-                Command::ReplaceTables(((_old1, _old2), _new)) => {
-                    todo!()
+                Command::NewestTable { responder } => {
+                    responder.send(Ok(self.index.newest())).ok();
                 }
+                Command::ReplaceTables {
+                    inputs,
+                    outputs,
+                    responder,
+                } => {
+                    if let Some(output) = outputs.iter().map(|entry| entry.id).max() {
+                        self.cache.rebase(&inputs, output);
+                    }
+                    let mut replaced: Vec<Entry> = self
+                        .index
+                        .entries
+                        .iter()
+                        .filter(|entry| inputs.contains(&entry.id))
+                        .cloned()
+                        .collect();
+                    self.index.replace(&inputs, outputs);
+                    if let Some(blocks) = &self.blocks {
+                        blocks.forget(&inputs);
+                    }
+
+                    // Outputs were persisted before the command is sent, so it is safe to drop
+                    // the inputs. The last output names them, so inputs left behind by a crash
+                    // are dropped at start. Inputs go from the oldest to the newest and the
+                    // deletion stops at the first failure. The inputs left behind only hide older
+                    // values then, they are put back to the index for a later compaction to take.
+                    let mut result = Ok(());
+                    for table_id in inputs.iter().rev() {
+                        if let Err(e) = self.storage.delete(table_id).await {
+                            tracing::error!("could not delete compacted table {}: {}", table_id, e);
+                            result = Err(crate::Error::from(e));
+                            break;
+                        }
+                        replaced.retain(|entry| entry.id != *table_id);
+                    }
+                    self.index.replace(&[], replaced);
+
+                    responder.send(result).ok();
+                }
+            }
+        }
+
+        self.save_cache_hints().await;
+    }
+
+    /// Goes through the tables from the newest to the oldest until the key is found. Returns
+    /// the value along with the id of the table it was found in, None if the key is deleted or
+    /// expired. The value is never a tombstone.
+    async fn lookup(&self, key: &Bytes) -> crate::Result<Option<(Value, Uuid)>> {
+        let now = self.now();
+        for entry in self.index.entries.iter() {
+            let lookup_key = key.clone();
+            let blocks = self.table_blocks(entry.id);
+            let value = read_table(&self.storage, &entry.id, self.lookup_hints(), move |blob| {
+                SsTable::lookup(blob, &lookup_key, now, blocks.as_ref())
+            })
+            .await?;
+
+            if let Some(value) = value {
+                return Ok((!value.is_tombstone()).then_some((value, entry.id)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Reads values of the keys that were cached before the last shutdown back to the cache.
+    /// Best-effort, a key that can't be read is skipped.
+    async fn warm_up(&mut self) {
+        let Some(path) = &self.cache_hints else {
+            return;
+        };
+
+        let keys = warmup::load(path, self.index.newest()).await;
+        if keys.is_empty() {
+            return;
+        }
+
+        let mut warmed = 0;
+        for key in keys {
+            if let Ok(Some((Value::Live(value), table_id))) = self.lookup(&key).await {
+                warmed += self.cache.restore(key, value, table_id) as usize;
+            }
+        }
+        tracing::info!("warmed up cache with {} values", warmed);
+    }
+
+    /// Remembers keys of the cached values for the next start.
+    async fn save_cache_hints(&self) {
+        let Some(path) = &self.cache_hints else {
+            return;
+        };
+
+        if let Err(e) = warmup::save(path, self.index.newest(), &self.cache.hot_keys()).await {
+            tracing::warn!("could not save cache hints: {}", e);
+        }
+    }
+
+    /// Spawns a task pushing tables down a level (see compaction::pick_any). Compaction task
+    /// holds until its result is applied to the index, so the next compaction can't pick the same
+    /// tables again.
+    fn compact(&mut self, responder: Responder<CompactionReport>) {
+        if self.compaction_running() {
+            responder
+                .send(Err(crate::Error::Overloaded(
+                    "compaction is already in progress".into(),
+                )))
+                .ok();
+            return;
+        }
+
+        let plan = match compaction::pick_any(&self.index) {
+            Some(plan) if self.index.entries.len() > 1 => plan,
+            _ => {
+                responder.send(Ok(CompactionReport::default())).ok(); // Nothing to compact.
+                return;
             }
+        };
+
+        tracing::debug!(
+            "compacting tables {:?} to level {}",
+            plan.inputs,
+            plan.level
+        );
+        let tables_merged = plan.inputs.len();
+        self.spawn_merge(plan, move |result| {
+            let result = result.map(|outputs| CompactionReport {
+                tables_merged,
+                tables_created: outputs.len(),
+                bytes_written: outputs.iter().map(|entry| entry.size).sum(),
+            });
+            responder.send(result).ok();
+        });
+    }
+
+    /// Starts compaction if no compaction is running and there is something to do: all the
+    /// level 0 tables go down once tables went over the configured limits, a deeper level goes
+    /// down once it is over its size (see compaction::pick). Nothing is compacted on its own if
+    /// no limit is configured.
+    fn compact_over_limits(&mut self) {
+        if self.compaction_running() || self.index.entries.len() < 2 {
+            return;
         }
+        if self.compaction_max_tables.is_none() && self.compaction_max_bytes.is_none() {
+            return;
+        }
+
+        let over_tables = self
+            .compaction_max_tables
+            .is_some_and(|max| self.index.entries.len() > max);
+        let over_bytes = self
+            .compaction_max_bytes
+            .is_some_and(|max| self.index.total_size() > max);
+        let Some(plan) = compaction::pick(&self.index, over_tables || over_bytes) else {
+            return;
+        };
+
+        tracing::info!(
+            "{} tables take {} bytes, compacting {} tables to level {}",
+            self.index.entries.len(),
+            self.index.total_size(),
+            plan.inputs.len(),
+            plan.level
+        );
+        self.spawn_merge(plan, |_| {});
+    }
+
+    /// Spawns a task rewriting a single table. Merging a table alone decodes its blocks and
+    /// builds it again, bloom filter included, so it takes the compaction path and can't run
+    /// along with a compaction.
+    fn rebuild_bloom(&mut self, table_id: Uuid, responder: Responder<Uuid>) {
+        if self.compaction_running() {
+            responder
+                .send(Err(crate::Error::Overloaded(
+                    "compaction is in progress".into(),
+                )))
+                .ok();
+            return;
+        }
+
+        let Some(entry) = self.index.entries.iter().find(|entry| entry.id == table_id) else {
+            responder
+                .send(Err(crate::Error::InvalidInput(format!(
+                    "no table {}",
+                    table_id
+                ))))
+                .ok();
+            return;
+        };
+
+        // Table stays at its level, a level 0 table keeps its place among the others.
+        let plan = Plan {
+            inputs: vec![table_id],
+            level: entry.level,
+            bottom: false,
+        };
+        tracing::info!("rebuilding bloom filter of table {}", table_id);
+        self.spawn_merge(plan, move |result| {
+            let rebuilt = result.and_then(|outputs| {
+                outputs
+                    .first()
+                    .map(|entry| entry.id)
+                    .ok_or_else(|| "table held no keys".into())
+            });
+            responder.send(rebuilt).ok();
+        });
     }
 
-    fn persist_table(&self, data: MemTable) -> Uuid {
-        let table = SsTable::build(data);
+    fn compaction_running(&self) -> bool {
+        self.compaction
+            .as_ref()
+            .is_some_and(|handle| !handle.is_finished())
+    }
+
+    /// Spawns a compaction task merging the inputs of the plan and putting the outputs in their
+    /// place in the index. Outcome is given to done once the index is updated.
+    fn spawn_merge(
+        &mut self,
+        plan: Plan,
+        done: impl FnOnce(crate::Result<Vec<Entry>>) + Send + 'static,
+    ) {
+        let storage = self.storage.clone();
+        let cmd_tx = self.cmd_tx.clone();
+        let validate = self.validate_tables;
+        let format = self.format;
+        let throttle = self.compaction_io_budget.map(compaction::Throttle::new);
+        let advisor = self.advisor.clone();
+        let clock = self.clock.clone();
+
+        self.compaction = Some(tokio::spawn(async move {
+            let merged = compaction::merge(
+                &storage,
+                &plan,
+                validate,
+                format,
+                throttle,
+                advisor,
+                clock.as_ref(),
+            );
+            let result = match merged.await {
+                Ok(outputs) => apply_compaction(cmd_tx, plan.inputs, outputs.clone())
+                    .await
+                    .map(|_| outputs),
+                Err(e) => Err(e),
+            };
+
+            if let Err(e) = &result {
+                tracing::error!("compaction failed: {}", e);
+            }
+
+            done(result);
+        }));
+    }
+
+    async fn get_at(&self, key: &Bytes, seq: u64) -> crate::Result<Option<Bytes>> {
+        let now = self.now();
+        for entry in self.index.entries.iter() {
+            let key = key.clone();
+            let blocks = self.table_blocks(entry.id);
+            let value = read_table(&self.storage, &entry.id, self.lookup_hints(), move |blob| {
+                SsTable::lookup_at(blob, &key, seq, now, blocks.as_ref())
+            })
+            .await?;
+            if let Some(value) = value {
+                return Ok(value.live());
+            }
+        }
+
+        Ok(None)
+    }
+
+    async fn get_typed(&self, key: &Bytes) -> crate::Result<Option<(Bytes, u8)>> {
+        let now = self.now();
+        for entry in self.index.entries.iter() {
+            let key = key.clone();
+            let blocks = self.table_blocks(entry.id);
+            let typed = read_table(&self.storage, &entry.id, self.lookup_hints(), move |blob| {
+                SsTable::lookup_typed(blob, &key, now, blocks.as_ref())
+            })
+            .await?;
+            if let Some((value, flags)) = typed {
+                return Ok(value.live().map(|value| (value, flags)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    async fn count_range(
+        &self,
+        start: &Bytes,
+        end: &Bytes,
+        memtable_count: u64,
+    ) -> crate::Result<RangeCount> {
+        let mut count = memtable_count;
+        let mut sources = (memtable_count > 0) as usize;
+        let now = self.now();
+
+        for entry in self.index.entries.iter() {
+            let (start, end) = (start.clone(), end.clone());
+            let table_count =
+                read_table(&self.storage, &entry.id, self.scan_hints(), move |blob| {
+                    SsTable::count_range(blob, &start, &end, now)
+                })
+                .await?;
+            if table_count > 0 {
+                count += table_count;
+                sources += 1;
+            }
+        }
+
+        Ok(RangeCount {
+            count,
+            exact: sources <= 1,
+        })
+    }
+
+    async fn scan_keys(
+        &self,
+        start: Bytes,
+        end: Bytes,
+        limit: usize,
+        memtable_keys: Vec<(Bytes, bool)>,
+    ) -> crate::Result<Vec<Bytes>> {
+        // Every source is sorted, so the first limit keys of the union are among the first
+        // limit keys of each source. Sources go from the newest to the oldest and the newest
+        // entry of a key wins, every key deleted in a newer source may hide a key of an older
+        // one, so older sources list that many keys more.
+        let mut keys: BTreeMap<Bytes, bool> = BTreeMap::new();
+        let mut deleted = 0;
+        let mut merge = |source: Vec<(Bytes, bool)>, deleted: &mut usize| {
+            for (key, live) in source {
+                *deleted += !live as usize;
+                keys.entry(key).or_insert(live);
+            }
+        };
+
+        merge(memtable_keys, &mut deleted);
+        let now = self.now();
+        for entry in self.index.entries.iter() {
+            let (start, end, want) = (start.clone(), end.clone(), limit + deleted);
+            let table_keys = read_table(&self.storage, &entry.id, self.scan_hints(), move |blob| {
+                SsTable::keys_range(blob, &start, &end, want, now)
+            })
+            .await?;
+            merge(table_keys, &mut deleted);
+        }
+
+        Ok(keys
+            .into_iter()
+            .filter(|(_, live)| *live)
+            .map(|(key, _)| key)
+            .take(limit)
+            .collect())
+    }
+
+    /// Same as scan_keys along with the values, they are merged the same way.
+    async fn scan(
+        &self,
+        start: Bytes,
+        end: Bytes,
+        limit: usize,
+        memtable_entries: Vec<(Bytes, Value)>,
+    ) -> crate::Result<Vec<(Bytes, Bytes)>> {
+        let mut entries: BTreeMap<Bytes, Value> = BTreeMap::new();
+        let mut deleted = 0;
+        let mut merge = |source: Vec<(Bytes, Value)>, deleted: &mut usize| {
+            for (key, value) in source {
+                *deleted += value.is_tombstone() as usize;
+                entries.entry(key).or_insert(value);
+            }
+        };
+
+        merge(memtable_entries, &mut deleted);
+        let now = self.now();
+        for entry in self.index.entries.iter() {
+            let (start, end, want) = (start.clone(), end.clone(), limit + deleted);
+            let table_entries =
+                read_table(&self.storage, &entry.id, self.scan_hints(), move |blob| {
+                    SsTable::entries_range(blob, &start, &end, want, now)
+                })
+                .await?;
+            merge(table_entries, &mut deleted);
+        }
+
+        Ok(entries
+            .into_iter()
+            .filter_map(|(key, value)| value.live().map(|value| (key, value)))
+            .take(limit)
+            .collect())
+    }
+
+    /// Unix time in milliseconds values are checked for expiration against.
+    fn now(&self) -> u64 {
+        self.clock.since_epoch().as_millis() as u64
+    }
+
+    /// Block cache of the table for lookups, None if the cache is off. Scans read blocks once
+    /// and go around it, so that they don't push out the blocks lookups need.
+    fn table_blocks(&self, table_id: Uuid) -> Option<TableCache> {
+        self.blocks.as_ref().map(|blocks| blocks.table(table_id))
+    }
+
+    /// Hints for a table that is looked up for a key.
+    fn lookup_hints(&self) -> (Advisor, Access) {
+        (self.advisor.clone(), Access::Lookup)
+    }
+
+    /// Hints for a table that is read over a range.
+    fn scan_hints(&self) -> (Advisor, Access) {
+        (self.advisor.clone(), Access::Scan)
+    }
+
+    /// Persists a table and puts it to the index.
+    async fn create_table(&mut self, data: MemTable) -> FlushReport {
+        // New table shadows whatever is cached for its keys, including keys remembered
+        // as missing. Until then written keys are served from the memtable.
+        self.cache.invalidate(&data.map);
+
+        let first_key = data.iter().next().map(|(key, _)| key.clone());
+        let last_key = data.iter().last().map(|(key, _)| key.clone());
+        let report = self.persist_table(data).await;
+        self.index.prepend(Entry {
+            id: report.table_id,
+            size: report.size,
+            level: 0,
+            first_key: first_key.unwrap_or_default(),
+            last_key: last_key.unwrap_or_default(),
+            damaged: false,
+        });
+        self.compact_over_limits();
+
+        report
+    }
+
+    async fn persist_table(&self, data: MemTable) -> FlushReport {
+        let id = SsTable::generate_id(self.clock.as_ref());
+        let mut table = SsTable::build_with_format(data, id, self.format);
+        if self.validate_tables {
+            if let Err(e) = table.validate() {
+                panic!("Built an invalid table {}: {}", table.id, e);
+            }
+        }
         let encoded_data = table.encode();
 
-        // TODO: Actually handle when table can't be persisted.
-        self.storage
-            .write(&table.id, &encoded_data)
-            .expect("Cant persist table");
+        // A full disk is waited out, the table stays in memory and the engine stops taking writes
+        // meanwhile (see Config::min_free_space). A table already there under the same id is
+        // never overwritten, the new one gets another id instead. Ids only grow, so it still
+        // sorts as the newest.
+        // TODO: Actually handle when table can't be persisted for other reasons.
+        loop {
+            match self.storage.write(&table.id, &encoded_data).await {
+                Ok(()) => break,
+                Err(e) if e.kind() == std::io::ErrorKind::StorageFull => {
+                    tracing::warn!("no space to persist table {}, retrying: {}", table.id, e);
+                    tokio::time::sleep(NO_SPACE_RETRY_INTERVAL).await;
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                    let id = SsTable::generate_id(self.clock.as_ref());
+                    tracing::error!("table {} already exists, persisting as {}", table.id, id);
+                    table.id = id;
+                }
+                Err(e) => panic!("Cant persist table: {}", e),
+            }
+        }
+
+        FlushReport {
+            table_id: table.id,
+            size: encoded_data.len() as u64,
+        }
+    }
+}
+
+/// Opens a table and reads it on the blocking thread pool. Table reads block on storage, doing
+/// them right in the dispatcher task would hold a runtime thread for the time of disk access.
+/// Tables are never modified once written, so they are safe to read from another thread.
+/// Storage is given hints about the read along the way (see Advisor).
+async fn read_table<T, R, F>(
+    storage: &T,
+    table_id: &Uuid,
+    (advisor, access): (Advisor, Access),
+    read: F,
+) -> crate::Result<R>
+where
+    T: crate::AsyncStorage,
+    R: Send + 'static,
+    F: FnOnce(&T::Entry) -> crate::Result<R> + Send + 'static,
+{
+    let blob = storage.open(table_id).await?;
+
+    let res = tokio::task::spawn_blocking(move || {
+        advisor.before(&blob, access);
+        let res = read(&blob);
+        advisor.after(&blob, access);
+        res
+    })
+    .await?;
+    // The read fails, other tables are still served (see fsck to find out what is damaged).
+    if let Err(crate::Error::Corrupted(e)) = &res {
+        tracing::error!("table {} is corrupted: {}", table_id, e);
+    }
+
+    res
+}
+
+/// Sends compaction results to the dispatcher and waits for the index to be updated.
+async fn apply_compaction(
+    cmd_tx: mpsc::WeakSender<Command>,
+    inputs: Vec<Uuid>,
+    outputs: Vec<Entry>,
+) -> crate::Result<()> {
+    let cmd_tx = cmd_tx.upgrade().ok_or("dispatcher is gone")?;
+    let (resp_tx, resp_rx) = oneshot::channel();
+
+    cmd_tx
+        .send(Command::ReplaceTables {
+            inputs,
+            outputs,
+            responder: resp_tx,
+        })
+        .await
+        .map_err(|_| "dispatcher is gone")?;
+
+    resp_rx.await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::memtable::SsTableSize;
+    use crate::storage::mem;
+    use crate::Storage;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// Memory storage that counts how many times tables were opened.
+    #[derive(Clone)]
+    struct CountingStorage {
+        inner: mem::MemStorage,
+        opened: Arc<AtomicUsize>,
+    }
+
+    impl Storage for CountingStorage {
+        type Entry = Vec<u8>;
+
+        fn bootstrap(&self) -> std::io::Result<()> {
+            self.inner.bootstrap()
+        }
+
+        fn list_entries(&self) -> std::io::Result<Vec<Uuid>> {
+            self.inner.list_entries()
+        }
+
+        fn write(&self, table_id: &Uuid, data: &[u8]) -> std::io::Result<()> {
+            self.inner.write(table_id, data)
+        }
+
+        fn open(&self, table_id: &Uuid) -> std::io::Result<Self::Entry> {
+            self.opened.fetch_add(1, Ordering::SeqCst);
+            self.inner.open(table_id)
+        }
+
+        fn delete(&self, table_id: &Uuid) -> std::io::Result<()> {
+            self.inner.delete(table_id)
+        }
+    }
+
+    /// Storage behind a slow network. Its calls give the runtime back while waiting.
+    #[derive(Clone)]
+    struct NetStorage {
+        inner: mem::MemStorage,
+        latency: Duration,
+    }
+
+    impl crate::AsyncStorage for NetStorage {
+        type Entry = Vec<u8>;
+
+        async fn bootstrap(&self) -> std::io::Result<()> {
+            Storage::bootstrap(&self.inner)
+        }
+
+        async fn list_entries(&self) -> std::io::Result<Vec<Uuid>> {
+            tokio::time::sleep(self.latency).await;
+            Storage::list_entries(&self.inner)
+        }
+
+        async fn write(&self, table_id: &Uuid, data: &[u8]) -> std::io::Result<()> {
+            tokio::time::sleep(self.latency).await;
+            Storage::write(&self.inner, table_id, data)
+        }
+
+        async fn open(&self, table_id: &Uuid) -> std::io::Result<Self::Entry> {
+            tokio::time::sleep(self.latency).await;
+            Storage::open(&self.inner, table_id)
+        }
+
+        async fn delete(&self, table_id: &Uuid) -> std::io::Result<()> {
+            tokio::time::sleep(self.latency).await;
+            Storage::delete(&self.inner, table_id)
+        }
+    }
+
+    /// Storage on a slow disk. Reads of its tables block the calling thread.
+    #[derive(Clone)]
+    struct DiskStorage {
+        inner: mem::MemStorage,
+        latency: Duration,
+    }
+
+    struct DiskEntry {
+        data: Vec<u8>,
+        latency: Duration,
+    }
+
+    impl crate::StorageEntry for DiskEntry {
+        fn read_at(&self, data: &mut Vec<u8>, position: u64) -> std::io::Result<()> {
+            std::thread::sleep(self.latency);
+            self.data.read_at(data, position)
+        }
+
+        fn size(&self) -> std::io::Result<u64> {
+            self.data.size()
+        }
+    }
+
+    impl Storage for DiskStorage {
+        type Entry = DiskEntry;
+
+        fn bootstrap(&self) -> std::io::Result<()> {
+            self.inner.bootstrap()
+        }
+
+        fn list_entries(&self) -> std::io::Result<Vec<Uuid>> {
+            self.inner.list_entries()
+        }
+
+        fn write(&self, table_id: &Uuid, data: &[u8]) -> std::io::Result<()> {
+            self.inner.write(table_id, data)
+        }
+
+        fn open(&self, table_id: &Uuid) -> std::io::Result<Self::Entry> {
+            Ok(DiskEntry {
+                data: self.inner.open(table_id)?,
+                latency: self.latency,
+            })
+        }
+
+        fn delete(&self, table_id: &Uuid) -> std::io::Result<()> {
+            self.inner.delete(table_id)
+        }
+    }
+
+    fn table(pairs: &[(&'static str, &'static str)]) -> MemTable {
+        let mut memtable = MemTable::new(SsTableSize::Default);
+        for (key, value) in pairs {
+            memtable.insert(Bytes::from(*key), Bytes::from(*value), 1, None);
+        }
+        memtable
+    }
+
+    async fn send<R>(
+        cmd_tx: &mpsc::Sender<Command>,
+        cmd: impl FnOnce(Responder<R>) -> Command,
+    ) -> crate::Result<R> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        cmd_tx.send(cmd(resp_tx)).await.unwrap();
+        resp_rx.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_cache_not_stale_after_compaction() {
+        let (cmd_tx, cmd_rx) = mpsc::channel(32);
+        let dispatcher =
+            Dispatcher::init(cmd_rx, cmd_tx.downgrade(), mem::new(), &Config::default())
+                .await
+                .unwrap();
+        tokio::spawn(dispatcher.run());
+
+        let key = Bytes::from("foo");
+        let get = |responder| Command::Get {
+            key: key.clone(),
+            fill_cache: true,
+            responder,
+        };
+
+        send(&cmd_tx, |responder| Command::CreateTable {
+            data: table(&[("foo", "old"), ("bar", "bar")]),
+            responder,
+        })
+        .await
+        .unwrap();
+        assert_eq!(send(&cmd_tx, get).await.unwrap(), Some(Bytes::from("old")));
+        assert_eq!(send(&cmd_tx, get).await.unwrap(), Some(Bytes::from("old")));
+
+        send(&cmd_tx, |responder| Command::CreateTable {
+            data: table(&[("foo", "new")]),
+            responder,
+        })
+        .await
+        .unwrap();
+        assert_eq!(send(&cmd_tx, get).await.unwrap(), Some(Bytes::from("new")));
+
+        send(&cmd_tx, |responder| Command::Compact { responder })
+            .await
+            .unwrap();
+        assert_eq!(send(&cmd_tx, get).await.unwrap(), Some(Bytes::from("new")));
+        assert_eq!(
+            send(&cmd_tx, |responder| Command::Get {
+                key: Bytes::from("bar"),
+                fill_cache: true,
+                responder,
+            })
+            .await
+            .unwrap(),
+            Some(Bytes::from("bar"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_newer_table_shadows_older() {
+        let storage = mem::new();
+        let get = |responder| Command::Get {
+            key: Bytes::from("foo"),
+            fill_cache: true,
+            responder,
+        };
+
+        let (cmd_tx, cmd_rx) = mpsc::channel(32);
+        let dispatcher = Dispatcher::init(
+            cmd_rx,
+            cmd_tx.downgrade(),
+            storage.clone(),
+            &Config::default(),
+        )
+        .await
+        .unwrap();
+        let handle = tokio::spawn(dispatcher.run());
+
+        for value in ["v1", "v2"] {
+            send(&cmd_tx, |responder| Command::CreateTable {
+                data: table(&[("foo", value)]),
+                responder,
+            })
+            .await
+            .unwrap();
+        }
+        assert_eq!(send(&cmd_tx, get).await.unwrap(), Some(Bytes::from("v2")));
+
+        drop(cmd_tx);
+        handle.await.unwrap();
+
+        // A fresh dispatcher starts with an empty cache and the index built from storage, so the
+        // value can only come from walking the tables on disk.
+        let (cmd_tx, cmd_rx) = mpsc::channel(32);
+        let dispatcher = Dispatcher::init(
+            cmd_rx,
+            cmd_tx.downgrade(),
+            storage.clone(),
+            &Config::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            dispatcher
+                .index
+                .entries
+                .iter()
+                .map(|entry| entry.id)
+                .collect::<Vec<_>>(),
+            storage.list_entries().unwrap(),
+            "index is ordered the same way storage lists tables, newest first"
+        );
+        tokio::spawn(dispatcher.run());
+
+        assert_eq!(send(&cmd_tx, get).await.unwrap(), Some(Bytes::from("v2")));
+    }
+
+    #[tokio::test]
+    async fn test_compaction_leftover_dropped() {
+        let storage = mem::new();
+        let get = |responder| Command::Get {
+            key: Bytes::from("foo"),
+            fill_cache: false,
+            responder,
+        };
+
+        let (cmd_tx, cmd_rx) = mpsc::channel(32);
+        let dispatcher = Dispatcher::init(
+            cmd_rx,
+            cmd_tx.downgrade(),
+            storage.clone(),
+            &Config::default(),
+        )
+        .await
+        .unwrap();
+        let handle = tokio::spawn(dispatcher.run());
+
+        send(&cmd_tx, |responder| Command::CreateTable {
+            data: table(&[("bar", "bar"), ("foo", "v1")]),
+            responder,
+        })
+        .await
+        .unwrap();
+        send(&cmd_tx, |responder| Command::Compact { responder })
+            .await
+            .unwrap();
+        let [older] = storage.list_entries().unwrap()[..] else {
+            panic!("a single level 1 table expected");
+        };
+        let older_data = storage.open(&older).unwrap();
+
+        // Push a newer value down to level 1 along with the table holding the older one.
+        send(&cmd_tx, |responder| Command::CreateTable {
+            data: table(&[("foo", "v2")]),
+            responder,
+        })
+        .await
+        .unwrap();
+        send(&cmd_tx, |responder| Command::Compact { responder })
+            .await
+            .unwrap();
+        drop(cmd_tx);
+        handle.await.unwrap();
+
+        // A crash right after the outputs were written leaves the older table behind.
+        storage.write(&older, &older_data).unwrap();
+        let (cmd_tx, cmd_rx) = mpsc::channel(32);
+        let dispatcher = Dispatcher::init(
+            cmd_rx,
+            cmd_tx.downgrade(),
+            storage.clone(),
+            &Config::default(),
+        )
+        .await
+        .unwrap();
+        assert!(dispatcher.index.entries.iter().all(|e| e.id != older));
+        assert!(!storage.list_entries().unwrap().contains(&older));
+        tokio::spawn(dispatcher.run());
+
+        assert_eq!(send(&cmd_tx, get).await.unwrap(), Some(Bytes::from("v2")));
+    }
+
+    #[tokio::test]
+    async fn test_async_storage_does_not_block() {
+        let storage = NetStorage {
+            inner: mem::new(),
+            latency: Duration::from_millis(50),
+        };
+        let (cmd_tx, cmd_rx) = mpsc::channel(32);
+        let dispatcher = Dispatcher::init(cmd_rx, cmd_tx.downgrade(), storage, &Config::default())
+            .await
+            .unwrap();
+        tokio::spawn(dispatcher.run());
+
+        send(&cmd_tx, |responder| Command::CreateTable {
+            data: table(&[("foo", "bar")]),
+            responder,
+        })
+        .await
+        .unwrap();
+
+        // Test runtime has a single thread, ticks only happen while dispatcher awaits storage.
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let ticker = tokio::spawn({
+            let ticks = ticks.clone();
+            async move {
+                loop {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                    ticks.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        });
+
+        let value = send(&cmd_tx, |responder| Command::Get {
+            key: Bytes::from("foo"),
+            fill_cache: true,
+            responder,
+        })
+        .await
+        .unwrap();
+        ticker.abort();
+
+        assert_eq!(value, Some(Bytes::from("bar")));
+        assert!(
+            ticks.load(Ordering::SeqCst) >= 2,
+            "runtime is blocked while storage is read"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_blocking_reads_off_runtime() {
+        let storage = DiskStorage {
+            inner: mem::new(),
+            latency: Duration::from_millis(10),
+        };
+        let (cmd_tx, cmd_rx) = mpsc::channel(32);
+        let dispatcher = Dispatcher::init(cmd_rx, cmd_tx.downgrade(), storage, &Config::default())
+            .await
+            .unwrap();
+        tokio::spawn(dispatcher.run());
+
+        send(&cmd_tx, |responder| Command::CreateTable {
+            data: table(&[("foo", "bar")]),
+            responder,
+        })
+        .await
+        .unwrap();
+
+        // Test runtime has a single thread. Were tables read on it, the ticker would stand still
+        // for as long as the reads take.
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let ticker = tokio::spawn({
+            let ticks = ticks.clone();
+            async move {
+                loop {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                    ticks.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        });
+
+        let mut readers = tokio::task::JoinSet::new();
+        for _ in 0..4 {
+            let cmd_tx = cmd_tx.clone();
+            readers.spawn(async move {
+                for _ in 0..3 {
+                    // Cache is bypassed, every get reads the table.
+                    let value = send(&cmd_tx, |responder| Command::Get {
+                        key: Bytes::from("foo"),
+                        fill_cache: false,
+                        responder,
+                    })
+                    .await
+                    .unwrap();
+                    assert_eq!(value, Some(Bytes::from("bar")));
+                }
+            });
+        }
+        while let Some(res) = readers.join_next().await {
+            res.unwrap();
+        }
+        ticker.abort();
+
+        // 12 gets take at least 360ms of blocked reads, each reads the bloom filter, the index and
+        // a block.
+        assert!(
+            ticks.load(Ordering::SeqCst) >= 20,
+            "runtime is blocked while tables are read"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrent_reads() {
+        let (cmd_tx, cmd_rx) = mpsc::channel(32);
+        let config = Config {
+            dispatcher_buffer_min: 16,
+            ..Default::default()
+        };
+        let dispatcher = Dispatcher::init(cmd_rx, cmd_tx.downgrade(), mem::new(), &config)
+            .await
+            .unwrap();
+        tokio::spawn(dispatcher.run());
+
+        let keys: Vec<String> = (0..8).map(|i| format!("key-{}", i)).collect();
+        for (i, key) in keys.iter().enumerate() {
+            let mut data = MemTable::new(SsTableSize::Default);
+            data.insert(
+                Bytes::from(key.clone()),
+                Bytes::from(format!("{}", i)),
+                1,
+                None,
+            );
+            send(&cmd_tx, |responder| Command::CreateTable {
+                data,
+                responder,
+            })
+            .await
+            .unwrap();
+        }
+
+        let mut readers = tokio::task::JoinSet::new();
+        for _ in 0..4 {
+            let cmd_tx = cmd_tx.clone();
+            let keys = keys.clone();
+            readers.spawn(async move {
+                for (i, key) in keys.iter().enumerate() {
+                    let value = send(&cmd_tx, |responder| Command::Get {
+                        key: Bytes::from(key.clone()),
+                        fill_cache: true,
+                        responder,
+                    })
+                    .await
+                    .unwrap();
+                    assert_eq!(value, Some(Bytes::from(format!("{}", i))));
+                }
+            });
+        }
+
+        while let Some(res) = readers.join_next().await {
+            res.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_missing_key_cached() {
+        let opened = Arc::new(AtomicUsize::new(0));
+        let storage = CountingStorage {
+            inner: mem::new(),
+            opened: opened.clone(),
+        };
+        let config = Config {
+            negative_cache_ttl: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+
+        let (cmd_tx, cmd_rx) = mpsc::channel(32);
+        let dispatcher = Dispatcher::init(cmd_rx, cmd_tx.downgrade(), storage, &config)
+            .await
+            .unwrap();
+        tokio::spawn(dispatcher.run());
+
+        send(&cmd_tx, |responder| Command::CreateTable {
+            data: table(&[("foo", "foo")]),
+            responder,
+        })
+        .await
+        .unwrap();
+
+        let get = |responder| Command::Get {
+            key: Bytes::from("bar"),
+            fill_cache: true,
+            responder,
+        };
+        for _ in 0..10 {
+            assert_eq!(send(&cmd_tx, get).await.unwrap(), None);
+        }
+        assert_eq!(opened.load(Ordering::SeqCst), 1);
+
+        send(&cmd_tx, |responder| Command::CreateTable {
+            data: table(&[("bar", "bar")]),
+            responder,
+        })
+        .await
+        .unwrap();
+        assert_eq!(send(&cmd_tx, get).await.unwrap(), Some(Bytes::from("bar")));
+    }
+
+    #[tokio::test]
+    async fn test_get_without_filling_cache() {
+        let (cmd_tx, cmd_rx) = mpsc::channel(32);
+        let dispatcher =
+            Dispatcher::init(cmd_rx, cmd_tx.downgrade(), mem::new(), &Default::default())
+                .await
+                .unwrap();
+        tokio::spawn(dispatcher.run());
+
+        let mut data = MemTable::new(SsTableSize::Default);
+        for i in 0..Config::default().cache_capacity * 3 {
+            data.insert(
+                Bytes::from(format!("key-{:04}", i)),
+                Bytes::from("v"),
+                1,
+                None,
+            );
+        }
+        data.insert(Bytes::from("hot"), Bytes::from("hot"), 1, None);
+        send(&cmd_tx, |responder| Command::CreateTable {
+            data,
+            responder,
+        })
+        .await
+        .unwrap();
+
+        let get = |key: Bytes, fill_cache| {
+            move |responder| Command::Get {
+                key,
+                fill_cache,
+                responder,
+            }
+        };
+        for _ in 0..2 {
+            send(&cmd_tx, get(Bytes::from("hot"), true)).await.unwrap();
+        }
+
+        // Read more often than the hot key, these would take the whole cache if they filled it.
+        for _ in 0..3 {
+            for i in 0..Config::default().cache_capacity * 3 {
+                let key = Bytes::from(format!("key-{:04}", i));
+                assert_eq!(
+                    send(&cmd_tx, get(key, false)).await.unwrap(),
+                    Some(Bytes::from("v"))
+                );
+            }
+        }
+
+        assert_eq!(
+            send(&cmd_tx, get(Bytes::from("hot"), false)).await.unwrap(),
+            Some(Bytes::from("hot"))
+        );
+        let (stats, _, _, _) = send(&cmd_tx, |responder| Command::Stats { responder })
+            .await
+            .unwrap();
+        assert_eq!(
+            (stats.insertions, stats.evictions, stats.size),
+            (1, 0, 1),
+            "only the hot key is cached"
+        );
+        assert_eq!(
+            (stats.hits, stats.misses),
+            (1, 1),
+            "reads without filling are not counted"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_block_cache() {
+        let config = Config {
+            cache_capacity: 0,
+            block_cache_capacity: 4,
+            ..Default::default()
+        };
+        let (cmd_tx, cmd_rx) = mpsc::channel(32);
+        let dispatcher = Dispatcher::init(cmd_rx, cmd_tx.downgrade(), mem::new(), &config)
+            .await
+            .unwrap();
+        tokio::spawn(dispatcher.run());
+
+        send(&cmd_tx, |responder| Command::CreateTable {
+            data: table(&[("bar", "bar"), ("foo", "foo")]),
+            responder,
+        })
+        .await
+        .unwrap();
+
+        // Both keys are in the same block, it is read once.
+        for key in ["foo", "bar", "foo"] {
+            let value = send(&cmd_tx, |responder| Command::Get {
+                key: Bytes::from(key),
+                fill_cache: true,
+                responder,
+            })
+            .await
+            .unwrap();
+            assert_eq!(value, Some(Bytes::from(key)));
+        }
+        let (_, blocks, _, _) = send(&cmd_tx, |responder| Command::Stats { responder })
+            .await
+            .unwrap();
+        assert_eq!((blocks.hits, blocks.misses, blocks.size), (2, 1, 1));
+    }
+
+    #[tokio::test]
+    async fn test_admin_reports() {
+        let stor = mem::new();
+        let (cmd_tx, cmd_rx) = mpsc::channel(32);
+        let dispatcher =
+            Dispatcher::init(cmd_rx, cmd_tx.downgrade(), stor.clone(), &Config::default())
+                .await
+                .unwrap();
+        tokio::spawn(dispatcher.run());
+
+        for data in [&[("a", "a")], &[("b", "b")], &[("c", "c")]] {
+            let report = send(&cmd_tx, |responder| Command::Flush {
+                data: table(data),
+                responder,
+            })
+            .await
+            .unwrap();
+            let blob = stor.open(&report.table_id).unwrap();
+            assert_eq!(report.size, blob.len() as u64);
+        }
+
+        let before = stor.list_entries().unwrap();
+        let report = send(&cmd_tx, |responder| Command::Compact { responder })
+            .await
+            .unwrap();
+        let after = stor.list_entries().unwrap();
+
+        assert_eq!(report.tables_merged, 3);
+        assert_eq!(report.tables_created, 1);
+        assert_eq!(
+            before.len() - after.len(),
+            report.tables_merged - report.tables_created
+        );
+
+        let created: Vec<_> = after.iter().filter(|id| !before.contains(id)).collect();
+        assert_eq!(created.len(), report.tables_created);
+        assert_eq!(
+            report.bytes_written,
+            stor.open(created[0]).unwrap().len() as u64
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rebuild_bloom() {
+        let stor = mem::new();
+        let mut damaged = SsTable::build(table(&[("foo", "1"), ("bar", "2")]));
+        damaged.bloom = crate::engine::sstable::bloom::new(1, 0.01); // Knows no keys.
+        stor.write(&damaged.id, &damaged.encode()).unwrap();
+
+        let (cmd_tx, cmd_rx) = mpsc::channel(32);
+        let dispatcher =
+            Dispatcher::init(cmd_rx, cmd_tx.downgrade(), stor.clone(), &Config::default())
+                .await
+                .unwrap();
+        tokio::spawn(dispatcher.run());
+
+        let get = |responder| Command::Get {
+            key: Bytes::from("foo"),
+            fill_cache: false,
+            responder,
+        };
+        assert_eq!(
+            send(&cmd_tx, get).await.unwrap(),
+            None,
+            "bloom short-circuits"
+        );
+
+        let unknown = Uuid::now_v7();
+        assert!(matches!(
+            send(&cmd_tx, |responder| Command::RebuildBloom {
+                table_id: unknown,
+                responder,
+            })
+            .await,
+            Err(crate::Error::InvalidInput(_))
+        ));
+
+        let rebuilt = send(&cmd_tx, |responder| Command::RebuildBloom {
+            table_id: damaged.id,
+            responder,
+        })
+        .await
+        .unwrap();
+        assert!(rebuilt > damaged.id);
+        assert_eq!(stor.list_entries().unwrap(), vec![rebuilt]);
+
+        assert_eq!(send(&cmd_tx, get).await.unwrap(), Some(Bytes::from("1")));
+        assert_eq!(
+            send(&cmd_tx, |responder| Command::Get {
+                key: Bytes::from("bar"),
+                fill_cache: false,
+                responder,
+            })
+            .await
+            .unwrap(),
+            Some(Bytes::from("2"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_compaction_skips_damaged() {
+        let stor = mem::new();
+        let damaged = SsTable::generate_id(&crate::SystemClock);
+        stor.write(&damaged, b"not a table").unwrap();
+        let table = SsTable::build(table(&[("foo", "1")]));
+        stor.write(&table.id, &table.encode()).unwrap();
+
+        let (cmd_tx, cmd_rx) = mpsc::channel(32);
+        let dispatcher =
+            Dispatcher::init(cmd_rx, cmd_tx.downgrade(), stor.clone(), &Config::default())
+                .await
+                .unwrap();
+        assert!(dispatcher.index.entries.iter().any(|e| e.damaged));
+        tokio::spawn(dispatcher.run());
+
+        send(&cmd_tx, |responder| Command::Compact { responder })
+            .await
+            .unwrap();
+        let tables = stor.list_entries().unwrap();
+        assert_eq!(tables.len(), 2);
+        assert!(tables.contains(&damaged), "damaged table is left be");
+        assert!(!tables.contains(&table.id));
+    }
+
+    #[tokio::test]
+    async fn test_index_sizes() {
+        let stor = mem::new();
+        let (cmd_tx, cmd_rx) = mpsc::channel(32);
+        let dispatcher =
+            Dispatcher::init(cmd_rx, cmd_tx.downgrade(), stor.clone(), &Config::default())
+                .await
+                .unwrap();
+        tokio::spawn(dispatcher.run());
+
+        for data in [&[("a", "a")], &[("b", "bb")], &[("c", "ccc")]] {
+            send(&cmd_tx, |responder| Command::Flush {
+                data: table(data),
+                responder,
+            })
+            .await
+            .unwrap();
+        }
+        send(&cmd_tx, |responder| Command::Compact { responder })
+            .await
+            .unwrap();
+        send(&cmd_tx, |responder| Command::Flush {
+            data: table(&[("b", "b"), ("d", "d")]),
+            responder,
+        })
+        .await
+        .unwrap();
+
+        // Index of a restarted dispatcher is built from what the storage lists, levels and keys
+        // are read from the tables.
+        let (cmd_tx, cmd_rx) = mpsc::channel(32);
+        let dispatcher =
+            Dispatcher::init(cmd_rx, cmd_tx.downgrade(), stor.clone(), &Config::default())
+                .await
+                .unwrap();
+
+        let placed: Vec<_> = dispatcher
+            .index
+            .entries
+            .iter()
+            .map(|entry| (entry.level, entry.first_key.clone(), entry.last_key.clone()))
+            .collect();
+        assert_eq!(
+            placed,
+            vec![
+                (0, Bytes::from("b"), Bytes::from("d")),
+                (1, Bytes::from("a"), Bytes::from("c")),
+            ]
+        );
+        for entry in &dispatcher.index.entries {
+            assert_eq!(entry.size, stor.open(&entry.id).unwrap().len() as u64);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_compaction_limits() {
+        let one_table = SsTable::build(table(&[("a", "a")])).encode().len() as u64;
+        let limits = [
+            Config {
+                compaction_max_tables: Some(3),
+                ..Default::default()
+            },
+            Config {
+                compaction_max_bytes: Some(one_table * 3 + one_table / 2),
+                ..Default::default()
+            },
+        ];
+
+        for config in limits {
+            let stor = mem::new();
+            let (cmd_tx, cmd_rx) = mpsc::channel(32);
+            let dispatcher = Dispatcher::init(cmd_rx, cmd_tx.downgrade(), stor.clone(), &config)
+                .await
+                .unwrap();
+            tokio::spawn(dispatcher.run());
+
+            let flush =
+                |data: MemTable| send(&cmd_tx, |responder| Command::Flush { data, responder });
+            for data in [&[("a", "a")], &[("b", "b")], &[("c", "c")]] {
+                flush(table(data)).await.unwrap();
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            assert_eq!(stor.list_entries().unwrap().len(), 3, "within the limits");
+
+            flush(table(&[("d", "d")])).await.unwrap();
+            let compacted = async {
+                while stor.list_entries().unwrap().len() > 3 {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            };
+            tokio::time::timeout(Duration::from_secs(2), compacted)
+                .await
+                .expect("compaction should start once over the limits");
+
+            for key in ["a", "b", "c", "d"] {
+                let value = send(&cmd_tx, |responder| Command::Get {
+                    key: Bytes::from(key),
+                    fill_cache: true,
+                    responder,
+                })
+                .await
+                .unwrap();
+                assert_eq!(value, Some(Bytes::from(key)));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_compaction_bounds_tables() {
+        let config = Config {
+            compaction_max_tables: Some(4),
+            ..Default::default()
+        };
+        let stor = mem::new();
+        let (cmd_tx, cmd_rx) = mpsc::channel(32);
+        let dispatcher = Dispatcher::init(cmd_rx, cmd_tx.downgrade(), stor.clone(), &config)
+            .await
+            .unwrap();
+        tokio::spawn(dispatcher.run());
+
+        // The same keys written over and over end up in a single level 1 table.
+        for round in 0..30 {
+            let mut data = MemTable::new(SsTableSize::Default);
+            for i in 0..100 {
+                let key = Bytes::from(format!("key-{:03}", i));
+                data.insert(key, Bytes::from(format!("{}", round)), round, None);
+            }
+            send(&cmd_tx, |responder| Command::Flush { data, responder })
+                .await
+                .unwrap();
+        }
+
+        let compacted = async {
+            while stor.list_entries().unwrap().len() > 4 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), compacted)
+            .await
+            .expect("tables should stay within the limit");
+
+        let value = send(&cmd_tx, |responder| Command::Get {
+            key: Bytes::from("key-042"),
+            fill_cache: false,
+            responder,
+        })
+        .await
+        .unwrap();
+        assert_eq!(value, Some(Bytes::from("29")));
+    }
+
+    #[tokio::test]
+    async fn test_cache_warm_up() {
+        let hints = std::env::temp_dir().join(format!("bureau-warmup-{}", std::process::id()));
+        let config = Config {
+            cache_hints: Some(hints.clone()),
+            ..Default::default()
+        };
+        let opened = Arc::new(AtomicUsize::new(0));
+        let storage = CountingStorage {
+            inner: mem::new(),
+            opened: opened.clone(),
+        };
+        let get = |responder| Command::Get {
+            key: Bytes::from("foo"),
+            fill_cache: true,
+            responder,
+        };
+
+        let (cmd_tx, cmd_rx) = mpsc::channel(32);
+        let dispatcher = Dispatcher::init(cmd_rx, cmd_tx.downgrade(), storage.clone(), &config)
+            .await
+            .unwrap();
+        let handle = tokio::spawn(dispatcher.run());
+        send(&cmd_tx, |responder| Command::CreateTable {
+            data: table(&[("foo", "bar"), ("baz", "qux")]),
+            responder,
+        })
+        .await
+        .unwrap();
+        send(&cmd_tx, get).await.unwrap();
+        drop(cmd_tx);
+        handle.await.unwrap();
+
+        let (cmd_tx, cmd_rx) = mpsc::channel(32);
+        let dispatcher = Dispatcher::init(cmd_rx, cmd_tx.downgrade(), storage, &config)
+            .await
+            .unwrap();
+        tokio::spawn(dispatcher.run());
+
+        let opened_before = opened.load(Ordering::SeqCst);
+        assert_eq!(send(&cmd_tx, get).await.unwrap(), Some(Bytes::from("bar")));
+        assert_eq!(opened.load(Ordering::SeqCst), opened_before);
+        let (stats, _, _, _) = send(&cmd_tx, |responder| Command::Stats { responder })
+            .await
+            .unwrap();
+        assert_eq!((stats.hits, stats.misses, stats.size), (1, 0, 1));
 
-        table.id
+        std::fs::remove_file(&hints).ok();
     }
 }
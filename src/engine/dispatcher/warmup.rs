@@ -0,0 +1,94 @@
+use bytes::{Buf, BufMut, Bytes};
+use std::path::Path;
+use uuid::Uuid;
+
+/*
+Cache hints file layout.
+----------------------------------------------------------------------------------
+| Newest table id (16B) | key_len (2B) | key #1 | ... | key_len (2B) | key #N |
+----------------------------------------------------------------------------------
+
+Newest table id is the one the index started with when hints were written, or zeroes if there
+were no tables.
+*/
+
+/// Writes keys of the cached values along with the newest table id, so that the next start can
+/// tell if tables changed since.
+pub async fn save(path: &Path, newest: Option<Uuid>, keys: &[Bytes]) -> std::io::Result<()> {
+    let mut buf = Vec::new();
+    buf.put_u128(newest.map_or(0, |id| id.as_u128()));
+    for key in keys {
+        buf.put_u16(key.len() as u16);
+        buf.put_slice(key);
+    }
+
+    tokio::fs::write(path, buf).await
+}
+
+/// Reads keys written by save. Hints are only good for the tables they were written for, if
+/// the newest table is not the same any more, or the file is missing or damaged, there are no
+/// hints.
+pub async fn load(path: &Path, newest: Option<Uuid>) -> Vec<Bytes> {
+    let data = match tokio::fs::read(path).await {
+        Ok(data) => Bytes::from(data),
+        Err(e) => {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                tracing::warn!("could not read cache hints: {}", e);
+            }
+            return Vec::new();
+        }
+    };
+
+    match decode(data, newest) {
+        Some(keys) => keys,
+        None => {
+            tracing::info!("cache hints are stale or damaged, skipping warmup");
+            Vec::new()
+        }
+    }
+}
+
+fn decode(mut data: Bytes, newest: Option<Uuid>) -> Option<Vec<Bytes>> {
+    if data.remaining() < 16 || data.get_u128() != newest.map_or(0, |id| id.as_u128()) {
+        return None;
+    }
+
+    let mut keys = Vec::new();
+    while data.has_remaining() {
+        if data.remaining() < 2 {
+            return None;
+        }
+        let len = data.get_u16() as usize;
+        if data.remaining() < len {
+            return None;
+        }
+        keys.push(data.split_to(len));
+    }
+
+    Some(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_save_and_load() {
+        let path = std::env::temp_dir().join(format!("bureau-hints-{}", std::process::id()));
+        let newest = Some(Uuid::now_v7());
+        let keys = vec![Bytes::from("foo"), Bytes::from("bar")];
+
+        save(&path, newest, &keys).await.unwrap();
+        assert_eq!(load(&path, newest).await, keys);
+        assert!(
+            load(&path, Some(Uuid::now_v7())).await.is_empty(),
+            "hints are stale once a new table is written"
+        );
+
+        tokio::fs::write(&path, &[0; 17]).await.unwrap();
+        assert!(load(&path, None).await.is_empty(), "damaged hints");
+
+        tokio::fs::remove_file(&path).await.unwrap();
+        assert!(load(&path, newest).await.is_empty());
+    }
+}
@@ -1,11 +1,12 @@
 use crate::engine;
 use crate::engine::sstable::block;
-use crate::engine::wal::Wal;
 use bytes::Bytes;
 use std::collections::btree_map::BTreeMap;
+use std::collections::HashMap;
 
 pub const SSTABLE_BYTESIZE: u32 = 64 * 1024; // 64KB (16 blocks).
-const MAX_ENTRY_SIZE: u32 = engine::MAX_KEY_SIZE + engine::MAX_VALUE_SIZE + block::ENTRY_OVERHEAD;
+const MAX_ENTRY_SIZE: u32 =
+    engine::MAX_KEY_SIZE + engine::MAX_VALUE_SIZE + block::EXPIRES_AT_SIZE + block::ENTRY_OVERHEAD;
 
 /// It's a map with ordered keys. Size keeps track of memtable size in bytes according to layout of sstable.
 /// Max size is a limit after which a table will be flushed to disk. Note that all the size calculations here
@@ -13,20 +14,148 @@ const MAX_ENTRY_SIZE: u32 = engine::MAX_KEY_SIZE + engine::MAX_VALUE_SIZE + bloc
 /// every time a key is added or being updated. Number of blocks in the table and its final size are nor strict
 /// numbers nor guaranteed. What really matters is that we have blocks that are 4Kb (a memory page) in size
 /// and that we want minimize blocks padding (zeroes at the end of a block) if possible.
+/// Every key also keeps the sequence number of the write that put its value and the flags
+/// (content type) it was set with. Only the latest value of a key is kept, an overwrite drops
+/// the previous version.
+/// A deleted key keeps a tombstone in place of its value (see Value).
+/// Keys and values live in an OrderedStore, a BTreeMap unless another one is asked for.
 #[derive(Debug, Clone)]
-pub struct MemTable {
-    pub map: BTreeMap<Bytes, Bytes>,
+pub struct MemTable<M = BTreeMap<Bytes, Value>> {
+    pub map: M,
+    seqs: HashMap<Bytes, u64>,
+    /// Only values set with non-zero flags are here.
+    flags: HashMap<Bytes, u8>,
     size: u32,
     max_size: u32,
 }
 
+/// What memtable and tables keep for a key. A delete doesn't remove the key, a tombstone is put in
+/// place of its value instead, so that it hides the older values of the key in the tables on disk.
+/// An expiring value is kept along with the unix time in milliseconds it expires at, readers
+/// turn it into a tombstone once the time has come (see Value::expire).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Live(Bytes),
+    Tombstone,
+    Expiring { value: Bytes, expires_at: u64 },
+}
+
+impl Value {
+    /// The value unless the key is deleted. Expiration is not checked, see expire.
+    pub fn live(self) -> Option<Bytes> {
+        match self {
+            Value::Live(value) | Value::Expiring { value, .. } => Some(value),
+            Value::Tombstone => None,
+        }
+    }
+
+    /// The same value unless it is expired by now (unix time in milliseconds). An expired value
+    /// reads as a tombstone, so that it still hides the older values of the key.
+    pub fn expire(self, now: u64) -> Value {
+        match self {
+            value if !value.is_live_at(now) => Value::Tombstone,
+            value => value,
+        }
+    }
+
+    /// Tells if the value is there as of now, that is it is neither deleted nor expired.
+    pub fn is_live_at(&self, now: u64) -> bool {
+        match self {
+            Value::Live(_) => true,
+            Value::Tombstone => false,
+            Value::Expiring { expires_at, .. } => *expires_at > now,
+        }
+    }
+
+    /// Unix time in milliseconds the value expires at, None if it never does.
+    pub fn expires_at(&self) -> Option<u64> {
+        match self {
+            Value::Expiring { expires_at, .. } => Some(*expires_at),
+            _ => None,
+        }
+    }
+
+    pub fn is_tombstone(&self) -> bool {
+        matches!(self, Value::Tombstone)
+    }
+
+    /// Bytes of the value, a tombstone has none.
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Value::Live(value) | Value::Expiring { value, .. } => value,
+            Value::Tombstone => &[],
+        }
+    }
+}
+
+/// Map with ordered keys memtable keeps its data in. It exists so that other data structures can
+/// be tried and benchmarked against each other without touching the engine.
+pub trait OrderedStore: Default {
+    /// Returns the previous value of the key if there was one.
+    fn insert(&mut self, key: Bytes, value: Value) -> Option<Value>;
+
+    fn get(&self, key: &Bytes) -> Option<&Value>;
+
+    /// Entries with keys within [start, end) in order.
+    fn range<'a>(
+        &'a self,
+        start: &Bytes,
+        end: &Bytes,
+    ) -> Box<dyn Iterator<Item = (&'a Bytes, &'a Value)> + 'a>;
+
+    /// All the entries in order.
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a Bytes, &'a Value)> + 'a>;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn clear(&mut self);
+}
+
+impl OrderedStore for BTreeMap<Bytes, Value> {
+    fn insert(&mut self, key: Bytes, value: Value) -> Option<Value> {
+        BTreeMap::insert(self, key, value)
+    }
+
+    fn get(&self, key: &Bytes) -> Option<&Value> {
+        BTreeMap::get(self, key)
+    }
+
+    fn range<'a>(
+        &'a self,
+        start: &Bytes,
+        end: &Bytes,
+    ) -> Box<dyn Iterator<Item = (&'a Bytes, &'a Value)> + 'a> {
+        // BTreeMap panics on a reversed range.
+        if start > end {
+            return Box::new(std::iter::empty());
+        }
+        Box::new(BTreeMap::range(self, start.clone()..end.clone()))
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a Bytes, &'a Value)> + 'a> {
+        Box::new(BTreeMap::iter(self))
+    }
+
+    fn len(&self) -> usize {
+        BTreeMap::len(self)
+    }
+
+    fn clear(&mut self) {
+        BTreeMap::clear(self)
+    }
+}
+
 #[derive(Debug)]
 pub enum ProbeResult {
     Available(u32),
     Full,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum SsTableSize {
     Default,
     Is(usize),
@@ -35,6 +164,13 @@ pub enum SsTableSize {
 impl MemTable {
     #[allow(clippy::new_without_default)]
     pub fn new(size: SsTableSize) -> MemTable {
+        MemTable::with_store(size)
+    }
+}
+
+impl<M: OrderedStore> MemTable<M> {
+    /// Same as new but the data is kept in the given kind of store.
+    pub fn with_store(size: SsTableSize) -> Self {
         let max_size = match size {
             SsTableSize::Default => SSTABLE_BYTESIZE,
             SsTableSize::Is(size) => size as u32,
@@ -49,19 +185,17 @@ impl MemTable {
         let initial_size = (max_size / block::BLOCK_BYTE_SIZE as u32) * (engine::MAX_KEY_SIZE / 2);
 
         MemTable {
-            map: BTreeMap::new(),
+            map: M::default(),
+            seqs: HashMap::new(),
+            flags: HashMap::new(),
             size: initial_size,
             max_size,
         }
     }
 
-    pub fn from_wal(_wal: Wal) -> MemTable {
-        todo!();
-    }
-
     /// The only purpose of this function is to check weither given key and value will owerflow
     /// the table size. If its not, the new table size will be returned with the result.
-    pub fn probe(&self, key: &Bytes, value: &Bytes) -> ProbeResult {
+    pub fn probe(&self, key: &Bytes, value: &Value) -> ProbeResult {
         let new_size = self.new_size(key, value);
         if self.will_overflow(new_size) {
             return ProbeResult::Full;
@@ -70,25 +204,115 @@ impl MemTable {
         ProbeResult::Available(new_size)
     }
 
+    /// Same as probe for several keys put at once. Keys are expected to be distinct.
+    pub fn probe_all<'a>(
+        &self,
+        entries: impl IntoIterator<Item = (&'a Bytes, &'a Value)>,
+    ) -> ProbeResult {
+        let new_size = entries.into_iter().fold(self.size, |size, (key, value)| {
+            size + self.new_size(key, value) - self.size
+        });
+        if self.will_overflow(new_size) {
+            return ProbeResult::Full;
+        }
+
+        ProbeResult::Available(new_size)
+    }
+
     /// Along with key and value insert can take an optional size to update its state. If the size
     /// isn't provided it will explicitly call a function to calculate it. It could be a size is
     /// already known if probe function was called befor inserting a value.
-    pub fn insert(&mut self, key: Bytes, value: Bytes, new_size: Option<u32>) {
+    pub fn insert(&mut self, key: Bytes, value: Bytes, seq: u64, new_size: Option<u32>) {
+        self.insert_typed(key, Value::Live(value), 0, seq, new_size)
+    }
+
+    /// Same as insert for a value set with the given flags, or a tombstone.
+    pub fn insert_typed(
+        &mut self,
+        key: Bytes,
+        value: Value,
+        flags: u8,
+        seq: u64,
+        new_size: Option<u32>,
+    ) {
+        match flags {
+            0 => self.flags.remove(&key),
+            _ => self.flags.insert(key.clone(), flags),
+        };
+
         if let Some(new_size) = new_size {
             self.size = new_size;
         } else {
             self.size = self.new_size(&key, &value);
         }
 
+        self.seqs.insert(key.clone(), seq);
         self.map.insert(key, value);
     }
 
-    pub fn get(&self, key: &Bytes) -> Option<Bytes> {
+    /// Value of the key, a tombstone if the key is deleted. None means the key is not in the
+    /// table, so it has to be looked for in the older ones.
+    pub fn get(&self, key: &Bytes) -> Option<Value> {
         self.map.get(key).cloned()
     }
 
+    /// Keys and values within [start, end) in order, tombstones included.
+    pub fn range<'a>(
+        &'a self,
+        start: &Bytes,
+        end: &Bytes,
+    ) -> impl Iterator<Item = (&'a Bytes, &'a Value)> + 'a {
+        self.map.range(start, end)
+    }
+
+    /// All the keys and values in order.
+    pub fn iter(&self) -> impl Iterator<Item = (&Bytes, &Value)> {
+        self.map.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Bytes the table is estimated to take once encoded, the size it is flushed at is compared
+    /// against (see probe).
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// Returns the value of the key if it was put no later than the given sequence number.
+    pub fn get_at(&self, key: &Bytes, seq: u64) -> Option<Value> {
+        if self.seq(key)? > seq {
+            return None;
+        }
+
+        self.get(key)
+    }
+
+    /// Value of the key along with the flags it was set with.
+    pub fn get_typed(&self, key: &Bytes) -> Option<(Value, u8)> {
+        let value = self.get(key)?;
+        Some((value, self.flags(key)))
+    }
+
+    /// Flags the current value of the key was set with, zero if there is no such key.
+    pub fn flags(&self, key: &Bytes) -> u8 {
+        self.flags.get(key).copied().unwrap_or_default()
+    }
+
+    /// Sequence number of the current value of the key.
+    pub fn seq(&self, key: &Bytes) -> Option<u64> {
+        self.seqs.get(key).copied()
+    }
+
     pub fn clear(&mut self) {
         self.map.clear();
+        self.seqs.clear();
+        self.flags.clear();
         self.size = 0;
     }
 
@@ -101,15 +325,18 @@ impl MemTable {
         false
     }
 
+    /// Tells if the table is filled over the given percentage of its capacity.
+    pub fn is_above(&self, percent: u8) -> bool {
+        self.size as u64 * 100 > self.max_size as u64 * percent as u64
+    }
+
     /// It calculates a new size of the table based on the values to be inserted.
     /// It should handle the case when the key is already present in the table so
     /// that it wont be caunted twice.
-    fn new_size(&self, key: &Bytes, value: &Bytes) -> u32 {
+    fn new_size(&self, key: &Bytes, value: &Value) -> u32 {
         // First, check if the key is already there.
         let mut old_entry_size: u32 = 0;
-        if self.map.contains_key(key) {
-            // It is fine to get value here since access is syncronized.
-            let old_value = self.map.get(key).unwrap(); // unwrap() is fine here.
+        if let Some(old_value) = self.map.get(key) {
             old_entry_size = block::entry_size(key, old_value);
         }
 
@@ -130,9 +357,8 @@ impl MemTable {
     pub fn keys(&self) -> Vec<String> {
         let keys: Vec<String> = self
             .map
-            .keys()
-            .cloned()
-            .map(|b| String::from_utf8(b.to_vec()).unwrap())
+            .iter()
+            .map(|(b, _)| String::from_utf8(b.to_vec()).unwrap())
             .collect();
 
         keys
@@ -144,66 +370,190 @@ mod tests {
     use super::*;
     use crate::engine::sstable::block;
 
-    #[test]
-    fn test_is_full() {
-        let mut mt = MemTable::new(SsTableSize::Default);
-        mt.size = mt.max_size - MAX_ENTRY_SIZE;
-        assert!(!mt.is_full());
-
-        mt.size += 1;
-        assert!(mt.is_full());
-    }
+    /// Keeps entries in a vector sorted by key, the simplest store there is, to run the tests
+    /// against more than one store.
+    #[derive(Debug, Default)]
+    struct SortedVec(Vec<(Bytes, Value)>);
+
+    impl OrderedStore for SortedVec {
+        fn insert(&mut self, key: Bytes, value: Value) -> Option<Value> {
+            match self.0.binary_search_by(|(k, _)| k.cmp(&key)) {
+                Ok(pos) => Some(std::mem::replace(&mut self.0[pos].1, value)),
+                Err(pos) => {
+                    self.0.insert(pos, (key, value));
+                    None
+                }
+            }
+        }
 
-    #[test]
-    fn test_new_size() {
-        let mut mt = MemTable::new(SsTableSize::Default);
-        let first_key = Bytes::from("foo");
-        let first_value = Bytes::from("bar");
-        let size = block::entry_size(&first_key, &first_value);
-        mt.insert(first_key, first_value, Some(size));
+        fn get(&self, key: &Bytes) -> Option<&Value> {
+            let pos = self.0.binary_search_by(|(k, _)| k.cmp(key)).ok()?;
+            Some(&self.0[pos].1)
+        }
 
-        let second_key = Bytes::from("language");
-        let second_value = Bytes::from("rust");
-        assert_eq!(
-            mt.new_size(&second_key, &second_value),
-            mt.size + block::entry_size(&second_key, &second_value)
-        );
+        fn range<'a>(
+            &'a self,
+            start: &Bytes,
+            end: &Bytes,
+        ) -> Box<dyn Iterator<Item = (&'a Bytes, &'a Value)> + 'a> {
+            let from = self.0.partition_point(|(k, _)| k < start);
+            let to = self.0.partition_point(|(k, _)| k < end).max(from);
+            Box::new(self.0[from..to].iter().map(|(k, v)| (k, v)))
+        }
 
-        let dup_key = Bytes::from("foo");
-        let new_value = Bytes::from("not-bar");
-        assert_eq!(
-            mt.new_size(&dup_key, &new_value),
-            block::entry_size(&dup_key, &new_value)
-        );
-    }
+        fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a Bytes, &'a Value)> + 'a> {
+            Box::new(self.0.iter().map(|(k, v)| (k, v)))
+        }
 
-    #[test]
-    fn test_insert() {
-        let mut mt = MemTable::new(SsTableSize::Is(block::BLOCK_BYTE_SIZE));
-        assert_eq!(mt.size, 256);
+        fn len(&self) -> usize {
+            self.0.len()
+        }
 
-        mt.insert(Bytes::from("foo"), Bytes::from("bar"), Some(256 + 12));
-        assert_eq!(mt.size, 268);
-        assert_eq!(mt.map.get(&Bytes::from("foo")), Some(&Bytes::from("bar")));
+        fn clear(&mut self) {
+            self.0.clear()
+        }
     }
 
-    #[test]
-    fn test_clear() {
-        let mut mt = MemTable::new(SsTableSize::Default);
-        mt.insert(Bytes::from("foo"), Bytes::from("bar"), Some(12));
-        mt.insert(Bytes::from("bar"), Bytes::from("foo"), Some(24));
-
-        mt.clear();
-
-        assert_eq!(mt.size, 0);
-        assert!(mt.get(&Bytes::from("foo")).is_none());
-        assert!(mt.get(&Bytes::from("bar")).is_none());
+    /// Memtable tests, run against every store.
+    macro_rules! store_tests {
+        ($name:ident, $store:ty) => {
+            mod $name {
+                use super::*;
+
+                #[test]
+                fn test_is_full() {
+                    let mut mt = MemTable::<$store>::with_store(SsTableSize::Default);
+                    mt.size = mt.max_size - MAX_ENTRY_SIZE;
+                    assert!(!mt.is_full());
+
+                    mt.size += 1;
+                    assert!(mt.is_full());
+                }
+
+                #[test]
+                fn test_is_above() {
+                    let mut mt = MemTable::<$store>::with_store(SsTableSize::Is(
+                        block::BLOCK_BYTE_SIZE * 10,
+                    ));
+                    mt.size = mt.max_size / 2;
+                    assert!(mt.is_above(49));
+                    assert!(!mt.is_above(50));
+                    assert!(!mt.is_above(80));
+                }
+
+                #[test]
+                fn test_new_size() {
+                    let mut mt = MemTable::<$store>::with_store(SsTableSize::Default);
+                    let first_key = Bytes::from("foo");
+                    let first_value = Value::Live(Bytes::from("bar"));
+                    let size = block::entry_size(&first_key, &first_value);
+                    mt.insert_typed(first_key, first_value, 0, 1, Some(size));
+
+                    let second_key = Bytes::from("language");
+                    let second_value = Value::Live(Bytes::from("rust"));
+                    assert_eq!(
+                        mt.new_size(&second_key, &second_value),
+                        mt.size + block::entry_size(&second_key, &second_value)
+                    );
+
+                    let dup_key = Bytes::from("foo");
+                    let new_value = Value::Tombstone;
+                    assert_eq!(
+                        mt.new_size(&dup_key, &new_value),
+                        block::entry_size(&dup_key, &new_value)
+                    );
+                }
+
+                #[test]
+                fn test_insert() {
+                    let mut mt =
+                        MemTable::<$store>::with_store(SsTableSize::Is(block::BLOCK_BYTE_SIZE));
+                    assert_eq!(mt.size, 256);
+
+                    mt.insert(Bytes::from("foo"), Bytes::from("bar"), 1, Some(256 + 12));
+                    assert_eq!(mt.size, 268);
+                    assert_eq!(
+                        OrderedStore::get(&mt.map, &Bytes::from("foo")),
+                        Some(&Value::Live(Bytes::from("bar")))
+                    );
+                }
+
+                #[test]
+                fn test_flags() {
+                    let mut mt = MemTable::<$store>::with_store(SsTableSize::Default);
+                    let key = Bytes::from("foo");
+                    mt.insert_typed(key.clone(), Value::Live(Bytes::from("{}")), 3, 1, None);
+                    assert_eq!(
+                        mt.get_typed(&key),
+                        Some((Value::Live(Bytes::from("{}")), 3))
+                    );
+
+                    mt.insert(key.clone(), Bytes::from("bar"), 2, None);
+                    assert_eq!(
+                        mt.get_typed(&key),
+                        Some((Value::Live(Bytes::from("bar")), 0))
+                    );
+                    assert_eq!(mt.get_typed(&Bytes::from("baz")), None);
+                }
+
+                #[test]
+                fn test_get_at() {
+                    let mut mt = MemTable::<$store>::with_store(SsTableSize::Default);
+                    let key = Bytes::from("foo");
+                    mt.insert(key.clone(), Bytes::from("bar"), 5, None);
+
+                    assert_eq!(mt.get_at(&key, 5), Some(Value::Live(Bytes::from("bar"))));
+                    assert_eq!(mt.get_at(&key, 9), Some(Value::Live(Bytes::from("bar"))));
+                    assert_eq!(mt.get_at(&key, 4), None, "value is newer than asked");
+                    assert_eq!(mt.get_at(&Bytes::from("baz"), 9), None);
+                }
+
+                #[test]
+                fn test_clear() {
+                    let mut mt = MemTable::<$store>::with_store(SsTableSize::Default);
+                    mt.insert(Bytes::from("foo"), Bytes::from("bar"), 1, Some(12));
+                    mt.insert(Bytes::from("bar"), Bytes::from("foo"), 2, Some(24));
+
+                    mt.clear();
+
+                    assert_eq!(mt.size, 0);
+                    assert!(mt.get(&Bytes::from("foo")).is_none());
+                    assert!(mt.get(&Bytes::from("bar")).is_none());
+                }
+
+                #[test]
+                fn test_will_overflow() {
+                    let mt =
+                        MemTable::<$store>::with_store(SsTableSize::Is(block::BLOCK_BYTE_SIZE));
+                    assert!(mt.will_overflow((block::BLOCK_BYTE_SIZE + 1) as u32));
+                    assert!(!mt.will_overflow(block::BLOCK_BYTE_SIZE as u32));
+                }
+
+                #[test]
+                fn test_range() {
+                    let mut mt = MemTable::<$store>::with_store(SsTableSize::Default);
+                    for (seq, key) in ["d", "b", "a", "c", "b"].into_iter().enumerate() {
+                        mt.insert(Bytes::from(key), Bytes::from(key), seq as u64, None);
+                    }
+                    assert_eq!(mt.len(), 4);
+
+                    let keys = |from: &'static str, to: &'static str| {
+                        mt.range(&Bytes::from(from), &Bytes::from(to))
+                            .map(|(key, _)| key.clone())
+                            .collect::<Vec<_>>()
+                    };
+                    assert_eq!(keys("b", "d"), vec!["b", "c"]);
+                    assert_eq!(keys("", "z"), vec!["a", "b", "c", "d"]);
+                    assert!(keys("d", "b").is_empty());
+                    assert_eq!(
+                        mt.iter().map(|(key, _)| key.clone()).collect::<Vec<_>>(),
+                        vec!["a", "b", "c", "d"]
+                    );
+                }
+            }
+        };
     }
 
-    #[test]
-    fn test_will_overflow() {
-        let mt = MemTable::new(SsTableSize::Is(block::BLOCK_BYTE_SIZE));
-        assert!(mt.will_overflow((block::BLOCK_BYTE_SIZE + 1) as u32));
-        assert!(!mt.will_overflow(block::BLOCK_BYTE_SIZE as u32));
-    }
+    store_tests!(btree_map, BTreeMap<Bytes, Value>);
+    store_tests!(sorted_vec, SortedVec);
 }
@@ -1,95 +1,492 @@
+mod dedupe;
 mod dispatcher;
 pub mod memtable;
 mod sstable;
 mod wal;
 
-use crate::engine::memtable::MemTable;
-use crate::engine::memtable::SsTableSize;
+pub use dispatcher::{AdviceStats, BlockCacheStats, CacheStats};
+pub(crate) use sstable::bloom::PROBABILITY as BLOOM_FP_RATE;
+pub use sstable::{BlockReport, BloomStats, SsTable, TableReport};
+pub use wal::ReplayStats;
+
+use crate::engine::memtable::{MemTable, Value};
+use crate::Config;
 use crate::Responder;
-use crate::Storage;
-use bytes::Bytes;
+use crate::WalStorage;
+use bytes::{Bytes, BytesMut};
+use dedupe::Dedupe;
 use dispatcher::Dispatcher;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::Duration;
 use tokio::sync::{mpsc, oneshot};
+use tokio::time::{self, Instant};
+use uuid::Uuid;
 
-/// This is where data files will be stored.
-pub const DATA_PATH: &str = "/var/lib/bureau"; // TODO: Make configurable.
+/// This is where data files are stored unless Config::data_path says otherwise.
+pub const DATA_PATH: &str = "/var/lib/bureau";
 
-/// This is how many tables are allowed to be in the process of writing to disk at the same time.
-/// Grow this number to make DB more tolerant to high amount writes, it will consume more memory
-/// in return.
-const DISPATCHER_BUFFER_SIZE: usize = 32; // TODO: Make configurable.
+/// The longest key there can be. Config::max_key_size may only lower it, the size of a memtable
+/// entry is estimated with it.
+pub(crate) const MAX_KEY_SIZE: u32 = 512; // 512B.
 
-// TODO: Make configurable.
-const MAX_KEY_SIZE: u32 = 512; // 512B.
+/// How often free space is checked while there is enough of it (see Config::min_free_space).
+const SPACE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 
-// TODO: Make configurable.
-const MAX_VALUE_SIZE: u32 = 2048; // 2KB.
+/// The longest value there can be, the same way as MAX_KEY_SIZE (see Config::max_value_size).
+pub(crate) const MAX_VALUE_SIZE: u32 = 2048; // 2KB.
 
 #[derive(Debug)]
 pub enum Command {
     Get {
         key: Bytes,
+        /// Whether the value read from disk may be cached. Reads that go over many keys once,
+        /// like a backup, turn it off so that they don't push hot values out of the cache.
+        fill_cache: bool,
         responder: Responder<Option<Bytes>>,
     },
     Set {
         key: Bytes,
         value: Bytes,
+        /// Id given by client to make retries safe. A request with an id that was recently
+        /// applied is acknowledged without being applied again (see Config::dedupe_window).
+        request_id: Option<u64>,
         // Having an optional responder here allows to issue 'fire-and-forget' set commands.
-        responder: Option<Responder<()>>,
+        // Responds with the sequence number the value was put at.
+        responder: Option<Responder<u64>>,
+    },
+    /// Sets several keys at once to save round trips, but unlike Transaction the values are
+    /// written one by one, each with its own sequence number, and memtable may be swapped in
+    /// between. All the pairs are validated before any is written. Responds with the sequence
+    /// number of the last write, or with an error naming the first pair that failed, the pairs
+    /// before it are written then.
+    SetBatch {
+        pairs: Vec<(Bytes, Bytes)>,
+        responder: Responder<u64>,
+    },
+    /// Removes the key. Nothing is removed from disk right away, a tombstone is written the way
+    /// a value is and hides the older values of the key. Responds with the sequence number of
+    /// the delete.
+    Delete {
+        key: Bytes,
+        responder: Responder<u64>,
+    },
+    /// Same as Set for a value that carries flags, a byte clients define to tell what kind of
+    /// content the value holds. Values set with Set have zero flags.
+    SetTyped {
+        key: Bytes,
+        value: Bytes,
+        flags: u8,
+        responder: Responder<u64>,
+    },
+    /// Same as Set for a value that expires in ttl, it is checked against Config::clock. An
+    /// expired value reads as deleted and hides the older values of the key, compaction drops it
+    /// leaving a tombstone. Responds with the sequence number the value was put at.
+    SetEx {
+        key: Bytes,
+        value: Bytes,
+        ttl: Duration,
+        responder: Responder<u64>,
+    },
+    /// Same as Get, along with the flags the value was set with. Values read from disk don't go
+    /// through the cache.
+    GetTyped {
+        key: Bytes,
+        responder: Responder<Option<(Bytes, u8)>>,
+    },
+    /// Reads the value of the key as of the given sequence number, which is the newest value
+    /// put no later than that. Older values only live as long as the tables they were flushed
+    /// to: an overwrite within the same memtable and compaction keep the newest value only, so
+    /// the value read may be older than the one the key actually had at the time.
+    GetAt {
+        key: Bytes,
+        seq: u64,
+        responder: Responder<Option<Bytes>>,
+    },
+    /// Appends suffix to the current value of the key, or sets the key to it if there is no value.
+    /// Responds with the length of the new value, which is refused if it gets longer than
+    /// Config::max_value_size. The new value has zero flags, whatever the current one was set
+    /// with. While the current value is read from disk, later writes of the same key wait for
+    /// the append and the rest of the commands go on.
+    Append {
+        key: Bytes,
+        suffix: Bytes,
+        responder: Responder<u64>,
+    },
+    /// Applies all the writes or none of them. Preconditions of Cas ops are checked against
+    /// the values the keys have at the time, values that are only on disk are read first while
+    /// the keys of the transaction are locked. Writes are logged as a single record, so they
+    /// all get the same sequence number and the transaction has to fit a WAL page. Keys must be
+    /// distinct.
+    Transaction {
+        ops: Vec<WriteOp>,
+        responder: Responder<TransactionOutcome>,
+    },
+    /// Sets the key to new only if its current value is the expected one, None expects no value.
+    /// Responds with whether the value was swapped. It is checked and written the way a
+    /// transaction of a single Cas op is: a current value that is only on disk is read in the
+    /// background while the key is locked, so later writes of the key wait for the swap and the
+    /// other commands go on.
+    Cas {
+        key: Bytes,
+        expected: Option<Bytes>,
+        new: Bytes,
+        responder: Responder<bool>,
+    },
+    /// Merges the oldest tables on disk. Responds when merged table is in use.
+    Compact {
+        responder: Responder<CompactionReport>,
+    },
+    /// Rewrites a table with its bloom filter built anew. Responds with the id the table is
+    /// rewritten to, once it is in use.
+    RebuildBloom {
+        table_id: Uuid,
+        responder: Responder<Uuid>,
+    },
+    /// Puts memtable to disk right away. Responds once the table is on disk, with None if
+    /// memtable was empty.
+    Flush {
+        responder: Responder<Option<FlushReport>>,
+    },
+    /// Puts the log to disk. Writes are acknowledged once appended to the log, which doesn't
+    /// mean the OS got them to disk yet, so a client that needs writes to survive a machine
+    /// crash issues this after them. Responds once everything written before it is on disk.
+    /// Tables storage is synced as well, after the tables sent to disk before it.
+    Sync { responder: Responder<()> },
+    /// Counts keys within [start, end) in memtable and all the tables.
+    CountRange {
+        start: Bytes,
+        end: Bytes,
+        responder: Responder<RangeCount>,
+    },
+    /// Lists up to limit first keys within [start, end) in order, without reading their values.
+    ScanKeys {
+        start: Bytes,
+        end: Bytes,
+        limit: usize,
+        responder: Responder<Vec<Bytes>>,
+    },
+    /// Lists up to limit first keys within [start, end) in order along with their values. The
+    /// newest value of a key wins, deleted keys are left out.
+    Scan {
+        start: Bytes,
+        end: Bytes,
+        limit: usize,
+        responder: Responder<Vec<(Bytes, Bytes)>>,
+    },
+    /// Reports counters of the engine internals, to tune the config against the actual load.
+    Stats { responder: Responder<Stats> },
+    /// Reports how far data got to disk (see Position).
+    Position { responder: Responder<Position> },
+    /// Stops the engine even though there are senders left. Memtable is flushed and dispatcher
+    /// is stopped before the response is sent. Commands queued behind it are dropped.
+    Shutdown { responder: Responder<()> },
+}
+
+/// A write of a transaction (see Command::Transaction).
+#[derive(Debug, Clone, PartialEq)]
+pub enum WriteOp {
+    Set {
+        key: Bytes,
+        value: Bytes,
     },
+    Delete {
+        key: Bytes,
+    },
+    /// Sets the key only if its current value is the expected one, None expects no value.
+    Cas {
+        key: Bytes,
+        expected: Option<Bytes>,
+        value: Bytes,
+    },
+}
+
+impl WriteOp {
+    pub fn key(&self) -> &Bytes {
+        match self {
+            WriteOp::Set { key, .. } | WriteOp::Delete { key } | WriteOp::Cas { key, .. } => key,
+        }
+    }
+
+    fn into_write(self) -> Write {
+        match self {
+            WriteOp::Set { key, value } | WriteOp::Cas { key, value, .. } => Write {
+                key,
+                value: Value::Live(value),
+                flags: 0,
+            },
+            WriteOp::Delete { key } => Write {
+                key,
+                value: Value::Tombstone,
+                flags: 0,
+            },
+        }
+    }
+}
+
+/// Outcome of a transaction that was checked and either applied or not.
+#[derive(Debug, PartialEq)]
+pub enum TransactionOutcome {
+    /// Every write is applied at the sequence number.
+    Committed { seq: u64 },
+    /// Precondition of the op at the index doesn't hold, nothing is applied.
+    Aborted { op: usize },
+}
+
+/// Number of keys in a range. Keys are counted in memtable and every table separately, so once
+/// more than one of them has keys in the range, the count is an estimate: an overwritten key is
+/// counted as many times as it was flushed.
+#[derive(Debug, PartialEq)]
+pub struct RangeCount {
+    pub count: u64,
+    pub exact: bool,
 }
 
+/// Outcome of a compaction. All zeroes when there was not enough tables to compact.
+#[derive(Debug, Default, PartialEq)]
+pub struct CompactionReport {
+    pub tables_merged: usize,
+    pub tables_created: usize,
+    pub bytes_written: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Stats {
+    pub cache: CacheStats,
+    /// Blocks lookups found in the block cache (see Config::block_cache_capacity).
+    pub blocks: BlockCacheStats,
+    /// Bytes taken by the log. It only grows until memtable is flushed, so a log that keeps
+    /// growing means flushes are stalled.
+    pub wal_size: u64,
+    /// What replaying the log took at the start.
+    pub wal_replay: ReplayStats,
+    /// Hints given to the OS cache (see Config::os_cache_advice).
+    pub os_cache: AdviceStats,
+    /// Number of tables on disk in use.
+    pub tables: usize,
+    /// Keys in the memtable writes go to, deleted ones included. Memtables waiting to be flushed
+    /// are not counted.
+    pub memtable_entries: usize,
+    /// Estimated encoded size of the same memtable (see MemTable::size).
+    pub memtable_size: u64,
+}
+
+/// How far the data got to disk, for tools that follow the database from outside, like a backup
+/// or a replica polling until it has caught up, and how far writes got. All parts only grow.
+#[derive(Debug, PartialEq)]
+pub struct Position {
+    /// Id of the newest table on disk. Ids are time ordered and every flush makes a newer table.
+    /// None while there are no tables.
+    pub newest_table: Option<Uuid>,
+    /// Generation of the log the writes in memtable go to, it is bumped every time memtable is
+    /// swapped. Zero if the log is disabled.
+    pub wal_generation: u64,
+    /// Sequence number every write visible to reads was put no later than. Writes from before
+    /// the start count as the ones made at the start, they are all recovered by then.
+    pub last_seq: u64,
+}
+
+/// Table memtable was flushed to.
+#[derive(Debug, PartialEq)]
+pub struct FlushReport {
+    pub table_id: Uuid,
+    pub size: u64,
+}
+
+/// Every write gets a sequence number which is kept with the value in memtable and tables, so
+/// that keys can be read as of a past write (see Command::GetAt). Numbers only grow, they are
+/// the microseconds since the epoch at the time of the write, or the previous number plus one
+/// when writes come faster, so they keep growing across restarts.
+///
+/// Once a write is acked, reads of its key return it (or a newer value) wherever it lives at the
+/// moment. Memtable is only swapped by the engine task itself, and the swapped table is either
+/// kept in the immutable tables or sent to the dispatcher before the next command is handled.
+/// Dispatcher handles commands one by one and puts a table to the index before it takes the next
+/// one, so a read that misses in memory queues up behind the table it needs. Tables leave the
+/// immutable ones only after they were sent, the same ordering covers them.
 #[derive(Debug)]
-#[allow(dead_code)]
 pub struct Engine {
     input_rx: mpsc::Receiver<Command>,
-    // TODO: Channel to shutdown + tokio::select! inside run loop.
-    // shutdown_rx: mpsc::Receiver<Command>,
     memtable: MemTable,
-    wal: wal::Wal,
+    /// Latest tables sent to disk, newest first. Reads go through them before the dispatcher,
+    /// which might be busy putting them to disk (see Config::immutable_memtables).
+    immutable: VecDeque<Immutable>,
+    /// When the first write got to the current memtable, None while it is empty.
+    first_write: Option<Instant>,
+    dedupe: Dedupe,
+    /// Sequence number of the latest write.
+    last_seq: u64,
+    /// Set while there is less free space than Config::min_free_space, writes are refused.
+    no_space: bool,
+    /// When free space was checked last time.
+    space_checked: Option<Instant>,
+    /// Keys with a read-modify-write waiting for its value from disk, along with the writes of
+    /// the same key that came after it. Writes of a locked key wait, everything else goes on.
+    locked: HashMap<Bytes, VecDeque<Command>>,
+    /// Commands to handle before taking new ones, writes let go once their key is unlocked.
+    ready: VecDeque<Command>,
+    /// Last log generation of the latest table the dispatcher acked.
+    wal_acked: Option<u64>,
+    /// Log generations up to this one only hold writes of tables on disk, they are discarded on
+    /// the next rotation.
+    wal_persisted: Option<u64>,
+    config: Config,
 }
 
 /// Engine is a working horse of the database. It holds memtable and a channel to communicate commands to.
 impl Engine {
-    pub fn new(rx: mpsc::Receiver<Command>) -> Self {
+    pub fn new(rx: mpsc::Receiver<Command>, config: Config) -> Self {
         Engine {
             input_rx: rx,
-            memtable: MemTable::new(SsTableSize::Default),
-            wal: wal::Wal {},
+            memtable: MemTable::new(config.memtable_size),
+            immutable: VecDeque::new(),
+            first_write: None,
+            dedupe: Dedupe::new(config.dedupe_window),
+            last_seq: 0,
+            no_space: false,
+            space_checked: None,
+            locked: HashMap::new(),
+            ready: VecDeque::new(),
+            wal_acked: None,
+            wal_persisted: None,
+            config,
         }
     }
 
     /// This function is to run in the background thread, to read and handle commands from
     /// the channel. It itself also spawns a dispathcher thread that works with everything
-    /// living on the disk.
-    pub async fn run<T: Storage>(mut self, storage: T) {
+    /// living on the disk. Before handling any commands memtable is recovered from the WAL.
+    /// Returns once the channel is closed or Shutdown command is received, and memtable is
+    /// flushed to disk.
+    pub async fn run<T: crate::AsyncStorage, W: WalStorage>(mut self, storage: T, wal_storage: W) {
+        self.config
+            .validate()
+            .unwrap_or_else(|e| panic!("Invalid config: {}", e));
+
         storage
             .bootstrap()
+            .await
             .unwrap_or_else(|e| panic!("Could not setup storage: {}", e));
 
-        let (disp_tx, disp_rx) = mpsc::channel::<dispatcher::Command>(64);
-        let disp = Dispatcher::init(disp_rx, DISPATCHER_BUFFER_SIZE, storage)
+        let (disp_tx, disp_rx) =
+            mpsc::channel::<dispatcher::Command>(self.config.dispatcher_queue_len.max(1));
+        let disp = Dispatcher::init(disp_rx, disp_tx.downgrade(), storage, &self.config)
+            .await
             .unwrap_or_else(|e| panic!("Could not initialize dispatcher: {}", e));
 
-        let join_handle = tokio::spawn(async move {
-            disp.run().await;
-            tracing::error!("dispatched exited");
+        let disp_handle = tokio::spawn(disp.run());
+
+        let (mut wal, recovered) = match self.config.unsafe_no_wal {
+            true => {
+                tracing::warn!("WAL is disabled, writes not yet on disk are lost on a crash");
+                (wal::Wal::disabled(wal_storage), Vec::new())
+            }
+            false => wal::Wal::init(
+                wal_storage,
+                self.config.wal_sync,
+                self.config.wal_segment_size,
+            )
+            .unwrap_or_else(|e| panic!("Could not initialize WAL: {}", e)),
+        };
+        // Writes made before the start are in the log or on disk, the ones to come get newer
+        // sequence numbers anyway.
+        self.last_seq = self
+            .last_seq
+            .max(self.config.clock.since_epoch().as_micros() as u64);
+        self.recover(recovered, &disp_tx).await;
+
+        // Responder of the Shutdown command if the engine is stopped by it.
+        let mut shutdown: Option<Responder<()>> = None;
+        // Set once no more commands come in. Writes of locked keys are finished before stopping.
+        let mut stopping = false;
+
+        // Values read from disk for commands of locked keys.
+        let (read_tx, mut read_rx) = mpsc::unbounded_channel::<PendingWrite>();
+
+        // Flushes memtable that holds writes for too long (see Config::flush_interval).
+        let mut flush_timer = self.config.flush_interval.map(|period| {
+            let mut timer = time::interval_at(Instant::now() + period, period);
+            timer.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+            timer
         });
-        tokio::spawn(async move {
-            tracing::error!("dispatched exit: {:?}", join_handle.await);
+        // Puts the log to disk in the background (see SyncPolicy::EveryMs).
+        let mut sync_timer = wal.sync_interval().map(|period| {
+            let mut timer = time::interval_at(Instant::now() + period, period);
+            timer.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+            timer
         });
 
-        // TODO: Change it to select! here to handle shutdown.
-        while let Some(cmd) = self.input_rx.recv().await {
+        loop {
+            let cmd = match self.ready.pop_front() {
+                Some(cmd) => cmd,
+                None if stopping && self.locked.is_empty() => break,
+                None => tokio::select! {
+                    cmd = self.input_rx.recv(), if !stopping => match cmd {
+                        Some(cmd) => cmd,
+                        None => {
+                            stopping = true;
+                            continue;
+                        }
+                    },
+                    Some(pending) = read_rx.recv() => {
+                        match pending {
+                            PendingWrite::Append(pending) => {
+                                let key = pending.key.clone();
+                                self.finish_append(pending, &mut wal, &disp_tx).await;
+                                self.unlock(&key);
+                            }
+                            PendingWrite::Transaction(pending) => {
+                                let keys: Vec<Bytes> =
+                                    pending.ops.iter().map(|op| op.key().clone()).collect();
+                                self.finish_transaction(pending, &mut wal, &disp_tx).await;
+                                for key in keys.iter() {
+                                    self.unlock(key);
+                                }
+                            }
+                        }
+                        continue;
+                    }
+                    _ = tick(&mut flush_timer) => {
+                        if self.is_stale() {
+                            self.flush_early(&mut wal, &disp_tx).await;
+                        }
+                        continue;
+                    }
+                    _ = tick(&mut sync_timer) => {
+                        if let Err(e) = wal.sync_pending() {
+                            tracing::error!("could not sync WAL: {}", e);
+                        }
+                        continue;
+                    }
+                },
+            };
+
+            let locked_key = cmd
+                .written_keys()
+                .into_iter()
+                .find(|key| self.locked.contains_key(*key))
+                .cloned();
+            if let Some(key) = locked_key {
+                self.locked.get_mut(&key).unwrap().push_back(cmd);
+                continue;
+            }
+
             match cmd {
-                Command::Get { key, responder } => {
+                Command::Get {
+                    key,
+                    fill_cache,
+                    responder,
+                } => {
                     match self.get_from_mem(&key) {
                         Some(value) => {
-                            responder.send(Ok(Some(value))).ok();
+                            responder.send(Ok(value.live())).ok();
                         }
                         None => {
                             disp_tx
-                                .send(dispatcher::Command::Get { key, responder })
+                                .send(dispatcher::Command::Get {
+                                    key,
+                                    fill_cache,
+                                    responder,
+                                })
                                 .await
                                 .ok();
                         }
@@ -98,93 +495,1198 @@ impl Engine {
                 Command::Set {
                     key,
                     value,
+                    request_id,
                     responder,
                 } => {
-                    if let Err(err) = validate(&key, &value) {
-                        responder.and_then(|r| r.send(Err(err)).ok());
+                    if let Some(seq) = request_id.and_then(|id| self.dedupe.get(id)) {
+                        responder.and_then(|r| r.send(Ok(seq)).ok());
                         continue;
                     }
 
-                    match self.memtable.probe(&key, &value) {
-                        memtable::ProbeResult::Available(new_size) => {
-                            self.memtable.insert(key, value, Some(new_size));
-                            responder.and_then(|r| r.send(Ok(())).ok());
+                    let respond = |res| {
+                        responder.and_then(|r: Responder<u64>| r.send(res).ok());
+                    };
+                    let write = match Write::new(key, value, 0, &self.config) {
+                        Ok(write) => write,
+                        Err(err) => {
+                            respond(Err(err));
+                            continue;
+                        }
+                    };
+                    let seq = self.write(write, respond, &mut wal, &disp_tx).await;
+                    if let (Some(id), Some(seq)) = (request_id, seq) {
+                        self.dedupe.record(id, seq);
+                    }
+                }
+                Command::SetTyped {
+                    key,
+                    value,
+                    flags,
+                    responder,
+                } => {
+                    let write = match Write::new(key, value, flags, &self.config) {
+                        Ok(write) => write,
+                        Err(err) => {
+                            responder.send(Err(err)).ok();
+                            continue;
+                        }
+                    };
+                    let respond = |res| {
+                        responder.send(res).ok();
+                    };
+                    self.write(write, respond, &mut wal, &disp_tx).await;
+                }
+                Command::SetEx {
+                    key,
+                    value,
+                    ttl,
+                    responder,
+                } => {
+                    let write = match ttl.is_zero() {
+                        true => Err(crate::Error::InvalidInput("ttl must be positive".into())),
+                        false => {
+                            let expires_at = self.now() + ttl.as_millis() as u64;
+                            Write::expiring(key, value, expires_at, &self.config)
+                        }
+                    };
+                    let write = match write {
+                        Ok(write) => write,
+                        Err(err) => {
+                            responder.send(Err(err)).ok();
+                            continue;
+                        }
+                    };
+                    let respond = |res| {
+                        responder.send(res).ok();
+                    };
+                    self.write(write, respond, &mut wal, &disp_tx).await;
+                }
+                Command::SetBatch { pairs, responder } => {
+                    let writes = pairs
+                        .into_iter()
+                        .enumerate()
+                        .map(|(num, (key, value))| {
+                            Write::new(key, value, 0, &self.config).map_err(|err| at_pair(num, err))
+                        })
+                        .collect::<crate::Result<Vec<_>>>();
+                    let writes = match writes {
+                        Ok(writes) if writes.is_empty() => {
+                            let err = crate::Error::InvalidInput("batch is empty".into());
+                            responder.send(Err(err)).ok();
+                            continue;
                         }
-                        memtable::ProbeResult::Full => {
-                            // Swap tables and respond to client first.
-                            let old_table = self.swap_table();
-                            self.memtable.insert(key, value, None);
-                            responder.and_then(|r| r.send(Ok(())).ok());
+                        Ok(writes) => writes,
+                        Err(err) => {
+                            responder.send(Err(err)).ok();
+                            continue;
+                        }
+                    };
 
-                            // Now send full table to dispatcher to put it to disk.
-                            let (resp_tx, resp_rx) = oneshot::channel();
+                    let mut outcome = Ok(0);
+                    for (num, write) in writes.into_iter().enumerate() {
+                        let mut res = Ok(0);
+                        self.write(write, |r| res = r, &mut wal, &disp_tx).await;
+                        outcome = res.map_err(|err| at_pair(num, err));
+                        if outcome.is_err() {
+                            break;
+                        }
+                    }
+                    responder.send(outcome).ok();
+                }
+                Command::Delete { key, responder } => {
+                    let write = match Write::tombstone(key, &self.config) {
+                        Ok(write) => write,
+                        Err(err) => {
+                            responder.send(Err(err)).ok();
+                            continue;
+                        }
+                    };
+                    let respond = |res| {
+                        responder.send(res).ok();
+                    };
+                    self.write(write, respond, &mut wal, &disp_tx).await;
+                }
+                Command::GetTyped { key, responder } => {
+                    match self.get_typed_from_mem(&key) {
+                        Some((value, flags)) => {
+                            let typed = value.live().map(|value| (value, flags));
+                            responder.send(Ok(typed)).ok();
+                        }
+                        None => {
+                            disp_tx
+                                .send(dispatcher::Command::GetTyped { key, responder })
+                                .await
+                                .ok();
+                        }
+                    };
+                }
+                Command::Append {
+                    key,
+                    suffix,
+                    responder,
+                } => {
+                    let current = match self.get_from_mem(&key) {
+                        Some(value) => Ok(value.live()),
+                        None => {
+                            // Value is read from disk without holding up the other commands,
+                            // the key stays locked until the append is written.
+                            self.locked.insert(key.clone(), VecDeque::new());
+                            let (disp_tx, read_tx) = (disp_tx.clone(), read_tx.clone());
+                            tokio::spawn(async move {
+                                let current = read_from_disk(&disp_tx, key.clone()).await;
+                                read_tx
+                                    .send(PendingWrite::Append(PendingAppend {
+                                        key,
+                                        suffix,
+                                        current,
+                                        responder,
+                                    }))
+                                    .ok();
+                            });
+                            continue;
+                        }
+                    };
+
+                    let pending = PendingAppend {
+                        key,
+                        suffix,
+                        current,
+                        responder,
+                    };
+                    self.finish_append(pending, &mut wal, &disp_tx).await;
+                }
+                Command::Transaction { ops, responder } => {
+                    if let Err(err) = validate_transaction(&ops, &self.config) {
+                        responder.send(Err(err)).ok();
+                        continue;
+                    }
+
+                    // Values the Cas ops are checked against, keys missing here are on disk
+                    // or have no value at all.
+                    let mut current = HashMap::new();
+                    let mut on_disk = Vec::new();
+                    for op in ops.iter() {
+                        if let WriteOp::Cas { key, .. } = op {
+                            match self.get_from_mem(key) {
+                                Some(value) => {
+                                    current.insert(key.clone(), value.live());
+                                }
+                                None => on_disk.push(key.clone()),
+                            }
+                        }
+                    }
+
+                    if on_disk.is_empty() {
+                        let pending = PendingTransaction {
+                            ops,
+                            current: Ok(current),
+                            responder,
+                        };
+                        self.finish_transaction(pending, &mut wal, &disp_tx).await;
+                        continue;
+                    }
 
+                    // Nothing else writes the keys until the transaction is finished, so the
+                    // values read from memtable stay current as well.
+                    for op in ops.iter() {
+                        self.locked.insert(op.key().clone(), VecDeque::new());
+                    }
+                    let (disp_tx, read_tx) = (disp_tx.clone(), read_tx.clone());
+                    tokio::spawn(async move {
+                        let mut read = Ok(current);
+                        for key in on_disk {
+                            read = match (read, read_from_disk(&disp_tx, key.clone()).await) {
+                                (Ok(mut current), Ok(value)) => {
+                                    current.insert(key, value);
+                                    Ok(current)
+                                }
+                                (Err(err), _) | (_, Err(err)) => Err(err),
+                            };
+                        }
+                        read_tx
+                            .send(PendingWrite::Transaction(PendingTransaction {
+                                ops,
+                                current: read,
+                                responder,
+                            }))
+                            .ok();
+                    });
+                }
+                Command::Cas {
+                    key,
+                    expected,
+                    new,
+                    responder,
+                } => {
+                    let (resp_tx, resp_rx) =
+                        oneshot::channel::<crate::Result<TransactionOutcome>>();
+                    tokio::spawn(async move {
+                        if let Ok(outcome) = resp_rx.await {
+                            let swapped = outcome.map(|outcome| {
+                                matches!(outcome, TransactionOutcome::Committed { .. })
+                            });
+                            responder.send(swapped).ok();
+                        }
+                    });
+                    let op = WriteOp::Cas {
+                        key,
+                        expected,
+                        value: new,
+                    };
+                    self.ready.push_front(Command::Transaction {
+                        ops: vec![op],
+                        responder: resp_tx,
+                    });
+                }
+                Command::GetAt {
+                    key,
+                    seq,
+                    responder,
+                } => {
+                    match self.get_at_from_mem(&key, seq) {
+                        Some(value) => {
+                            responder.send(Ok(value.live())).ok();
+                        }
+                        None => {
                             disp_tx
-                                .send(dispatcher::Command::CreateTable {
-                                    data: old_table,
-                                    responder: resp_tx,
+                                .send(dispatcher::Command::GetAt {
+                                    key,
+                                    seq,
+                                    responder,
                                 })
                                 .await
                                 .ok();
+                        }
+                    };
+                }
+                Command::Compact { responder } => {
+                    disp_tx
+                        .send(dispatcher::Command::Compact { responder })
+                        .await
+                        .ok();
+                }
+                Command::RebuildBloom {
+                    table_id,
+                    responder,
+                } => {
+                    disp_tx
+                        .send(dispatcher::Command::RebuildBloom {
+                            table_id,
+                            responder,
+                        })
+                        .await
+                        .ok();
+                }
+                Command::Flush { responder } => {
+                    self.wait_immutable(0).await;
+
+                    if self.memtable.is_empty() {
+                        responder.send(Ok(None)).ok();
+                        continue;
+                    }
+
+                    let old_table = self.swap_table();
+                    let generation = self.rotate_wal(&mut wal);
+
+                    let (resp_tx, resp_rx) = oneshot::channel();
+                    disp_tx
+                        .send(dispatcher::Command::Flush {
+                            data: old_table,
+                            responder: resp_tx,
+                        })
+                        .await
+                        .ok();
+
+                    let report = match resp_rx.await {
+                        Ok(report) => report.map(Some),
+                        Err(e) => Err(crate::Error::from(e)),
+                    };
+                    // The table is on disk, the log doesn't need its writes anymore.
+                    if report.is_ok() {
+                        self.wal_persisted = self.wal_persisted.max(generation);
+                        self.discard_wal(&mut wal);
+                    }
+                    responder.send(report).ok();
+                }
+                Command::Sync { responder } => {
+                    if let Err(err) = wal.sync() {
+                        tracing::error!("could not sync WAL: {}", err);
+                        responder.send(Err(err)).ok();
+                        continue;
+                    }
+
+                    let (resp_tx, resp_rx) = oneshot::channel();
+                    disp_tx
+                        .send(dispatcher::Command::Sync { responder: resp_tx })
+                        .await
+                        .ok();
+                    let res = match resp_rx.await {
+                        Ok(res) => res,
+                        Err(e) => Err(crate::Error::from(e)),
+                    };
+                    responder.send(res).ok();
+                }
+                Command::CountRange {
+                    start,
+                    end,
+                    responder,
+                } => {
+                    if start >= end {
+                        responder
+                            .send(Ok(RangeCount {
+                                count: 0,
+                                exact: true,
+                            }))
+                            .ok();
+                        continue;
+                    }
+
+                    let now = self.now();
+                    let memtable_count = self
+                        .memtable
+                        .range(&start, &end)
+                        .filter(|(_, value)| value.is_live_at(now))
+                        .count() as u64;
+                    disp_tx
+                        .send(dispatcher::Command::CountRange {
+                            start,
+                            end,
+                            memtable_count,
+                            responder,
+                        })
+                        .await
+                        .ok();
+                }
+                Command::ScanKeys {
+                    start,
+                    end,
+                    limit,
+                    responder,
+                } => {
+                    if start >= end || limit == 0 {
+                        responder.send(Ok(Vec::new())).ok();
+                        continue;
+                    }
+
+                    // Deleted and expired keys are listed too, they hide the keys of the tables.
+                    let mut memtable_keys = Vec::new();
+                    let mut live_keys = 0;
+                    let now = self.now();
+                    for (key, value) in self.memtable.range(&start, &end) {
+                        if live_keys == limit {
+                            break;
+                        }
+                        let live = value.is_live_at(now);
+                        live_keys += live as usize;
+                        memtable_keys.push((key.clone(), live));
+                    }
+                    disp_tx
+                        .send(dispatcher::Command::ScanKeys {
+                            start,
+                            end,
+                            limit,
+                            memtable_keys,
+                            responder,
+                        })
+                        .await
+                        .ok();
+                }
+                Command::Scan {
+                    start,
+                    end,
+                    limit,
+                    responder,
+                } => {
+                    if start >= end || limit == 0 {
+                        responder.send(Ok(Vec::new())).ok();
+                        continue;
+                    }
 
-                            resp_rx.await.ok(); // Blocks if dispatcher tables buffer is full.
+                    // Tombstones are sent too, they hide the keys of the tables.
+                    let mut memtable_entries = Vec::new();
+                    let mut live_entries = 0;
+                    let now = self.now();
+                    for (key, value) in self.memtable.range(&start, &end) {
+                        if live_entries == limit {
+                            break;
                         }
+                        let value = value.clone().expire(now);
+                        live_entries += !value.is_tombstone() as usize;
+                        memtable_entries.push((key.clone(), value));
                     }
+                    disp_tx
+                        .send(dispatcher::Command::Scan {
+                            start,
+                            end,
+                            limit,
+                            memtable_entries,
+                            responder,
+                        })
+                        .await
+                        .ok();
+                }
+                Command::Stats { responder } => {
+                    let (resp_tx, resp_rx) = oneshot::channel();
+                    disp_tx
+                        .send(dispatcher::Command::Stats { responder: resp_tx })
+                        .await
+                        .ok();
+
+                    let stats = match (resp_rx.await, wal.size()) {
+                        (Ok(Ok((cache, blocks, os_cache, tables))), Ok(wal_size)) => Ok(Stats {
+                            cache,
+                            blocks,
+                            wal_size,
+                            wal_replay: wal.replay_stats(),
+                            os_cache,
+                            tables,
+                            memtable_entries: self.memtable.len(),
+                            memtable_size: self.memtable.size() as u64,
+                        }),
+                        (Ok(Err(e)), _) | (_, Err(e)) => Err(e),
+                        (Err(e), _) => Err(crate::Error::from(e)),
+                    };
+                    responder.send(stats).ok();
+                }
+                Command::Position { responder } => {
+                    let (resp_tx, resp_rx) = oneshot::channel();
+                    disp_tx
+                        .send(dispatcher::Command::NewestTable { responder: resp_tx })
+                        .await
+                        .ok();
+
+                    let position = match resp_rx.await {
+                        Ok(Ok(newest_table)) => Ok(Position {
+                            newest_table,
+                            wal_generation: wal.generation(),
+                            last_seq: self.last_seq,
+                        }),
+                        Ok(Err(e)) => Err(e),
+                        Err(e) => Err(crate::Error::from(e)),
+                    };
+                    responder.send(position).ok();
+                }
+                Command::Shutdown { responder } => {
+                    self.input_rx.close();
+                    shutdown = Some(responder);
+                    stopping = true;
                 }
             };
         }
-    }
 
-    /// It only checks hot spots: cache, memtable.
-    fn get_from_mem(&self, key: &Bytes) -> Option<Bytes> {
-        // TODO: First search cache.
+        // There will be no more commands. Put memtable to disk and let dispatcher finish with all
+        // the tables before returning.
+        self.wait_immutable(0).await;
+        if !self.memtable.is_empty() {
+            let old_table = self.swap_table();
+            self.rotate_wal(&mut wal);
+            send_table(&disp_tx, old_table).await.await.ok();
+        }
 
-        if let Some(value) = self.memtable.get(key) {
-            return Some(value);
+        drop(disp_tx);
+        if let Err(e) = disp_handle.await {
+            tracing::error!("dispatcher exited with error: {}", e);
         }
+        tracing::info!("engine stopped");
 
-        None
+        if let Some(responder) = shutdown {
+            responder.send(Ok(())).ok();
+        }
     }
 
-    /// Swaps memtable with fresh one and sends full table to dispatcher that syncronously write it to disk.
-    fn swap_table(&mut self) -> MemTable {
-        // TODO: When SSTable is written WAL should be rotated.
-        let mut swapped = MemTable::new(SsTableSize::Default);
-        std::mem::swap(&mut self.memtable, &mut swapped);
-        swapped
+    /// Puts records recovered from the log to memtable. The log may hold more than a memtable
+    /// can take: the previous table might not be on disk by the time of a crash, or memtable
+    /// size might be configured smaller than it was when the log was written. Full tables are
+    /// sent to disk right away then. Records are already logged, so the log is not rotated.
+    async fn recover(
+        &mut self,
+        entries: Vec<wal::Entry>,
+        disp_tx: &mpsc::Sender<dispatcher::Command>,
+    ) {
+        if entries.is_empty() {
+            return;
+        }
+
+        tracing::info!("recovering {} records from WAL", entries.len());
+        for entry in entries {
+            let writes = match entry {
+                wal::Entry::Set { key, value } => vec![(key, Value::Live(value), 0)],
+                wal::Entry::SetTyped { key, value, flags } => {
+                    vec![(key, Value::Live(value), flags)]
+                }
+                // A value that expired by now is recovered too, it still hides the older values
+                // of the key.
+                wal::Entry::SetEx {
+                    key,
+                    value,
+                    expires_at,
+                } => vec![(key, Value::Expiring { value, expires_at }, 0)],
+                wal::Entry::Delete { key } => vec![(key, Value::Tombstone, 0)],
+                wal::Entry::Batch { writes } => writes
+                    .into_iter()
+                    .map(|(key, value)| (key, value.map_or(Value::Tombstone, Value::Live), 0))
+                    .collect(),
+            };
+
+            // Log doesn't keep sequence numbers, recovered records get new ones in the order
+            // they were logged. Writes of a batch share one and go to the same memtable.
+            let seq = self.next_seq();
+            let probe = self
+                .memtable
+                .probe_all(writes.iter().map(|(key, value, _)| (key, value)));
+            if matches!(probe, memtable::ProbeResult::Full) {
+                let old_table = self.swap_table();
+                send_table(disp_tx, old_table).await.await.ok();
+            }
+            for (key, value, flags) in writes {
+                self.memtable.insert_typed(key, value, flags, seq, None);
+            }
+        }
+
+        if !self.memtable.is_empty() {
+            self.first_write = Some(Instant::now());
+        }
     }
-}
 
-fn validate(key: &Bytes, value: &Bytes) -> crate::Result<()> {
-    if key.is_empty() {
-        return Err(crate::Error::from("key is empty"));
+    /// Writes the value of an append once its current value is known.
+    async fn finish_append<W: WalStorage>(
+        &mut self,
+        pending: PendingAppend,
+        wal: &mut wal::Wal<W>,
+        disp_tx: &mpsc::Sender<dispatcher::Command>,
+    ) {
+        let PendingAppend {
+            key,
+            suffix,
+            current,
+            responder,
+        } = pending;
+        let current = match current {
+            Ok(current) => current,
+            Err(err) => {
+                responder.send(Err(err)).ok();
+                return;
+            }
+        };
+
+        let mut value = BytesMut::from(current.unwrap_or_default());
+        value.extend_from_slice(&suffix);
+        let len = value.len() as u64;
+
+        let write = match Write::new(key, value.freeze(), 0, &self.config) {
+            Ok(write) => write,
+            Err(err) => {
+                responder.send(Err(err)).ok();
+                return;
+            }
+        };
+        let respond = |res: crate::Result<u64>| {
+            responder.send(res.map(|_| len)).ok();
+        };
+        self.write(write, respond, wal, disp_tx).await;
     }
 
-    if key.len() > MAX_KEY_SIZE as usize {
-        return Err(crate::Error::from("key is too long"));
+    /// Checks preconditions of a transaction once the current values of its keys are known and
+    /// writes it if they all hold.
+    async fn finish_transaction<W: WalStorage>(
+        &mut self,
+        pending: PendingTransaction,
+        wal: &mut wal::Wal<W>,
+        disp_tx: &mpsc::Sender<dispatcher::Command>,
+    ) {
+        let PendingTransaction {
+            ops,
+            current,
+            responder,
+        } = pending;
+        let current = match current {
+            Ok(current) => current,
+            Err(err) => {
+                responder.send(Err(err)).ok();
+                return;
+            }
+        };
+
+        for (num, op) in ops.iter().enumerate() {
+            if let WriteOp::Cas { key, expected, .. } = op {
+                if current.get(key).cloned().flatten() != *expected {
+                    responder
+                        .send(Ok(TransactionOutcome::Aborted { op: num }))
+                        .ok();
+                    return;
+                }
+            }
+        }
+
+        let writes = ops.into_iter().map(WriteOp::into_write).collect();
+        let respond = |res: crate::Result<u64>| {
+            responder
+                .send(res.map(|seq| TransactionOutcome::Committed { seq }))
+                .ok();
+        };
+        self.write_all(writes, respond, wal, disp_tx).await;
     }
 
-    if value.is_empty() {
-        return Err(crate::Error::from("value is empty"));
+    /// Lets the writes that waited for the key go, in the order they came.
+    fn unlock(&mut self, key: &Bytes) {
+        if let Some(parked) = self.locked.remove(key) {
+            for cmd in parked.into_iter().rev() {
+                self.ready.push_front(cmd);
+            }
+        }
     }
 
-    if value.len() > MAX_VALUE_SIZE as usize {
-        return Err(crate::Error::from("value is too long"));
+    /// Puts the value to the log and memtable, memtable is swapped first if it is full. The
+    /// outcome is given to respond as soon as it is known, before any table is sent to disk.
+    /// Returns the sequence number of the write if it was applied.
+    async fn write<W: WalStorage>(
+        &mut self,
+        write: Write,
+        respond: impl FnOnce(crate::Result<u64>),
+        wal: &mut wal::Wal<W>,
+        disp_tx: &mpsc::Sender<dispatcher::Command>,
+    ) -> Option<u64> {
+        self.write_all(vec![write], respond, wal, disp_tx).await
     }
 
-    Ok(())
-}
+    /// Same as write for several values put at once. They are logged as a single record, get
+    /// the same sequence number and go to the same memtable.
+    async fn write_all<W: WalStorage>(
+        &mut self,
+        writes: Vec<Write>,
+        respond: impl FnOnce(crate::Result<u64>),
+        wal: &mut wal::Wal<W>,
+        disp_tx: &mpsc::Sender<dispatcher::Command>,
+    ) -> Option<u64> {
+        let entry = match writes.as_slice() {
+            [write] => write.wal_entry(),
+            writes => wal::Entry::Batch {
+                writes: writes
+                    .iter()
+                    .map(|write| (write.key.clone(), write.value.clone().live()))
+                    .collect(),
+            },
+        };
+        if !self.has_space(wal) {
+            respond(Err(crate::Error::Storage(std::io::Error::new(
+                std::io::ErrorKind::StorageFull,
+                "no space left on disk, writes are refused until some is freed",
+            ))));
+            return None;
+        }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::storage::mem;
-    use rand::{rngs::ThreadRng, thread_rng, Rng};
-    use tracing::debug;
-    use tracing_test::traced_test;
+        let probe = self
+            .memtable
+            .probe_all(writes.iter().map(|write| (&write.key, &write.value)));
+        match probe {
+            memtable::ProbeResult::Available(_) => {
+                if let Err(err) = wal.append(&entry) {
+                    tracing::error!("could not append to WAL: {}", err);
+                    self.no_space |= err.is_storage_full();
+                    respond(Err(err));
+                    return None;
+                }
 
-    static DATA: [&str; 337] = [
-        "#+VsKVS&d=7nVxtak2kohq@wxk&g6dQ6yLacn_zu1Cp7CgvWI21aI~=bIDVwGs^8=r*58F6@JXVEn867d0r&m-DVd+0DptveqK0m",
+                let seq = self.next_seq();
+                self.insert_all(writes, seq);
+                self.first_write.get_or_insert_with(Instant::now);
+                respond(Ok(seq));
+
+                if self.reached_watermark() {
+                    self.flush_early(wal, disp_tx).await;
+                }
+
+                Some(seq)
+            }
+            memtable::ProbeResult::Full => {
+                // Swap tables and respond to client first.
+                let old_table = self.swap_table();
+                let generation = self.rotate_wal(wal);
+
+                let seq = match wal.append(&entry) {
+                    Ok(()) => {
+                        let seq = self.next_seq();
+                        self.insert_all(writes, seq);
+                        self.first_write.get_or_insert_with(Instant::now);
+                        respond(Ok(seq));
+                        Some(seq)
+                    }
+                    Err(err) => {
+                        tracing::error!("could not append to WAL: {}", err);
+                        self.no_space |= err.is_storage_full();
+                        respond(Err(err));
+                        None
+                    }
+                };
+
+                // Now send full table to dispatcher to put it to disk. Blocks if dispatcher
+                // tables buffer is full and there is no room for one more immutable table.
+                self.queue_table(
+                    disp_tx,
+                    old_table,
+                    generation,
+                    self.config.immutable_memtables,
+                )
+                .await;
+
+                seq
+            }
+        }
+    }
+
+    fn insert_all(&mut self, writes: Vec<Write>, seq: u64) {
+        for Write { key, value, flags } in writes {
+            self.memtable.insert_typed(key, value, flags, seq, None);
+        }
+    }
+
+    /// Tells if there is enough free space to take a write. Space is checked at most once per
+    /// SPACE_CHECK_INTERVAL while there is enough of it, and on every write while there is not,
+    /// so that writes are taken again as soon as space is freed.
+    fn has_space<W: WalStorage>(&mut self, wal: &wal::Wal<W>) -> bool {
+        if self.config.min_free_space == 0 {
+            return true;
+        }
+
+        let due = self.no_space
+            || self
+                .space_checked
+                .is_none_or(|checked| checked.elapsed() >= SPACE_CHECK_INTERVAL);
+        if !due {
+            return true;
+        }
+
+        self.space_checked = Some(Instant::now());
+        let no_space = match wal.available_space() {
+            Ok(space) => space < self.config.min_free_space,
+            Err(e) => {
+                tracing::warn!("could not check free space: {}", e);
+                false
+            }
+        };
+        if no_space && !self.no_space {
+            tracing::error!("running out of disk space, refusing writes");
+        } else if !no_space && self.no_space {
+            tracing::info!("disk space is freed, taking writes again");
+        }
+        self.no_space = no_space;
+
+        !self.no_space
+    }
+
+    /// Swaps memtable and sends it to disk without waiting for the dispatcher, the ack is awaited
+    /// lazily once there are more tables in flight than allowed. There is always room for one
+    /// early flush, even if no immutable tables are allowed.
+    async fn flush_early<W: WalStorage>(
+        &mut self,
+        wal: &mut wal::Wal<W>,
+        disp_tx: &mpsc::Sender<dispatcher::Command>,
+    ) {
+        let old_table = self.swap_table();
+        let generation = self.rotate_wal(wal);
+        let keep = self.config.immutable_memtables.max(1);
+        self.queue_table(disp_tx, old_table, generation, keep).await;
+    }
+
+    /// Sends a table to disk and keeps it to serve reads. Once there are keep tables kept, the
+    /// oldest one is let go, after waiting for the dispatcher to take it. Dispatcher acks a table
+    /// before it is on disk and reads of it would wait for the dispatcher to get it there, so
+    /// tables are only let go once newer ones push them out.
+    async fn queue_table(
+        &mut self,
+        disp_tx: &mpsc::Sender<dispatcher::Command>,
+        table: MemTable,
+        wal_generation: Option<u64>,
+        keep: usize,
+    ) {
+        self.wait_immutable(keep.saturating_sub(1)).await;
+
+        if keep == 0 {
+            send_table(disp_tx, table).await.await.ok();
+            self.on_table_acked(wal_generation);
+            return;
+        }
+
+        let ack = send_table(disp_tx, table.clone()).await;
+        self.immutable.push_front(Immutable {
+            table,
+            ack,
+            wal_generation,
+        });
+    }
+
+    /// Awaits acks of the oldest immutable tables until no more than keep of them are left.
+    async fn wait_immutable(&mut self, keep: usize) {
+        while self.immutable.len() > keep {
+            if let Some(oldest) = self.immutable.pop_back() {
+                oldest.ack.await.ok();
+                self.on_table_acked(oldest.wal_generation);
+            }
+        }
+    }
+
+    /// Dispatcher takes tables one by one and puts each of them to disk before it takes the next
+    /// one, so once a table is acked the table sent before it is on disk, along with the log
+    /// generations holding its writes.
+    fn on_table_acked(&mut self, wal_generation: Option<u64>) {
+        let previous = std::mem::replace(&mut self.wal_acked, wal_generation);
+        self.wal_persisted = self.wal_persisted.max(previous);
+    }
+
+    /// Starts a new WAL generation for the fresh memtable and returns the last generation
+    /// holding writes of the swapped one, None if there is none. Generations of the tables
+    /// known to be on disk are discarded. If rotating fails the log keeps going in the current
+    /// generation, which only makes it longer to recover from.
+    fn rotate_wal<W: WalStorage>(&mut self, wal: &mut wal::Wal<W>) -> Option<u64> {
+        let generation = wal.generation();
+        let swapped = match wal.rotate() {
+            Ok(()) => Some(generation),
+            Err(e) => {
+                tracing::error!("could not rotate WAL: {}", e);
+                // Writes of the fresh memtable go to the same generation.
+                generation.checked_sub(1)
+            }
+        };
+        self.discard_wal(wal);
+
+        swapped
+    }
+
+    /// Removes log generations whose writes are all in tables on disk.
+    fn discard_wal<W: WalStorage>(&mut self, wal: &mut wal::Wal<W>) {
+        if let Some(generation) = self.wal_persisted.take() {
+            if let Err(e) = wal.discard_through(generation) {
+                tracing::error!("could not discard WAL generations: {}", e);
+            }
+        }
+    }
+
+    /// It only checks hot spots: cache, memtable and immutable tables.
+    /// A deleted key is found as a tombstone, it is not looked for on disk then.
+    /// An expired value is found as a tombstone as well.
+    fn get_from_mem(&self, key: &Bytes) -> Option<Value> {
+        // TODO: First search cache.
+
+        let value = match self.memtable.get(key) {
+            Some(value) => Some(value),
+            None => self.immutable.iter().find_map(|imm| imm.table.get(key)),
+        };
+        value.map(|value| value.expire(self.now()))
+    }
+
+    /// Same as get_from_mem, along with the flags of the value.
+    fn get_typed_from_mem(&self, key: &Bytes) -> Option<(Value, u8)> {
+        let typed = match self.memtable.get_typed(key) {
+            Some(typed) => Some(typed),
+            None => self
+                .immutable
+                .iter()
+                .find_map(|imm| imm.table.get_typed(key)),
+        };
+        typed.map(|(value, flags)| (value.expire(self.now()), flags))
+    }
+
+    /// Same as get_from_mem for a read as of the given sequence number.
+    fn get_at_from_mem(&self, key: &Bytes, seq: u64) -> Option<Value> {
+        let value = match self.memtable.get_at(key, seq) {
+            Some(value) => Some(value),
+            None => self
+                .immutable
+                .iter()
+                .find_map(|imm| imm.table.get_at(key, seq)),
+        };
+        value.map(|value| value.expire(self.now()))
+    }
+
+    fn reached_watermark(&self) -> bool {
+        match self.config.flush_watermark {
+            Some(percent) => self.memtable.is_above(percent),
+            None => false,
+        }
+    }
+
+    /// Unix time in milliseconds values are checked for expiration against.
+    fn now(&self) -> u64 {
+        self.config.clock.since_epoch().as_millis() as u64
+    }
+
+    fn next_seq(&mut self) -> u64 {
+        let now = self.config.clock.since_epoch().as_micros() as u64;
+        self.last_seq = now.max(self.last_seq + 1);
+        self.last_seq
+    }
+
+    fn is_stale(&self) -> bool {
+        match (self.config.flush_interval, self.first_write) {
+            (Some(interval), Some(first_write)) => first_write.elapsed() >= interval,
+            _ => false,
+        }
+    }
+
+    /// Swaps memtable with fresh one and sends full table to dispatcher that syncronously write it to disk.
+    fn swap_table(&mut self) -> MemTable {
+        let mut swapped = MemTable::new(self.config.memtable_size);
+        std::mem::swap(&mut self.memtable, &mut swapped);
+        self.first_write = None;
+        swapped
+    }
+}
+
+/// Key and value that passed validation. Writes get to the log only in this form, so a record
+/// that would be refused again on recovery never gets there. A command writing several values
+/// has to validate all of them before writing any.
+#[derive(Debug)]
+struct Write {
+    key: Bytes,
+    value: Value,
+    flags: u8,
+}
+
+impl Write {
+    fn new(key: Bytes, value: Bytes, flags: u8, config: &Config) -> crate::Result<Self> {
+        validate(&key, &value, config)?;
+        Ok(Write {
+            key,
+            value: Value::Live(value),
+            flags,
+        })
+    }
+
+    fn expiring(key: Bytes, value: Bytes, expires_at: u64, config: &Config) -> crate::Result<Self> {
+        validate(&key, &value, config)?;
+        Ok(Write {
+            key,
+            value: Value::Expiring { value, expires_at },
+            flags: 0,
+        })
+    }
+
+    fn tombstone(key: Bytes, config: &Config) -> crate::Result<Self> {
+        validate_key(&key, config)?;
+        Ok(Write {
+            key,
+            value: Value::Tombstone,
+            flags: 0,
+        })
+    }
+
+    /// Untyped values are logged the way they were before flags were introduced.
+    fn wal_entry(&self) -> wal::Entry {
+        let key = self.key.clone();
+        match (&self.value, self.flags) {
+            (Value::Tombstone, _) => wal::Entry::Delete { key },
+            (Value::Live(value), 0) => wal::Entry::Set {
+                key,
+                value: value.clone(),
+            },
+            (Value::Live(value), flags) => wal::Entry::SetTyped {
+                key,
+                value: value.clone(),
+                flags,
+            },
+            (Value::Expiring { value, expires_at }, _) => wal::Entry::SetEx {
+                key,
+                value: value.clone(),
+                expires_at: *expires_at,
+            },
+        }
+    }
+}
+
+/// Append waiting for the current value of its key.
+struct PendingAppend {
+    key: Bytes,
+    suffix: Bytes,
+    current: crate::Result<Option<Bytes>>,
+    responder: Responder<u64>,
+}
+
+/// Transaction waiting for the current values of its keys.
+struct PendingTransaction {
+    ops: Vec<WriteOp>,
+    current: crate::Result<HashMap<Bytes, Option<Bytes>>>,
+    responder: Responder<TransactionOutcome>,
+}
+
+/// Write that read values from disk and is ready to be finished.
+enum PendingWrite {
+    Append(PendingAppend),
+    Transaction(PendingTransaction),
+}
+
+impl Command {
+    /// Keys the command writes to, none if it is not a write.
+    fn written_keys(&self) -> Vec<&Bytes> {
+        match self {
+            Command::Set { key, .. }
+            | Command::SetTyped { key, .. }
+            | Command::SetEx { key, .. }
+            | Command::Delete { key, .. }
+            | Command::Cas { key, .. }
+            | Command::Append { key, .. } => vec![key],
+            Command::Transaction { ops, .. } => ops.iter().map(WriteOp::key).collect(),
+            Command::SetBatch { pairs, .. } => pairs.iter().map(|(key, _)| key).collect(),
+            _ => vec![],
+        }
+    }
+}
+
+/// Reads the value of the key from the tables.
+async fn read_from_disk(
+    disp_tx: &mpsc::Sender<dispatcher::Command>,
+    key: Bytes,
+) -> crate::Result<Option<Bytes>> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+    disp_tx
+        .send(dispatcher::Command::Get {
+            key,
+            fill_cache: true,
+            responder: resp_tx,
+        })
+        .await
+        .map_err(|_| "dispatcher is gone")?;
+
+    resp_rx.await?
+}
+
+/// Full memtable that was sent to disk, along with the ack of the dispatcher taking it.
+#[derive(Debug)]
+struct Immutable {
+    table: MemTable,
+    ack: oneshot::Receiver<crate::Result<()>>,
+    /// Last log generation holding writes of the table.
+    wal_generation: Option<u64>,
+}
+
+/// Resolves on the next tick of the timer. Never resolves if there is no timer.
+async fn tick(timer: &mut Option<time::Interval>) {
+    match timer {
+        Some(timer) => {
+            timer.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Sends a table to dispatcher to put it to disk and returns the channel dispatcher acks to.
+async fn send_table(
+    disp_tx: &mpsc::Sender<dispatcher::Command>,
+    table: MemTable,
+) -> oneshot::Receiver<crate::Result<()>> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+
+    disp_tx
+        .send(dispatcher::Command::CreateTable {
+            data: table,
+            responder: resp_tx,
+        })
+        .await
+        .ok();
+
+    resp_rx
+}
+
+/// Checks a transaction can be written as a whole before any of its preconditions are read.
+fn validate_transaction(ops: &[WriteOp], config: &Config) -> crate::Result<()> {
+    if ops.is_empty() {
+        return Err(crate::Error::InvalidInput("transaction is empty".into()));
+    }
+
+    let mut keys = HashSet::new();
+    let mut writes = Vec::with_capacity(ops.len());
+    for op in ops {
+        let (key, value) = match op.clone() {
+            WriteOp::Set { key, value } | WriteOp::Cas { key, value, .. } => {
+                validate(&key, &value, config)?;
+                (key, Some(value))
+            }
+            WriteOp::Delete { key } => {
+                validate_key(&key, config)?;
+                (key, None)
+            }
+        };
+        if !keys.insert(key.clone()) {
+            return Err(crate::Error::InvalidInput(format!(
+                "key {:?} is written twice",
+                key
+            )));
+        }
+        writes.push((key, value));
+    }
+
+    let entry = wal::Entry::Batch { writes };
+    if entry.encode().len() > wal::PAGE_SIZE {
+        return Err(crate::Error::InvalidInput("transaction is too big".into()));
+    }
+
+    Ok(())
+}
+
+/// Tells which pair of a batch an error is about, keeping the kind of the error.
+fn at_pair(num: usize, err: crate::Error) -> crate::Error {
+    let msg = format!("pair {}: {}", num, err);
+    match err {
+        crate::Error::InvalidInput(_) => crate::Error::InvalidInput(msg),
+        crate::Error::Corrupted(_) => crate::Error::Corrupted(msg),
+        crate::Error::Overloaded(_) => crate::Error::Overloaded(msg),
+        crate::Error::Storage(e) => crate::Error::Storage(std::io::Error::new(e.kind(), msg)),
+        crate::Error::Other(_) => crate::Error::from(msg),
+    }
+}
+
+fn validate(key: &Bytes, value: &Bytes, config: &Config) -> crate::Result<()> {
+    validate_key(key, config)?;
+
+    if value.is_empty() {
+        return Err(crate::Error::InvalidInput("value is empty".into()));
+    }
+
+    if value.len() > config.max_value_size as usize {
+        return Err(crate::Error::InvalidInput("value is too long".into()));
+    }
+
+    Ok(())
+}
+
+fn validate_key(key: &Bytes, config: &Config) -> crate::Result<()> {
+    if key.is_empty() {
+        return Err(crate::Error::InvalidInput("key is empty".into()));
+    }
+
+    if key.len() > config.max_key_size as usize {
+        return Err(crate::Error::InvalidInput("key is too long".into()));
+    }
+
+    Ok(())
+}
+
+/// Exclusive upper bound of the keys starting with prefix, to turn a prefix into a [start, end)
+/// range. It is the prefix with trailing 0xFF bytes dropped and the last byte left incremented.
+/// None means there is no such bound and the range goes to the end of the keyspace, which is the
+/// case for an empty prefix and a prefix of 0xFF bytes only.
+pub fn prefix_end(prefix: &[u8]) -> Option<Bytes> {
+    let last = prefix.iter().rposition(|byte| *byte != 0xFF)?;
+
+    let mut end = prefix[..=last].to_vec();
+    end[last] += 1;
+
+    Some(Bytes::from(end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::memtable::SsTableSize;
+    use crate::storage::mem;
+    use crate::Storage;
+    use rand::{thread_rng, Rng, SeedableRng};
+    use std::sync::Arc;
+    use std::time::UNIX_EPOCH;
+    use tracing::debug;
+    use tracing_test::traced_test;
+
+    static DATA: [&str; 337] = [
+        "#+VsKVS&d=7nVxtak2kohq@wxk&g6dQ6yLacn_zu1Cp7CgvWI21aI~=bIDVwGs^8=r*58F6@JXVEn867d0r&m-DVd+0DptveqK0m",
         "#^K0aAC4hbAldT16t=fHJvxo0o8FSVwt&0iNeA&6*5J#VLy9Ogmg^9vdkXdFkc9SMIuvSX@kQDuUF4fl=p31SK0hX~BRveYxTiki",
         "#cfr0iHCypr^ft8#TLPz0*A-=#K@Rg*t^*nPMy=qg&yEhn=k@oVYfPfowFpGnqQPVrut4O#gnO4w3Zg8^88Pa7m&ADH57dHBkX*_",
         "#oGvDTzDWxB0cODxzE07um2d38750tzVMeiYkkXTgoXG3UN#iVn6Hsyd6LULDAlYEQEQ-U-JZK=l=CN=7NouOuIg=C9K#SlBa9sq",
@@ -524,15 +2026,34 @@ mod tests {
     ];
 
     #[traced_test]
+    async fn send<T>(
+        req_tx: &mpsc::Sender<Command>,
+        cmd: impl FnOnce(Responder<T>) -> Command,
+    ) -> crate::Result<T> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        req_tx.send(cmd(resp_tx)).await.unwrap();
+        resp_rx.await.unwrap()
+    }
+
+    async fn get(req_tx: &mpsc::Sender<Command>, key: impl Into<Bytes>) -> Option<Bytes> {
+        send(req_tx, |responder| Command::Get {
+            key: key.into(),
+            fill_cache: true,
+            responder,
+        })
+        .await
+        .unwrap()
+    }
+
     #[tokio::test]
     async fn test_run_with_fixtures() {
         // Initialize engine.
         let stor = mem::new();
         let (req_tx, req_rx) = mpsc::channel(64);
-        let engine = Engine::new(req_rx);
+        let engine = Engine::new(req_rx, Config::default());
 
         tokio::spawn(async move {
-            engine.run(stor).await;
+            engine.run(stor, mem::new_wal()).await;
             tracing::error!("engine exited");
         });
 
@@ -544,6 +2065,7 @@ mod tests {
                 .send(Command::Set {
                     key,
                     value,
+                    request_id: None,
                     responder: None
                 })
                 .await
@@ -557,6 +2079,7 @@ mod tests {
 
             let cmd = Command::Get {
                 key: Bytes::from(str),
+                fill_cache: true,
                 responder: resp_tx,
             };
 
@@ -592,6 +2115,7 @@ mod tests {
         let missing_key = Bytes::from("example-key-that-was-never-set");
         let cmd = Command::Get {
             key: missing_key.clone(),
+            fill_cache: true,
             responder: resp_tx,
         };
 
@@ -602,13 +2126,1480 @@ mod tests {
         let resp = resp.unwrap();
         assert!(resp.is_ok(), "engine returned an error: {:?}", resp);
 
-        let resp = resp.unwrap();
-        assert!(
-            resp.is_none(),
-            "key {:?} should be none but {:?} been returned",
-            missing_key,
-            resp
+        let resp = resp.unwrap();
+        assert!(
+            resp.is_none(),
+            "key {:?} should be none but {:?} been returned",
+            missing_key,
+            resp
+        );
+    }
+
+    /// Runs an engine that writes all the fixtures and crashes. Tables it writes are dropped, so
+    /// the data is only left in the log.
+    async fn write_fixtures_to_wal(wal_stor: mem::MemWalStorage) {
+        let (req_tx, req_rx) = mpsc::channel(64);
+        let engine = Engine::new(req_rx, Config::default());
+        let handle = tokio::spawn(async move {
+            engine.run(mem::new(), wal_stor).await;
+        });
+
+        for str in DATA {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            req_tx
+                .send(Command::Set {
+                    key: Bytes::from(str),
+                    value: Bytes::from(str),
+                    request_id: None,
+                    responder: Some(resp_tx),
+                })
+                .await
+                .unwrap();
+            resp_rx.await.unwrap().unwrap();
+        }
+
+        // Engine is aborted so it doesn't get to flush memtable on its way out.
+        handle.abort();
+        handle.await.ok();
+    }
+
+    async fn assert_fixtures_readable(req_tx: &mpsc::Sender<Command>) {
+        for str in DATA {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            req_tx
+                .send(Command::Get {
+                    key: Bytes::from(str),
+                    fill_cache: true,
+                    responder: resp_tx,
+                })
+                .await
+                .unwrap();
+            assert_eq!(
+                resp_rx.await.unwrap().unwrap(),
+                Some(Bytes::from(str)),
+                "key {} is not recovered",
+                str
+            );
+        }
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn test_recover_from_wal() {
+        let wal_stor = mem::new_wal();
+        write_fixtures_to_wal(wal_stor.clone()).await;
+
+        let (req_tx, req_rx) = mpsc::channel(64);
+        let engine = Engine::new(req_rx, Config::default());
+        tokio::spawn(async move {
+            engine.run(mem::new(), wal_stor).await;
+        });
+
+        assert_fixtures_readable(&req_tx).await;
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn test_recover_expiring_with_clock() {
+        let start = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let start_ms = 1_700_000_000_000;
+        let wal_stor = mem::new_wal();
+        let (mut wal, _) =
+            wal::Wal::init(wal_stor.clone(), crate::SyncPolicy::Never, None).unwrap();
+        for (key, ttl_ms) in [("foo", 1000), ("bar", 1001)] {
+            wal.append(&wal::Entry::SetEx {
+                key: Bytes::from(key),
+                value: Bytes::from(key),
+                expires_at: start_ms + ttl_ms,
+            })
+            .unwrap();
+        }
+        drop(wal);
+
+        let clock = crate::ManualClock::new(start);
+        clock.advance(Duration::from_millis(1000));
+        let config = Config {
+            clock: Arc::new(clock.clone()),
+            ..Default::default()
+        };
+        let (req_tx, req_rx) = mpsc::channel(64);
+        let engine = Engine::new(req_rx, config);
+        tokio::spawn(engine.run(mem::new(), wal_stor));
+
+        for (key, alive) in [("foo", false), ("bar", true)] {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            req_tx
+                .send(Command::Get {
+                    key: Bytes::from(key),
+                    fill_cache: true,
+                    responder: resp_tx,
+                })
+                .await
+                .unwrap();
+            assert_eq!(
+                resp_rx.await.unwrap().unwrap().is_some(),
+                alive,
+                "{} expires at its expiration time, not a moment later",
+                key
+            );
+        }
+
+        // Sequence numbers are taken from the clock as well, once it is past the recovered ones.
+        clock.advance(Duration::from_secs(1));
+        let (resp_tx, resp_rx) = oneshot::channel();
+        req_tx
+            .send(Command::Set {
+                key: Bytes::from("baz"),
+                value: Bytes::from("baz"),
+                request_id: None,
+                responder: Some(resp_tx),
+            })
+            .await
+            .unwrap();
+        assert_eq!(resp_rx.await.unwrap().unwrap(), (start_ms + 2000) * 1000);
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn test_recover_into_smaller_memtable() {
+        let wal_stor = mem::new_wal();
+        write_fixtures_to_wal(wal_stor.clone()).await;
+
+        let stor = mem::new();
+        let config = Config {
+            memtable_size: SsTableSize::Is(8 * 1024),
+            ..Default::default()
+        };
+        let (req_tx, req_rx) = mpsc::channel(64);
+        let engine = Engine::new(req_rx, config);
+        let engine_stor = stor.clone();
+        tokio::spawn(async move {
+            engine.run(engine_stor, wal_stor).await;
+        });
+
+        assert_fixtures_readable(&req_tx).await;
+        assert!(
+            stor.list_entries().unwrap().len() > 1,
+            "records that don't fit memtable are flushed on recovery"
+        );
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn test_shutdown() {
+        let stor = mem::new();
+        let (req_tx, req_rx) = mpsc::channel(64);
+        let engine = Engine::new(req_rx, Config::default());
+        let handle = tokio::spawn(engine.run(stor.clone(), mem::new_wal()));
+
+        let (resp_tx, resp_rx) = oneshot::channel();
+        req_tx
+            .send(Command::Set {
+                key: Bytes::from("foo"),
+                value: Bytes::from("bar"),
+                request_id: None,
+                responder: Some(resp_tx),
+            })
+            .await
+            .unwrap();
+        resp_rx.await.unwrap().unwrap();
+
+        let (resp_tx, resp_rx) = oneshot::channel();
+        req_tx
+            .send(Command::Shutdown { responder: resp_tx })
+            .await
+            .unwrap();
+        resp_rx.await.unwrap().unwrap();
+
+        // Sender is still alive, engine stops anyway.
+        let stopped = tokio::time::timeout(std::time::Duration::from_secs(1), handle).await;
+        assert!(stopped.is_ok(), "engine task must complete");
+        assert_eq!(stor.list_entries().unwrap().len(), 1, "memtable is flushed");
+        assert!(
+            req_tx
+                .send(Command::Compact {
+                    responder: oneshot::channel().0
+                })
+                .await
+                .is_err(),
+            "no commands are taken after shutdown"
+        );
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn test_retried_set_applied_once() {
+        let (req_tx, req_rx) = mpsc::channel(64);
+        let engine = Engine::new(req_rx, Config::default());
+        tokio::spawn(engine.run(mem::new(), mem::new_wal()));
+
+        let set = |value: &'static str, request_id| {
+            let req_tx = req_tx.clone();
+            async move {
+                let (resp_tx, resp_rx) = oneshot::channel();
+                req_tx
+                    .send(Command::Set {
+                        key: Bytes::from("foo"),
+                        value: Bytes::from(value),
+                        request_id,
+                        responder: Some(resp_tx),
+                    })
+                    .await
+                    .unwrap();
+                resp_rx.await.unwrap()
+            }
+        };
+        let get = || async {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            req_tx
+                .send(Command::Get {
+                    key: Bytes::from("foo"),
+                    fill_cache: true,
+                    responder: resp_tx,
+                })
+                .await
+                .unwrap();
+            resp_rx.await.unwrap().unwrap()
+        };
+
+        set("1", Some(7)).await.unwrap();
+        set("2", Some(7)).await.unwrap();
+        assert_eq!(get().await, Some(Bytes::from("1")), "retry is not applied");
+
+        set("3", Some(8)).await.unwrap();
+        assert_eq!(get().await, Some(Bytes::from("3")));
+
+        set("4", None).await.unwrap();
+        set("5", None).await.unwrap();
+        assert_eq!(get().await, Some(Bytes::from("5")));
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn test_flush_interval() {
+        let stor = mem::new();
+        let config = Config {
+            flush_interval: Some(std::time::Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let (req_tx, req_rx) = mpsc::channel(64);
+        let engine = Engine::new(req_rx, config);
+        tokio::spawn(engine.run(stor.clone(), mem::new_wal()));
+
+        for str in &DATA[..3] {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            req_tx
+                .send(Command::Set {
+                    key: Bytes::from(*str),
+                    value: Bytes::from(*str),
+                    request_id: None,
+                    responder: Some(resp_tx),
+                })
+                .await
+                .unwrap();
+            resp_rx.await.unwrap().unwrap();
+        }
+        assert!(stor.list_entries().unwrap().is_empty());
+
+        tokio::time::sleep(std::time::Duration::from_millis(350)).await;
+        assert_eq!(
+            stor.list_entries().unwrap().len(),
+            1,
+            "memtable is flushed before it gets full"
+        );
+
+        tokio::time::sleep(std::time::Duration::from_millis(250)).await;
+        assert_eq!(
+            stor.list_entries().unwrap().len(),
+            1,
+            "empty memtable is not flushed"
+        );
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn test_flush() {
+        let stor = mem::new();
+        let (req_tx, req_rx) = mpsc::channel(64);
+        let engine = Engine::new(req_rx, Config::default());
+        tokio::spawn(engine.run(stor.clone(), mem::new_wal()));
+
+        let flush = || async {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            req_tx
+                .send(Command::Flush { responder: resp_tx })
+                .await
+                .unwrap();
+            resp_rx.await.unwrap().unwrap()
+        };
+
+        assert_eq!(flush().await, None, "empty memtable is not flushed");
+
+        let (resp_tx, resp_rx) = oneshot::channel();
+        req_tx
+            .send(Command::Set {
+                key: Bytes::from("foo"),
+                value: Bytes::from("bar"),
+                request_id: None,
+                responder: Some(resp_tx),
+            })
+            .await
+            .unwrap();
+        resp_rx.await.unwrap().unwrap();
+
+        let report = flush().await.expect("memtable should be flushed");
+        assert_eq!(stor.list_entries().unwrap(), vec![report.table_id]);
+        assert_eq!(
+            stor.open(&report.table_id).unwrap().len() as u64,
+            report.size
+        );
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn test_position() {
+        let (req_tx, req_rx) = mpsc::channel(64);
+        let engine = Engine::new(req_rx, Config::default());
+        tokio::spawn(engine.run(mem::new(), mem::new_wal()));
+
+        let position = || async {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            req_tx
+                .send(Command::Position { responder: resp_tx })
+                .await
+                .unwrap();
+            resp_rx.await.unwrap().unwrap()
+        };
+        let set_and_flush = |key: &'static str| async {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            req_tx
+                .send(Command::Set {
+                    key: Bytes::from_static(key.as_bytes()),
+                    value: Bytes::from("bar"),
+                    request_id: None,
+                    responder: Some(resp_tx),
+                })
+                .await
+                .unwrap();
+            resp_rx.await.unwrap().unwrap();
+
+            let (resp_tx, resp_rx) = oneshot::channel();
+            req_tx
+                .send(Command::Flush { responder: resp_tx })
+                .await
+                .unwrap();
+            resp_rx.await.unwrap().unwrap().unwrap().table_id
+        };
+
+        let start = position().await;
+        assert_eq!(start.newest_table, None);
+
+        let first = set_and_flush("foo").await;
+        let after_first = position().await;
+        assert_eq!(after_first.newest_table, Some(first));
+        assert!(after_first.wal_generation > start.wal_generation);
+
+        let second = set_and_flush("bar").await;
+        let after_second = position().await;
+        assert_eq!(after_second.newest_table, Some(second));
+        assert!(after_second.newest_table > after_first.newest_table);
+        assert!(after_second.wal_generation > after_first.wal_generation);
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn test_append() {
+        let (req_tx, req_rx) = mpsc::channel(64);
+        let engine = Engine::new(req_rx, Config::default());
+        tokio::spawn(engine.run(mem::new(), mem::new_wal()));
+
+        let append = |key: &'static str, suffix: Bytes| {
+            let req_tx = req_tx.clone();
+            async move {
+                let (resp_tx, resp_rx) = oneshot::channel();
+                req_tx
+                    .send(Command::Append {
+                        key: Bytes::from(key),
+                        suffix,
+                        responder: resp_tx,
+                    })
+                    .await
+                    .unwrap();
+                resp_rx.await.unwrap()
+            }
+        };
+
+        assert_eq!(append("foo", Bytes::from("bar")).await.unwrap(), 3);
+        assert_eq!(append("foo", Bytes::from("baz")).await.unwrap(), 6);
+
+        // Value that is not in memtable any more is read from disk.
+        let (resp_tx, resp_rx) = oneshot::channel();
+        req_tx
+            .send(Command::Flush { responder: resp_tx })
+            .await
+            .unwrap();
+        resp_rx.await.unwrap().unwrap();
+        assert_eq!(append("foo", Bytes::from("qux")).await.unwrap(), 9);
+
+        let (resp_tx, resp_rx) = oneshot::channel();
+        req_tx
+            .send(Command::Get {
+                key: Bytes::from("foo"),
+                fill_cache: true,
+                responder: resp_tx,
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            resp_rx.await.unwrap().unwrap(),
+            Some(Bytes::from("barbazqux"))
+        );
+
+        let suffix = Bytes::from(vec![b'x'; MAX_VALUE_SIZE as usize - 8]);
+        assert!(
+            append("foo", suffix).await.is_err(),
+            "appended value is too long"
+        );
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn test_append_from_disk_does_not_block() {
+        let stor = SlowReadStorage {
+            inner: mem::new(),
+            open_delay: std::time::Duration::from_millis(300),
+        };
+        let (req_tx, req_rx) = mpsc::channel(64);
+        let engine = Engine::new(req_rx, Config::default());
+        tokio::spawn(engine.run(stor, mem::new_wal()));
+
+        // Requests are sent without waiting for the responses.
+        let set = |key: &'static str| {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            req_tx
+                .try_send(Command::Set {
+                    key: Bytes::from(key),
+                    value: Bytes::from("a"),
+                    request_id: None,
+                    responder: Some(resp_tx),
+                })
+                .unwrap();
+            resp_rx
+        };
+        let append = |key: &'static str, suffix: &'static str| {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            req_tx
+                .try_send(Command::Append {
+                    key: Bytes::from(key),
+                    suffix: Bytes::from(suffix),
+                    responder: resp_tx,
+                })
+                .unwrap();
+            resp_rx
+        };
+
+        set("cold").await.unwrap().unwrap();
+        let (resp_tx, resp_rx) = oneshot::channel();
+        req_tx
+            .send(Command::Flush { responder: resp_tx })
+            .await
+            .unwrap();
+        resp_rx.await.unwrap().unwrap();
+        set("hot").await.unwrap().unwrap();
+
+        // Both appends to the cold key wait for its value from disk, in order.
+        let cold_first = append("cold", "b");
+        let cold_second = append("cold", "c");
+        let hot = append("hot", "b");
+        let other = set("other");
+
+        let quick = std::time::Duration::from_millis(100);
+        let hot = tokio::time::timeout(quick, hot).await;
+        assert_eq!(
+            hot.expect("append of another key should not wait")
+                .unwrap()
+                .unwrap(),
+            2
+        );
+        let other = tokio::time::timeout(quick, other).await;
+        assert!(other
+            .expect("set of another key should not wait")
+            .unwrap()
+            .is_ok());
+
+        assert_eq!(cold_first.await.unwrap().unwrap(), 2);
+        assert_eq!(cold_second.await.unwrap().unwrap(), 3);
+
+        let (resp_tx, resp_rx) = oneshot::channel();
+        req_tx
+            .send(Command::Get {
+                key: Bytes::from("cold"),
+                fill_cache: true,
+                responder: resp_tx,
+            })
+            .await
+            .unwrap();
+        assert_eq!(resp_rx.await.unwrap().unwrap(), Some(Bytes::from("abc")));
+    }
+
+    #[tokio::test]
+    async fn test_refused_writes_not_logged() {
+        let wal_stor = mem::new_wal();
+        let (req_tx, req_rx) = mpsc::channel(64);
+        let engine = Engine::new(req_rx, Config::default());
+        let handle = tokio::spawn(engine.run(mem::new(), wal_stor.clone()));
+
+        let (resp_tx, resp_rx) = oneshot::channel();
+        req_tx
+            .send(Command::Set {
+                key: Bytes::from("foo"),
+                value: Bytes::from(vec![b'x'; MAX_VALUE_SIZE as usize]),
+                request_id: None,
+                responder: Some(resp_tx),
+            })
+            .await
+            .unwrap();
+        resp_rx.await.unwrap().unwrap();
+        let logged = wal_stor.size().unwrap();
+
+        let (resp_tx, resp_rx) = oneshot::channel();
+        req_tx
+            .send(Command::Set {
+                key: Bytes::from("bar"),
+                value: Bytes::new(),
+                request_id: None,
+                responder: Some(resp_tx),
+            })
+            .await
+            .unwrap();
+        assert!(matches!(
+            resp_rx.await.unwrap(),
+            Err(crate::Error::InvalidInput(_))
+        ));
+
+        let (resp_tx, resp_rx) = oneshot::channel();
+        req_tx
+            .send(Command::Append {
+                key: Bytes::from("foo"),
+                suffix: Bytes::from("y"),
+                responder: resp_tx,
+            })
+            .await
+            .unwrap();
+        assert!(matches!(
+            resp_rx.await.unwrap(),
+            Err(crate::Error::InvalidInput(_))
+        ));
+
+        assert_eq!(wal_stor.size().unwrap(), logged, "nothing else is logged");
+
+        // Engine is aborted so that memtable is only recovered from the log.
+        handle.abort();
+        let (req_tx, req_rx) = mpsc::channel(64);
+        let engine = Engine::new(req_rx, Config::default());
+        tokio::spawn(engine.run(mem::new(), wal_stor));
+
+        let (resp_tx, resp_rx) = oneshot::channel();
+        req_tx
+            .send(Command::Get {
+                key: Bytes::from("foo"),
+                fill_cache: true,
+                responder: resp_tx,
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            resp_rx.await.unwrap().unwrap(),
+            Some(Bytes::from(vec![b'x'; MAX_VALUE_SIZE as usize]))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_typed_values() {
+        async fn set_typed(req_tx: &mpsc::Sender<Command>, key: &'static str, flags: u8) {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            req_tx
+                .send(Command::SetTyped {
+                    key: Bytes::from(key),
+                    value: Bytes::from("{}"),
+                    flags,
+                    responder: resp_tx,
+                })
+                .await
+                .unwrap();
+            resp_rx.await.unwrap().unwrap();
+        }
+        async fn get_typed(req_tx: &mpsc::Sender<Command>, key: &'static str) -> Option<u8> {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            req_tx
+                .send(Command::GetTyped {
+                    key: Bytes::from(key),
+                    responder: resp_tx,
+                })
+                .await
+                .unwrap();
+            resp_rx.await.unwrap().unwrap().map(|(value, flags)| {
+                assert_eq!(value, Bytes::from("{}"));
+                flags
+            })
+        }
+        async fn flush(req_tx: &mpsc::Sender<Command>) {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            req_tx
+                .send(Command::Flush { responder: resp_tx })
+                .await
+                .unwrap();
+            resp_rx.await.unwrap().unwrap();
+        }
+
+        let stor = mem::new();
+        let wal_stor = mem::new_wal();
+        let (req_tx, req_rx) = mpsc::channel(64);
+        let engine = Engine::new(req_rx, Config::default());
+        let handle = tokio::spawn(engine.run(stor.clone(), wal_stor.clone()));
+
+        set_typed(&req_tx, "foo", 3).await;
+        set_typed(&req_tx, "bar", 0).await;
+        assert_eq!(get_typed(&req_tx, "foo").await, Some(3), "from memtable");
+        assert_eq!(get_typed(&req_tx, "bar").await, Some(0));
+        assert_eq!(get_typed(&req_tx, "baz").await, None);
+
+        // Engine is aborted so that memtable is only recovered from the log.
+        handle.abort();
+        handle.await.ok();
+        let (req_tx, req_rx) = mpsc::channel(64);
+        let engine = Engine::new(req_rx, Config::default());
+        tokio::spawn(engine.run(stor, wal_stor));
+        assert_eq!(get_typed(&req_tx, "foo").await, Some(3), "from WAL");
+
+        flush(&req_tx).await;
+        set_typed(&req_tx, "baz", 255).await;
+        flush(&req_tx).await;
+        assert_eq!(get_typed(&req_tx, "foo").await, Some(3), "from a table");
+
+        let (resp_tx, resp_rx) = oneshot::channel();
+        req_tx
+            .send(Command::Compact { responder: resp_tx })
+            .await
+            .unwrap();
+        assert_eq!(resp_rx.await.unwrap().unwrap().tables_merged, 2);
+        assert_eq!(get_typed(&req_tx, "foo").await, Some(3), "after compaction");
+        assert_eq!(get_typed(&req_tx, "baz").await, Some(255));
+        assert_eq!(get_typed(&req_tx, "bar").await, Some(0));
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn test_transaction() {
+        async fn transaction(
+            req_tx: &mpsc::Sender<Command>,
+            ops: Vec<WriteOp>,
+        ) -> crate::Result<TransactionOutcome> {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            req_tx
+                .send(Command::Transaction {
+                    ops,
+                    responder: resp_tx,
+                })
+                .await
+                .unwrap();
+            resp_rx.await.unwrap()
+        }
+        let set = |key: &'static str, value: &'static str| WriteOp::Set {
+            key: Bytes::from(key),
+            value: Bytes::from(value),
+        };
+        let cas =
+            |key: &'static str, expected: Option<&'static str>, value: &'static str| WriteOp::Cas {
+                key: Bytes::from(key),
+                expected: expected.map(Bytes::from),
+                value: Bytes::from(value),
+            };
+        let del = |key: &'static str| WriteOp::Delete {
+            key: Bytes::from(key),
+        };
+
+        let stor = mem::new();
+        let wal_stor = mem::new_wal();
+        let (req_tx, req_rx) = mpsc::channel(64);
+        let engine = Engine::new(req_rx, Config::default());
+        let handle = tokio::spawn(engine.run(stor.clone(), wal_stor.clone()));
+
+        let outcome = transaction(&req_tx, vec![set("foo", "1"), cas("bar", None, "1")]).await;
+        assert!(matches!(
+            outcome.unwrap(),
+            TransactionOutcome::Committed { .. }
+        ));
+
+        // Preconditions of keys that are only on disk are read from there.
+        let (resp_tx, resp_rx) = oneshot::channel();
+        req_tx
+            .send(Command::Flush { responder: resp_tx })
+            .await
+            .unwrap();
+        resp_rx.await.unwrap().unwrap();
+        let outcome = transaction(
+            &req_tx,
+            vec![
+                cas("foo", Some("1"), "2"),
+                set("baz", "2"),
+                cas("bar", Some("2"), "2"),
+            ],
+        )
+        .await;
+        assert_eq!(outcome.unwrap(), TransactionOutcome::Aborted { op: 2 });
+        assert_eq!(get(&req_tx, "foo").await, Some(Bytes::from("1")));
+        assert_eq!(get(&req_tx, "bar").await, Some(Bytes::from("1")));
+        assert_eq!(get(&req_tx, "baz").await, None);
+
+        // A delete is not applied either when a precondition doesn't hold.
+        let outcome = transaction(&req_tx, vec![del("foo"), cas("bar", Some("2"), "2")]).await;
+        assert_eq!(outcome.unwrap(), TransactionOutcome::Aborted { op: 1 });
+        assert_eq!(get(&req_tx, "foo").await, Some(Bytes::from("1")));
+
+        let outcome = transaction(
+            &req_tx,
+            vec![
+                cas("foo", Some("1"), "2"),
+                set("baz", "2"),
+                cas("bar", Some("1"), "2"),
+            ],
+        )
+        .await;
+        assert!(matches!(
+            outcome.unwrap(),
+            TransactionOutcome::Committed { .. }
+        ));
+
+        async fn cas_command(
+            req_tx: &mpsc::Sender<Command>,
+            key: &'static str,
+            expected: Option<&'static str>,
+            new: &'static str,
+        ) -> bool {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            req_tx
+                .send(Command::Cas {
+                    key: Bytes::from(key),
+                    expected: expected.map(Bytes::from),
+                    new: Bytes::from(new),
+                    responder: resp_tx,
+                })
+                .await
+                .unwrap();
+            resp_rx.await.unwrap().unwrap()
+        }
+        // Current value is on disk.
+        let (resp_tx, resp_rx) = oneshot::channel();
+        req_tx
+            .send(Command::Flush { responder: resp_tx })
+            .await
+            .unwrap();
+        resp_rx.await.unwrap().unwrap();
+        assert!(!cas_command(&req_tx, "bar", Some("1"), "3").await);
+        assert!(!cas_command(&req_tx, "bar", None, "3").await);
+        assert!(cas_command(&req_tx, "qux", None, "1").await);
+        assert!(cas_command(&req_tx, "qux", Some("1"), "2").await);
+        assert_eq!(get(&req_tx, "qux").await, Some(Bytes::from("2")));
+        assert_eq!(get(&req_tx, "bar").await, Some(Bytes::from("2")));
+
+        let outcome = transaction(&req_tx, vec![set("qux", "1"), set("qux", "2")]).await;
+        assert!(
+            matches!(outcome, Err(crate::Error::InvalidInput(_))),
+            "key is written twice"
+        );
+
+        let outcome = transaction(&req_tx, vec![del("qux"), set("quux", "1")]).await;
+        assert!(matches!(
+            outcome.unwrap(),
+            TransactionOutcome::Committed { .. }
+        ));
+        assert_eq!(get(&req_tx, "qux").await, None);
+
+        // Engine is aborted so that the transaction is only recovered from the log.
+        handle.abort();
+        handle.await.ok();
+        let (req_tx, req_rx) = mpsc::channel(64);
+        let engine = Engine::new(req_rx, Config::default());
+        tokio::spawn(engine.run(stor, wal_stor));
+        for key in ["foo", "bar", "baz"] {
+            assert_eq!(get(&req_tx, key).await, Some(Bytes::from("2")), "{}", key);
+        }
+        assert_eq!(get(&req_tx, "qux").await, None);
+        assert_eq!(get(&req_tx, "quux").await, Some(Bytes::from("1")));
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn test_wal_size_stats() {
+        let (req_tx, req_rx) = mpsc::channel(64);
+        let engine = Engine::new(req_rx, Config::default());
+        tokio::spawn(engine.run(mem::new(), mem::new_wal()));
+
+        let wal_size = || async {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            req_tx
+                .send(Command::Stats { responder: resp_tx })
+                .await
+                .unwrap();
+            resp_rx.await.unwrap().unwrap().wal_size
+        };
+        let set_and_flush = |key: &'static str, flush: bool| {
+            let req_tx = req_tx.clone();
+            async move {
+                let (resp_tx, resp_rx) = oneshot::channel();
+                req_tx
+                    .send(Command::Set {
+                        key: Bytes::from(key),
+                        value: Bytes::from("bar"),
+                        request_id: None,
+                        responder: Some(resp_tx),
+                    })
+                    .await
+                    .unwrap();
+                resp_rx.await.unwrap().unwrap();
+
+                if flush {
+                    let (resp_tx, resp_rx) = oneshot::channel();
+                    req_tx
+                        .send(Command::Flush { responder: resp_tx })
+                        .await
+                        .unwrap();
+                    resp_rx.await.unwrap().unwrap();
+                }
+            }
+        };
+
+        assert_eq!(wal_size().await, 0);
+        set_and_flush("foo", false).await;
+        let one_entry = wal_size().await;
+        assert!(one_entry > 0);
+        set_and_flush("bar", true).await;
+        assert_eq!(
+            wal_size().await,
+            0,
+            "flushed generation is removed once its table is on disk"
+        );
+
+        set_and_flush("baz", false).await;
+        assert_eq!(wal_size().await, one_entry);
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn test_wal_bounded() {
+        let wal_stor = mem::new_wal();
+        let config = Config {
+            memtable_size: SsTableSize::Is(8 * 1024),
+            wal_segment_size: Some(4 * 1024),
+            ..Default::default()
+        };
+        let (req_tx, req_rx) = mpsc::channel(64);
+        let engine = Engine::new(req_rx, config);
+        tokio::spawn(engine.run(mem::new(), wal_stor.clone()));
+
+        let value = Bytes::from(vec![b'v'; 100]);
+        let mut largest = 0;
+        for i in 0..2000 {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            req_tx
+                .send(Command::Set {
+                    key: Bytes::from(format!("key-{:05}", i)),
+                    value: value.clone(),
+                    request_id: None,
+                    responder: Some(resp_tx),
+                })
+                .await
+                .unwrap();
+            resp_rx.await.unwrap().unwrap();
+            largest = largest.max(wal_stor.size().unwrap());
+        }
+
+        // Over 200KB is logged, generations of the tables on disk are removed as writes go.
+        assert!(largest < 48 * 1024, "log grew to {} bytes", largest);
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn test_sync() {
+        let wal_stor = mem::new_wal();
+        let stor = SyncCountingStorage {
+            inner: mem::new(),
+            syncs: Default::default(),
+        };
+        let (req_tx, req_rx) = mpsc::channel(64);
+        let engine = Engine::new(req_rx, Config::default());
+        let handle = tokio::spawn(engine.run(stor.clone(), wal_stor.clone()));
+
+        for key in ["foo", "bar"] {
+            req_tx
+                .send(Command::Set {
+                    key: Bytes::from(key),
+                    value: Bytes::from("baz"),
+                    request_id: None,
+                    responder: None,
+                })
+                .await
+                .unwrap();
+        }
+        let (resp_tx, resp_rx) = oneshot::channel();
+        req_tx
+            .send(Command::Sync { responder: resp_tx })
+            .await
+            .unwrap();
+        resp_rx.await.unwrap().unwrap();
+        assert_eq!(
+            stor.syncs.load(std::sync::atomic::Ordering::SeqCst),
+            1,
+            "tables storage is synced too"
+        );
+
+        let (resp_tx, resp_rx) = oneshot::channel();
+        req_tx
+            .send(Command::Set {
+                key: Bytes::from("qux"),
+                value: Bytes::from("baz"),
+                request_id: None,
+                responder: Some(resp_tx),
+            })
+            .await
+            .unwrap();
+        resp_rx.await.unwrap().unwrap();
+
+        // Machine goes down, memtable is never flushed.
+        handle.abort();
+        drop(req_tx);
+        wal_stor.crash();
+
+        let (req_tx, req_rx) = mpsc::channel(64);
+        let engine = Engine::new(req_rx, Config::default());
+        tokio::spawn(engine.run(mem::new(), wal_stor));
+
+        for (key, expected) in [("foo", true), ("bar", true), ("qux", false)] {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            req_tx
+                .send(Command::Get {
+                    key: Bytes::from(key),
+                    fill_cache: true,
+                    responder: resp_tx,
+                })
+                .await
+                .unwrap();
+            assert_eq!(
+                resp_rx.await.unwrap().unwrap().is_some(),
+                expected,
+                "key {} recovered",
+                key
+            );
+        }
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn test_wal_sync_interval() {
+        let wal_stor = mem::new_wal();
+        let config = Config {
+            wal_sync: crate::SyncPolicy::EveryMs(20),
+            ..Default::default()
+        };
+        let (req_tx, req_rx) = mpsc::channel(64);
+        let engine = Engine::new(req_rx, config);
+        let handle = tokio::spawn(engine.run(mem::new(), wal_stor.clone()));
+
+        let (resp_tx, resp_rx) = oneshot::channel();
+        req_tx
+            .send(Command::Set {
+                key: Bytes::from("foo"),
+                value: Bytes::from("bar"),
+                request_id: None,
+                responder: Some(resp_tx),
+            })
+            .await
+            .unwrap();
+        resp_rx.await.unwrap().unwrap();
+
+        // The log is put to disk in the background, no Sync is sent.
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        handle.abort();
+        drop(req_tx);
+        wal_stor.crash();
+
+        let (req_tx, req_rx) = mpsc::channel(64);
+        let engine = Engine::new(req_rx, Config::default());
+        tokio::spawn(engine.run(mem::new(), wal_stor));
+
+        let (resp_tx, resp_rx) = oneshot::channel();
+        req_tx
+            .send(Command::Get {
+                key: Bytes::from("foo"),
+                fill_cache: true,
+                responder: resp_tx,
+            })
+            .await
+            .unwrap();
+        assert_eq!(resp_rx.await.unwrap().unwrap(), Some(Bytes::from("bar")));
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn test_get_at() {
+        let (req_tx, req_rx) = mpsc::channel(64);
+        let engine = Engine::new(req_rx, Config::default());
+        tokio::spawn(engine.run(mem::new(), mem::new_wal()));
+
+        let key = Bytes::from("foo");
+        let set = |value: &'static str| {
+            let (req_tx, key) = (req_tx.clone(), key.clone());
+            async move {
+                let (resp_tx, resp_rx) = oneshot::channel();
+                req_tx
+                    .send(Command::Set {
+                        key,
+                        value: Bytes::from(value),
+                        request_id: None,
+                        responder: Some(resp_tx),
+                    })
+                    .await
+                    .unwrap();
+                resp_rx.await.unwrap().unwrap()
+            }
+        };
+        let flush = || async {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            req_tx
+                .send(Command::Flush { responder: resp_tx })
+                .await
+                .unwrap();
+            resp_rx.await.unwrap().unwrap();
+        };
+        let get_at = |seq| {
+            let (req_tx, key) = (req_tx.clone(), key.clone());
+            async move {
+                let (resp_tx, resp_rx) = oneshot::channel();
+                req_tx
+                    .send(Command::GetAt {
+                        key,
+                        seq,
+                        responder: resp_tx,
+                    })
+                    .await
+                    .unwrap();
+                resp_rx.await.unwrap().unwrap()
+            }
+        };
+
+        let first = set("1").await;
+        flush().await;
+        let second = set("2").await;
+        flush().await;
+        let third = set("3").await;
+        assert!(first < second && second < third);
+
+        assert_eq!(get_at(first - 1).await, None, "key did not exist yet");
+        assert_eq!(get_at(first).await, Some(Bytes::from("1")));
+        assert_eq!(get_at(second - 1).await, Some(Bytes::from("1")));
+        assert_eq!(get_at(second).await, Some(Bytes::from("2")));
+        assert_eq!(get_at(third).await, Some(Bytes::from("3")));
+        assert_eq!(get_at(u64::MAX).await, Some(Bytes::from("3")));
+    }
+
+    async fn count_range(req_tx: &mpsc::Sender<Command>, start: &str, end: &str) -> RangeCount {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        req_tx
+            .send(Command::CountRange {
+                start: Bytes::copy_from_slice(start.as_bytes()),
+                end: Bytes::copy_from_slice(end.as_bytes()),
+                responder: resp_tx,
+            })
+            .await
+            .unwrap();
+        resp_rx.await.unwrap().unwrap()
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn test_count_range() {
+        let stor = mem::new();
+        let (req_tx, req_rx) = mpsc::channel(64);
+        let engine = Engine::new(req_rx, Config::default());
+        let engine_stor = stor.clone();
+        tokio::spawn(async move {
+            engine.run(engine_stor, mem::new_wal()).await;
+        });
+
+        for str in &DATA[..3] {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            req_tx
+                .send(Command::Set {
+                    key: Bytes::from(*str),
+                    value: Bytes::from(*str),
+                    request_id: None,
+                    responder: Some(resp_tx),
+                })
+                .await
+                .unwrap();
+            resp_rx.await.unwrap().unwrap();
+        }
+
+        assert_eq!(
+            count_range(&req_tx, DATA[0], DATA[2]).await,
+            RangeCount {
+                count: 2,
+                exact: true
+            },
+            "memtable only"
+        );
+
+        for str in &DATA[3..] {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            req_tx
+                .send(Command::Set {
+                    key: Bytes::from(*str),
+                    value: Bytes::from(*str),
+                    request_id: None,
+                    responder: Some(resp_tx),
+                })
+                .await
+                .unwrap();
+            resp_rx.await.unwrap().unwrap();
+        }
+        assert!(!stor.list_entries().unwrap().is_empty());
+
+        // Fixtures are sorted and have no duplicates, so even an estimate has to match.
+        let ranges = [(0, DATA.len() - 1), (10, 200), (300, 330), (50, 51), (7, 7)];
+        for (start, end) in ranges {
+            let counted = count_range(&req_tx, DATA[start], DATA[end]).await;
+            assert_eq!(counted.count, (end - start) as u64);
+        }
+
+        assert_eq!(
+            count_range(&req_tx, DATA[10], DATA[5]).await,
+            RangeCount {
+                count: 0,
+                exact: true
+            },
+            "inverted range"
+        );
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn test_scan_keys() {
+        let stor = mem::new();
+        let (req_tx, req_rx) = mpsc::channel(64);
+        let engine = Engine::new(req_rx, Config::default());
+        tokio::spawn(engine.run(stor.clone(), mem::new_wal()));
+
+        // Some keys are written twice, so they are both on disk and in memtable.
+        for str in DATA.iter().chain(&DATA[100..120]) {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            req_tx
+                .send(Command::Set {
+                    key: Bytes::from(*str),
+                    value: Bytes::from(*str),
+                    request_id: None,
+                    responder: Some(resp_tx),
+                })
+                .await
+                .unwrap();
+            resp_rx.await.unwrap().unwrap();
+        }
+        assert!(!stor.list_entries().unwrap().is_empty());
+
+        let scan_keys = |start: usize, end: usize, limit: usize| {
+            let req_tx = req_tx.clone();
+            async move {
+                let (resp_tx, resp_rx) = oneshot::channel();
+                req_tx
+                    .send(Command::ScanKeys {
+                        start: Bytes::from(DATA[start]),
+                        end: Bytes::from(DATA[end]),
+                        limit,
+                        responder: resp_tx,
+                    })
+                    .await
+                    .unwrap();
+                resp_rx.await.unwrap().unwrap()
+            }
+        };
+
+        let ranges = [
+            (0, DATA.len() - 1, DATA.len()),
+            (90, 130, 100),
+            (90, 130, 15),
+            (300, 330, 5),
+            (7, 7, 10),
+            (10, 5, 10),
+        ];
+        for (start, end, limit) in ranges {
+            let expected: Vec<_> = DATA[start..end.max(start)]
+                .iter()
+                .take(limit)
+                .map(|str| Bytes::from(*str))
+                .collect();
+            assert_eq!(
+                scan_keys(start, end, limit).await,
+                expected,
+                "range {}..{} limited to {}",
+                start,
+                end,
+                limit
+            );
+        }
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn test_set_ex() {
+        async fn set_ex(
+            req_tx: &mpsc::Sender<Command>,
+            key: &'static str,
+            ttl: Duration,
+        ) -> crate::Result<u64> {
+            send(req_tx, |responder| Command::SetEx {
+                key: Bytes::from(key),
+                value: Bytes::from("new"),
+                ttl,
+                responder,
+            })
+            .await
+        }
+        async fn keys(req_tx: &mpsc::Sender<Command>) -> Vec<Bytes> {
+            send(req_tx, |responder| Command::ScanKeys {
+                start: Bytes::from("a"),
+                end: Bytes::from("z"),
+                limit: 10,
+                responder,
+            })
+            .await
+            .unwrap()
+        }
+
+        let clock = crate::ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        let config = Config {
+            clock: Arc::new(clock.clone()),
+            ..Default::default()
+        };
+        let (req_tx, req_rx) = mpsc::channel(64);
+        let engine = Engine::new(req_rx, config);
+        tokio::spawn(engine.run(mem::new(), mem::new_wal()));
+
+        send(&req_tx, |responder| Command::Set {
+            key: Bytes::from("a"),
+            value: Bytes::from("old"),
+            request_id: None,
+            responder: Some(responder),
+        })
+        .await
+        .unwrap();
+        send(&req_tx, |responder| Command::Flush { responder })
+            .await
+            .unwrap();
+
+        set_ex(&req_tx, "a", Duration::from_secs(10)).await.unwrap();
+        set_ex(&req_tx, "b", Duration::from_secs(20)).await.unwrap();
+        assert_eq!(get(&req_tx, "a").await, Some(Bytes::from("new")));
+        assert_eq!(keys(&req_tx).await, vec!["a", "b"]);
+
+        // Expired value in memtable hides the one on disk.
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(get(&req_tx, "a").await, None);
+        assert_eq!(get(&req_tx, "b").await, Some(Bytes::from("new")));
+        assert_eq!(keys(&req_tx).await, vec!["b"]);
+
+        // So does it once on disk.
+        send(&req_tx, |responder| Command::Flush { responder })
+            .await
+            .unwrap();
+        assert_eq!(get(&req_tx, "a").await, None);
+        assert_eq!(get(&req_tx, "b").await, Some(Bytes::from("new")));
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(get(&req_tx, "b").await, None);
+        assert!(keys(&req_tx).await.is_empty());
+
+        assert!(matches!(
+            set_ex(&req_tx, "c", Duration::ZERO).await,
+            Err(crate::Error::InvalidInput(_))
+        ));
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn test_delete() {
+        async fn set(req_tx: &mpsc::Sender<Command>, key: &'static str, value: &'static str) {
+            send(req_tx, |responder| Command::Set {
+                key: Bytes::from(key),
+                value: Bytes::from(value),
+                request_id: None,
+                responder: Some(responder),
+            })
+            .await
+            .unwrap();
+        }
+        async fn delete(req_tx: &mpsc::Sender<Command>, key: &'static str) {
+            send(req_tx, |responder| Command::Delete {
+                key: Bytes::from(key),
+                responder,
+            })
+            .await
+            .unwrap();
+        }
+        async fn scan(req_tx: &mpsc::Sender<Command>, limit: usize) -> Vec<Bytes> {
+            send(req_tx, |responder| Command::ScanKeys {
+                start: Bytes::from("a"),
+                end: Bytes::from("z"),
+                limit,
+                responder,
+            })
+            .await
+            .unwrap()
+        }
+        async fn scan_values(
+            req_tx: &mpsc::Sender<Command>,
+            start: &'static str,
+            end: &'static str,
+            limit: usize,
+        ) -> Vec<(Bytes, Bytes)> {
+            send(req_tx, |responder| Command::Scan {
+                start: Bytes::from(start),
+                end: Bytes::from(end),
+                limit,
+                responder,
+            })
+            .await
+            .unwrap()
+        }
+        let keys = |keys: &[&'static str]| -> Vec<Bytes> {
+            keys.iter().map(|key| Bytes::from(*key)).collect()
+        };
+        let pairs = |pairs: &[(&'static str, &'static str)]| -> Vec<(Bytes, Bytes)> {
+            pairs
+                .iter()
+                .map(|(key, value)| (Bytes::from(*key), Bytes::from(*value)))
+                .collect()
+        };
+
+        let stor = mem::new();
+        let wal_stor = mem::new_wal();
+        let (req_tx, req_rx) = mpsc::channel(64);
+        let engine = Engine::new(req_rx, Config::default());
+        let handle = tokio::spawn(engine.run(stor.clone(), wal_stor.clone()));
+
+        for key in ["a", "b", "c", "d"] {
+            set(&req_tx, key, "value").await;
+        }
+        send(&req_tx, |responder| Command::Flush { responder })
+            .await
+            .unwrap();
+
+        // Tombstones in memtable hide the values on disk.
+        delete(&req_tx, "a").await;
+        delete(&req_tx, "b").await;
+        assert_eq!(get(&req_tx, "a").await, None);
+        assert_eq!(scan(&req_tx, 1).await, keys(&["c"]));
+        assert_eq!(scan(&req_tx, 10).await, keys(&["c", "d"]));
+        assert_eq!(
+            scan_values(&req_tx, "a", "z", 1).await,
+            pairs(&[("c", "value")])
+        );
+
+        // And so do the ones flushed to a newer table.
+        send(&req_tx, |responder| Command::Flush { responder })
+            .await
+            .unwrap();
+        assert_eq!(get(&req_tx, "a").await, None);
+        assert_eq!(get(&req_tx, "c").await, Some(Bytes::from("value")));
+        assert_eq!(scan(&req_tx, 1).await, keys(&["c"]));
+
+        set(&req_tx, "b", "again").await;
+        send(&req_tx, |responder| Command::Flush { responder })
+            .await
+            .unwrap();
+        let report = send(&req_tx, |responder| Command::Compact { responder })
+            .await
+            .unwrap();
+        assert!(report.tables_merged > 1);
+        assert_eq!(get(&req_tx, "a").await, None, "after compaction");
+        assert_eq!(get(&req_tx, "b").await, Some(Bytes::from("again")));
+        assert_eq!(scan(&req_tx, 10).await, keys(&["b", "c", "d"]));
+        set(&req_tx, "c", "newer").await;
+        assert_eq!(
+            scan_values(&req_tx, "a", "d", 10).await,
+            pairs(&[("b", "again"), ("c", "newer")]),
+            "newest value wins"
+        );
+        assert!(scan_values(&req_tx, "c", "c", 10).await.is_empty());
+        assert!(scan_values(&req_tx, "d", "a", 10).await.is_empty());
+
+        // Engine is aborted so that the delete is only recovered from the log.
+        delete(&req_tx, "c").await;
+        handle.abort();
+        handle.await.ok();
+        let (req_tx, req_rx) = mpsc::channel(64);
+        let engine = Engine::new(req_rx, Config::default());
+        tokio::spawn(engine.run(stor, wal_stor));
+        assert_eq!(get(&req_tx, "c").await, None, "from WAL");
+        assert_eq!(scan(&req_tx, 10).await, keys(&["b", "d"]));
+    }
+
+    #[tokio::test]
+    async fn test_set_batch() {
+        async fn set_batch(
+            req_tx: &mpsc::Sender<Command>,
+            pairs: Vec<(Bytes, Bytes)>,
+        ) -> crate::Result<u64> {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            req_tx
+                .send(Command::SetBatch {
+                    pairs,
+                    responder: resp_tx,
+                })
+                .await
+                .unwrap();
+            resp_rx.await.unwrap()
+        }
+
+        let stor = mem::new();
+        let config = Config {
+            memtable_size: SsTableSize::Is(8 * 1024),
+            ..Default::default()
+        };
+        let (req_tx, req_rx) = mpsc::channel(64);
+        let engine = Engine::new(req_rx, config);
+        tokio::spawn(engine.run(stor.clone(), mem::new_wal()));
+
+        // Takes more than a memtable, which is swapped in the middle of the batch.
+        let pairs: Vec<(Bytes, Bytes)> = (0..100)
+            .map(|i| {
+                let key = Bytes::from(format!("key-{:03}", i));
+                (key, Bytes::from(vec![b'v'; 100]))
+            })
+            .collect();
+        let seq = set_batch(&req_tx, pairs.clone()).await.unwrap();
+        assert!(seq > 0);
+        for (key, value) in pairs {
+            assert_eq!(get(&req_tx, key).await, Some(value));
+        }
+        assert!(!stor.list_entries().unwrap().is_empty());
+
+        let res = set_batch(
+            &req_tx,
+            vec![
+                (Bytes::from("foo"), Bytes::from("bar")),
+                (Bytes::from("baz"), Bytes::new()),
+            ],
+        )
+        .await;
+        match res {
+            Err(crate::Error::InvalidInput(msg)) => assert_eq!(msg, "pair 1: value is empty"),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        assert_eq!(
+            get(&req_tx, Bytes::from("foo")).await,
+            None,
+            "nothing is written if a pair is invalid"
         );
+        assert!(set_batch(&req_tx, vec![]).await.is_err());
     }
 
     #[traced_test]
@@ -617,9 +3608,9 @@ mod tests {
         // Initialize engine.
         let stor = mem::new();
         let (req_tx, req_rx) = mpsc::channel(64);
-        let engine = Engine::new(req_rx);
+        let engine = Engine::new(req_rx, Config::default());
         tokio::spawn(async move {
-            engine.run(stor).await;
+            engine.run(stor, mem::new_wal()).await;
             tracing::error!("engine exited");
         });
 
@@ -636,6 +3627,7 @@ mod tests {
                 .send(Command::Set {
                     key,
                     value,
+                    request_id: None,
                     responder: None
                 })
                 .await
@@ -649,6 +3641,7 @@ mod tests {
 
             let cmd = Command::Get {
                 key: entry.0.clone(),
+                fill_cache: true,
                 responder: resp_tx,
             };
 
@@ -680,6 +3673,689 @@ mod tests {
         );
     }
 
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_compaction_keeps_keys_readable() {
+        let stor = mem::new();
+        let (req_tx, req_rx) = mpsc::channel(64);
+        let engine = Engine::new(req_rx, Config::default());
+        let engine_stor = stor.clone();
+        tokio::spawn(async move {
+            engine.run(engine_stor, mem::new_wal()).await;
+        });
+
+        let mut entries: Vec<(Bytes, Bytes)> = vec![];
+        let mut seen = std::collections::HashSet::new();
+        while entries.len() < 2000 {
+            let (key, value) = (generate_valid_key(), generate_valid_value());
+            // Short random keys repeat, an overwritten key would be counted as a missed read.
+            if !seen.insert(key.clone()) {
+                continue;
+            }
+            entries.push((key.clone(), value.clone()));
+
+            req_tx
+                .send(Command::Set {
+                    key,
+                    value,
+                    request_id: None,
+                    responder: None,
+                })
+                .await
+                .unwrap();
+        }
+
+        // Commands are handled in order, so once this read is answered all the tables are on disk.
+        let (resp_tx, resp_rx) = oneshot::channel();
+        req_tx
+            .send(Command::Get {
+                key: entries[0].0.clone(),
+                fill_cache: true,
+                responder: resp_tx,
+            })
+            .await
+            .unwrap();
+        resp_rx.await.unwrap().unwrap();
+
+        let tables_before = stor.list_entries().unwrap().len();
+        assert!(tables_before >= 2, "expected at least two tables on disk");
+
+        // Keep reading all the keys while compaction is in progress.
+        let stop = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let reader = {
+            let (req_tx, entries, stop) = (req_tx.clone(), entries.clone(), stop.clone());
+            tokio::spawn(async move {
+                let mut misses = 0;
+                while !stop.load(std::sync::atomic::Ordering::Relaxed) {
+                    for (key, value) in entries.iter() {
+                        let (resp_tx, resp_rx) = oneshot::channel();
+                        req_tx
+                            .send(Command::Get {
+                                key: key.clone(),
+                                fill_cache: true,
+                                responder: resp_tx,
+                            })
+                            .await
+                            .unwrap();
+
+                        if resp_rx.await.unwrap().unwrap().as_ref() != Some(value) {
+                            misses += 1;
+                        }
+                    }
+                }
+                misses
+            })
+        };
+
+        let (resp_tx, resp_rx) = oneshot::channel();
+        req_tx
+            .send(Command::Compact { responder: resp_tx })
+            .await
+            .unwrap();
+        let compacted = resp_rx.await.unwrap();
+        assert!(compacted.is_ok(), "compaction err: {:?}", compacted);
+
+        stop.store(true, std::sync::atomic::Ordering::Relaxed);
+        assert_eq!(
+            reader.await.unwrap(),
+            0,
+            "some reads missed during compaction"
+        );
+
+        let report = compacted.unwrap();
+        assert_eq!(
+            report.tables_merged, tables_before,
+            "all of level 0 goes down"
+        );
+        assert_eq!(
+            stor.list_entries().unwrap().len(),
+            tables_before - report.tables_merged + report.tables_created,
+            "merged tables should be replaced with the created ones"
+        );
+    }
+
+    /// Memory storage that takes its time to persist a table, without holding the runtime.
+    #[derive(Clone)]
+    struct SlowStorage {
+        inner: mem::MemStorage,
+        write_delay: std::time::Duration,
+    }
+
+    impl crate::AsyncStorage for SlowStorage {
+        type Entry = Vec<u8>;
+
+        async fn bootstrap(&self) -> std::io::Result<()> {
+            Storage::bootstrap(&self.inner)
+        }
+
+        async fn list_entries(&self) -> std::io::Result<Vec<uuid::Uuid>> {
+            Storage::list_entries(&self.inner)
+        }
+
+        async fn write(&self, table_id: &uuid::Uuid, data: &[u8]) -> std::io::Result<()> {
+            tokio::time::sleep(self.write_delay).await;
+            Storage::write(&self.inner, table_id, data)
+        }
+
+        async fn open(&self, table_id: &uuid::Uuid) -> std::io::Result<Self::Entry> {
+            Storage::open(&self.inner, table_id)
+        }
+
+        async fn delete(&self, table_id: &uuid::Uuid) -> std::io::Result<()> {
+            Storage::delete(&self.inner, table_id)
+        }
+    }
+
+    /// Memory storage that takes its time to open a table, so reads from disk stay in flight.
+    #[derive(Clone)]
+    struct SlowReadStorage {
+        inner: mem::MemStorage,
+        open_delay: std::time::Duration,
+    }
+
+    impl crate::AsyncStorage for SlowReadStorage {
+        type Entry = Vec<u8>;
+
+        async fn bootstrap(&self) -> std::io::Result<()> {
+            Storage::bootstrap(&self.inner)
+        }
+
+        async fn list_entries(&self) -> std::io::Result<Vec<uuid::Uuid>> {
+            Storage::list_entries(&self.inner)
+        }
+
+        async fn write(&self, table_id: &uuid::Uuid, data: &[u8]) -> std::io::Result<()> {
+            Storage::write(&self.inner, table_id, data)
+        }
+
+        async fn open(&self, table_id: &uuid::Uuid) -> std::io::Result<Self::Entry> {
+            tokio::time::sleep(self.open_delay).await;
+            Storage::open(&self.inner, table_id)
+        }
+
+        async fn delete(&self, table_id: &uuid::Uuid) -> std::io::Result<()> {
+            Storage::delete(&self.inner, table_id)
+        }
+    }
+
+    /// Memory storage that counts how many times it was synced.
+    #[derive(Clone)]
+    struct SyncCountingStorage {
+        inner: mem::MemStorage,
+        syncs: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl Storage for SyncCountingStorage {
+        type Entry = Vec<u8>;
+
+        fn bootstrap(&self) -> std::io::Result<()> {
+            self.inner.bootstrap()
+        }
+
+        fn list_entries(&self) -> std::io::Result<Vec<uuid::Uuid>> {
+            self.inner.list_entries()
+        }
+
+        fn write(&self, table_id: &uuid::Uuid, data: &[u8]) -> std::io::Result<()> {
+            self.inner.write(table_id, data)
+        }
+
+        fn open(&self, table_id: &uuid::Uuid) -> std::io::Result<Self::Entry> {
+            self.inner.open(table_id)
+        }
+
+        fn delete(&self, table_id: &uuid::Uuid) -> std::io::Result<()> {
+            self.inner.delete(table_id)
+        }
+
+        fn sync(&self) -> std::io::Result<()> {
+            self.syncs.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    /// Log storage that fails to append or runs out of space once told to.
+    #[derive(Clone)]
+    struct FailingWalStorage {
+        inner: mem::MemWalStorage,
+        failing: std::sync::Arc<std::sync::atomic::AtomicBool>,
+        available_space: std::sync::Arc<std::sync::atomic::AtomicU64>,
+    }
+
+    impl FailingWalStorage {
+        fn new() -> Self {
+            FailingWalStorage {
+                inner: mem::new_wal(),
+                failing: Default::default(),
+                available_space: std::sync::Arc::new(u64::MAX.into()),
+            }
+        }
+    }
+
+    impl WalStorage for FailingWalStorage {
+        fn init(&mut self) -> std::io::Result<()> {
+            self.inner.init()
+        }
+
+        fn persisted_data(&self) -> std::io::Result<Vec<Vec<u8>>> {
+            self.inner.persisted_data()
+        }
+
+        fn append(&mut self, data: &[u8]) -> std::io::Result<()> {
+            if self.failing.load(std::sync::atomic::Ordering::SeqCst) {
+                return Err(std::io::Error::other("disk is gone"));
+            }
+            self.inner.append(data)
+        }
+
+        fn rotate(&mut self) -> std::io::Result<()> {
+            self.inner.rotate()
+        }
+
+        fn discard_through(&mut self, generation: u64) -> std::io::Result<()> {
+            self.inner.discard_through(generation)
+        }
+
+        fn sync(&mut self) -> std::io::Result<()> {
+            self.inner.sync()
+        }
+
+        fn available_space(&self) -> std::io::Result<u64> {
+            Ok(self
+                .available_space
+                .load(std::sync::atomic::Ordering::SeqCst))
+        }
+
+        fn size(&self) -> std::io::Result<u64> {
+            self.inner.size()
+        }
+
+        fn generation(&self) -> u64 {
+            self.inner.generation()
+        }
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn test_set_wal_failure() {
+        let wal_stor = FailingWalStorage::new();
+        let failing = wal_stor.failing.clone();
+        let stor = mem::new();
+        let config = Config {
+            memtable_size: SsTableSize::Is(sstable::block::BLOCK_BYTE_SIZE),
+            ..Default::default()
+        };
+        let (req_tx, req_rx) = mpsc::channel(64);
+        let engine = Engine::new(req_rx, config);
+        tokio::spawn(engine.run(stor.clone(), wal_stor));
+
+        // A single value fills memtable up, so the second set swaps it and the third one goes
+        // to the fresh memtable.
+        let value = Bytes::from(vec![b'v'; MAX_VALUE_SIZE as usize]);
+        for (key, fail) in [("foo", false), ("bar", true), ("baz", true)] {
+            failing.store(fail, std::sync::atomic::Ordering::SeqCst);
+
+            let (resp_tx, resp_rx) = oneshot::channel();
+            req_tx
+                .send(Command::Set {
+                    key: Bytes::from(key),
+                    value: value.clone(),
+                    request_id: None,
+                    responder: Some(resp_tx),
+                })
+                .await
+                .unwrap();
+            let resp = tokio::time::timeout(std::time::Duration::from_secs(1), resp_rx)
+                .await
+                .expect("set should be responded to")
+                .expect("responder should not be dropped");
+            assert_eq!(resp.is_err(), fail, "set of {}", key);
+            if let Err(err) = resp {
+                assert!(matches!(err, crate::Error::Storage(_)), "{:?}", err);
+            }
+        }
+
+        assert_eq!(
+            stor.list_entries().unwrap().len(),
+            1,
+            "memtable should be swapped"
+        );
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn test_no_wal() {
+        let wal_stor = mem::new_wal();
+        write_fixtures_to_wal(wal_stor.clone()).await;
+        let logged = wal_stor.persisted_data().unwrap();
+
+        let config = Config {
+            memtable_size: SsTableSize::Is(sstable::block::BLOCK_BYTE_SIZE),
+            unsafe_no_wal: true,
+            ..Default::default()
+        };
+        let (req_tx, req_rx) = mpsc::channel(64);
+        let engine = Engine::new(req_rx, config);
+        tokio::spawn(engine.run(mem::new(), wal_stor.clone()));
+
+        let get = |key: &'static str| {
+            let req_tx = req_tx.clone();
+            async move {
+                let (resp_tx, resp_rx) = oneshot::channel();
+                req_tx
+                    .send(Command::Get {
+                        key: Bytes::from(key),
+                        fill_cache: true,
+                        responder: resp_tx,
+                    })
+                    .await
+                    .unwrap();
+                resp_rx.await.unwrap().unwrap()
+            }
+        };
+        assert_eq!(get(DATA[0]).await, None, "nothing is recovered");
+
+        // Values are large enough for memtable to be swapped a few times.
+        let value = Bytes::from(vec![b'v'; MAX_VALUE_SIZE as usize]);
+        let keys = ["foo", "bar", "baz", "qux"];
+        for key in keys {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            req_tx
+                .send(Command::Set {
+                    key: Bytes::from(key),
+                    value: value.clone(),
+                    request_id: None,
+                    responder: Some(resp_tx),
+                })
+                .await
+                .unwrap();
+            resp_rx.await.unwrap().unwrap();
+        }
+        for key in keys {
+            assert_eq!(get(key).await, Some(value.clone()), "key {}", key);
+        }
+
+        let (resp_tx, resp_rx) = oneshot::channel();
+        req_tx
+            .send(Command::Sync { responder: resp_tx })
+            .await
+            .unwrap();
+        resp_rx.await.unwrap().unwrap();
+
+        assert_eq!(
+            wal_stor.persisted_data().unwrap(),
+            logged,
+            "log is not touched"
+        );
+    }
+
+    #[traced_test]
+    #[tokio::test]
+    async fn test_no_space() {
+        let wal_stor = FailingWalStorage::new();
+        let available_space = wal_stor.available_space.clone();
+        let (req_tx, req_rx) = mpsc::channel(64);
+        let engine = Engine::new(req_rx, Config::default());
+        tokio::spawn(engine.run(mem::new(), wal_stor));
+
+        let set = |key: &'static str| {
+            let req_tx = req_tx.clone();
+            async move {
+                let (resp_tx, resp_rx) = oneshot::channel();
+                req_tx
+                    .send(Command::Set {
+                        key: Bytes::from(key),
+                        value: Bytes::from("bar"),
+                        request_id: None,
+                        responder: Some(resp_tx),
+                    })
+                    .await
+                    .unwrap();
+                resp_rx.await.unwrap()
+            }
+        };
+
+        set("foo").await.unwrap();
+
+        available_space.store(0, std::sync::atomic::Ordering::SeqCst);
+        // Space is checked once a second while there is enough of it.
+        tokio::time::sleep(SPACE_CHECK_INTERVAL).await;
+        for key in ["bar", "baz"] {
+            let err = set(key).await.expect_err("write should be refused");
+            assert!(err.to_string().contains("no space left"), "{}", err);
+            assert!(err.is_storage_full(), "{:?}", err);
+        }
+
+        let (resp_tx, resp_rx) = oneshot::channel();
+        req_tx
+            .send(Command::Get {
+                key: Bytes::from("foo"),
+                fill_cache: true,
+                responder: resp_tx,
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            resp_rx.await.unwrap().unwrap(),
+            Some(Bytes::from("bar")),
+            "reads are still served"
+        );
+
+        available_space.store(u64::MAX, std::sync::atomic::Ordering::SeqCst);
+        set("bar")
+            .await
+            .expect("write should be taken once space is freed");
+    }
+
+    /// Writes a bit more than two tables worth of data one by one and returns the longest time
+    /// a single set took.
+    async fn max_set_latency(config: Config) -> std::time::Duration {
+        let stor = SlowStorage {
+            inner: mem::new(),
+            write_delay: std::time::Duration::from_millis(300),
+        };
+        let (req_tx, req_rx) = mpsc::channel(64);
+        let engine = Engine::new(req_rx, config);
+        let handle = tokio::spawn(async move {
+            engine.run(stor, mem::new_wal()).await;
+        });
+
+        let value = Bytes::from(vec![b'v'; 100]);
+        let entry_size =
+            sstable::block::entry_size(&Bytes::from("key-00000"), &Value::Live(value.clone()));
+        let entries_cnt = memtable::SSTABLE_BYTESIZE / entry_size * 22 / 10;
+
+        let mut max_latency = std::time::Duration::ZERO;
+        for i in 0..entries_cnt {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            let started = std::time::Instant::now();
+
+            req_tx
+                .send(Command::Set {
+                    key: Bytes::from(format!("key-{:05}", i)),
+                    value: value.clone(),
+                    request_id: None,
+                    responder: Some(resp_tx),
+                })
+                .await
+                .unwrap();
+            resp_rx.await.unwrap().unwrap();
+
+            max_latency = std::cmp::max(max_latency, started.elapsed());
+        }
+
+        // Let the engine flush what's left, so it doesn't hold up the next run.
+        drop(req_tx);
+        handle.await.unwrap();
+
+        max_latency
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_early_flush_latency() {
+        let without_watermark = max_set_latency(Config::default()).await;
+        let with_watermark = max_set_latency(Config {
+            flush_watermark: Some(80),
+            ..Default::default()
+        })
+        .await;
+
+        assert!(
+            without_watermark >= std::time::Duration::from_millis(150),
+            "set without early flush was expected to stall on dispatcher, max latency {:?}",
+            without_watermark
+        );
+        assert!(
+            with_watermark < without_watermark / 2,
+            "early flush max latency {:?} should be lower than {:?}",
+            with_watermark,
+            without_watermark
+        );
+    }
+
+    /// Writers set their own keys and read them right back, along with some they wrote earlier,
+    /// while memtables are swapped, persisted and compacted underneath.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_read_your_writes() {
+        for immutable_memtables in [0, 2] {
+            let stor = SlowStorage {
+                inner: mem::new(),
+                write_delay: std::time::Duration::from_millis(5),
+            };
+            let config = Config {
+                memtable_size: SsTableSize::Is(4 * sstable::block::BLOCK_BYTE_SIZE),
+                immutable_memtables,
+                dispatcher_buffer_min: 1,
+                dispatcher_buffer_max: 2,
+                ..Default::default()
+            };
+            let (req_tx, req_rx) = mpsc::channel(64);
+            let engine = Engine::new(req_rx, config);
+            let handle = tokio::spawn(engine.run(stor.clone(), mem::new_wal()));
+
+            let compactor = {
+                let req_tx = req_tx.clone();
+                tokio::spawn(async move {
+                    loop {
+                        let (resp_tx, resp_rx) = oneshot::channel();
+                        if req_tx
+                            .send(Command::Compact { responder: resp_tx })
+                            .await
+                            .is_err()
+                        {
+                            return;
+                        }
+                        resp_rx.await.ok();
+                        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                    }
+                })
+            };
+
+            let mut writers = Vec::new();
+            for writer in 0..4 {
+                let req_tx = req_tx.clone();
+                writers.push(tokio::spawn(async move {
+                    let get = |key: Bytes| {
+                        let req_tx = req_tx.clone();
+                        async move {
+                            let (resp_tx, resp_rx) = oneshot::channel();
+                            req_tx
+                                .send(Command::Get {
+                                    key,
+                                    fill_cache: true,
+                                    responder: resp_tx,
+                                })
+                                .await
+                                .unwrap();
+                            resp_rx.await.unwrap().unwrap()
+                        }
+                    };
+
+                    let mut rng = rand::rngs::StdRng::seed_from_u64(writer);
+                    let mut latest = Vec::new();
+                    for i in 0..300 {
+                        // Every key is written twice, the second time over a flushed value.
+                        let key_num = i % 150;
+                        let key = Bytes::from(format!("writer-{}-key-{:04}", writer, key_num));
+                        let value = Bytes::from(format!("{}-{}", "v".repeat(200), i));
+
+                        let (resp_tx, resp_rx) = oneshot::channel();
+                        req_tx
+                            .send(Command::Set {
+                                key: key.clone(),
+                                value: value.clone(),
+                                request_id: None,
+                                responder: Some(resp_tx),
+                            })
+                            .await
+                            .unwrap();
+                        resp_rx.await.unwrap().unwrap();
+                        match latest.get_mut(key_num) {
+                            Some(latest) => *latest = value.clone(),
+                            None => latest.push(value.clone()),
+                        }
+
+                        assert_eq!(get(key.clone()).await, Some(value), "key {:?}", key);
+
+                        let earlier = rng.gen_range(0..latest.len());
+                        let key = Bytes::from(format!("writer-{}-key-{:04}", writer, earlier));
+                        assert_eq!(
+                            get(key.clone()).await.as_ref(),
+                            Some(&latest[earlier]),
+                            "key {:?}",
+                            key
+                        );
+                    }
+                }));
+            }
+
+            for writer in writers {
+                writer.await.unwrap();
+            }
+            let (resp_tx, resp_rx) = oneshot::channel();
+            req_tx
+                .send(Command::Position { responder: resp_tx })
+                .await
+                .unwrap();
+            let position = resp_rx.await.unwrap().unwrap();
+            assert!(
+                position.wal_generation > 5,
+                "memtable is swapped along the way"
+            );
+
+            compactor.abort();
+            drop(req_tx);
+            handle.await.unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_immutable_memtables() {
+        let write_delay = std::time::Duration::from_millis(500);
+        let stor = SlowStorage {
+            inner: mem::new(),
+            write_delay,
+        };
+        let (req_tx, req_rx) = mpsc::channel(64);
+        let engine = Engine::new(
+            req_rx,
+            Config {
+                immutable_memtables: 3,
+                ..Default::default()
+            },
+        );
+        let handle = tokio::spawn(engine.run(stor.clone(), mem::new_wal()));
+
+        let value = Bytes::from(vec![b'v'; 100]);
+        let entry_size =
+            sstable::block::entry_size(&Bytes::from("key-00000"), &Value::Live(value.clone()));
+        let entries_cnt = memtable::SSTABLE_BYTESIZE / entry_size * 32 / 10;
+
+        // Dispatcher is stuck on the first table all along, writes don't wait for it.
+        let started = std::time::Instant::now();
+        for i in 0..entries_cnt {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            req_tx
+                .send(Command::Set {
+                    key: Bytes::from(format!("key-{:05}", i)),
+                    value: value.clone(),
+                    request_id: None,
+                    responder: Some(resp_tx),
+                })
+                .await
+                .unwrap();
+            resp_rx.await.unwrap().unwrap();
+        }
+        assert!(
+            started.elapsed() < write_delay,
+            "writes stalled on dispatcher for {:?}",
+            started.elapsed()
+        );
+
+        // So are reads of all the keys, every one of them is still in memory.
+        for i in 0..entries_cnt {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            req_tx
+                .send(Command::Get {
+                    key: Bytes::from(format!("key-{:05}", i)),
+                    fill_cache: true,
+                    responder: resp_tx,
+                })
+                .await
+                .unwrap();
+            assert_eq!(resp_rx.await.unwrap().unwrap(), Some(value.clone()));
+        }
+        assert!(
+            started.elapsed() < write_delay,
+            "reads waited for dispatcher for {:?}",
+            started.elapsed()
+        );
+        assert!(stor.inner.list_entries().unwrap().is_empty());
+
+        drop(req_tx);
+        handle.await.unwrap();
+        assert_eq!(stor.inner.list_entries().unwrap().len(), 4);
+    }
+
     fn generate_valid_key() -> Bytes {
         let mut rng = thread_rng();
         let length = rng.gen_range(1..=MAX_KEY_SIZE);
@@ -694,27 +4370,80 @@ mod tests {
         Bytes::from(random_bytes)
     }
 
+    #[test]
+    fn test_prefix_end() {
+        assert_eq!(prefix_end(b""), None);
+        assert_eq!(prefix_end(&[0xFF]), None);
+        assert_eq!(prefix_end(&[0xFF, 0xFF]), None);
+        assert_eq!(prefix_end(&[0x01, 0xFF]), Some(Bytes::from_static(&[0x02])));
+        assert_eq!(prefix_end(b"foo"), Some(Bytes::from("fop")));
+        assert_eq!(
+            prefix_end(&[0x01, 0xFE, 0xFF]),
+            Some(Bytes::from_static(&[0x01, 0xFF]))
+        );
+    }
+
     #[test]
     fn test_validate() {
         let long_arr: &'static [u8; 513] = &[0; 513];
         let longer_arr: &'static [u8; 2049] = &[0; 2049];
         let long_key = Bytes::from_static(long_arr);
         let long_value = Bytes::from_static(longer_arr);
+        let config = Config::default();
 
-        let res = validate(&long_key, &Bytes::from("asdf"));
+        let res = validate(&long_key, &Bytes::from("asdf"), &config);
         assert!(res.is_err());
         assert_eq!(res.err().unwrap().to_string(), "key is too long");
 
-        let res = validate(&Bytes::from("asdf"), &long_value);
+        let res = validate(&Bytes::from("asdf"), &long_value, &config);
         assert!(res.is_err());
         assert_eq!(res.err().unwrap().to_string(), "value is too long");
 
-        let res = validate(&Bytes::default(), &Bytes::from("asdf"));
+        let res = validate(&Bytes::default(), &Bytes::from("asdf"), &config);
         assert!(res.is_err());
         assert_eq!(res.err().unwrap().to_string(), "key is empty");
 
-        let res = validate(&Bytes::from("asdf"), &Bytes::default());
+        let res = validate(&Bytes::from("asdf"), &Bytes::default(), &config);
         assert!(res.is_err());
         assert_eq!(res.err().unwrap().to_string(), "value is empty");
+
+        assert!(matches!(
+            validate(&long_key, &long_value, &config),
+            Err(crate::Error::InvalidInput(_))
+        ));
+
+        let config = Config {
+            max_key_size: 4,
+            max_value_size: 8,
+            ..Default::default()
+        };
+        assert!(validate(&Bytes::from("asdf"), &Bytes::from("12345678"), &config).is_ok());
+        let res = validate(&Bytes::from("asdfg"), &Bytes::from("1"), &config);
+        assert_eq!(res.err().unwrap().to_string(), "key is too long");
+        let res = validate(&Bytes::from("asdf"), &Bytes::from("123456789"), &config);
+        assert_eq!(res.err().unwrap().to_string(), "value is too long");
+
+        assert!(Config::default().validate().is_ok());
+        for (depth, width) in [(0, 4096), (4, 0)] {
+            let config = Config {
+                cache_sketch: crate::SketchSize { depth, width },
+                ..Default::default()
+            };
+            assert_eq!(
+                config.validate().unwrap_err().to_string(),
+                "cache_sketch depth and width must be positive"
+            );
+        }
+
+        for percent in [0, 101] {
+            let config = Config {
+                flush_watermark: Some(percent),
+                ..Default::default()
+            };
+            assert_eq!(
+                config.validate().unwrap_err().to_string(),
+                "flush_watermark must be from 1 to 100"
+            );
+        }
     }
 }
@@ -1,3 +1,5 @@
+use crate::engine::memtable::Value;
+use crate::Result;
 use bytes::{Buf, BufMut, Bytes};
 use std::io::Cursor;
 
@@ -10,27 +12,62 @@ Block layout schema.
 ------------------------------------------------------------------------------------------------------------------
 
 Single entry layout schema.
------------------------------------------------------
-|                  Entry #1                   | ... |
------------------------------------------------------
-| key_len (2B) | key | value_len (2B) | value | ... |
------------------------------------------------------
+--------------------------------------------------------------------------------------------------------------
+|                                               Entry #1                                               | ... |
+--------------------------------------------------------------------------------------------------------------
+| key_len (2B) | key | kind (1B) | [expires_at (8B)] | value_len (2B) | value | seq (8B) | flags (1B) | ... |
+--------------------------------------------------------------------------------------------------------------
+
+Kind tells a value (KIND_LIVE) from a tombstone (KIND_TOMBSTONE) left by a delete, a tombstone
+has an empty value. It comes right after the key, so that scans tell deleted keys without reading
+values. Seq is the sequence number of the write that put the value, see Engine for details. Flags
+is the content type byte client set the value with, zero for values set without one.
+
+An expiring value (KIND_EXPIRING) is the only one followed by expires_at, the unix time in
+milliseconds it expires at. Other entries don't have the field at all.
+
+A block may be compressed with LZ4 (see Config::compression), it is then only as long as it takes
+and the table index keeps its len. The top bit of the num of offsets (BLOCK_COMPRESSED) marks it,
+offsets and data sections are compressed together. Content len is the len they take uncompressed.
+A block is only compressed if it takes less than an uncompressed one.
+-----------------------------------------------------------------------------------------------
+| Num of offsets (2B) | Content len (2B) | Compressed offsets and data | Block Checksum (4B) |
+-----------------------------------------------------------------------------------------------
 */
 
-/// A block will be always exactly this size for the sake of easy time reading it from disk.
+/// An uncompressed block will be always exactly this size, a compressed one is shorter.
 pub const BLOCK_BYTE_SIZE: usize = 4 * 1024; // 4 KB.
 
+/// Set in the num of offsets of a compressed block.
+const BLOCK_COMPRESSED: u16 = 1 << 15;
+
 /// 2B key/value len hint.
 const U16_SIZE: u32 = std::mem::size_of::<u16>() as u32; // 2.
 
+/// Sequence number of an entry.
+const SEQ_SIZE: u32 = std::mem::size_of::<u64>() as u32; // 8.
+
+/// Content type of an entry value.
+const FLAGS_SIZE: u32 = std::mem::size_of::<u8>() as u32; // 1.
+
+/// Whether an entry holds a value or a tombstone.
+const KIND_SIZE: u32 = std::mem::size_of::<u8>() as u32; // 1.
+const KIND_LIVE: u8 = 0;
+const KIND_TOMBSTONE: u8 = 1;
+const KIND_EXPIRING: u8 = 2;
+
+/// Expiration time of an expiring entry.
+pub const EXPIRES_AT_SIZE: u32 = std::mem::size_of::<u64>() as u32; // 8.
+
 const CHECKSUM_SIZE: usize = std::mem::size_of::<u32>(); // 4.
 
 /// The size of an empty block. Reserved for offsets count and checksum.
 const INITIAL_BLOCK_SIZE: u32 = U16_SIZE + CHECKSUM_SIZE as u32;
 
 /// An overhead that a single k/v pair adds to the block.
-/// Includes key len flag, value len flag, and a spot in the offsets section.
-pub const ENTRY_OVERHEAD: u32 = U16_SIZE * 3;
+/// Includes key len flag, kind, value len flag, sequence number, flags and a spot in the offsets
+/// section.
+pub const ENTRY_OVERHEAD: u32 = U16_SIZE * 3 + KIND_SIZE + SEQ_SIZE + FLAGS_SIZE;
 
 #[derive(Debug)]
 pub struct Block {
@@ -54,7 +91,7 @@ impl Block {
 
     /// Adds a key/value pair to block and returns true.
     /// If the block is full it does not add it and returns false.
-    pub fn add(&mut self, key: Bytes, value: Bytes) -> bool {
+    pub fn add(&mut self, key: Bytes, value: Value, seq: u64, flags: u8) -> bool {
         let entry_size = entry_size(&key, &value);
 
         if self.size + entry_size > BLOCK_BYTE_SIZE as u32 {
@@ -77,17 +114,31 @@ impl Block {
         self.data.put_u16((key.len()) as u16);
         // Encode key content.
         self.data.put(key);
+        // Encode kind, along with the expiration time of an expiring value.
+        match value {
+            Value::Live(_) => self.data.put_u8(KIND_LIVE),
+            Value::Tombstone => self.data.put_u8(KIND_TOMBSTONE),
+            Value::Expiring { expires_at, .. } => {
+                self.data.put_u8(KIND_EXPIRING);
+                self.data.put_u64(expires_at);
+            }
+        }
         // Encode value length.
-        self.data.put_u16(value.len() as u16);
+        self.data.put_u16(value.as_bytes().len() as u16);
         // Encode value content.
-        self.data.put(value);
+        self.data.put(value.as_bytes());
+        // Encode sequence number.
+        self.data.put_u64(seq);
+        // Encode flags.
+        self.data.put_u8(flags);
 
         true
     }
 
-    /// Puts the contents of the block into a sequence of bytes.
-    /// Schema that is used can be found on top of the mod source code.
-    pub fn encode(&self) -> Vec<u8> {
+    /// Puts the contents of the block into a sequence of bytes, compressed if compress is set
+    /// and compressing makes it shorter. Schema that is used can be found on top of the mod
+    /// source code.
+    pub fn encode(&self, compress: bool) -> Vec<u8> {
         assert!(!self.is_empty(), "Attempt to encode an empty block");
         let mut buf = Vec::with_capacity(BLOCK_BYTE_SIZE);
 
@@ -97,6 +148,12 @@ impl Block {
         }
         buf.extend(&self.data);
 
+        if compress {
+            if let Some(compressed) = self.encode_compressed(&buf[U16_SIZE as usize..]) {
+                return compressed;
+            }
+        }
+
         // Fill the vector up to its capacity (leaving the space required for checksum).
         if buf.len() != buf.capacity() - CHECKSUM_SIZE {
             buf.extend((buf.len()..buf.capacity() - CHECKSUM_SIZE).map(|_| 0));
@@ -120,60 +177,183 @@ impl Block {
         buf
     }
 
-    pub fn decode(raw: &[u8]) -> Self {
-        assert_eq!(
-            raw.len(),
-            BLOCK_BYTE_SIZE,
-            "Byte slice to decode a block exceeds the block size"
-        );
+    /// Encodes the block with its offsets and data sections given in content compressed. None if
+    /// it would not be shorter than an uncompressed block.
+    fn encode_compressed(&self, content: &[u8]) -> Option<Vec<u8>> {
+        let compressed = compress(content)?;
+        let len = 2 * U16_SIZE as usize + compressed.len() + CHECKSUM_SIZE;
+        if len >= BLOCK_BYTE_SIZE {
+            return None;
+        }
+
+        let mut buf = Vec::with_capacity(len);
+        buf.put_u16(self.offsets.len() as u16 | BLOCK_COMPRESSED);
+        buf.put_u16(content.len() as u16);
+        buf.extend(compressed);
+        let checksum = crc32fast::hash(&buf);
+        buf.put_u32(checksum);
+
+        Some(buf)
+    }
+
+    /// Decodes an encoded block. A block that doesn't check out is Corrupted, so that a damaged
+    /// table fails the read instead of taking the engine down.
+    pub fn decode(raw: &[u8]) -> Result<Self> {
+        if !checksum_ok(raw) {
+            Err(crate::Error::Corrupted("checksum mismatch in block".into()))?
+        }
 
         let mut buf = Cursor::new(raw);
+        let header = buf.get_u16();
+        let offsets_cnt = header & !BLOCK_COMPRESSED;
+
+        // Offsets and data sections, a compressed block has them decompressed first.
+        let content_end = raw.len() - CHECKSUM_SIZE;
+        let content = match header & BLOCK_COMPRESSED {
+            0 => raw[buf.position() as usize..content_end].to_vec(),
+            _ => {
+                let content_len = buf.get_u16() as usize;
+                let compressed = raw
+                    .get(buf.position() as usize..content_end)
+                    .ok_or_else(|| crate::Error::Corrupted("compressed block is cut".into()))?;
+                decompress(compressed, content_len)?
+            }
+        };
+        if content.len() < offsets_cnt as usize * U16_SIZE as usize {
+            Err(crate::Error::Corrupted(format!(
+                "block has {} bytes of content, too few for {} offsets",
+                content.len(),
+                offsets_cnt
+            )))?
+        }
 
-        let checksum = crc32fast::hash(&raw[..buf.remaining() - CHECKSUM_SIZE]);
-        let offsets_cnt = buf.get_u16();
+        let mut content = Cursor::new(content);
         let mut offsets = Vec::with_capacity(offsets_cnt as usize * std::mem::size_of::<u16>());
         for _ in 0..offsets_cnt {
-            offsets.push(buf.get_u16());
+            offsets.push(content.get_u16());
         }
 
-        let data_start = buf.position() as usize;
-        let data_end = data_start + buf.remaining() - CHECKSUM_SIZE;
-        let data_len = data_end - data_start;
-        let data: Vec<u8> = raw[data_start..data_end].to_vec();
-        buf.advance(data_len);
+        let data_start = content.position() as usize;
+        let data: Vec<u8> = content.into_inner().split_off(data_start);
 
-        assert_eq!(buf.get_u32(), checksum, "Checksum mismatch in block decode");
-
-        Self {
+        Ok(Self {
             data,
             offsets,
             first_key: Bytes::default(), // Field used while decoding the SsTable.
             last_key: Bytes::default(),  // Field used while decoding the SsTable.
             size: 0,                     // The field only used should not be used on decoded block.
-        }
+        })
     }
 
-    pub fn get(&self, key: Bytes) -> Option<Bytes> {
+    /// Returns the value of the key along with its sequence number and flags. A deleted key is
+    /// found too, with a tombstone for the value.
+    pub fn get(&self, key: Bytes) -> Option<(Value, u64, u8)> {
         assert!(!self.is_empty(), "Attempt to get value from an empty block");
 
-        let mut low = 0;
-        let mut high = self.offsets.len() - 1;
+        self.offsets
+            .binary_search_by(|offset| self.parse_frame(*offset as usize).cmp(&key))
+            .ok()
+            .map(|pos| self.parse_value(self.offsets[pos] as usize))
+    }
+
+    /// Returns all the entries of the block as key, value, sequence number and flags in the
+    /// order they were added.
+    pub fn entries(&self) -> Vec<(Bytes, Value, u64, u8)> {
+        self.offsets
+            .iter()
+            .map(|offset| {
+                let key = self.parse_frame(*offset as usize);
+                let (value, seq, flags) = self.parse_value(*offset as usize);
+                (key, value, seq, flags)
+            })
+            .collect()
+    }
 
-        while low <= high {
-            let mid = low + (high - low) / 2;
+    /// Counts live keys of the block within [start, end), values expired by now (unix time in
+    /// milliseconds) are not live.
+    pub fn count_range(&self, start: &Bytes, end: &Bytes, now: u64) -> usize {
+        self.keys_range(start, end, now)
+            .filter(|(_, live)| *live)
+            .count()
+    }
 
-            let read_key = self.parse_frame(self.offsets[mid] as usize);
+    /// Keys of the block within [start, end) in order, along with whether they are live as of
+    /// now. Values are not read.
+    pub fn keys_range<'a>(
+        &'a self,
+        start: &'a Bytes,
+        end: &'a Bytes,
+        now: u64,
+    ) -> impl Iterator<Item = (Bytes, bool)> + 'a {
+        self.offsets
+            .iter()
+            .map(move |offset| {
+                let key = self.parse_frame(*offset as usize);
+                let live = self.is_live(*offset as usize, key.len(), now);
+                (key, live)
+            })
+            .filter(move |(key, _)| key >= start && key < end)
+    }
 
-            match read_key.cmp(&key) {
-                std::cmp::Ordering::Less => low = mid + 1,
-                std::cmp::Ordering::Greater => high = mid - 1,
-                std::cmp::Ordering::Equal => {
-                    return Some(self.parse_frame(self.offsets[mid] as usize + 2 + key.len()))
-                }
-            }
+    /// Entries of the block within [start, end) in order, tombstones included. The first one is
+    /// found with a binary search, entries are read from there until the end of the range.
+    pub fn entries_range<'a>(
+        &'a self,
+        start: &Bytes,
+        end: &'a Bytes,
+    ) -> impl Iterator<Item = (Bytes, Value)> + 'a {
+        let first = self
+            .offsets
+            .partition_point(|offset| self.parse_frame(*offset as usize) < start);
+
+        self.offsets[first..]
+            .iter()
+            .map(|offset| {
+                let key = self.parse_frame(*offset as usize);
+                (key, *offset)
+            })
+            .take_while(move |(key, _)| key < end)
+            .map(|(key, offset)| (key, self.parse_value(offset as usize).0))
+    }
+
+    /// Reads value, sequence number and flags of the entry at the given offset.
+    fn parse_value(&self, offset: usize) -> (Value, u64, u8) {
+        let kind_at = offset + 2 + self.parse_frame(offset).len();
+        let kind = self.data[kind_at];
+        let mut value_at = kind_at + KIND_SIZE as usize;
+        if kind == KIND_EXPIRING {
+            value_at += EXPIRES_AT_SIZE as usize;
+        }
+        let value = self.parse_frame(value_at);
+
+        let seq_at = value_at + 2 + value.len();
+        let flags = self.data[seq_at + SEQ_SIZE as usize];
+
+        let value = match kind {
+            KIND_TOMBSTONE => Value::Tombstone,
+            KIND_EXPIRING => Value::Expiring {
+                value,
+                expires_at: self.parse_u64(kind_at + KIND_SIZE as usize),
+            },
+            _ => Value::Live(value),
+        };
+        (value, self.parse_u64(seq_at), flags)
+    }
+
+    /// Tells if the entry at the given offset holds a value that is not expired by now.
+    fn is_live(&self, offset: usize, key_len: usize, now: u64) -> bool {
+        let kind_at = offset + 2 + key_len;
+        match self.data[kind_at] {
+            KIND_TOMBSTONE => false,
+            KIND_EXPIRING => self.parse_u64(kind_at + KIND_SIZE as usize) > now,
+            _ => true,
         }
+    }
 
-        None
+    fn parse_u64(&self, offset: usize) -> u64 {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&self.data[offset..offset + 8]);
+        u64::from_be_bytes(bytes)
     }
 
     fn parse_frame(&self, offset: usize) -> Bytes {
@@ -183,6 +363,11 @@ impl Block {
         Bytes::copy_from_slice(&self.data[offset + 2..offset + 2 + len])
     }
 
+    /// Number of bytes the block content takes once encoded, without padding.
+    pub fn encoded_len(&self) -> usize {
+        U16_SIZE as usize * (1 + self.offsets.len()) + self.data.len() + CHECKSUM_SIZE
+    }
+
     pub fn is_empty(&self) -> bool {
         self.offsets.len() < 1
     }
@@ -200,8 +385,57 @@ impl std::fmt::Display for Block {
     }
 }
 
-pub fn entry_size(key: &Bytes, value: &Bytes) -> u32 {
-    key.len() as u32 + value.len() as u32 + ENTRY_OVERHEAD
+pub fn entry_size(key: &Bytes, value: &Value) -> u32 {
+    let expires_at = match value {
+        Value::Expiring { .. } => EXPIRES_AT_SIZE,
+        _ => 0,
+    };
+    key.len() as u32 + value.as_bytes().len() as u32 + expires_at + ENTRY_OVERHEAD
+}
+
+/// Tells if the checksum of an encoded block matches its content.
+pub fn checksum_ok(raw: &[u8]) -> bool {
+    if raw.len() < U16_SIZE as usize + CHECKSUM_SIZE || raw.len() > BLOCK_BYTE_SIZE {
+        return false;
+    }
+    if !is_compressed(raw) && raw.len() != BLOCK_BYTE_SIZE {
+        return false;
+    }
+
+    let (content, checksum) = raw.split_at(raw.len() - CHECKSUM_SIZE);
+    crc32fast::hash(content).to_be_bytes() == checksum
+}
+
+/// Number of entries an encoded block claims to have.
+pub fn entries_num(raw: &[u8]) -> u16 {
+    u16::from_be_bytes([raw[0], raw[1]]) & !BLOCK_COMPRESSED
+}
+
+/// Tells if an encoded block is compressed.
+pub fn is_compressed(raw: &[u8]) -> bool {
+    u16::from_be_bytes([raw[0], raw[1]]) & BLOCK_COMPRESSED != 0
+}
+
+#[cfg(feature = "compression")]
+fn compress(content: &[u8]) -> Option<Vec<u8>> {
+    Some(lz4_flex::block::compress(content))
+}
+
+/// Blocks are never compressed without the compression feature.
+#[cfg(not(feature = "compression"))]
+fn compress(_content: &[u8]) -> Option<Vec<u8>> {
+    None
+}
+
+#[cfg(feature = "compression")]
+fn decompress(compressed: &[u8], content_len: usize) -> Result<Vec<u8>> {
+    lz4_flex::block::decompress(compressed, content_len)
+        .map_err(|e| crate::Error::Corrupted(format!("malformed compressed block: {}", e)))
+}
+
+#[cfg(not(feature = "compression"))]
+fn decompress(_compressed: &[u8], _content_len: usize) -> Result<Vec<u8>> {
+    Err("block is compressed, but the compression feature is off".into())
 }
 
 #[cfg(test)]
@@ -214,7 +448,9 @@ mod tests {
         loop {
             if !bl.add(
                 Bytes::from(Uuid::now_v7().to_string()),
-                Bytes::from(Uuid::now_v7().to_string()),
+                Value::Live(Bytes::from(Uuid::now_v7().to_string())),
+                1,
+                0,
             ) {
                 break;
             }
@@ -224,53 +460,190 @@ mod tests {
 
     #[test]
     fn test_entry_size() {
-        assert_eq!(entry_size(&Bytes::from("foo"), &Bytes::from("bar")), 12);
+        assert_eq!(
+            entry_size(&Bytes::from("foo"), &Value::Live(Bytes::from("bar"))),
+            22
+        );
+        assert_eq!(entry_size(&Bytes::from("foo"), &Value::Tombstone), 19);
     }
 
     #[test]
     fn test_add() {
         let mut bl = Block::new();
-        let entry = (Bytes::from("foo"), Bytes::from("bar"));
-        bl.add(entry.0.clone(), entry.1.clone());
+        let entry = (Bytes::from("foo"), Value::Live(Bytes::from("bar")));
+        bl.add(entry.0.clone(), entry.1.clone(), 1, 0);
         assert_eq!(bl.size, INITIAL_BLOCK_SIZE + entry_size(&entry.0, &entry.1));
     }
 
     #[test]
     fn test_get() {
         let mut bl = Block::new();
-        bl.add(Bytes::from("buddha"), Bytes::from("om"));
-        bl.add(Bytes::from("dharma"), Bytes::from("ah"));
-        bl.add(Bytes::from("sangha"), Bytes::from("hum"));
+        bl.add(Bytes::from("buddha"), Value::Live(Bytes::from("om")), 1, 0);
+        bl.add(Bytes::from("dharma"), Value::Live(Bytes::from("ah")), 2, 0);
+        bl.add(Bytes::from("sangha"), Value::Live(Bytes::from("hum")), 3, 0);
 
         let value = bl.get(Bytes::from("buddha"));
         assert!(value.is_some());
-        assert_eq!(value.unwrap(), Bytes::from("om"));
+        assert_eq!(value.unwrap(), (Value::Live(Bytes::from("om")), 1, 0));
 
         let value = bl.get(Bytes::from("dharma"));
         assert!(value.is_some());
-        assert_eq!(value.unwrap(), Bytes::from("ah"));
+        assert_eq!(value.unwrap(), (Value::Live(Bytes::from("ah")), 2, 0));
 
         let value = bl.get(Bytes::from("sangha"));
         assert!(value.is_some());
-        assert_eq!(value.unwrap(), Bytes::from("hum"));
+        assert_eq!(value.unwrap(), (Value::Live(Bytes::from("hum")), 3, 0));
 
         let value = bl.get(Bytes::from("grief"));
         assert!(value.is_none());
+
+        // Keys out of the block range, a hash index collision may look them up here.
+        assert!(bl.get(Bytes::from("anatta")).is_none());
+        assert!(bl.get(Bytes::from("zen")).is_none());
+    }
+
+    #[test]
+    fn test_entries() {
+        let mut bl = Block::new();
+        bl.add(Bytes::from("buddha"), Value::Live(Bytes::from("om")), 1, 0);
+        bl.add(Bytes::from("dharma"), Value::Live(Bytes::from("ah")), 2, 7);
+
+        let decoded = Block::decode(&bl.encode(false)).unwrap();
+        assert_eq!(
+            decoded.entries(),
+            vec![
+                (Bytes::from("buddha"), Value::Live(Bytes::from("om")), 1, 0),
+                (Bytes::from("dharma"), Value::Live(Bytes::from("ah")), 2, 7),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_expiring() {
+        let expiring = Value::Expiring {
+            value: Bytes::from("ah"),
+            expires_at: 1000,
+        };
+        assert_eq!(entry_size(&Bytes::from("dharma"), &expiring), 32);
+
+        let mut bl = Block::new();
+        bl.add(Bytes::from("buddha"), Value::Live(Bytes::from("om")), 1, 0);
+        bl.add(Bytes::from("dharma"), expiring.clone(), 2, 7);
+        let bl = Block::decode(&bl.encode(false)).unwrap();
+
+        assert_eq!(bl.get(Bytes::from("dharma")), Some((expiring, 2, 7)));
+        let live = |now| {
+            bl.keys_range(&Bytes::from("a"), &Bytes::from("z"), now)
+                .map(|(_, live)| live)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(live(999), vec![true, true]);
+        assert_eq!(
+            live(1000),
+            vec![true, false],
+            "expires at its expiration time"
+        );
+        assert_eq!(
+            bl.count_range(&Bytes::from("a"), &Bytes::from("z"), 1000),
+            1
+        );
+    }
+
+    #[test]
+    fn test_entries_range() {
+        let mut bl = Block::new();
+        bl.add(Bytes::from("a"), Value::Live(Bytes::from("1")), 1, 0);
+        bl.add(Bytes::from("b"), Value::Tombstone, 1, 0);
+        bl.add(Bytes::from("c"), Value::Live(Bytes::from("3")), 1, 0);
+        bl.add(Bytes::from("d"), Value::Live(Bytes::from("4")), 1, 0);
+        let bl = Block::decode(&bl.encode(false)).unwrap();
+
+        let range = |start: &'static str, end: &'static str| -> Vec<(Bytes, Value)> {
+            bl.entries_range(&Bytes::from(start), &Bytes::from(end))
+                .collect()
+        };
+        assert_eq!(
+            range("aa", "d"),
+            vec![
+                (Bytes::from("b"), Value::Tombstone),
+                (Bytes::from("c"), Value::Live(Bytes::from("3"))),
+            ]
+        );
+        assert_eq!(range("", "b").len(), 1);
+        assert_eq!(range("d", "z").len(), 1);
+        assert!(range("c", "c").is_empty());
+        assert!(range("d", "a").is_empty());
+    }
+
+    #[test]
+    fn test_checksum_ok() {
+        let mut encoded = make_full_block().encode(false);
+        assert!(checksum_ok(&encoded));
+        assert_eq!(entries_num(&encoded), 46);
+
+        encoded[100] ^= 1;
+        assert!(!checksum_ok(&encoded));
+        assert!(!checksum_ok(&encoded[..100]));
+    }
+
+    #[test]
+    fn test_decode_corrupted() {
+        let mut encoded = make_full_block().encode(false);
+        encoded[100] ^= 1;
+        assert!(matches!(
+            Block::decode(&encoded),
+            Err(crate::Error::Corrupted(_))
+        ));
+        assert!(matches!(
+            Block::decode(&encoded[..100]),
+            Err(crate::Error::Corrupted(_))
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "compression")]
+    fn test_compressed() {
+        let mut bl = Block::new();
+        for i in 0..40 {
+            let key = Bytes::from(format!("key-{:03}", i));
+            bl.add(key, Value::Live(Bytes::from("v".repeat(64))), i, 0);
+        }
+        bl.add(Bytes::from("key-999"), Value::Tombstone, 40, 0);
+
+        let encoded = bl.encode(true);
+        assert!(encoded.len() < BLOCK_BYTE_SIZE / 2, "{}", encoded.len());
+        assert!(is_compressed(&encoded));
+        assert!(checksum_ok(&encoded));
+        assert_eq!(entries_num(&encoded), 41);
+        assert_eq!(Block::decode(&encoded).unwrap().entries(), bl.entries());
+
+        let mut damaged = encoded.clone();
+        damaged[10] ^= 1;
+        assert!(!checksum_ok(&damaged));
+
+        // Random content doesn't get shorter, the block is kept as it is.
+        let mut bl = Block::new();
+        let value: Vec<u8> = (0..4071).map(|_| rand::random()).collect();
+        assert!(bl.add(Bytes::from("key"), Value::Live(value.into()), 1, 0));
+        assert_eq!(bl.encoded_len(), BLOCK_BYTE_SIZE);
+        let encoded = bl.encode(true);
+        assert!(!is_compressed(&encoded));
+        assert_eq!(encoded, bl.encode(false));
     }
 
     #[test]
     fn test_parse_frame() {
         let mut bl = Block::new();
-        bl.add(Bytes::from("foo"), Bytes::from("bar"));
-        bl.add(Bytes::from("bar"), Bytes::from("foo"));
+        bl.add(Bytes::from("foo"), Value::Live(Bytes::from("bar")), 1, 0);
+        bl.add(Bytes::from("bar"), Value::Live(Bytes::from("foo")), 1, 0);
 
         let key_1 = bl.parse_frame(0);
         assert_eq!(key_1, Bytes::from("foo"));
-        let value_1 = bl.parse_frame(5);
+        let value_1 = bl.parse_frame(6);
         assert_eq!(value_1, Bytes::from("bar"));
-        let key_2 = bl.parse_frame(10);
+        let key_2 = bl.parse_frame(20);
         assert_eq!(key_2, Bytes::from("bar"));
-        let value_2 = bl.parse_frame(15);
+        let value_2 = bl.parse_frame(26);
         assert_eq!(value_2, Bytes::from("foo"));
     }
 
@@ -279,9 +652,9 @@ mod tests {
         let mut bl = Block::new();
         assert!(bl.is_empty());
 
-        bl.add(Bytes::from("buddha"), Bytes::from("om"));
-        bl.add(Bytes::from("dharma"), Bytes::from("ah"));
-        bl.add(Bytes::from("sangha"), Bytes::from("hum"));
+        bl.add(Bytes::from("buddha"), Value::Live(Bytes::from("om")), 1, 0);
+        bl.add(Bytes::from("dharma"), Value::Live(Bytes::from("ah")), 1, 0);
+        bl.add(Bytes::from("sangha"), Value::Live(Bytes::from("hum")), 1, 0);
         assert!(!bl.is_empty());
     }
 
@@ -289,29 +662,29 @@ mod tests {
     #[should_panic]
     fn test_encode_empty_table_panics() {
         let bl = Block::new();
-        bl.encode();
+        bl.encode(false);
     }
 
     #[test]
     fn test_encode() {
         let bl = make_full_block();
-        let encoded = bl.encode();
+        let encoded = bl.encode(false);
         let mut encoded = Cursor::new(encoded);
         assert_eq!(encoded.remaining(), 4 * 1024);
 
         let offsets_cnt = encoded.get_u16();
-        assert_eq!(offsets_cnt, 52);
+        assert_eq!(offsets_cnt, 46);
     }
 
     #[test]
     fn test_decode() {
         let bl = make_full_block();
-        let encoded = bl.encode();
-        let decoded = Block::decode(encoded.as_ref());
+        let encoded = bl.encode(false);
+        let decoded = Block::decode(encoded.as_ref()).unwrap();
         assert_eq!(decoded.first_key, Bytes::default());
         assert_eq!(decoded.last_key, Bytes::default());
-        assert_eq!(decoded.data.len(), 3986);
-        assert_eq!(decoded.offsets.len(), 52);
+        assert_eq!(decoded.data.len(), 3998);
+        assert_eq!(decoded.offsets.len(), 46);
         assert_eq!(decoded.size, 0);
         let first_frame = decoded.parse_frame(0);
         assert_eq!(first_frame.len(), 36);
@@ -1,24 +1,26 @@
+use crate::Result;
 use bloomfilter::Bloom;
-use bytes::{Buf, BufMut, Bytes};
-use std::io::Cursor;
+use bytes::{BufMut, Bytes};
 
-pub const MAX_ELEM: usize = 6400;
+/// False positive rate filters are built for unless told otherwise (see Config::bloom_fp_rate).
 pub const PROBABILITY: f64 = 0.01;
-pub const BLOOM_SIZE: usize = 7714; // 7714B.
 pub const CHECKSUM_SIZE: usize = 4; // 4B.
-pub const ENCODED_LEN: usize = BLOOM_SIZE + CHECKSUM_SIZE; // 7718B.
 
 pub trait BloomSerializable {
     fn encode(&self) -> Vec<u8>;
-    fn decode(src: &[u8]) -> Self;
+    /// A filter that doesn't check out is Corrupted.
+    fn decode(src: &[u8]) -> Result<Self>
+    where
+        Self: Sized;
 }
 
 /*
-Bloom filter layout schema.
+Bloom filter layout schema. Size of the serialized filter depends on the number of keys and the
+false positive rate it was built for, the table keeps the len of the encoded filter (see SsTable).
 ----------------------------------------------
 | Bloomfilter serialized to bytes | Checksum |
 ----------------------------------------------
-|              7714B              | u32 (4B) |
+|             varies              | u32 (4B) |
 ----------------------------------------------
 */
 impl BloomSerializable for Bloom<Bytes> {
@@ -28,41 +30,69 @@ impl BloomSerializable for Bloom<Bytes> {
         let checksum = crc32fast::hash(&encoded);
         encoded.put_u32(checksum);
 
-        assert_eq!(encoded.len(), BLOOM_SIZE + CHECKSUM_SIZE);
-
         encoded
     }
 
-    // TODO: Remove panics, return Result.
-    fn decode(raw: &[u8]) -> Self {
-        assert_eq!(
-            raw.len(),
-            ENCODED_LEN,
-            "Blob should be {} bytes, but {} was passed",
-            ENCODED_LEN,
-            raw.len()
-        );
+    fn decode(raw: &[u8]) -> Result<Self> {
+        if !checksum_ok(raw) {
+            Err(crate::Error::Corrupted(
+                "checksum mismatch in bloom filter".into(),
+            ))?
+        }
 
-        let checksum = crc32fast::hash(&raw[..BLOOM_SIZE]);
+        Bloom::<Bytes>::from_bytes(raw[..raw.len() - CHECKSUM_SIZE].to_vec())
+            .map_err(|e| crate::Error::Corrupted(format!("malformed bloom filter: {}", e)))
+    }
+}
 
-        let mut vec = Vec::from(raw);
+/// Tells if the checksum of the encoded filter matches its content.
+pub fn checksum_ok(raw: &[u8]) -> bool {
+    let Some(content_len) = raw.len().checked_sub(CHECKSUM_SIZE) else {
+        return false;
+    };
 
-        let sum_vec: Vec<u8> = vec.drain(BLOOM_SIZE..).collect();
-        let sum_decoded = Cursor::new(sum_vec).get_u32();
+    let (content, checksum) = raw.split_at(content_len);
+    crc32fast::hash(content).to_be_bytes() == checksum
+}
 
-        let decoded = Bloom::<Bytes>::from_bytes(vec).unwrap();
+/// Filter sized for the number of keys to have the given false positive rate once they are all
+/// in. The rate is expected to be within (0, 1), see Config::validate.
+pub fn new(keys: usize, fp_rate: f64) -> Bloom<Bytes> {
+    Bloom::new_for_fp_rate(keys.max(1), fp_rate).unwrap()
+}
 
-        assert_eq!(
-            checksum, sum_decoded,
-            "Checksum mismatch in bloom filter decode"
-        );
+/// How saturated a filter is. A filter that got more keys than it was sized for has most of its
+/// bits set and lets through lookups of keys the table doesn't have.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BloomStats {
+    pub bits: u64,
+    pub bits_set: u64,
+    pub hash_functions: u32,
+}
 
-        decoded
+impl BloomStats {
+    /// Fraction of the filter bits that are set.
+    pub fn fill_ratio(&self) -> f64 {
+        self.bits_set as f64 / self.bits as f64
+    }
+
+    /// Chance that a key the filter doesn't know passes it: every one of its bits is set, which
+    /// given the bits already set is fill ratio to the power of hash functions number.
+    pub fn fp_rate(&self) -> f64 {
+        self.fill_ratio().powi(self.hash_functions as i32)
     }
 }
 
-pub fn new() -> Bloom<Bytes> {
-    Bloom::new_for_fp_rate(MAX_ELEM, PROBABILITY).unwrap()
+pub fn stats(bloom: &Bloom<Bytes>) -> BloomStats {
+    // Serialized filter is a header followed by the bits.
+    let raw = bloom.as_slice();
+    let bits = &raw[raw.len() - (bloom.len() / 8) as usize..];
+
+    BloomStats {
+        bits: bloom.len(),
+        bits_set: bits.iter().map(|byte| byte.count_ones() as u64).sum(),
+        hash_functions: bloom.number_of_hash_functions(),
+    }
 }
 
 #[cfg(test)]
@@ -71,15 +101,52 @@ mod tests {
 
     #[test]
     fn test_encode_decode() {
-        let mut original = new();
+        let mut original = new(2, PROBABILITY);
         original.set(&Bytes::from("foo"));
         original.set(&Bytes::from("bar"));
 
         let encoded = original.encode();
-        assert_eq!(encoded.len(), ENCODED_LEN);
+        // Serialized filter header takes 45 bytes.
+        assert_eq!(
+            encoded.len(),
+            45 + original.len() as usize / 8 + CHECKSUM_SIZE
+        );
 
-        let decoded = Bloom::decode(encoded.as_slice());
+        let decoded = Bloom::decode(encoded.as_slice()).unwrap();
         assert!(decoded.check(&Bytes::from("foo")));
         assert!(decoded.check(&Bytes::from("bar")));
+
+        assert!(Bloom::decode(&encoded[..encoded.len() - 1]).is_err());
+        assert!(Bloom::decode(&[]).is_err());
+    }
+
+    #[test]
+    fn test_stats() {
+        const KEYS: usize = 6400;
+        let fill = |keys: usize, fp_rate: f64| {
+            let mut bloom = new(KEYS, fp_rate);
+            for i in 0..keys {
+                bloom.set(&Bytes::from(format!("key-{}", i)));
+            }
+            stats(&bloom)
+        };
+
+        let empty = fill(0, PROBABILITY);
+        assert_eq!(empty.bits, 7669 * 8);
+        assert_eq!(empty.bits_set, 0);
+        assert_eq!(empty.fp_rate(), 0.0);
+
+        let light = fill(KEYS / 10, PROBABILITY);
+        let full = fill(KEYS, PROBABILITY);
+        assert!(light.fill_ratio() < full.fill_ratio());
+        assert!(light.fp_rate() < full.fp_rate());
+        assert!(light.fp_rate() < PROBABILITY / 100.0, "{}", light.fp_rate());
+        // A filter at capacity is about as good as it was sized to be.
+        assert!((0.5..2.0).contains(&(full.fp_rate() / PROBABILITY)));
+
+        // A lower rate takes more bits and gets there.
+        let dense = fill(KEYS, 0.001);
+        assert!(dense.bits > full.bits);
+        assert!((0.5..2.0).contains(&(dense.fp_rate() / 0.001)));
     }
 }
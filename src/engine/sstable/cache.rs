@@ -0,0 +1,227 @@
+use super::block::Block;
+use crate::Result;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+/// Least recently used decoded blocks, keyed by the table id and the block offset. Lookups that
+/// land in the same block, like reads of neighbouring keys or a bloom filter false positive,
+/// don't read and decode it again. Clones share the blocks, so that lookups run off the
+/// dispatcher task go through the same cache. With zero capacity it holds nothing and every
+/// block is read.
+///
+/// Tables are never changed once written and their ids are never reused, so a cached block is
+/// valid as long as its table lives. Blocks of removed tables are dropped with forget.
+#[derive(Debug, Clone, Default)]
+pub struct BlockCache {
+    inner: Arc<Mutex<Lru>>,
+}
+
+/// Block cache counters since the start.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct BlockCacheStats {
+    /// Blocks found in the cache.
+    pub hits: u64,
+    /// Blocks that had to be read from storage.
+    pub misses: u64,
+    /// Blocks pushed out by more recently read ones.
+    pub evictions: u64,
+    /// Number of blocks in the cache right now.
+    pub size: usize,
+}
+
+#[derive(Debug, Default)]
+struct Lru {
+    capacity: usize,
+    /// Bumped on every access, the block with the lowest tick is the least recently used.
+    tick: u64,
+    blocks: HashMap<(Uuid, u32), (Arc<Block>, u64)>,
+    /// Keys of the blocks by their last access tick.
+    order: BTreeMap<u64, (Uuid, u32)>,
+    stats: BlockCacheStats,
+}
+
+impl BlockCache {
+    /// Makes a cache holding up to capacity blocks.
+    pub fn new(capacity: usize) -> Self {
+        BlockCache {
+            inner: Arc::new(Mutex::new(Lru {
+                capacity,
+                ..Default::default()
+            })),
+        }
+    }
+
+    /// Handle to read the blocks of a single table through the cache.
+    pub fn table(&self, table_id: Uuid) -> TableCache {
+        TableCache {
+            cache: self.clone(),
+            table_id,
+        }
+    }
+
+    /// Drops blocks of the tables, they are gone from storage.
+    pub fn forget(&self, tables: &[Uuid]) {
+        let mut lru = self.lock();
+        let Lru { blocks, order, .. } = &mut *lru;
+        blocks.retain(|(table_id, _), (_, tick)| {
+            let keep = !tables.contains(table_id);
+            if !keep {
+                order.remove(tick);
+            }
+            keep
+        });
+    }
+
+    pub fn stats(&self) -> BlockCacheStats {
+        let lru = self.lock();
+        BlockCacheStats {
+            size: lru.blocks.len(),
+            ..lru.stats.clone()
+        }
+    }
+
+    /// A panic while holding the lock leaves the cache consistent, every change is made in
+    /// a single step.
+    fn lock(&self) -> std::sync::MutexGuard<'_, Lru> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Lru {
+    fn get(&mut self, key: &(Uuid, u32)) -> Option<Arc<Block>> {
+        self.tick += 1;
+        let tick = self.tick;
+        let (block, last) = self.blocks.get_mut(key)?;
+        self.order.remove(last);
+        self.order.insert(tick, *key);
+        *last = tick;
+
+        Some(block.clone())
+    }
+
+    fn insert(&mut self, key: (Uuid, u32), block: Arc<Block>) {
+        if self.capacity == 0 || self.blocks.contains_key(&key) {
+            return;
+        }
+
+        if self.blocks.len() >= self.capacity {
+            if let Some((_, oldest)) = self.order.pop_first() {
+                self.blocks.remove(&oldest);
+                self.stats.evictions += 1;
+            }
+        }
+
+        self.tick += 1;
+        self.order.insert(self.tick, key);
+        self.blocks.insert(key, (block, self.tick));
+    }
+}
+
+/// Blocks of a single table read through the block cache, see BlockCache::table.
+#[derive(Debug, Clone)]
+pub struct TableCache {
+    cache: BlockCache,
+    table_id: Uuid,
+}
+
+impl TableCache {
+    /// Returns the block at the offset from the cache, or reads it with read and caches it.
+    /// The lock is not held while the block is read.
+    pub fn get_or_read(
+        &self,
+        offset: u32,
+        read: impl FnOnce() -> Result<Block>,
+    ) -> Result<Arc<Block>> {
+        let key = (self.table_id, offset);
+        {
+            let mut lru = self.cache.lock();
+            match lru.get(&key) {
+                Some(block) => {
+                    lru.stats.hits += 1;
+                    return Ok(block);
+                }
+                None => lru.stats.misses += 1,
+            }
+        }
+
+        let block = Arc::new(read()?);
+        self.cache.lock().insert(key, block.clone());
+
+        Ok(block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::memtable::Value;
+    use bytes::Bytes;
+
+    fn block(key: &str) -> Block {
+        let mut block = Block::new();
+        block.add(Bytes::from(key.to_string()), Value::Tombstone, 1, 0);
+        Block::decode(&block.encode(false)).unwrap()
+    }
+
+    #[test]
+    fn test_get_or_read() {
+        let cache = BlockCache::new(2);
+        let (first, second) = (
+            cache.table(Uuid::from_u128(1)),
+            cache.table(Uuid::from_u128(2)),
+        );
+        let read = |key: &'static str| move || Ok(block(key));
+
+        first.get_or_read(0, read("a")).unwrap();
+        first.get_or_read(4096, read("b")).unwrap();
+        let found = first.get_or_read(0, || panic!("block is cached")).unwrap();
+        assert_eq!(found.entries()[0].0, Bytes::from("a"));
+
+        // Same offset of another table is another block. Block "b" is the least recently used
+        // one, it is pushed out.
+        second.get_or_read(0, read("c")).unwrap();
+        first.get_or_read(0, || panic!("block is cached")).unwrap();
+        first.get_or_read(4096, read("b")).unwrap();
+        assert_eq!(
+            cache.stats(),
+            BlockCacheStats {
+                hits: 2,
+                misses: 4,
+                evictions: 2,
+                size: 2,
+            }
+        );
+
+        // A failed read caches nothing.
+        assert!(second
+            .get_or_read(8192, || Err("no such block".into()))
+            .is_err());
+        assert_eq!(cache.stats().size, 2);
+    }
+
+    #[test]
+    fn test_forget() {
+        let cache = BlockCache::new(10);
+        let (first, second) = (
+            cache.table(Uuid::from_u128(1)),
+            cache.table(Uuid::from_u128(2)),
+        );
+        first.get_or_read(0, || Ok(block("a"))).unwrap();
+        second.get_or_read(0, || Ok(block("b"))).unwrap();
+
+        cache.forget(&[Uuid::from_u128(1)]);
+        assert_eq!(cache.stats().size, 1);
+        second.get_or_read(0, || panic!("block is cached")).unwrap();
+        assert!(first.get_or_read(0, || Err("gone".into())).is_err());
+    }
+
+    #[test]
+    fn test_disabled() {
+        let cache = BlockCache::new(0);
+        let table = cache.table(Uuid::from_u128(1));
+        table.get_or_read(0, || Ok(block("a"))).unwrap();
+        table.get_or_read(0, || Ok(block("a"))).unwrap();
+        assert_eq!((cache.stats().misses, cache.stats().size), (2, 0));
+    }
+}
@@ -1,38 +1,137 @@
 pub mod block;
 pub mod bloom;
+pub mod cache;
 
-use crate::engine::memtable::MemTable;
+use crate::engine::memtable::{MemTable, OrderedStore, Value};
 use crate::Result;
 use crate::StorageEntry;
+use crate::{Clock, SystemClock};
 use block::Block;
 use bloom::BloomSerializable;
+pub use bloom::BloomStats;
 use bloomfilter::Bloom;
 use bytes::{Buf, BufMut, Bytes};
+use cache::TableCache;
+use std::collections::BTreeMap;
 use std::io::Cursor;
-use uuid::Uuid;
+use std::sync::Mutex;
+use uuid::{NoContext, Timestamp, Uuid};
 
 /*
 SST layout schema. First section is to be read first to make the initial checks of the table.
-----------------------------------------------------------------------------------------------------------------------------
-| Bloom filter |                                Table Index                                    |       Blocks Section      |
-----------------------------------------------------------------------------------------------------------------------------
-|  7717 Bytes  | Index len (2B) | Entries num (2B) | Entry #1 | ... | Entry #N | Checksum (4B) | Block #1 | ... | Block #N |
-----------------------------------------------------------------------------------------------------------------------------
-
-Table index entry layout.
---------------------------------------------------------------------------
-|                              Entry #1                            | ... |
---------------------------------------------------------------------------
-| key_len (2B) | first_key | key_len (2B) | last_key | Offset (4B) | ... |
---------------------------------------------------------------------------
+------------------------------------------------------------------------------------------------------------------------------------------
+| Version and    | Replaced | Replaced ids | Bloom filter |                 Table Index                     |      Blocks Section      |
+| bloom len (4B) | num (2B) |              |              |                                                 |                          |
+------------------------------------------------------------------------------------------------------------------------------------------
+|                |          |  16B each    |  Bloom len   | Index len (4B) | Top index | Page #1 | ... | Page #N | Block #1 | ... | Block #N |
+------------------------------------------------------------------------------------------------------------------------------------------
+
+The top bits of the first field hold the version of the format (see VERSION_SHIFT), a table of
+any version other than FORMAT_VERSION is refused. Any change of the layout bumps the version.
+
+Replaced ids are the ids of the tables a compaction put this one in place of. Only the last table
+a compaction writes holds them, so once it is on disk the compaction is done, and the inputs left
+on disk by a crash before they were deleted are deleted at start (see Dispatcher::init).
+
+Bloom filter is sized for the keys of the table and the false positive rate it is built with (see
+Format::bloom_fp_rate), so its len is kept in front of it. The len is read first along with the
+replaced num, then the filter along with the index len and the top len.
+
+Table index is split into pages of up to INDEX_PAGE_ENTRIES entries, the top index points to the
+pages. A lookup reads the top index and a single page, not the whole table index. Index len covers
+the whole table index section, itself included. Its top bits hold the level of the table (see
+LEVEL_SHIFT).
+
+Top index layout, it starts with its own len which is read along with the bloom filter.
+--------------------------------------------------------------------------------
+| Top len (4B) | Pages num (2B) | Top entry #1 | ... | Top entry #N | Checksum (4B) |
+--------------------------------------------------------------------------------
+
+Top index entry layout. Offset of a page is relative to the table index section start.
+------------------------------------------------------------------------------------
+| key_len (2B) | first_key | key_len (2B) | last_key | Offset (4B) | Page len (2B) |
+------------------------------------------------------------------------------------
+
+Index page layout.
+-------------------------------------------------------------------------
+| Page len (2B) | Entries num (2B) | Entry #1 | ... | Entry #N | Checksum (4B) |
+-------------------------------------------------------------------------
+
+Index page entry layout. Offset of a block is relative to the blocks section start. Blocks may be
+compressed, so their lens differ and are kept along with the offsets.
+-----------------------------------------------------------------------------------------
+|                                    Entry #1                                     | ... |
+-----------------------------------------------------------------------------------------
+| key_len (2B) | first_key | key_len (2B) | last_key | Offset (4B) | Block len (2B) | ... |
+-----------------------------------------------------------------------------------------
+
+Table index may end with a hash index, see below.
+
+Hash index is an optional open addressing table put after the last index page. A key is looked for
+among HASH_PROBE_SLOTS slots starting from the one it hashes to, so a lookup reads a few slots
+instead of the top index and a page. Number of slots keys hash to is a power of two kept in the top
+bits of the top len (see HASH_BITS_SHIFT), so it comes along with the bloom filter. Zero there
+means there is no hash index. There are HASH_PROBE_SLOTS - 1 more slots at the end so that probing
+never wraps around.
+----------------------------------------------
+| Slot #1 (8B) | Slot #2 (8B) | ... | Slot #N |
+----------------------------------------------
+
+Hash index slot layout. Fingerprint is the upper half of the key hash, offset and len are the ones
+of the block holding the key, or offset is one of HASH_SLOT_EMPTY and HASH_SLOT_SHARED.
+-------------------------------------------------
+| Fingerprint (4B) | Offset (4B) | Block len (2B) |
+-------------------------------------------------
 
 Individual block layout is given where Block is defined.
 */
 
-/// Byte size of the first section to read in the table. It is a sum of encoded bloom filter
-/// data and table index byte len so we know how much to read in the next step if needed.
-const FIRST_READ_LEN: usize = bloom::ENCODED_LEN + std::mem::size_of::<u16>();
-const CHECKSUM_SIZE: usize = std::mem::size_of::<u32>(); // 4.
+const U32_SIZE: usize = std::mem::size_of::<u32>(); // 4.
+
+const CHECKSUM_SIZE: usize = U32_SIZE;
+
+/// Version of the table layout, kept in the top bits of the bloom len field. Bloom len is kept
+/// in the low bits, which bound it the same way the table index len is bound.
+const FORMAT_VERSION: u32 = 2;
+const VERSION_SHIFT: u32 = 28;
+const BLOOM_LEN_MASK: u32 = (1 << VERSION_SHIFT) - 1;
+
+/// Most entries an index page holds. Bounds how much of the index a lookup reads.
+const INDEX_PAGE_ENTRIES: usize = 32;
+
+/// Top index len is kept in the low bits of its field, the top bits hold the base 2 log of the
+/// number of hash index slots.
+const HASH_BITS_SHIFT: u32 = 27;
+const TOP_LEN_MASK: u32 = (1 << HASH_BITS_SHIFT) - 1;
+
+/// Table index len is kept in the low bits of its field, the top bits hold the level of the table.
+const LEVEL_SHIFT: u32 = 28;
+const INDEX_LEN_MASK: u32 = (1 << LEVEL_SHIFT) - 1;
+/// Deepest level a table can be put to.
+pub const MAX_LEVEL: u8 = (u32::MAX >> LEVEL_SHIFT) as u8;
+
+/// Slots a key is looked for in the hash index, they are read at once. A key that found no free
+/// slot among them is not in the hash index and is looked up through the table index.
+const HASH_PROBE_SLOTS: usize = 8;
+const HASH_SLOT_SIZE: usize = 2 * U32_SIZE + 2;
+
+/// Offset of a hash index slot that holds no key.
+const HASH_SLOT_EMPTY: u32 = u32::MAX;
+/// Offset of a hash index slot whose fingerprint is shared by keys of different blocks, the table
+/// index has to be looked up.
+const HASH_SLOT_SHARED: u32 = u32::MAX - 1;
+
+/// Most blocks a range read takes from storage at once. Adjacent blocks are read with a single
+/// read, this bounds the memory it takes and how much a scan that stops early reads in vain.
+const SCAN_BATCH_BLOCKS: usize = 16;
+
+/// The greatest table id generated or seen on disk by this process, see SsTable::generate_id.
+static LAST_ID: Mutex<u128> = Mutex::new(0);
+
+/// How far an id is moved past the previous one when the clock is behind. Compaction output
+/// takes the id right after its newest input, so generated ids leave room for it. The step
+/// only touches random bits of the id.
+const ID_STEP: u128 = 1 << 32;
 
 /// SsTable is meant to be used the following way. Typical lifecicle of an instance
 /// can be described as a set of calls: build -> encode -> persist and then many lookups.
@@ -41,21 +140,64 @@ pub struct SsTable {
     blocks: Vec<Block>,
     pub id: Uuid,
     pub bloom: Bloom<Bytes>,
+    /// How the table is laid out once encoded.
+    pub format: Format,
+    /// Level of the table in the tree, 0 for tables flushed from memtable. Kept in the header,
+    /// so it is only to be set before encode.
+    pub level: u8,
+    /// Ids of the tables this one replaces, kept in the header. Only set by compaction on the
+    /// last table it writes.
+    pub replaces: Vec<Uuid>,
+}
+
+/// How a table is laid out once encoded, set from Config for every table built.
+#[derive(Debug, Clone, Copy)]
+pub struct Format {
+    /// Put a hash index to the table (see Config::hash_index).
+    pub hash_index: bool,
+    /// Compress blocks (see Config::compression).
+    pub compression: bool,
+    /// False positive rate the bloom filter is sized for (see Config::bloom_fp_rate).
+    pub bloom_fp_rate: f64,
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Format {
+            hash_index: false,
+            compression: false,
+            bloom_fp_rate: bloom::PROBABILITY,
+        }
+    }
 }
 
 impl SsTable {
-    pub fn build(src: MemTable) -> Self {
-        assert!(src.is_full(), "Flushing a memtable that is not full yet");
+    pub fn build<M: OrderedStore>(src: MemTable<M>) -> Self {
+        Self::build_with_id(src, Self::generate_id(&SystemClock))
+    }
+
+    /// Same as build but the table gets the given id instead of a new one. Compaction uses it
+    /// to put the merged table in place of its inputs in the tables order.
+    pub fn build_with_id<M: OrderedStore>(src: MemTable<M>, id: Uuid) -> Self {
+        Self::build_with_format(src, id, Format::default())
+    }
+
+    /// Same as build_with_id for a table laid out the given way. The bloom filter is sized for
+    /// the keys of the table at build time, the rest of the format only matters to encode.
+    pub fn build_with_format<M: OrderedStore>(src: MemTable<M>, id: Uuid, format: Format) -> Self {
+        assert!(!src.is_empty(), "Flushing an empty memtable");
 
         let mut blocks = Vec::new();
-        let mut bf = bloom::new();
+        let mut bf = bloom::new(src.len(), format.bloom_fp_rate);
         let mut cur_block = Block::new();
 
-        for (k, v) in src.map.iter() {
-            if !cur_block.add(k.clone(), v.clone()) {
+        for (k, v) in src.iter() {
+            let seq = src.seq(k).unwrap_or_default();
+            let flags = src.flags(k);
+            if !cur_block.add(k.clone(), v.clone(), seq, flags) {
                 blocks.push(cur_block); // Block is full. Put it to the blocks vector.
                 cur_block = Block::new(); // Replace current block with an empty one.
-                cur_block.add(k.clone(), v.clone()); // Put the value to a new block.
+                cur_block.add(k.clone(), v.clone(), seq, flags); // Put the value to a new block.
             }
 
             bf.set(k);
@@ -64,91 +206,1006 @@ impl SsTable {
         blocks.push(cur_block); // Finalize with the last block to add.
 
         Self {
-            id: Self::generate_id(),
+            id,
             blocks,
             bloom: bf,
+            format,
+            level: 0,
+            replaces: Vec::new(),
         }
     }
 
     pub fn encode(&self) -> Vec<u8> {
         let mut offset = 0;
         let mut blocks_encoded = Vec::<u8>::new();
-        let mut index = TableIndex::new();
+        let mut index = Vec::new();
         for block in self.blocks.as_slice() {
-            index.0.push(IndexEntry::new(
-                offset,
+            let block_encoded = block.encode(self.format.compression);
+            index.push(IndexEntry::new(
+                BlockPos {
+                    offset,
+                    len: block_encoded.len() as u16,
+                },
                 block.first_key.clone(),
                 block.last_key.clone(),
             ));
 
-            let block_encoded = block.encode();
             offset += block_encoded.len() as u32;
             blocks_encoded.extend(block_encoded);
         }
 
-        let mut content = self.bloom.encode();
-        content.extend(index.encode());
+        let hash_index = self
+            .format
+            .hash_index
+            .then(|| self.build_hash_index(&index));
+
+        let bloom = self.bloom.encode();
+        let mut content = Vec::with_capacity(U32_SIZE + 2 + self.replaces.len() * 16 + bloom.len());
+        content.put_u32(FORMAT_VERSION << VERSION_SHIFT | bloom.len() as u32);
+        content.put_u16(self.replaces.len() as u16);
+        for id in &self.replaces {
+            content.put_u128(id.as_u128());
+        }
+        content.extend(bloom);
+        content.extend(encode_index(index, hash_index, self.level));
         content.extend(blocks_encoded);
 
         content
     }
 
-    /// Generates a simple and time ordered uuid (v7).
-    fn generate_id() -> Uuid {
-        Uuid::now_v7()
+    /// Builds the hash index for blocks at the given index entries.
+    fn build_hash_index(&self, index: &[IndexEntry]) -> HashIndex {
+        let entries: Vec<_> = self.blocks.iter().map(|block| block.entries()).collect();
+        let mut hash_index = HashIndex::new(entries.iter().map(Vec::len).sum());
+        for (block, entry) in entries.iter().zip(index) {
+            for (key, _, _, _) in block {
+                hash_index.insert(key, entry.pos());
+            }
+        }
+
+        hash_index
     }
 
-    pub fn lookup(blob: &impl StorageEntry, key: &Bytes) -> Result<Option<Bytes>> {
-        if let (true, index_len) = Self::probe_bloom(blob, key)? {
-            if let Some(offset) = Self::lookup_index(blob, index_len as usize, key)? {
-                let block = Self::read_block(blob, index_len, offset)?;
-                return Ok(block.get(key.clone()));
+    /// Checks that the table is laid out the way lookups expect it to be: every block is
+    /// non-empty and fits the block size, keys are sorted across the whole table so that block
+    /// ranges in the index don't overlap, and the bloom filter knows every key. A table that
+    /// fails it would return wrong results once persisted.
+    pub fn validate(&self) -> Result<()> {
+        if self.blocks.is_empty() {
+            Err(crate::Error::Corrupted("table has no blocks".into()))?
+        }
+
+        let mut prev_key: Option<Bytes> = None;
+        for (num, block) in self.blocks.iter().enumerate() {
+            if block.is_empty() {
+                Err(crate::Error::Corrupted(format!("block #{} is empty", num)))?
+            }
+
+            if block.encoded_len() > block::BLOCK_BYTE_SIZE {
+                Err(crate::Error::Corrupted(format!(
+                    "block #{} takes {} bytes, more than a block can hold",
+                    num,
+                    block.encoded_len()
+                )))?
+            }
+
+            let entries = block.entries();
+            if entries.first().map(|e| &e.0) != Some(&block.first_key)
+                || entries.last().map(|e| &e.0) != Some(&block.last_key)
+            {
+                Err(crate::Error::Corrupted(format!(
+                    "block #{} key range doesn't match its keys",
+                    num
+                )))?
+            }
+
+            for (key, _, _, _) in entries {
+                if prev_key.as_ref().is_some_and(|prev| *prev >= key) {
+                    Err(crate::Error::Corrupted(format!(
+                        "key {:?} in block #{} is out of order",
+                        key, num
+                    )))?
+                }
+
+                if !self.bloom.check(&key) {
+                    Err(crate::Error::Corrupted(format!(
+                        "key {:?} is missing in bloom filter",
+                        key
+                    )))?
+                }
+
+                prev_key = Some(key);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Generates a simple and time ordered uuid (v7) out of the clock time. Tables order relies
+    /// on ids growing, so if the clock steps back the new id is put right after the previous one
+    /// instead (see ID_STEP) until the clock catches up.
+    pub fn generate_id(clock: &dyn Clock) -> Uuid {
+        let now = clock.since_epoch();
+        let candidate = Uuid::new_v7(Timestamp::from_unix(
+            NoContext,
+            now.as_secs(),
+            now.subsec_nanos(),
+        ));
+
+        let mut last = LAST_ID.lock().unwrap_or_else(|e| e.into_inner());
+        monotonic_id(&mut last, candidate)
+    }
+
+    /// Makes ids generated from now on sort after the given one. Dispatcher calls it with the
+    /// newest table on disk, so that a clock that stepped back between restarts doesn't put new
+    /// tables behind the old ones.
+    pub fn observe_id(id: &Uuid) {
+        let mut last = LAST_ID.lock().unwrap_or_else(|e| e.into_inner());
+        *last = (*last).max(id.as_u128());
+    }
+
+    /// Returns the newest value of the key in the table. A key deleted in the table is found
+    /// as a tombstone, so that callers don't look for it in older tables. So is a value expired
+    /// by now, unix time in milliseconds. With a cache the block holding the key is taken from
+    /// it if it is there, and put to it otherwise.
+    pub fn lookup(
+        blob: &impl StorageEntry,
+        key: &Bytes,
+        now: u64,
+        cache: Option<&TableCache>,
+    ) -> Result<Option<Value>> {
+        Ok(Self::lookup_versioned(blob, key, cache)?.map(|(value, _, _)| value.expire(now)))
+    }
+
+    /// Same as lookup, along with the flags the value was set with.
+    pub fn lookup_typed(
+        blob: &impl StorageEntry,
+        key: &Bytes,
+        now: u64,
+        cache: Option<&TableCache>,
+    ) -> Result<Option<(Value, u8)>> {
+        Ok(Self::lookup_versioned(blob, key, cache)?
+            .map(|(value, _, flags)| (value.expire(now), flags)))
+    }
+
+    /// Same as lookup, but the value is only returned if it was put no later than the given
+    /// sequence number.
+    pub fn lookup_at(
+        blob: &impl StorageEntry,
+        key: &Bytes,
+        seq: u64,
+        now: u64,
+        cache: Option<&TableCache>,
+    ) -> Result<Option<Value>> {
+        match Self::lookup_versioned(blob, key, cache)? {
+            Some((value, value_seq, _)) if value_seq <= seq => Ok(Some(value.expire(now))),
+            _ => Ok(None),
+        }
+    }
+
+    fn lookup_versioned(
+        blob: &impl StorageEntry,
+        key: &Bytes,
+        cache: Option<&TableCache>,
+    ) -> Result<Option<(Value, u64, u8)>> {
+        if let (true, header) = Self::probe_bloom(blob, key)? {
+            let offset = match header.hash_slots {
+                0 => Self::lookup_index(blob, header, key)?,
+                _ => match Self::lookup_hash(blob, header, key)? {
+                    HashProbe::Found(pos) => Some(pos),
+                    HashProbe::Missing => None,
+                    HashProbe::Unknown => Self::lookup_index(blob, header, key)?,
+                },
+            };
+            if let Some(pos) = offset {
+                let read = || Self::read_block(blob, &header, pos);
+                let value = match cache {
+                    Some(cache) => cache.get_or_read(pos.offset, read)?.get(key.clone()),
+                    None => read()?.get(key.clone()),
+                };
+                return Ok(value);
             }
         }
 
         Ok(None)
     }
 
-    /// Reads the bloom filter and a couple extra bytes from the table index to get the table
-    /// index len for the next call if it will be necessary. Reading index len in advance is made
-    /// to avoid extra read from disk on the next step.
-    fn probe_bloom(blob: &impl StorageEntry, key: &Bytes) -> Result<(bool, u16)> {
-        let mut data = vec![0; FIRST_READ_LEN];
+    /// Reads the whole table back to memory, values come along with their sequence numbers.
+    /// Tombstones are kept, they have to mask the key in older tables the result is merged with.
+    /// It is meant for compaction, lookups should never need it.
+    pub fn decode(blob: &impl StorageEntry) -> Result<BTreeMap<Bytes, (Value, u64, u8)>> {
+        let (index, header, _) = Self::read_index(blob)?;
+
+        let mut map = BTreeMap::new();
+        for entry in index.0 {
+            let block = Self::read_block(blob, &header, entry.pos())?;
+            map.extend(
+                block
+                    .entries()
+                    .into_iter()
+                    .map(|(key, value, seq, flags)| (key, (value, seq, flags))),
+            );
+        }
+
+        Ok(map)
+    }
+
+    /// Counts keys of the table within [start, end) that are live as of now, unix time in
+    /// milliseconds. Only blocks that overlap the range are read.
+    pub fn count_range(
+        blob: &impl StorageEntry,
+        start: &Bytes,
+        end: &Bytes,
+        now: u64,
+    ) -> Result<u64> {
+        let (index, header, _) = Self::read_index(blob)?;
+
+        let blocks: Vec<BlockPos> = index
+            .0
+            .iter()
+            .filter(|entry| entry.last_key >= start && entry.first_key < end)
+            .map(IndexEntry::pos)
+            .collect();
+
+        let mut count = 0;
+        for batch in blocks.chunks(SCAN_BATCH_BLOCKS) {
+            for block in Self::read_blocks(blob, &header, batch)? {
+                count += block.count_range(start, end, now) as u64;
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Returns first keys of the table within [start, end) along with whether they are live as
+    /// of now, up to limit live ones and the deleted or expired ones in between. Only blocks that
+    /// overlap the range are read, reading stops once there are enough keys.
+    pub fn keys_range(
+        blob: &impl StorageEntry,
+        start: &Bytes,
+        end: &Bytes,
+        limit: usize,
+        now: u64,
+    ) -> Result<Vec<(Bytes, bool)>> {
+        let (index, header, _) = Self::read_index(blob)?;
+
+        let blocks: Vec<BlockPos> = index
+            .0
+            .iter()
+            .skip_while(|entry| entry.last_key < start)
+            .take_while(|entry| entry.first_key < end)
+            .map(IndexEntry::pos)
+            .collect();
+
+        let mut keys = Vec::new();
+        let mut live_keys = 0;
+        'read: for batch in blocks.chunks(SCAN_BATCH_BLOCKS) {
+            for block in Self::read_blocks(blob, &header, batch)? {
+                for (key, live) in block.keys_range(start, end, now) {
+                    if live_keys == limit {
+                        break 'read;
+                    }
+                    live_keys += live as usize;
+                    keys.push((key, live));
+                }
+            }
+        }
+
+        Ok(keys)
+    }
+
+    /// Same as keys_range along with the values of the keys, a deleted or expired key comes
+    /// with a tombstone.
+    pub fn entries_range(
+        blob: &impl StorageEntry,
+        start: &Bytes,
+        end: &Bytes,
+        limit: usize,
+        now: u64,
+    ) -> Result<Vec<(Bytes, Value)>> {
+        let (index, header, _) = Self::read_index(blob)?;
+
+        let blocks: Vec<BlockPos> = index
+            .0
+            .iter()
+            .skip_while(|entry| entry.last_key < start)
+            .take_while(|entry| entry.first_key < end)
+            .map(IndexEntry::pos)
+            .collect();
+
+        let mut entries = Vec::new();
+        let mut live_entries = 0;
+        'read: for batch in blocks.chunks(SCAN_BATCH_BLOCKS) {
+            for block in Self::read_blocks(blob, &header, batch)? {
+                for (key, value) in block.entries_range(start, end) {
+                    if live_entries == limit {
+                        break 'read;
+                    }
+                    let value = value.expire(now);
+                    live_entries += !value.is_tombstone() as usize;
+                    entries.push((key, value));
+                }
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Reads the layout of the table for debugging and inspection. Unlike the other reads it
+    /// does not panic on damaged blocks, whether their checksums match is reported instead.
+    /// Table index is required to find the blocks, so a damaged index is an error.
+    pub fn inspect(blob: &impl StorageEntry) -> Result<TableReport> {
+        let (index, header, index_pages) = Self::read_index(blob)?;
+
+        let (bloom, _) = Self::read_header(blob)?;
+        let bloom_stats = Bloom::decode(&bloom).ok().map(|bloom| bloom::stats(&bloom));
+
+        let mut blocks = Vec::with_capacity(index.0.len());
+        for entry in index.0 {
+            let data = Self::read_block_data(blob, &header, entry.pos())?;
+            blocks.push(BlockReport {
+                offset: entry.offset,
+                len: entry.len,
+                first_key: entry.first_key,
+                last_key: entry.last_key,
+                entries: block::entries_num(&data),
+                compressed: block::is_compressed(&data),
+                checksum_ok: block::checksum_ok(&data),
+            });
+        }
+
+        Ok(TableReport {
+            level: header.level,
+            bloom_len: header.bloom_len as usize,
+            bloom: bloom_stats,
+            index_len: header.index_len,
+            index_pages,
+            hash_slots: header.hash_slots,
+            blocks,
+        })
+    }
+
+    /// Checks a persisted table the way validate checks a built one, without panicking on damage:
+    /// checksums of the bloom filter, the table index and every block, the table size, the order
+    /// of keys and that the bloom filter knows them. The first problem found is returned as
+    /// Corrupted, a table that is cut short fails to be read.
+    pub fn check(blob: &impl StorageEntry) -> Result<()> {
+        let size = blob.size()?;
+        let (bloom, header) = Self::read_header(blob)?;
+        if !bloom::checksum_ok(&bloom) {
+            Err(crate::Error::Corrupted(
+                "checksum mismatch in bloom filter".into(),
+            ))?
+        }
+        if header.block_position(0) > size {
+            Err(crate::Error::Corrupted(format!(
+                "table index takes {} bytes, more than the table has",
+                header.index_len
+            )))?
+        }
+
+        let report = Self::inspect(blob)?;
+        if let Some(num) = report.blocks.iter().position(|block| !block.checksum_ok) {
+            Err(crate::Error::Corrupted(format!(
+                "checksum mismatch in block #{}",
+                num
+            )))?
+        }
+
+        let expected = header.block_position(0)
+            + report
+                .blocks
+                .iter()
+                .map(|block| block.len as u64)
+                .sum::<u64>();
+        if size != expected {
+            Err(crate::Error::Corrupted(format!(
+                "table takes {} bytes, {} expected",
+                size, expected
+            )))?
+        }
+
+        let bloom = Bloom::decode(&bloom)?;
+        let mut prev_key: Option<Bytes> = None;
+        for (num, entry) in report.blocks.iter().enumerate() {
+            let pos = BlockPos {
+                offset: entry.offset,
+                len: entry.len,
+            };
+            let entries = Self::read_block(blob, &header, pos)?.entries();
+            if entries.first().map(|e| &e.0) != Some(&entry.first_key)
+                || entries.last().map(|e| &e.0) != Some(&entry.last_key)
+            {
+                Err(crate::Error::Corrupted(format!(
+                    "block #{} keys don't match the table index",
+                    num
+                )))?
+            }
+
+            for (key, _, _, _) in entries {
+                if prev_key.as_ref().is_some_and(|prev| *prev >= key) {
+                    Err(crate::Error::Corrupted(format!(
+                        "key {:?} in block #{} is out of order",
+                        key, num
+                    )))?
+                }
+
+                if !bloom.check(&key) {
+                    Err(crate::Error::Corrupted(format!(
+                        "key {:?} is missing in bloom filter",
+                        key
+                    )))?
+                }
+
+                prev_key = Some(key);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads the whole table index, all of its pages, along with the header and the number of
+    /// pages. A damaged index is an error.
+    fn read_index(blob: &impl StorageEntry) -> Result<(TableIndex, Header, usize)> {
+        let (_, header) = Self::read_header(blob)?;
+
+        let mut data = vec![0; header.index_len as usize];
+        blob.read_at(&mut data, header.index_start())?;
+
+        let top_data = data
+            .get(U32_SIZE..U32_SIZE + header.top_len as usize)
+            .ok_or_else(|| crate::Error::Corrupted("top index is cut".into()))?;
+        let top = TopIndex::decode(top_data)?;
+
+        let mut index = TableIndex::new();
+        for (num, page) in top.0.iter().enumerate() {
+            let start = page.offset as usize;
+            let page = data
+                .get(start..start + page.len as usize)
+                .and_then(|raw| TableIndex::decode(raw).ok())
+                .ok_or_else(|| {
+                    crate::Error::Corrupted(format!("checksum mismatch in index page #{}", num))
+                })?;
+            index.0.extend(page.0);
+        }
+
+        Ok((index, header, top.0.len()))
+    }
+
+    /// Reads where the table goes in the tree: its level, the first and the last key it holds and
+    /// the tables it replaces. Only the header and the top index are read. A damaged top index is
+    /// an error.
+    pub fn placement(blob: &impl StorageEntry) -> Result<Placement> {
+        let (_, header) = Self::read_header(blob)?;
+
+        let mut replaces = Vec::with_capacity(header.replaced as usize);
+        if header.replaced > 0 {
+            let mut data = vec![0; header.replaced as usize * 16];
+            blob.read_at(&mut data, (U32_SIZE + 2) as u64)?;
+            let mut buf = Cursor::new(&data);
+            while buf.has_remaining() {
+                replaces.push(Uuid::from_u128(buf.get_u128()));
+            }
+        }
+
+        let mut data = vec![0; header.top_len as usize];
+        blob.read_at(&mut data, header.index_start() + U32_SIZE as u64)?;
+
+        let top = TopIndex::decode(&data)?;
+        match (top.0.first(), top.0.last()) {
+            (Some(first), Some(last)) => Ok(Placement {
+                level: header.level,
+                first_key: first.first_key.clone(),
+                last_key: last.last_key.clone(),
+                replaces,
+            }),
+            _ => Err(crate::Error::Corrupted("table index is empty".into())),
+        }
+    }
+
+    /// Reads the bloom filter and the lengths of the table index and the top index for the next
+    /// call if it will be necessary. Reading them in advance is made to avoid extra read from
+    /// disk on the next step.
+    fn probe_bloom(blob: &impl StorageEntry, key: &Bytes) -> Result<(bool, Header)> {
+        let (bloom, header) = Self::read_header(blob)?;
+        let b = Bloom::decode(&bloom)?;
+
+        Ok((b.check(key), header))
+    }
+
+    /// Reads the encoded bloom filter along with the header that follows it. Bloom filter len
+    /// and the replaced num come first, so it takes two reads. Replaced ids are skipped.
+    fn read_header(blob: &impl StorageEntry) -> Result<(Vec<u8>, Header)> {
+        let mut data = vec![0; U32_SIZE + 2];
         blob.read_at(&mut data, 0)?;
+        let mut buf = Cursor::new(&data);
+        let field = buf.get_u32();
+        let replaced = buf.get_u16();
+        let version = field >> VERSION_SHIFT;
+        if version != FORMAT_VERSION {
+            Err(crate::Error::Corrupted(format!(
+                "unsupported table format v{}",
+                version
+            )))?
+        }
+        let bloom_len = field & BLOOM_LEN_MASK;
 
-        let mut index_len_bytes: [u8; 2] = [0, 0];
-        index_len_bytes.copy_from_slice(&data[bloom::ENCODED_LEN..]);
-        let index_len = u16::from_be_bytes(index_len_bytes);
-        let b = Bloom::decode(&data[..bloom::ENCODED_LEN]);
+        let mut data = vec![0; bloom_len as usize + 2 * U32_SIZE];
+        blob.read_at(&mut data, (U32_SIZE + 2 + replaced as usize * 16) as u64)?;
+        let header = Header::decode(bloom_len, replaced, &data[bloom_len as usize..]);
+        data.truncate(bloom_len as usize);
 
-        Ok((b.check(key), index_len))
+        Ok((data, header))
     }
 
-    fn lookup_index(blob: &impl StorageEntry, len: usize, key: &Bytes) -> Result<Option<u32>> {
-        let mut data = vec![0; len];
-        blob.read_at(&mut data, bloom::ENCODED_LEN as u64)?;
+    /// Finds the block that may hold the key. Only the top index and the page it points to are
+    /// read.
+    fn lookup_index(
+        blob: &impl StorageEntry,
+        header: Header,
+        key: &Bytes,
+    ) -> Result<Option<BlockPos>> {
+        let mut data = vec![0; header.top_len as usize];
+        blob.read_at(&mut data, header.index_start() + U32_SIZE as u64)?;
+
+        let top = TopIndex::decode(&data)?;
+        let page = match top
+            .0
+            .into_iter()
+            .find(|p| p.first_key <= key && p.last_key >= key)
+        {
+            Some(page) => page,
+            None => return Ok(None),
+        };
+
+        let mut data = vec![0; page.len as usize];
+        blob.read_at(&mut data, header.index_start() + page.offset as u64)?;
 
-        // TODO: Could be optimised so that offset will be returned immediately when it is found.
-        // Wont add much to performance though.
-        let index = TableIndex::decode(&data);
-        let entry = index
+        let entry = TableIndex::decode(&data)?
             .0
             .into_iter()
             .find(|e| e.first_key <= key && e.last_key >= key);
-        match entry {
-            Some(IndexEntry { offset, .. }) => Ok(Some(offset)),
-            None => Ok(None),
+        Ok(entry.as_ref().map(IndexEntry::pos))
+    }
+
+    /// Looks the key up in the hash index, only the probe window of the key is read.
+    fn lookup_hash(blob: &impl StorageEntry, header: Header, key: &Bytes) -> Result<HashProbe> {
+        let slots_start = header.index_len as usize - HashIndex::encoded_len(header.hash_slots);
+        let (slot, fingerprint) = hash_slot(key, header.hash_slots);
+
+        let mut data = vec![0; HASH_PROBE_SLOTS * HASH_SLOT_SIZE];
+        let position = header.index_start() + (slots_start + slot * HASH_SLOT_SIZE) as u64;
+        blob.read_at(&mut data, position)?;
+
+        Ok(HashIndex::probe(&data, fingerprint))
+    }
+
+    fn read_block(blob: &impl StorageEntry, header: &Header, pos: BlockPos) -> Result<Block> {
+        let data = Self::read_block_data(blob, header, pos)?;
+
+        Block::decode(&data)
+    }
+
+    fn read_block_data(
+        blob: &impl StorageEntry,
+        header: &Header,
+        pos: BlockPos,
+    ) -> Result<Vec<u8>> {
+        let mut data = vec![0; pos.len as usize];
+        blob.read_at(&mut data, header.block_position(pos.offset))?;
+
+        Ok(data)
+    }
+
+    /// Reads the given blocks in ascending order. Blocks lie one after another in the blocks
+    /// section, so every run of adjacent ones is taken with a single read and split in memory.
+    /// It saves round trips on storages where every read is a request.
+    fn read_blocks(
+        blob: &impl StorageEntry,
+        header: &Header,
+        positions: &[BlockPos],
+    ) -> Result<Vec<Block>> {
+        let mut blocks = Vec::with_capacity(positions.len());
+
+        let mut rest = positions;
+        while let Some(&first) = rest.first() {
+            let run = 1 + rest
+                .windows(2)
+                .take_while(|pair| pair[1].offset == pair[0].offset + pair[0].len as u32)
+                .count();
+
+            let run_len: usize = rest[..run].iter().map(|pos| pos.len as usize).sum();
+            let mut data = vec![0; run_len];
+            blob.read_at(&mut data, header.block_position(first.offset))?;
+            let mut start = 0;
+            for pos in &rest[..run] {
+                blocks.push(Block::decode(&data[start..start + pos.len as usize])?);
+                start += pos.len as usize;
+            }
+
+            rest = &rest[run..];
         }
+
+        Ok(blocks)
     }
+}
+
+/// Returns the candidate id if it sorts after the last one, or the last one moved by ID_STEP
+/// otherwise. The last id is updated to the returned one.
+fn monotonic_id(last: &mut u128, candidate: Uuid) -> Uuid {
+    let id = if candidate.as_u128() > *last {
+        candidate.as_u128()
+    } else {
+        *last + ID_STEP
+    };
+    *last = id;
+    Uuid::from_u128(id)
+}
+
+/// Where a persisted table goes in the tree as SsTable::placement reads it.
+#[derive(Debug, PartialEq)]
+pub struct Placement {
+    pub level: u8,
+    pub first_key: Bytes,
+    pub last_key: Bytes,
+    /// Ids of the tables the table replaces, see SsTable::replaces.
+    pub replaces: Vec<Uuid>,
+}
 
-    fn read_block(blob: &impl StorageEntry, index_len: u16, offset: u32) -> Result<Block> {
-        let mut data = vec![0; block::BLOCK_BYTE_SIZE];
-        // Offsets are being set in index relative to Data Section start, so to get offset
-        // relative to the whole blob start we need to sum up bloom filter length and index length.
-        let offset = index_len as u32 + bloom::ENCODED_LEN as u32 + offset;
-        blob.read_at(&mut data, offset as u64)?;
+/// Layout of a persisted table as SsTable::inspect sees it.
+#[derive(Debug)]
+pub struct TableReport {
+    pub level: u8,
+    pub bloom_len: usize,
+    /// None if the filter checksum doesn't match.
+    pub bloom: Option<BloomStats>,
+    pub index_len: u32,
+    pub index_pages: usize,
+    /// Number of hash index slots keys hash to, zero if the table has no hash index.
+    pub hash_slots: usize,
+    pub blocks: Vec<BlockReport>,
+}
 
-        Ok(Block::decode(&data))
+/// A single block of a table along with what the table index says about it.
+#[derive(Debug)]
+pub struct BlockReport {
+    /// Offset of the block relative to the blocks section start.
+    pub offset: u32,
+    pub len: u16,
+    pub first_key: Bytes,
+    pub last_key: Bytes,
+    pub entries: u16,
+    pub compressed: bool,
+    pub checksum_ok: bool,
+}
+
+impl std::fmt::Display for TableReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
+        writeln!(f, "level: {}", self.level)?;
+        match self.bloom {
+            Some(stats) => writeln!(
+                f,
+                "bloom filter: {} bytes, {:.1}% bits set, estimated fp rate {:.3}%",
+                self.bloom_len,
+                stats.fill_ratio() * 100.0,
+                stats.fp_rate() * 100.0
+            )?,
+            None => writeln!(
+                f,
+                "bloom filter: {} bytes, checksum MISMATCH",
+                self.bloom_len
+            )?,
+        }
+        writeln!(
+            f,
+            "table index: {} bytes, {} pages, {} entries",
+            self.index_len,
+            self.index_pages,
+            self.blocks.len()
+        )?;
+        if self.hash_slots > 0 {
+            writeln!(f, "hash index: {} slots", self.hash_slots)?;
+        }
+
+        for (num, block) in self.blocks.iter().enumerate() {
+            writeln!(
+                f,
+                "block #{}: offset {}, {} bytes{}, {} entries, keys {:?}..={:?}, checksum {}",
+                num,
+                block.offset,
+                block.len,
+                if block.compressed { " compressed" } else { "" },
+                block.entries,
+                block.first_key,
+                block.last_key,
+                if block.checksum_ok { "ok" } else { "MISMATCH" }
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the slot the key hashes to among the given number of slots, which is a power of two,
+/// and the fingerprint of the key. Hash is FNV-1a, it has to stay the same for tables on disk.
+fn hash_slot(key: &[u8], slots_num: usize) -> (usize, u32) {
+    let hash = key.iter().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    });
+
+    (hash as usize & (slots_num - 1), (hash >> 32) as u32)
+}
+
+/// What the hash index tells about a key.
+#[derive(Debug, PartialEq)]
+enum HashProbe {
+    /// Block that may hold the key.
+    Found(BlockPos),
+    /// Table doesn't hold the key.
+    Missing,
+    /// Hash index can't tell, the table index has to be looked up.
+    Unknown,
+}
+
+/// Hash index of a table being built, see the layout at the top.
+struct HashIndex {
+    /// Number of slots keys hash to.
+    slots_num: usize,
+    /// Fingerprints and blocks, including the slots past slots_num.
+    slots: Vec<(u32, BlockPos)>,
+}
+
+impl HashIndex {
+    /// Makes an empty index with at least twice as many slots as keys.
+    fn new(keys: usize) -> Self {
+        let slots_num = (2 * keys).next_power_of_two().max(2);
+        HashIndex {
+            slots_num,
+            slots: vec![(0, BlockPos::at(HASH_SLOT_EMPTY)); slots_num + HASH_PROBE_SLOTS - 1],
+        }
+    }
+
+    fn encoded_len(slots_num: usize) -> usize {
+        (slots_num + HASH_PROBE_SLOTS - 1) * HASH_SLOT_SIZE
+    }
+
+    /// Puts the key to the first free slot of its probe window. Keys of the same block with
+    /// the same fingerprint share a slot. Fingerprint shared with a key of another block makes
+    /// the slot useless for both, and a key with no free slot is left out of the index, lookups
+    /// of such keys go through the table index.
+    fn insert(&mut self, key: &[u8], pos: BlockPos) {
+        let (start, fingerprint) = hash_slot(key, self.slots_num);
+        for slot in &mut self.slots[start..start + HASH_PROBE_SLOTS] {
+            match *slot {
+                (
+                    _,
+                    BlockPos {
+                        offset: HASH_SLOT_EMPTY,
+                        ..
+                    },
+                ) => {
+                    *slot = (fingerprint, pos);
+                    return;
+                }
+                (f, p) if f == fingerprint => {
+                    if p != pos {
+                        slot.1 = BlockPos::at(HASH_SLOT_SHARED);
+                    }
+                    return;
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(Self::encoded_len(self.slots_num));
+        for (fingerprint, pos) in self.slots.iter() {
+            buf.put_u32(*fingerprint);
+            buf.put_u32(pos.offset);
+            buf.put_u16(pos.len);
+        }
+
+        buf
+    }
+
+    /// Looks for the fingerprint in the encoded probe window of a key.
+    fn probe(window: &[u8], fingerprint: u32) -> HashProbe {
+        let mut buf = Cursor::new(window);
+        while buf.remaining() >= HASH_SLOT_SIZE {
+            match (buf.get_u32(), buf.get_u32(), buf.get_u16()) {
+                (_, HASH_SLOT_EMPTY, _) => return HashProbe::Missing,
+                (f, HASH_SLOT_SHARED, _) if f == fingerprint => return HashProbe::Unknown,
+                (f, offset, len) if f == fingerprint => {
+                    return HashProbe::Found(BlockPos { offset, len })
+                }
+                _ => continue,
+            }
+        }
+
+        HashProbe::Unknown
+    }
+}
+
+/// Encodes the table index section. Index entries are split into pages, pages are put after the
+/// top index that points to them. Hash index slots, if any, go last.
+fn encode_index(entries: Vec<IndexEntry>, hash_index: Option<HashIndex>, level: u8) -> Vec<u8> {
+    let mut top = TopIndex(Vec::new());
+    let mut pages = Vec::new();
+    let mut entries = entries.into_iter().peekable();
+    while entries.peek().is_some() {
+        let page = TableIndex(entries.by_ref().take(INDEX_PAGE_ENTRIES).collect());
+        let encoded = page.encode();
+        top.0.push(TopEntry {
+            offset: 0, // Set once the top index len is known.
+            len: encoded.len() as u16,
+            first_key: page.0[0].first_key.clone(),
+            last_key: page.0[page.0.len() - 1].last_key.clone(),
+        });
+        pages.push(encoded);
+    }
+
+    // Pages go right after the index len and the top index.
+    let mut offset = (U32_SIZE + top.encoded_len()) as u32;
+    for (entry, page) in top.0.iter_mut().zip(&pages) {
+        entry.offset = offset;
+        offset += page.len() as u32;
+    }
+
+    let (hash_encoded, hash_bits) = match hash_index {
+        Some(hash_index) => (hash_index.encode(), hash_index.slots_num.trailing_zeros()),
+        None => (Vec::new(), 0),
+    };
+    let index_len = offset + hash_encoded.len() as u32;
+
+    let mut buf = Vec::with_capacity(index_len as usize);
+    buf.put_u32(index_len | (level as u32) << LEVEL_SHIFT);
+    buf.extend(top.encode(hash_bits));
+    for page in pages {
+        buf.extend(page);
+    }
+    buf.extend(hash_encoded);
+
+    buf
+}
+
+/// Len of the bloom filter along with the lengths put right after it, they are read along with
+/// the filter.
+#[derive(Debug, Clone, Copy)]
+struct Header {
+    bloom_len: u32,
+    /// Number of the replaced ids in front of the bloom filter.
+    replaced: u16,
+    index_len: u32,
+    top_len: u32,
+    /// Number of hash index slots keys hash to, zero if there is no hash index.
+    hash_slots: usize,
+    level: u8,
+}
+
+impl Header {
+    /// Decodes the lengths that follow the bloom filter of the given len.
+    fn decode(bloom_len: u32, replaced: u16, raw: &[u8]) -> Self {
+        let mut buf = Cursor::new(raw);
+        let index_len = buf.get_u32();
+        let top_len = buf.get_u32();
+        let hash_bits = top_len >> HASH_BITS_SHIFT;
+
+        Header {
+            bloom_len,
+            replaced,
+            index_len: index_len & INDEX_LEN_MASK,
+            top_len: top_len & TOP_LEN_MASK,
+            hash_slots: if hash_bits == 0 { 0 } else { 1 << hash_bits },
+            level: (index_len >> LEVEL_SHIFT) as u8,
+        }
+    }
+
+    /// Position of the table index section in the table.
+    fn index_start(&self) -> u64 {
+        (U32_SIZE + 2 + self.replaced as usize * 16 + self.bloom_len as usize) as u64
+    }
+
+    /// Offsets are being set in index relative to Data Section start, so to get offset relative
+    /// to the whole blob start we need to sum up bloom filter length and index length.
+    fn block_position(&self, offset: u32) -> u64 {
+        self.index_start() + self.index_len as u64 + offset as u64
+    }
+}
+
+/// Points to a single page of the table index.
+#[derive(Debug)]
+struct TopEntry {
+    /// Offset of the page relative to the table index section start.
+    offset: u32,
+    len: u16,
+    first_key: Bytes,
+    last_key: Bytes,
+}
+
+#[derive(Debug)]
+struct TopIndex(Vec<TopEntry>);
+
+impl TopIndex {
+    fn encoded_len(&self) -> usize {
+        let entries: usize = self
+            .0
+            .iter()
+            .map(|e| 2 + e.first_key.len() + 2 + e.last_key.len() + U32_SIZE + 2)
+            .sum();
+
+        U32_SIZE + 2 + entries + CHECKSUM_SIZE
+    }
+
+    /// Hash bits are put to the top of the len (see HASH_BITS_SHIFT).
+    fn encode(&self, hash_bits: u32) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.encoded_len());
+
+        let pages_num = self.0.len();
+        assert_ne!(pages_num, 0, "Attempt to encode an empty top index");
+        assert!(
+            self.encoded_len() <= TOP_LEN_MASK as usize,
+            "Top index is too long to encode"
+        );
+
+        buf.put_u32(self.encoded_len() as u32 | hash_bits << HASH_BITS_SHIFT);
+        buf.put_u16(pages_num as u16);
+
+        for entry in self.0.as_slice() {
+            buf.put_u16(entry.first_key.len() as u16);
+            buf.put_slice(entry.first_key.as_ref());
+            buf.put_u16(entry.last_key.len() as u16);
+            buf.put_slice(entry.last_key.as_ref());
+            buf.put_u32(entry.offset);
+            buf.put_u16(entry.len);
+        }
+
+        let checksum = crc32fast::hash(&buf[..]);
+        buf.put_u32(checksum);
+
+        buf
+    }
+
+    /// Tells if the encoded top index is consistent: its checksum matches and it is as long as
+    /// it claims to be.
+    fn checksum_ok(raw: &[u8]) -> bool {
+        if raw.len() < U32_SIZE + 2 + CHECKSUM_SIZE {
+            return false;
+        }
+
+        let (content, checksum) = raw.split_at(raw.len() - CHECKSUM_SIZE);
+        let encoded_len = u32::from_be_bytes([raw[0], raw[1], raw[2], raw[3]]) & TOP_LEN_MASK;
+        encoded_len as usize == raw.len() && crc32fast::hash(content).to_be_bytes() == checksum
+    }
+
+    /// A top index that doesn't check out is Corrupted.
+    fn decode(raw: &[u8]) -> Result<Self> {
+        if !Self::checksum_ok(raw) {
+            Err(crate::Error::Corrupted(
+                "checksum mismatch in top index".into(),
+            ))?
+        }
+
+        let mut buf = Cursor::new(&raw[U32_SIZE..raw.len() - CHECKSUM_SIZE]);
+
+        let mut top = TopIndex(Vec::new());
+        let pages_num = buf.get_u16() as usize;
+        for _ in 0..pages_num {
+            let first_key_len = buf.get_u16() as usize;
+            let first_key = buf.copy_to_bytes(first_key_len);
+            let last_key_len = buf.get_u16() as usize;
+            let last_key = buf.copy_to_bytes(last_key_len);
+            let offset = buf.get_u32();
+            let len = buf.get_u16();
+            top.0.push(TopEntry {
+                offset,
+                len,
+                first_key,
+                last_key,
+            });
+        }
+
+        Ok(top)
     }
 }
 
@@ -156,18 +1213,42 @@ impl SsTable {
 struct IndexEntry {
     /// Offset of a data block.
     pub offset: u32,
+    /// Len of the encoded block.
+    pub len: u16,
     pub first_key: Bytes,
     pub last_key: Bytes,
 }
 
 impl IndexEntry {
-    fn new(offset: u32, first_key: Bytes, last_key: Bytes) -> Self {
+    fn new(pos: BlockPos, first_key: Bytes, last_key: Bytes) -> Self {
         IndexEntry {
-            offset,
+            offset: pos.offset,
+            len: pos.len,
             first_key,
             last_key,
         }
     }
+
+    fn pos(&self) -> BlockPos {
+        BlockPos {
+            offset: self.offset,
+            len: self.len,
+        }
+    }
+}
+
+/// Where an encoded block lies in the blocks section.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct BlockPos {
+    offset: u32,
+    len: u16,
+}
+
+impl BlockPos {
+    /// Position with the given offset and no len, for the hash index slots that point nowhere.
+    fn at(offset: u32) -> Self {
+        BlockPos { offset, len: 0 }
+    }
 }
 
 #[derive(Debug)]
@@ -194,6 +1275,7 @@ impl TableIndex {
             buf.put_u16(entry.last_key.len() as u16);
             buf.put_slice(entry.last_key.as_ref());
             buf.put_u32(entry.offset);
+            buf.put_u16(entry.len);
         }
 
         let index_len = buf.len() + CHECKSUM_SIZE;
@@ -207,18 +1289,27 @@ impl TableIndex {
         buf
     }
 
-    fn decode(raw: &[u8]) -> Self {
-        let mut buf = Cursor::new(raw);
-        let checksum = crc32fast::hash(&raw[..buf.remaining() - CHECKSUM_SIZE]);
+    /// Tells if the encoded index is consistent: its checksum matches and it is as long as it
+    /// claims to be.
+    fn checksum_ok(raw: &[u8]) -> bool {
+        if raw.len() < 2 * std::mem::size_of::<u16>() + CHECKSUM_SIZE {
+            return false;
+        }
 
-        let encoded_len = buf.get_u16();
-        assert_eq!(
-            encoded_len as usize,
-            raw.len(),
-            "Blob len encoded {}, but {} was passed",
-            encoded_len,
-            raw.len()
-        );
+        let (content, checksum) = raw.split_at(raw.len() - CHECKSUM_SIZE);
+        let encoded_len = u16::from_be_bytes([raw[0], raw[1]]);
+        encoded_len as usize == raw.len() && crc32fast::hash(content).to_be_bytes() == checksum
+    }
+
+    /// An index page that doesn't check out is Corrupted.
+    fn decode(raw: &[u8]) -> Result<Self> {
+        if !Self::checksum_ok(raw) {
+            Err(crate::Error::Corrupted(
+                "checksum mismatch in table index".into(),
+            ))?
+        }
+
+        let mut buf = Cursor::new(&raw[2..raw.len() - CHECKSUM_SIZE]);
 
         let mut table_index = TableIndex::new();
         let entries_num = buf.get_u16() as usize;
@@ -228,27 +1319,23 @@ impl TableIndex {
             let last_key_len: usize = buf.get_u16() as usize;
             let last_key = buf.copy_to_bytes(last_key_len);
             let offset = buf.get_u32();
+            let len = buf.get_u16();
             table_index.0.push(IndexEntry {
                 offset,
+                len,
                 first_key,
                 last_key,
             });
         }
 
-        assert_eq!(
-            buf.get_u32(),
-            checksum,
-            "Checksum mismatch in table index decode"
-        );
-
-        table_index
+        Ok(table_index)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::engine::memtable::{MemTable, ProbeResult, SsTableSize};
+    use crate::engine::memtable::{MemTable, ProbeResult, SsTableSize, Value};
     use crate::storage::mem;
     use crate::Storage;
     use bytes::Bytes;
@@ -258,15 +1345,15 @@ mod tests {
 
     /// Generates a full memtable that is filled with UUIDs as keys and values. Returns
     /// a memtable and a random pair of key and value present in the table.
-    fn create_full_memtable(size: SsTableSize) -> (MemTable, Bytes, Bytes) {
+    fn create_full_memtable(size: SsTableSize) -> (MemTable, Bytes, Value) {
         let mut mt = MemTable::new(size);
         loop {
             // Fill it with random simple uuids.
             let key = Bytes::from(Uuid::now_v7().to_string());
-            let value = Bytes::from(Uuid::now_v7().to_string());
+            let value = Value::Live(Bytes::from(Uuid::now_v7().to_string()));
             match mt.probe(&key, &value) {
                 ProbeResult::Available(new_size) => {
-                    mt.insert(key, value, Some(new_size));
+                    mt.insert_typed(key, value, 0, 1, Some(new_size));
                 }
                 ProbeResult::Full => {
                     let internal_map = mt.map.clone();
@@ -311,13 +1398,38 @@ mod tests {
         let blob = open.unwrap();
 
         for key in mt.keys() {
-            let res = SsTable::lookup(&blob, &Bytes::from(key));
+            let res = SsTable::lookup(&blob, &Bytes::from(key), 0, None);
             assert!(res.is_ok(), "lookup err: {:?}", res.err().unwrap());
             let res = res.unwrap();
             assert!(res.is_some());
         }
     }
 
+    #[test]
+    fn test_bloom_fp_rate() {
+        let (mt, _, _) = create_full_memtable(SsTableSize::Is(8 * 1024));
+        let default = SsTable::build(mt.clone()).encode();
+        let format = Format {
+            bloom_fp_rate: 0.001,
+            ..Default::default()
+        };
+        let built = SsTable::build_with_format(mt.clone(), Uuid::now_v7(), format);
+        let encoded = built.encode();
+
+        let default_bloom = SsTable::inspect(&default).unwrap().bloom_len;
+        let report = SsTable::inspect(&encoded).unwrap();
+        assert!(report.bloom_len > default_bloom);
+        assert!(report.bloom.is_some());
+        assert!(SsTable::check(&encoded).is_ok());
+
+        for key in mt.keys() {
+            let res = SsTable::lookup(&encoded, &Bytes::from(key), 0, None).unwrap();
+            assert!(res.is_some());
+        }
+        let res = SsTable::lookup(&encoded, &Bytes::from("missing"), 0, None).unwrap();
+        assert!(res.is_none());
+    }
+
     #[traced_test]
     #[test]
     fn test_probe_bloom_and_lookup_index() {
@@ -327,11 +1439,13 @@ mod tests {
 
         let res = SsTable::probe_bloom(&encoded, &key);
         assert!(res.is_ok(), "probe bloom err: {:?}", res.err().unwrap());
-        let res = res.unwrap();
-        assert!(res.0);
-        assert_eq!(res.1, 168);
+        let (found, header) = res.unwrap();
+        assert!(found);
+        // Index len, a single page of two 36 byte key entries and a top index pointing to it.
+        assert_eq!(header.top_len, 92);
+        assert_eq!(header.index_len, 4 + 92 + 172);
 
-        let res = SsTable::lookup_index(&encoded, 168, &key);
+        let res = SsTable::lookup_index(&encoded, header, &key);
         assert!(res.is_ok(), "lookup index err: {:?}", res.err().unwrap());
 
         let res = res.unwrap();
@@ -343,30 +1457,14 @@ mod tests {
             debug!("memtable keys: {:?}", mt.keys());
 
             // Index
-            let index_len = 168;
-            let mut index_data = vec![0; index_len];
-            encoded
-                .read_at(&mut index_data, bloom::ENCODED_LEN as u64)
-                .unwrap();
-            let index = TableIndex::decode(&index_data);
+            let (index, header, _) = SsTable::read_index(&encoded).unwrap();
             debug!("index: {:?}", index);
 
             // Blocks
-            let block_len = 4096;
-            let mut block_1_data = vec![0; block_len];
-            encoded
-                .read_at(&mut block_1_data, (bloom::ENCODED_LEN + index_len) as u64)
-                .unwrap();
-            let block_1 = Block::decode(&block_1_data);
-            let mut block_2_data = vec![0; block_len];
-            encoded
-                .read_at(
-                    &mut block_2_data,
-                    (bloom::ENCODED_LEN + index_len + block_len) as u64,
-                )
-                .unwrap();
-            let block_2 = Block::decode(&block_2_data);
-            debug!("blocks: {}, {}", block_1, block_2);
+            for entry in index.0 {
+                let block = SsTable::read_block(&encoded, &header, entry.pos()).unwrap();
+                debug!("block: {}", block);
+            }
         }
         // END DEBUG
 
@@ -388,23 +1486,589 @@ mod tests {
         assert!(res.is_ok(), "probe bloom err: {:?}", res.err().unwrap());
         let res = res.unwrap();
         assert!(res.0);
-        assert_eq!(res.1, 168);
+        assert_eq!(res.1.index_len, 268);
+    }
+
+    #[test]
+    fn test_decode() {
+        let (mt, _, _) = create_full_memtable(SsTableSize::Is(8 * 1024));
+        let built = SsTable::build(mt.clone());
+        let encoded = built.encode();
+
+        let decoded = SsTable::decode(&encoded);
+        assert!(decoded.is_ok(), "decode err: {:?}", decoded.err().unwrap());
+        let expected: BTreeMap<Bytes, (Value, u64, u8)> = mt
+            .map
+            .iter()
+            .map(|(key, value)| (key.clone(), (value.clone(), 1, 0)))
+            .collect();
+        assert_eq!(decoded.unwrap(), expected);
+    }
+
+    #[test]
+    fn test_validate() {
+        let (mt, _, _) = create_full_memtable(SsTableSize::Is(8 * 1024));
+        let mut table = SsTable::build(mt);
+        assert!(table.validate().is_ok());
+
+        table.blocks.swap(0, 1);
+        assert!(table.validate().is_err(), "blocks are out of order");
+        table.blocks.swap(0, 1);
+
+        table.blocks.push(Block::new());
+        assert!(table.validate().is_err(), "block is empty");
+        table.blocks.pop();
+
+        table.bloom = bloom::new(1, bloom::PROBABILITY);
+        assert!(table.validate().is_err(), "keys are not in bloom filter");
+    }
+
+    #[test]
+    fn test_check() {
+        let (mt, _, _) = create_full_memtable(SsTableSize::Is(8 * 1024));
+        let mut table = SsTable::build(mt);
+        let encoded = table.encode();
+        assert!(SsTable::check(&encoded).is_ok());
+
+        let mut damaged = encoded.clone();
+        damaged[10] ^= 1;
+        assert!(SsTable::check(&damaged).is_err(), "bloom filter is damaged");
+
+        let mut damaged = encoded.clone();
+        let len = damaged.len();
+        damaged[len - 100] ^= 1;
+        assert!(SsTable::check(&damaged).is_err(), "last block is damaged");
+
+        let truncated = encoded[..encoded.len() - block::BLOCK_BYTE_SIZE].to_vec();
+        assert!(SsTable::check(&truncated).is_err(), "last block is gone");
+
+        let mut padded = encoded.clone();
+        padded.extend([0; 10]);
+        assert!(SsTable::check(&padded).is_err(), "table is longer");
+
+        table.blocks.swap(0, 1);
+        assert!(
+            SsTable::check(&table.encode()).is_err(),
+            "blocks are out of order"
+        );
+    }
+
+    #[test]
+    fn test_lookup_damaged() {
+        let mut mt = MemTable::new(SsTableSize::Default);
+        mt.insert(Bytes::from("foo"), Bytes::from("bar"), 1, None);
+        let mut encoded = SsTable::build(mt).encode();
+
+        let len = encoded.len();
+        encoded[len - 100] ^= 1;
+        let res = SsTable::lookup(&encoded, &Bytes::from("foo"), 0, None);
+        assert!(matches!(res, Err(crate::Error::Corrupted(_))), "{:?}", res);
+        let res = SsTable::decode(&encoded);
+        assert!(matches!(res, Err(crate::Error::Corrupted(_))), "{:?}", res);
+
+        encoded[len - 100] ^= 1;
+        encoded[10] ^= 1;
+        let res = SsTable::lookup(&encoded, &Bytes::from("foo"), 0, None);
+        assert!(matches!(res, Err(crate::Error::Corrupted(_))), "{:?}", res);
+    }
+
+    #[test]
+    fn test_lookup_at() {
+        let mut mt = MemTable::new(SsTableSize::Default);
+        mt.insert(Bytes::from("foo"), Bytes::from("bar"), 10, None);
+        let encoded = SsTable::build(mt).encode();
+
+        let key = Bytes::from("foo");
+        assert_eq!(
+            SsTable::lookup_at(&encoded, &key, 10, 0, None).unwrap(),
+            Some(Value::Live(Bytes::from("bar")))
+        );
+        assert_eq!(
+            SsTable::lookup_at(&encoded, &key, 9, 0, None).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_format_version() {
+        let mut mt = MemTable::new(SsTableSize::Default);
+        mt.insert(Bytes::from("buddha"), Bytes::from("om"), 1, None);
+        let mut encoded = SsTable::build(mt).encode();
+        assert_eq!(
+            u32::from_be_bytes(encoded[..U32_SIZE].try_into().unwrap()) >> VERSION_SHIFT,
+            FORMAT_VERSION
+        );
+
+        // Any version but the current one is refused, zero included.
+        for (first_byte, version) in [(0x00, 0), (0x10, 1), (0x30, 3)] {
+            encoded[0] = first_byte;
+            let err = SsTable::lookup(&encoded, &Bytes::from("buddha"), 0, None).unwrap_err();
+            assert!(matches!(err, crate::Error::Corrupted(_)));
+            assert_eq!(
+                err.to_string(),
+                format!("unsupported table format v{}", version)
+            );
+            assert!(SsTable::inspect(&encoded).is_err());
+        }
+    }
+
+    #[test]
+    fn test_inspect() {
+        let mut mt = MemTable::new(SsTableSize::Default);
+        mt.insert(Bytes::from("buddha"), Bytes::from("om"), 1, None);
+        mt.insert(Bytes::from("dharma"), Bytes::from("ah"), 2, None);
+        mt.insert(Bytes::from("sangha"), Bytes::from("hum"), 3, None);
+        let mut encoded = SsTable::build(mt).encode();
+
+        let report = SsTable::inspect(&encoded).unwrap();
+        // Filter sized for three keys: 45 bytes of its header, 4 bytes of bits and the checksum.
+        assert_eq!(report.bloom_len, 45 + 4 + bloom::CHECKSUM_SIZE);
+        let bloom = report.bloom.unwrap();
+        assert!(bloom.bits_set > 0 && bloom.bits_set <= 3 * bloom.hash_functions as u64);
+        // Index len, then the top index: len, pages num, a single entry with two 6 byte keys,
+        // offset and page len, checksum. Then the page: len, entries num, a single entry with
+        // two 6 byte keys, offset and block len, checksum.
+        assert_eq!(
+            report.index_len,
+            4 + (4 + 2 + (2 + 6 + 2 + 6 + 4 + 2) + 4) + (2 + 2 + (2 + 6 + 2 + 6 + 4 + 2) + 4)
+        );
+        assert_eq!(report.index_pages, 1);
+        assert_eq!(report.blocks.len(), 1);
+        assert_eq!(report.blocks[0].offset, 0);
+        assert_eq!(report.blocks[0].len as usize, block::BLOCK_BYTE_SIZE);
+        assert!(!report.blocks[0].compressed);
+        assert_eq!(report.blocks[0].first_key, Bytes::from("buddha"));
+        assert_eq!(report.blocks[0].last_key, Bytes::from("sangha"));
+        assert_eq!(report.blocks[0].entries, 3);
+        assert!(report.blocks[0].checksum_ok);
+
+        // Damage the block data.
+        let len = encoded.len();
+        encoded[len - 100] ^= 1;
+        let report = SsTable::inspect(&encoded).unwrap();
+        assert!(!report.blocks[0].checksum_ok);
+
+        // Damage the bloom filter.
+        encoded[U32_SIZE + 2 + 40] ^= 1;
+        assert!(SsTable::inspect(&encoded).unwrap().bloom.is_none());
+
+        // Damage the index.
+        encoded[U32_SIZE + 2 + report.bloom_len + 10] ^= 1;
+        assert!(SsTable::inspect(&encoded).is_err());
+    }
+
+    #[test]
+    fn test_placement() {
+        let mut mt = MemTable::new(SsTableSize::Default);
+        for i in 0..1000 {
+            mt.insert(
+                Bytes::from(format!("key-{:04}", i)),
+                Bytes::from("v"),
+                1,
+                None,
+            );
+        }
+        let mut table = SsTable::build(mt);
+        table.level = 3;
+        let encoded = table.encode();
+
+        assert_eq!(
+            SsTable::placement(&encoded).unwrap(),
+            Placement {
+                level: 3,
+                first_key: Bytes::from("key-0000"),
+                last_key: Bytes::from("key-0999"),
+                replaces: Vec::new(),
+            }
+        );
+        // Level bits don't get in the way of reading the table.
+        assert_eq!(SsTable::inspect(&encoded).unwrap().level, 3);
+        assert_eq!(
+            SsTable::lookup(&encoded, &Bytes::from("key-0500"), 0, None).unwrap(),
+            Some(Value::Live(Bytes::from("v")))
+        );
+        SsTable::check(&encoded).unwrap();
+    }
+
+    #[test]
+    fn test_replaces() {
+        let mut mt = MemTable::new(SsTableSize::Default);
+        mt.insert(Bytes::from("buddha"), Bytes::from("om"), 1, None);
+        let mut table = SsTable::build(mt);
+        let replaces = vec![Uuid::from_u128(1), Uuid::from_u128(2)];
+        table.replaces = replaces.clone();
+        let encoded = table.encode();
+
+        assert_eq!(SsTable::placement(&encoded).unwrap().replaces, replaces);
+        // Replaced ids don't get in the way of reading the table.
+        assert_eq!(
+            SsTable::lookup(&encoded, &Bytes::from("buddha"), 0, None).unwrap(),
+            Some(Value::Live(Bytes::from("om")))
+        );
+        assert_eq!(SsTable::decode(&encoded).unwrap().len(), 1);
+        SsTable::check(&encoded).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "compression")]
+    fn test_compression() {
+        let mut mt = MemTable::new(SsTableSize::Is(256 * 1024));
+        for i in 0..2000 {
+            let value = Bytes::from(format!("value-{}", i % 10).repeat(8));
+            mt.insert(Bytes::from(format!("key-{:05}", i)), value, i, None);
+        }
+        let plain = SsTable::build(mt.clone()).encode();
+        let mut table = SsTable::build(mt.clone());
+        table.format = Format {
+            hash_index: true,
+            compression: true,
+            ..Default::default()
+        };
+        let compressed = table.encode();
+        assert!(compressed.len() < plain.len() / 2, "{}", compressed.len());
+
+        SsTable::check(&compressed).unwrap();
+        let report = SsTable::inspect(&compressed).unwrap();
+        assert!(report.blocks.iter().all(|block| block.compressed));
+        assert_eq!(
+            SsTable::decode(&compressed).unwrap(),
+            SsTable::decode(&plain).unwrap()
+        );
+        for (key, value) in mt.map.iter().step_by(7) {
+            assert_eq!(
+                SsTable::lookup(&compressed, key, 0, None).unwrap(),
+                Some(value.clone())
+            );
+        }
+
+        let (start, end) = (Bytes::from("key-00100"), Bytes::from("key-01900"));
+        assert_eq!(
+            SsTable::count_range(&compressed, &start, &end, 0).unwrap(),
+            1800
+        );
+        assert_eq!(
+            SsTable::entries_range(&compressed, &start, &end, 500, 0).unwrap(),
+            SsTable::entries_range(&plain, &start, &end, 500, 0).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_count_range() {
+        let (mt, _, _) = create_full_memtable(SsTableSize::Is(8 * 1024));
+        let encoded = SsTable::build(mt.clone()).encode();
+
+        let keys: Vec<&Bytes> = mt.map.keys().collect();
+        let ranges = [
+            (keys[0].clone(), keys[keys.len() - 1].clone()),
+            (keys[3].clone(), keys[keys.len() / 2].clone()),
+            (Bytes::new(), Bytes::from_static(&[0xff; 8])),
+            (keys[5].clone(), keys[5].clone()),
+        ];
+
+        for (start, end) in ranges {
+            let expected = mt.map.range(start.clone()..end.clone()).count() as u64;
+            assert_eq!(
+                SsTable::count_range(&encoded, &start, &end, 0).unwrap(),
+                expected,
+                "range {:?}..{:?}",
+                start,
+                end
+            );
+
+            for limit in [1, 10, usize::MAX] {
+                let expected: Vec<(Bytes, bool)> = mt
+                    .map
+                    .range(start.clone()..end.clone())
+                    .take(limit)
+                    .map(|(key, _)| (key.clone(), true))
+                    .collect();
+                assert_eq!(
+                    SsTable::keys_range(&encoded, &start, &end, limit, 0).unwrap(),
+                    expected
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_tombstones() {
+        let mut mt = MemTable::new(SsTableSize::Default);
+        mt.insert(Bytes::from("a"), Bytes::from("1"), 1, None);
+        mt.insert_typed(Bytes::from("b"), Value::Tombstone, 0, 2, None);
+        mt.insert(Bytes::from("c"), Bytes::from("3"), 1, None);
+        let encoded = SsTable::build(mt).encode();
+
+        // Found, so that older tables are not read.
+        assert_eq!(
+            SsTable::lookup(&encoded, &Bytes::from("b"), 0, None).unwrap(),
+            Some(Value::Tombstone)
+        );
+        assert_eq!(
+            SsTable::decode(&encoded).unwrap().get(&Bytes::from("b")),
+            Some(&(Value::Tombstone, 2, 0))
+        );
+        let (start, end) = (Bytes::from("a"), Bytes::from("z"));
+        assert_eq!(SsTable::count_range(&encoded, &start, &end, 0).unwrap(), 2);
+        assert_eq!(
+            SsTable::keys_range(&encoded, &start, &end, 2, 0).unwrap(),
+            vec![
+                (Bytes::from("a"), true),
+                (Bytes::from("b"), false),
+                (Bytes::from("c"), true)
+            ]
+        );
+        assert_eq!(
+            SsTable::keys_range(&encoded, &start, &end, 1, 0).unwrap(),
+            vec![(Bytes::from("a"), true)]
+        );
+        assert_eq!(
+            SsTable::entries_range(&encoded, &Bytes::from("b"), &end, 1, 0).unwrap(),
+            vec![
+                (Bytes::from("b"), Value::Tombstone),
+                (Bytes::from("c"), Value::Live(Bytes::from("3")))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_monotonic_id() {
+        let mut last = 0;
+        let now = Uuid::now_v7();
+        let before = Uuid::from_u128(now.as_u128() - (1 << 80)); // Clock is a few seconds back.
+
+        let first = monotonic_id(&mut last, now);
+        assert_eq!(first, now);
+
+        let second = monotonic_id(&mut last, before);
+        assert!(second > first, "id grows even though the clock went back");
+        assert!(
+            second.as_u128() - first.as_u128() > 1,
+            "there is room for a compaction output"
+        );
+        let third = monotonic_id(&mut last, before);
+        assert!(third > second);
+
+        let later = Uuid::from_u128(now.as_u128() + (1 << 80));
+        assert_eq!(
+            monotonic_id(&mut last, later),
+            later,
+            "clock time is used once it catches up"
+        );
+    }
+
+    /// Table blob that counts the bytes read from it.
+    struct CountingEntry {
+        data: Vec<u8>,
+        bytes_read: std::cell::Cell<u64>,
+        reads: std::cell::Cell<u64>,
+    }
+
+    impl StorageEntry for CountingEntry {
+        fn read_at(&self, data: &mut Vec<u8>, position: u64) -> std::io::Result<()> {
+            self.bytes_read
+                .set(self.bytes_read.get() + data.len() as u64);
+            self.reads.set(self.reads.get() + 1);
+            self.data.read_at(data, position)
+        }
+
+        fn size(&self) -> std::io::Result<u64> {
+            self.data.size()
+        }
+    }
+
+    #[test]
+    fn test_index_pages() {
+        let (mt, _, _) = create_full_memtable(SsTableSize::Is(1024 * 1024));
+        let blob = CountingEntry {
+            data: SsTable::build(mt.clone()).encode(),
+            bytes_read: std::cell::Cell::new(0),
+            reads: std::cell::Cell::new(0),
+        };
+
+        let (index, header, index_pages) = SsTable::read_index(&blob).unwrap();
+        assert!(index.0.len() > 4 * INDEX_PAGE_ENTRIES, "{}", index.0.len());
+        assert_eq!(index_pages, index.0.len().div_ceil(INDEX_PAGE_ENTRIES));
+        let top_len = header.top_len as u64;
+        let header_read = header.index_start() + 2 * U32_SIZE as u64;
+        // Every entry of a page holds two 36 byte keys.
+        let max_page_len = (2 + 2 + INDEX_PAGE_ENTRIES * (2 + 36 + 2 + 36 + 4 + 2) + 4) as u64;
+
+        for (key, value) in mt.map.iter().step_by(97) {
+            blob.bytes_read.set(0);
+            assert_eq!(
+                SsTable::lookup(&blob, key, 0, None).unwrap(),
+                Some(value.clone())
+            );
+
+            let index_read = blob.bytes_read.get() - header_read - block::BLOCK_BYTE_SIZE as u64;
+            assert!(
+                index_read <= top_len + max_page_len,
+                "lookup read {} bytes of the index",
+                index_read
+            );
+            assert!(index_read < header.index_len as u64 / 2);
+        }
+    }
+
+    #[test]
+    fn test_hash_index() {
+        let (mt, _, _) = create_full_memtable(SsTableSize::Is(1024 * 1024));
+        let plain = SsTable::build(mt.clone()).encode();
+        let mut table = SsTable::build(mt.clone());
+        table.format.hash_index = true;
+        let hashed = CountingEntry {
+            data: table.encode(),
+            bytes_read: std::cell::Cell::new(0),
+            reads: std::cell::Cell::new(0),
+        };
+
+        let report = SsTable::inspect(&hashed).unwrap();
+        assert!(report.hash_slots >= 2 * mt.map.len());
+        assert_eq!(SsTable::inspect(&plain).unwrap().hash_slots, 0);
+        assert_eq!(
+            SsTable::decode(&hashed).unwrap(),
+            SsTable::decode(&plain).unwrap()
+        );
+
+        // Bloom filter len, the filter, a probe window and a block, unless the key is not in the
+        // hash index.
+        let mut shared = 0;
+        for (key, value) in mt.map.iter() {
+            hashed.reads.set(0);
+            assert_eq!(
+                SsTable::lookup(&hashed, key, 0, None).unwrap(),
+                Some(value.clone())
+            );
+            assert_eq!(
+                SsTable::lookup(&plain, key, 0, None).unwrap(),
+                SsTable::lookup(&hashed, key, 0, None).unwrap()
+            );
+            if hashed.reads.get() != 8 {
+                shared += 1;
+            }
+        }
+        assert!(shared < mt.map.len() / 100, "{} lookups", shared);
+
+        for _ in 0..1000 {
+            let key = Bytes::from(Uuid::now_v7().to_string());
+            assert_eq!(SsTable::lookup(&hashed, &key, 0, None).unwrap(), None);
+            assert_eq!(SsTable::lookup(&plain, &key, 0, None).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn test_hash_index_overflow() {
+        // Far more keys than slots, most of them find no free slot.
+        let mut hash_index = HashIndex::new(1);
+        for num in 0..100u32 {
+            hash_index.insert(
+                num.to_string().as_bytes(),
+                BlockPos {
+                    offset: num,
+                    len: 1,
+                },
+            );
+        }
+        hash_index.insert(
+            b"0",
+            BlockPos {
+                offset: 100,
+                len: 1,
+            },
+        );
+        let encoded = hash_index.encode();
+        assert_eq!(encoded.len(), HashIndex::encoded_len(hash_index.slots_num));
+
+        let mut found = 0;
+        for num in 0..100u32 {
+            let (slot, fingerprint) = hash_slot(num.to_string().as_bytes(), hash_index.slots_num);
+            let window =
+                &encoded[slot * HASH_SLOT_SIZE..(slot + HASH_PROBE_SLOTS) * HASH_SLOT_SIZE];
+            match HashIndex::probe(window, fingerprint) {
+                HashProbe::Found(pos) => {
+                    assert_eq!(
+                        pos,
+                        BlockPos {
+                            offset: num,
+                            len: 1
+                        }
+                    );
+                    found += 1;
+                }
+                HashProbe::Unknown => {}
+                HashProbe::Missing => panic!("key {} is missing", num),
+            }
+        }
+        // Every slot is taken, key 0 is in two blocks so its slot tells nothing.
+        assert_eq!(found, encoded.len() / HASH_SLOT_SIZE - 1);
+    }
+
+    #[test]
+    fn test_read_adjacent_blocks() {
+        let (mt, _, _) = create_full_memtable(SsTableSize::Is(256 * 1024));
+        let blob = CountingEntry {
+            data: SsTable::build(mt.clone()).encode(),
+            bytes_read: std::cell::Cell::new(0),
+            reads: std::cell::Cell::new(0),
+        };
+        let (index, header, _) = SsTable::read_index(&blob).unwrap();
+        let offsets: Vec<BlockPos> = index.0.iter().map(IndexEntry::pos).collect();
+        assert!(offsets.len() > 20, "{}", offsets.len());
+
+        // Two runs of adjacent blocks with a gap in between.
+        let wanted: Vec<BlockPos> = offsets[2..10]
+            .iter()
+            .chain(&offsets[12..15])
+            .copied()
+            .collect();
+        blob.reads.set(0);
+        let batched = SsTable::read_blocks(&blob, &header, &wanted).unwrap();
+        assert_eq!(blob.reads.get(), 2);
+
+        assert_eq!(batched.len(), wanted.len());
+        for (block, pos) in batched.iter().zip(&wanted) {
+            let single = SsTable::read_block(&blob, &header, *pos).unwrap();
+            assert_eq!(block.entries(), single.entries());
+        }
+
+        let keys: Vec<&Bytes> = mt.map.keys().collect();
+        let (start, end) = (keys[10].clone(), keys[keys.len() - 10].clone());
+        blob.reads.set(0);
+        assert_eq!(
+            SsTable::count_range(&blob, &start, &end, 0).unwrap(),
+            (keys.len() - 20) as u64
+        );
+        let batches = offsets.len().div_ceil(SCAN_BATCH_BLOCKS) as u64;
+        assert!(
+            blob.reads.get() <= 3 + batches,
+            "{} reads",
+            blob.reads.get()
+        );
     }
 
     fn make_test_index() -> TableIndex {
         let mut ti = TableIndex::new();
         ti.0.push(IndexEntry::new(
-            1000,
+            BlockPos {
+                offset: 1000,
+                len: 4096,
+            },
             Bytes::from("1_block_start"),
             Bytes::from("1_block_end"),
         ));
         ti.0.push(IndexEntry::new(
-            2000,
+            BlockPos {
+                offset: 2000,
+                len: 4096,
+            },
             Bytes::from("2_block_start"),
             Bytes::from("2_block_end"),
         ));
         ti.0.push(IndexEntry::new(
-            3000,
+            BlockPos {
+                offset: 3000,
+                len: 4096,
+            },
             Bytes::from("3_block_start"),
             Bytes::from("3_block_end"),
         ));
@@ -416,11 +2080,11 @@ mod tests {
     fn test_index_encode() {
         let ti = make_test_index();
         let encoded = ti.encode();
-        assert_eq!(encoded.len(), 104);
+        assert_eq!(encoded.len(), 110);
 
         let mut cloned = Cursor::new(encoded.clone());
         let len_encoded = cloned.get_u16();
-        assert_eq!(len_encoded, 104);
+        assert_eq!(len_encoded, 110);
         let blocks_count = cloned.get_u16();
         assert_eq!(blocks_count, ti.0.len() as u16);
     }
@@ -430,9 +2094,17 @@ mod tests {
         let ti = make_test_index();
         let encoded = ti.encode();
 
-        let decoded = TableIndex::decode(encoded.as_ref());
+        let decoded = TableIndex::decode(encoded.as_ref()).unwrap();
         assert_eq!(decoded.0.len(), ti.0.len());
         assert_eq!(decoded.0[0].offset, ti.0[0].offset);
         assert_eq!(decoded.0[2].offset, ti.0[2].offset);
+        assert_eq!(decoded.0[2].len, ti.0[2].len);
+
+        let mut damaged = encoded.clone();
+        damaged[10] ^= 1;
+        assert!(matches!(
+            TableIndex::decode(&damaged),
+            Err(crate::Error::Corrupted(_))
+        ));
     }
 }
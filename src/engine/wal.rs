@@ -1,2 +1,842 @@
+use crate::{SyncPolicy, WalStorage};
+use bytes::{Buf, BufMut, Bytes};
+use std::io::Cursor;
+use std::time::{Duration, Instant};
+
+/*
+WAL layout schema. Log is split into pages, an entry never crosses a page boundary. When the next
+entry doesn't fit into the rest of a page, the rest is filled with zeroes and the entry goes to
+the next page. So a damaged entry can only spoil the page it lives on.
+-----------------------------------------------------------------------
+|                       Page #1 (4KB)                      | Page #2 |
+-----------------------------------------------------------------------
+| Entry #1 | Entry #2 | ... | Entry #N | Padding (zeroes) |   ...   |
+-----------------------------------------------------------------------
+
+Single entry layout schema. Entry len covers everything after itself, checksum covers everything
+after itself. Zero entry len means the rest of the page is padding. The top bits of the entry len
+field hold the version of the format (see VERSION_SHIFT), an entry of any version other than
+FORMAT_VERSION is refused. Any change of the layout bumps the version. What follows the record
+type depends on the type.
+-------------------------------------------------------------------------------------
+| version and entry_len (2B) | checksum (4B) | record_type (1B) | record fields |
+-------------------------------------------------------------------------------------
+
+Record fields by type.
+---------------------------------------------------------------------------------------
+| Set      |                       | key_len (2B) | key | value_len (2B) | value |
+| Delete   |                       | key_len (2B) | key |                        |
+| SetEx    | expires_at (8B, ms)   | key_len (2B) | key | value_len (2B) | value |
+| SetTyped | flags (1B)            | key_len (2B) | key | value_len (2B) | value |
+| Batch    | writes_num (2B)       | key_len (2B) | key | value_len (2B) | value | ... |
+---------------------------------------------------------------------------------------
+
+Batch holds the writes of a transaction. Being a single entry it is recovered either whole or not
+at all, so it can't be larger than a page. Values are never empty, a zero value_len marks a delete
+of the key.
+*/
+
+pub const PAGE_SIZE: usize = 4 * 1024; // 4 KB.
+
+/// Version of the log layout, kept in the top bits of the entry len field. Entry len is kept in
+/// the low bits, an entry is never longer than a page.
+const FORMAT_VERSION: u16 = 1;
+const VERSION_SHIFT: u32 = 13;
+const ENTRY_LEN_MASK: u16 = (1 << VERSION_SHIFT) - 1;
+
+const U16_SIZE: usize = std::mem::size_of::<u16>(); // 2.
+
+const CHECKSUM_SIZE: usize = std::mem::size_of::<u32>(); // 4.
+
+const RECORD_TYPE_SIZE: usize = std::mem::size_of::<u8>(); // 1.
+
+const EXPIRES_AT_SIZE: usize = std::mem::size_of::<u64>(); // 8.
+
+const FLAGS_SIZE: usize = std::mem::size_of::<u8>(); // 1.
+
+const RECORD_SET: u8 = 1;
+const RECORD_DELETE: u8 = 2;
+const RECORD_SET_EX: u8 = 3;
+const RECORD_SET_TYPED: u8 = 4;
+const RECORD_BATCH: u8 = 5;
+
+/// A single record of the log.
+#[derive(Debug, PartialEq)]
+pub enum Entry {
+    Set {
+        key: Bytes,
+        value: Bytes,
+    },
+    Delete {
+        key: Bytes,
+    },
+    /// Value that expires at the given unix time in milliseconds.
+    SetEx {
+        key: Bytes,
+        value: Bytes,
+        expires_at: u64,
+    },
+    /// Value set with the given flags (content type), see Command::SetTyped.
+    SetTyped {
+        key: Bytes,
+        value: Bytes,
+        flags: u8,
+    },
+    /// Keys set and deleted together, see Command::Transaction. None deletes the key.
+    Batch {
+        writes: Vec<(Bytes, Option<Bytes>)>,
+    },
+}
+
+impl Entry {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        match self {
+            Entry::Set { key, value } => {
+                payload.put_u8(RECORD_SET);
+                put_frame(&mut payload, key);
+                put_frame(&mut payload, value);
+            }
+            Entry::Delete { key } => {
+                payload.put_u8(RECORD_DELETE);
+                put_frame(&mut payload, key);
+            }
+            Entry::SetEx {
+                key,
+                value,
+                expires_at,
+            } => {
+                payload.put_u8(RECORD_SET_EX);
+                payload.put_u64(*expires_at);
+                put_frame(&mut payload, key);
+                put_frame(&mut payload, value);
+            }
+            Entry::SetTyped { key, value, flags } => {
+                payload.put_u8(RECORD_SET_TYPED);
+                payload.put_u8(*flags);
+                put_frame(&mut payload, key);
+                put_frame(&mut payload, value);
+            }
+            Entry::Batch { writes } => {
+                payload.put_u8(RECORD_BATCH);
+                payload.put_u16(writes.len() as u16);
+                for (key, value) in writes {
+                    put_frame(&mut payload, key);
+                    put_frame(&mut payload, value.as_ref().unwrap_or(&Bytes::new()));
+                }
+            }
+        }
+
+        let mut buf = Vec::with_capacity(U16_SIZE + CHECKSUM_SIZE + payload.len());
+        buf.put_u16(FORMAT_VERSION << VERSION_SHIFT | (CHECKSUM_SIZE + payload.len()) as u16);
+        buf.put_u32(crc32fast::hash(&payload));
+        buf.extend(payload);
+
+        buf
+    }
+
+    /// Decodes an entry at the current position of the buffer. Returns None if there are no
+    /// more entries on the page.
+    pub fn decode(buf: &mut Cursor<&[u8]>) -> crate::Result<Option<Self>> {
+        if buf.remaining() < U16_SIZE {
+            return Ok(None);
+        }
+
+        let field = buf.get_u16();
+        if field == 0 {
+            return Ok(None); // Padding.
+        }
+        let version = field >> VERSION_SHIFT;
+        if version != FORMAT_VERSION {
+            Err(crate::Error::Corrupted(format!(
+                "unsupported wal format v{}",
+                version
+            )))?
+        }
+        let entry_len = (field & ENTRY_LEN_MASK) as usize;
+        if entry_len > buf.remaining() || entry_len < CHECKSUM_SIZE + RECORD_TYPE_SIZE + U16_SIZE {
+            Err(crate::Error::Corrupted(format!(
+                "invalid wal entry length {}",
+                entry_len
+            )))?
+        }
+
+        let checksum = buf.get_u32();
+        let start = buf.position() as usize;
+        let payload = &buf.get_ref()[start..start + entry_len - CHECKSUM_SIZE];
+        if crc32fast::hash(payload) != checksum {
+            Err(crate::Error::Corrupted(
+                "wal entry checksum mismatch".into(),
+            ))?
+        }
+
+        let mut payload = Cursor::new(payload);
+        let entry = match payload.get_u8() {
+            RECORD_SET => {
+                let key = get_frame(&mut payload, "key")?;
+                let value = get_frame(&mut payload, "value")?;
+                Entry::Set { key, value }
+            }
+            RECORD_DELETE => {
+                let key = get_frame(&mut payload, "key")?;
+                Entry::Delete { key }
+            }
+            RECORD_SET_EX => {
+                if payload.remaining() < EXPIRES_AT_SIZE {
+                    Err(crate::Error::Corrupted(
+                        "invalid wal entry expiration".into(),
+                    ))?
+                }
+                let expires_at = payload.get_u64();
+                let key = get_frame(&mut payload, "key")?;
+                let value = get_frame(&mut payload, "value")?;
+                Entry::SetEx {
+                    key,
+                    value,
+                    expires_at,
+                }
+            }
+            RECORD_SET_TYPED => {
+                if payload.remaining() < FLAGS_SIZE {
+                    Err(crate::Error::Corrupted("invalid wal entry flags".into()))?
+                }
+                let flags = payload.get_u8();
+                let key = get_frame(&mut payload, "key")?;
+                let value = get_frame(&mut payload, "value")?;
+                Entry::SetTyped { key, value, flags }
+            }
+            RECORD_BATCH => {
+                if payload.remaining() < U16_SIZE {
+                    Err(crate::Error::Corrupted(
+                        "invalid wal entry writes number".into(),
+                    ))?
+                }
+                let writes_num = payload.get_u16() as usize;
+                let mut writes = Vec::with_capacity(writes_num);
+                for _ in 0..writes_num {
+                    let key = get_frame(&mut payload, "key")?;
+                    let value = get_frame(&mut payload, "value")?;
+                    writes.push((key, Some(value).filter(|value| !value.is_empty())));
+                }
+                Entry::Batch { writes }
+            }
+            record_type => Err(crate::Error::Corrupted(format!(
+                "unknown wal record type {}",
+                record_type
+            )))?,
+        };
+        if payload.has_remaining() {
+            Err(crate::Error::Corrupted(
+                "wal entry has trailing bytes".into(),
+            ))?
+        }
+
+        buf.advance(entry_len - CHECKSUM_SIZE);
+
+        Ok(Some(entry))
+    }
+}
+
+fn put_frame(buf: &mut Vec<u8>, data: &Bytes) {
+    buf.put_u16(data.len() as u16);
+    buf.put(&data[..]);
+}
+
+fn get_frame(buf: &mut Cursor<&[u8]>, name: &str) -> crate::Result<Bytes> {
+    if buf.remaining() < U16_SIZE {
+        Err(crate::Error::Corrupted(format!(
+            "invalid wal entry {} length",
+            name
+        )))?
+    }
+
+    let len = buf.get_u16() as usize;
+    if len > buf.remaining() {
+        Err(crate::Error::Corrupted(format!(
+            "invalid wal entry {} length",
+            name
+        )))?
+    }
+
+    Ok(buf.copy_to_bytes(len))
+}
+
+/// Parses all the entries of a page and puts them to entries. Stops at the first entry that
+/// can't be decoded and returns an error, entries before it are still put to entries. An entry
+/// running past the end of the page is not an error, that's what an interrupted write looks like,
+/// so it just ends the page.
+pub fn parse_page(page: &[u8], entries: &mut Vec<Entry>) -> crate::Result<()> {
+    let mut buf = Cursor::new(page);
+
+    loop {
+        let pos = buf.position() as usize;
+        if buf.remaining() >= U16_SIZE {
+            let entry_len =
+                (u16::from_be_bytes([page[pos], page[pos + 1]]) & ENTRY_LEN_MASK) as usize;
+            if entry_len > buf.remaining() - U16_SIZE {
+                tracing::warn!("wal page ends with a partial entry at offset {}", pos);
+                return Ok(());
+            }
+        }
+
+        match Entry::decode(&mut buf)? {
+            Some(entry) => entries.push(entry),
+            None => return Ok(()),
+        }
+    }
+}
+
+/// What replaying the log at the start took, to size the log against the recovery time.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ReplayStats {
+    /// Pages read, including the ones with nothing but padding.
+    pub pages: u64,
+    /// Entries recovered. Damaged entries and the rest of their pages are not counted.
+    pub records: u64,
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+/// Write ahead log. Every write is appended to the log before it gets to memtable, so memtable
+/// can be recovered after a crash. Log is rotated every time memtable is sent to disk, and every
+/// time the current generation grows over the segment size if there is one. Generations are
+/// discarded by the engine once the tables holding their writes are on disk.
+///
+/// Appends are put to disk as the sync policy says (see SyncPolicy). A disabled log (see
+/// Config::unsafe_no_wal) never touches its storage: appends, rotations and syncs do nothing and
+/// there is nothing to recover.
 #[derive(Debug)]
-pub struct Wal {}
+pub struct Wal<W: WalStorage> {
+    storage: W,
+    page_used: usize,
+    disabled: bool,
+    replay: ReplayStats,
+    sync_policy: SyncPolicy,
+    /// Something was appended since the log was last put to disk.
+    unsynced: bool,
+    /// Bytes after which a new generation is started (see Config::wal_segment_size).
+    segment_size: Option<u64>,
+    /// Bytes in the current generation.
+    segment_len: u64,
+}
+
+impl<W: WalStorage> Wal<W> {
+    /// Initializes the storage and returns the log along with all the entries recovered from it,
+    /// oldest first.
+    pub fn init(
+        mut storage: W,
+        sync_policy: SyncPolicy,
+        segment_size: Option<u64>,
+    ) -> crate::Result<(Self, Vec<Entry>)> {
+        storage.init()?;
+
+        let started = Instant::now();
+        let generations = storage.persisted_data()?;
+        let mut entries = Vec::new();
+        let mut replay = ReplayStats::default();
+        for (gen_num, data) in generations.iter().enumerate() {
+            replay.bytes += data.len() as u64;
+            for (page_num, page) in data.chunks(PAGE_SIZE).enumerate() {
+                replay.pages += 1;
+                // Rest of a page after a damaged entry is skipped. Appends after recovery go to
+                // a fresh page, so nothing was written after it on the same page.
+                if let Err(e) = parse_page(page, &mut entries) {
+                    tracing::warn!(
+                        "skipping the rest of wal page {} of generation {}: {}",
+                        page_num,
+                        gen_num,
+                        e
+                    );
+                }
+            }
+        }
+
+        replay.records = entries.len() as u64;
+        replay.elapsed = started.elapsed();
+        tracing::info!(
+            "replayed WAL: {} records from {} pages ({} bytes) in {:?}",
+            replay.records,
+            replay.pages,
+            replay.bytes,
+            replay.elapsed
+        );
+
+        let mut wal = Wal {
+            storage,
+            page_used: generations.last().map_or(0, |data| data.len() % PAGE_SIZE),
+            disabled: false,
+            replay,
+            sync_policy,
+            unsynced: false,
+            segment_size,
+            segment_len: generations.last().map_or(0, |data| data.len() as u64),
+        };
+
+        // Start appending from a fresh page, so new entries never follow a damaged one.
+        if wal.page_used != 0 {
+            let padding = PAGE_SIZE - wal.page_used;
+            wal.storage.append(&vec![0; padding])?;
+            wal.segment_len += padding as u64;
+            wal.page_used = 0;
+        }
+
+        Ok((wal, entries))
+    }
+
+    /// Log that doesn't keep anything. The storage is not even initialized.
+    pub fn disabled(storage: W) -> Self {
+        Wal {
+            storage,
+            page_used: 0,
+            disabled: true,
+            replay: ReplayStats::default(),
+            sync_policy: SyncPolicy::Never,
+            unsynced: false,
+            segment_size: None,
+            segment_len: 0,
+        }
+    }
+
+    /// What replaying the log took when it was initialized. All zeroes if the log is disabled.
+    pub fn replay_stats(&self) -> ReplayStats {
+        self.replay
+    }
+
+    pub fn append(&mut self, entry: &Entry) -> crate::Result<()> {
+        if self.disabled {
+            return Ok(());
+        }
+
+        let entry = entry.encode();
+        assert!(entry.len() <= PAGE_SIZE, "wal entry exceeds page size");
+
+        if self.page_used + entry.len() > PAGE_SIZE {
+            let mut buf = vec![0; PAGE_SIZE - self.page_used];
+            buf.extend(&entry);
+            self.storage.append(&buf)?;
+            self.segment_len += buf.len() as u64;
+            self.page_used = entry.len();
+        } else {
+            self.storage.append(&entry)?;
+            self.segment_len += entry.len() as u64;
+            self.page_used += entry.len();
+        }
+
+        self.page_used %= PAGE_SIZE;
+        self.unsynced = true;
+
+        if self.sync_policy == SyncPolicy::Always {
+            self.sync()?;
+        }
+
+        if self
+            .segment_size
+            .is_some_and(|size| self.segment_len >= size)
+        {
+            // The entry is in the log already, failing to rotate only makes the generation longer.
+            if let Err(e) = self.rotate() {
+                tracing::error!("could not start a new WAL generation: {}", e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Puts all the entries appended so far to disk.
+    pub fn sync(&mut self) -> crate::Result<()> {
+        if self.disabled {
+            return Ok(());
+        }
+
+        self.storage.sync()?;
+        self.unsynced = false;
+
+        Ok(())
+    }
+
+    /// Puts the entries appended since the last sync to disk, if there are any. Called on the
+    /// timer of SyncPolicy::EveryMs, so that an idle log is not synced over and over.
+    pub fn sync_pending(&mut self) -> crate::Result<()> {
+        match self.unsynced {
+            true => self.sync(),
+            false => Ok(()),
+        }
+    }
+
+    /// How often the log is to be synced in the background, None if it is not.
+    pub fn sync_interval(&self) -> Option<Duration> {
+        match self.sync_policy {
+            SyncPolicy::EveryMs(ms) if !self.disabled => Some(Duration::from_millis(ms)),
+            _ => None,
+        }
+    }
+
+    /// Bytes left for the log to grow into. Asked even if the log is disabled, since tables
+    /// usually live on the same disk.
+    pub fn available_space(&self) -> crate::Result<u64> {
+        Ok(self.storage.available_space()?)
+    }
+
+    /// Bytes taken by the log.
+    pub fn size(&self) -> crate::Result<u64> {
+        if self.disabled {
+            return Ok(0);
+        }
+
+        Ok(self.storage.size()?)
+    }
+
+    /// Generation of the log new entries go to. Zero if the log is disabled.
+    pub fn generation(&self) -> u64 {
+        if self.disabled {
+            return 0;
+        }
+
+        self.storage.generation()
+    }
+
+    /// Starts a new generation of the log. Called once memtable is swapped.
+    pub fn rotate(&mut self) -> crate::Result<()> {
+        if self.disabled {
+            return Ok(());
+        }
+
+        self.storage.rotate()?;
+        self.page_used = 0;
+        self.unsynced = false;
+        self.segment_len = 0;
+
+        Ok(())
+    }
+
+    /// Removes the generations up to and including the given one, every write they hold is in
+    /// a table on disk. The current generation is never removed.
+    pub fn discard_through(&mut self, generation: u64) -> crate::Result<()> {
+        if self.disabled {
+            return Ok(());
+        }
+
+        self.storage.discard_through(generation)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::mem;
+
+    fn entry(key: &'static str, value: &'static str) -> Entry {
+        Entry::Set {
+            key: Bytes::from(key),
+            value: Bytes::from(value),
+        }
+    }
+
+    #[test]
+    fn test_entry() {
+        let encoded = entry("foo", "bar").encode();
+        assert_eq!(encoded.len(), 2 + 4 + 1 + 2 + 3 + 2 + 3);
+
+        let mut buf = Cursor::new(&encoded[..]);
+        assert_eq!(Entry::decode(&mut buf).unwrap(), Some(entry("foo", "bar")));
+        assert_eq!(Entry::decode(&mut buf).unwrap(), None);
+
+        let mut damaged = encoded.clone();
+        damaged[10] ^= 1;
+        assert!(matches!(
+            Entry::decode(&mut Cursor::new(&damaged[..])),
+            Err(crate::Error::Corrupted(_))
+        ));
+
+        assert!(
+            Entry::decode(&mut Cursor::new(&encoded[..encoded.len() - 1])).is_err(),
+            "entry cut short"
+        );
+    }
+
+    #[test]
+    fn test_record_types() {
+        let entries = [
+            entry("foo", "bar"),
+            Entry::Delete {
+                key: Bytes::from("foo"),
+            },
+            Entry::SetEx {
+                key: Bytes::from("foo"),
+                value: Bytes::from("bar"),
+                expires_at: 1_700_000_000_000,
+            },
+            Entry::SetTyped {
+                key: Bytes::from("foo"),
+                value: Bytes::from("bar"),
+                flags: 7,
+            },
+            Entry::Batch {
+                writes: vec![
+                    (Bytes::from("foo"), Some(Bytes::from("bar"))),
+                    (Bytes::from("bar"), Some(Bytes::from("baz"))),
+                    (Bytes::from("baz"), None),
+                ],
+            },
+        ];
+        let lens = [
+            2 + 4 + 1 + 2 + 3 + 2 + 3,
+            2 + 4 + 1 + 2 + 3,
+            2 + 4 + 1 + 8 + 2 + 3 + 2 + 3,
+            2 + 4 + 1 + 1 + 2 + 3 + 2 + 3,
+            2 + 4 + 1 + 2 + 2 * (2 + 3 + 2 + 3) + (2 + 3 + 2),
+        ];
+
+        for (entry, len) in entries.iter().zip(lens) {
+            let encoded = entry.encode();
+            assert_eq!(encoded.len(), len);
+            assert_eq!(
+                Entry::decode(&mut Cursor::new(&encoded[..]))
+                    .unwrap()
+                    .as_ref(),
+                Some(entry)
+            );
+        }
+
+        // Unknown record type with a valid checksum.
+        let payload = [9, 0, 1, b'x'];
+        let mut encoded = Vec::new();
+        encoded.put_u16(FORMAT_VERSION << VERSION_SHIFT | (CHECKSUM_SIZE + payload.len()) as u16);
+        encoded.put_u32(crc32fast::hash(&payload));
+        encoded.extend(payload);
+        let err = Entry::decode(&mut Cursor::new(&encoded[..])).unwrap_err();
+        assert_eq!(err.to_string(), "unknown wal record type 9");
+
+        // Any version but the current one is refused, zero included.
+        let mut encoded = entry("foo", "bar").encode();
+        encoded[0] &= 0x1F;
+        let err = Entry::decode(&mut Cursor::new(&encoded[..])).unwrap_err();
+        assert!(matches!(err, crate::Error::Corrupted(_)));
+        assert_eq!(err.to_string(), "unsupported wal format v0");
+    }
+
+    #[test]
+    fn test_parse_page() {
+        let mut page = Vec::new();
+        page.extend(entry("foo", "1").encode());
+        page.extend(entry("bar", "2").encode());
+        page.resize(PAGE_SIZE, 0);
+
+        let mut entries = Vec::new();
+        assert!(parse_page(&page, &mut entries).is_ok());
+        assert_eq!(entries, vec![entry("foo", "1"), entry("bar", "2")]);
+
+        let mut page = Vec::new();
+        page.extend(entry("foo", "1").encode());
+        page.extend(&entry("bar", "2").encode()[..5]); // Interrupted write.
+
+        let mut entries = Vec::new();
+        assert!(parse_page(&page, &mut entries).is_ok());
+        assert_eq!(entries, vec![entry("foo", "1")]);
+
+        // Length field of the last entry points past the end of the page.
+        let mut page = Vec::new();
+        page.extend(entry("foo", "1").encode());
+        page.extend(entry("bar", "2").encode());
+        page.put_u16(PAGE_SIZE as u16);
+        page.resize(PAGE_SIZE, 0);
+
+        let mut entries = Vec::new();
+        assert!(parse_page(&page, &mut entries).is_ok());
+        assert_eq!(entries, vec![entry("foo", "1"), entry("bar", "2")]);
+
+        let mut page = Vec::new();
+        page.extend(entry("foo", "1").encode());
+        let mut damaged = entry("bar", "2").encode();
+        damaged[10] ^= 1;
+        page.extend(damaged);
+        page.extend(entry("baz", "3").encode());
+
+        let mut entries = Vec::new();
+        assert!(parse_page(&page, &mut entries).is_err(), "damaged entry");
+        assert_eq!(entries, vec![entry("foo", "1")]);
+    }
+
+    #[test]
+    fn test_init() {
+        let storage = mem::new_wal();
+
+        let (_, entries) = Wal::init(storage.clone(), SyncPolicy::Never, None).unwrap();
+        assert!(entries.is_empty());
+
+        let (mut wal, _) = Wal::init(storage.clone(), SyncPolicy::Never, None).unwrap();
+        wal.append(&entry("foo", "1")).unwrap();
+        wal.append(&entry("bar", "2")).unwrap();
+        drop(wal);
+
+        let (mut wal, entries) = Wal::init(storage.clone(), SyncPolicy::Never, None).unwrap();
+        assert_eq!(entries, vec![entry("foo", "1"), entry("bar", "2")]);
+
+        // Appends after recovery go to a fresh page.
+        wal.append(&entry("baz", "3")).unwrap();
+        let data = storage.persisted_data().unwrap();
+        assert_eq!(data.last().unwrap().len(), PAGE_SIZE + 15);
+
+        let (_, entries) = Wal::init(storage, SyncPolicy::Never, None).unwrap();
+        assert_eq!(entries.len(), 3);
+    }
+
+    #[test]
+    fn test_replay_stats() {
+        let storage = mem::new_wal();
+        let (mut wal, _) = Wal::init(storage.clone(), SyncPolicy::Never, None).unwrap();
+        assert_eq!(wal.replay_stats().records, 0);
+
+        // Entries take 17 bytes, 240 of them fill a page.
+        for _ in 0..300 {
+            wal.append(&entry("foo", "bar")).unwrap();
+        }
+        drop(wal);
+        let logged = storage.persisted_data().unwrap().concat().len() as u64;
+
+        let (wal, entries) = Wal::init(storage.clone(), SyncPolicy::Never, None).unwrap();
+        let stats = wal.replay_stats();
+        assert_eq!(entries.len(), 300);
+        assert_eq!(stats.records, 300);
+        assert_eq!(stats.pages, 2);
+        assert_eq!(stats.bytes, logged);
+
+        assert_eq!(
+            Wal::disabled(storage).replay_stats(),
+            ReplayStats::default()
+        );
+    }
+
+    #[test]
+    fn test_replay_mixed_log() {
+        let storage = mem::new_wal();
+        let log = vec![
+            entry("foo", "1"),
+            Entry::SetEx {
+                key: Bytes::from("bar"),
+                value: Bytes::from("2"),
+                expires_at: 42,
+            },
+            Entry::Delete {
+                key: Bytes::from("foo"),
+            },
+            entry("baz", "3"),
+        ];
+
+        let (mut wal, _) = Wal::init(storage.clone(), SyncPolicy::Never, None).unwrap();
+        for entry in &log {
+            wal.append(entry).unwrap();
+        }
+        drop(wal);
+
+        let (_, entries) = Wal::init(storage, SyncPolicy::Never, None).unwrap();
+        assert_eq!(entries, log);
+    }
+
+    #[test]
+    fn test_append() {
+        let storage = mem::new_wal();
+        let (mut wal, _) = Wal::init(storage.clone(), SyncPolicy::Never, None).unwrap();
+
+        let value = Bytes::from(vec![b'x'; 1000]);
+        let mut keys = Vec::new();
+        for i in 0..10 {
+            let key = Bytes::from(format!("key{}", i));
+            wal.append(&Entry::Set {
+                key: key.clone(),
+                value: value.clone(),
+            })
+            .unwrap();
+            keys.push(key);
+        }
+
+        // Four entries fit a page, the rest of it is padding.
+        let data = storage.persisted_data().unwrap();
+        let entry_len = 2 + 4 + 1 + 2 + 4 + 2 + 1000;
+        assert_eq!(data[0].len(), PAGE_SIZE * 2 + entry_len * 2);
+
+        let (_, entries) = Wal::init(storage, SyncPolicy::Never, None).unwrap();
+        assert_eq!(
+            entries
+                .into_iter()
+                .map(|e| match e {
+                    Entry::Set { key, .. } => key,
+                    e => panic!("unexpected record {:?}", e),
+                })
+                .collect::<Vec<_>>(),
+            keys
+        );
+    }
+
+    #[test]
+    fn test_sync_policy() {
+        let storage = mem::new_wal();
+        let (mut wal, _) = Wal::init(storage.clone(), SyncPolicy::Always, None).unwrap();
+        wal.append(&entry("foo", "1")).unwrap();
+
+        // Every append is on disk before it returns, a crash right after it loses nothing.
+        storage.crash();
+        let data = storage.persisted_data().unwrap();
+        let mut entries = Vec::new();
+        parse_page(&data[0], &mut entries).unwrap();
+        assert_eq!(entries, vec![entry("foo", "1")]);
+        assert_eq!(wal.sync_interval(), None);
+
+        let storage = mem::new_wal();
+        let (mut wal, _) = Wal::init(storage.clone(), SyncPolicy::Never, None).unwrap();
+        wal.append(&entry("foo", "1")).unwrap();
+        storage.crash();
+        assert!(storage.persisted_data().unwrap()[0].is_empty());
+
+        let (wal, _) = Wal::init(mem::new_wal(), SyncPolicy::EveryMs(10), None).unwrap();
+        assert_eq!(wal.sync_interval(), Some(Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn test_rotate() {
+        let storage = mem::new_wal();
+        let (mut wal, _) = Wal::init(storage.clone(), SyncPolicy::Never, None).unwrap();
+
+        wal.append(&entry("foo", "1")).unwrap();
+        wal.rotate().unwrap();
+        wal.append(&entry("bar", "2")).unwrap();
+        wal.rotate().unwrap();
+        wal.append(&entry("baz", "3")).unwrap();
+
+        let (_, entries) = Wal::init(storage.clone(), SyncPolicy::Never, None).unwrap();
+        assert_eq!(entries.len(), 3, "generations are kept until discarded");
+
+        wal.discard_through(wal.generation() - 2).unwrap();
+        let (_, entries) = Wal::init(storage, SyncPolicy::Never, None).unwrap();
+        assert_eq!(entries, vec![entry("bar", "2"), entry("baz", "3")]);
+    }
+
+    #[test]
+    fn test_segment_size() {
+        let storage = mem::new_wal();
+        let (mut wal, _) = Wal::init(storage.clone(), SyncPolicy::Never, Some(100)).unwrap();
+
+        let value = Bytes::from(vec![b'x'; 40]);
+        for i in 0..5 {
+            wal.append(&Entry::Set {
+                key: Bytes::from(format!("key{}", i)),
+                value: value.clone(),
+            })
+            .unwrap();
+        }
+
+        // An entry takes 2 + 4 + 1 + 2 + 4 + 2 + 40 bytes, a generation is started after every
+        // second one.
+        assert_eq!(wal.generation(), 2);
+        let data = storage.persisted_data().unwrap();
+        assert_eq!(
+            data.iter().map(Vec::len).collect::<Vec<_>>(),
+            vec![110, 110, 55]
+        );
+
+        let (_, entries) = Wal::init(storage, SyncPolicy::Never, Some(100)).unwrap();
+        assert_eq!(entries.len(), 5);
+    }
+}
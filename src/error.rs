@@ -0,0 +1,77 @@
+use std::fmt;
+use std::io;
+
+/// Errors of the database. Variants tell apart the cases a caller may want to handle differently,
+/// the rest ends up in Other. Displayed as the message alone, which is what clients get.
+#[derive(Debug)]
+pub enum BureauError {
+    /// Request or data given by the caller is not valid, like a key that is too long or a request
+    /// line that can't be parsed. Retrying it won't help.
+    InvalidInput(String),
+    /// Data read back from the log or a table doesn't check out.
+    Corrupted(String),
+    /// Storage of tables or the log failed. A full disk is reported as ErrorKind::StorageFull.
+    Storage(io::Error),
+    /// The request can't be taken right now, the same request may succeed later.
+    Overloaded(String),
+    Other(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl BureauError {
+    /// Tells if the error is a full disk reported by storage.
+    pub fn is_storage_full(&self) -> bool {
+        matches!(self, BureauError::Storage(e) if e.kind() == io::ErrorKind::StorageFull)
+    }
+}
+
+impl fmt::Display for BureauError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BureauError::InvalidInput(msg)
+            | BureauError::Corrupted(msg)
+            | BureauError::Overloaded(msg) => f.write_str(msg),
+            BureauError::Storage(e) => e.fmt(f),
+            BureauError::Other(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for BureauError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BureauError::Storage(e) => Some(e),
+            BureauError::Other(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for BureauError {
+    fn from(e: io::Error) -> Self {
+        BureauError::Storage(e)
+    }
+}
+
+impl From<&str> for BureauError {
+    fn from(msg: &str) -> Self {
+        BureauError::Other(msg.into())
+    }
+}
+
+impl From<String> for BureauError {
+    fn from(msg: String) -> Self {
+        BureauError::Other(msg.into())
+    }
+}
+
+impl From<tokio::sync::oneshot::error::RecvError> for BureauError {
+    fn from(e: tokio::sync::oneshot::error::RecvError) -> Self {
+        BureauError::Other(Box::new(e))
+    }
+}
+
+impl From<tokio::task::JoinError> for BureauError {
+    fn from(e: tokio::task::JoinError) -> Self {
+        BureauError::Other(Box::new(e))
+    }
+}
@@ -0,0 +1,203 @@
+use crate::engine::SsTable;
+use crate::{Storage, StorageEntry};
+use std::fmt;
+use uuid::Uuid;
+
+/// Outcome of checking every table in a storage.
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    /// Number of tables checked.
+    pub checked: usize,
+    pub problems: Vec<TableProblem>,
+}
+
+/// A table that can't be trusted, along with what is wrong with it.
+#[derive(Debug)]
+pub struct TableProblem {
+    pub table_id: Uuid,
+    pub kind: ProblemKind,
+    pub problem: String,
+}
+
+/// What sort of problem a table has.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProblemKind {
+    /// The table can't be opened or fails SsTable::check.
+    Damaged,
+    /// The table is fine to read, but its id doesn't sort the way it was made.
+    IdOrder,
+}
+
+impl FsckReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    /// Ids of the tables that are damaged. Tables whose only problem is their id are left out,
+    /// there is nothing wrong with their data.
+    pub fn damaged(&self) -> Vec<Uuid> {
+        self.problems
+            .iter()
+            .filter(|problem| problem.kind == ProblemKind::Damaged)
+            .map(|problem| problem.table_id)
+            .collect()
+    }
+}
+
+impl fmt::Display for FsckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "checked {} tables, {} with problems",
+            self.checked,
+            self.problems.len()
+        )?;
+
+        for problem in self.problems.iter() {
+            writeln!(f, "table {}: {}", problem.table_id, problem.problem)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks every table in the storage (see SsTable::check), a table that can't be opened counts
+/// as damaged too. Tables order relies on ids being time ordered, so ids that are not UUID v7 or
+/// sort against their timestamps are reported as well. Only listing the tables fails the check
+/// as a whole, tables are neither changed nor removed.
+pub fn fsck<S: Storage>(storage: &S) -> crate::Result<FsckReport> {
+    let mut report = FsckReport::default();
+    let mut newer: Option<(Uuid, u64)> = None;
+    for table_id in storage.list_entries()? {
+        report.checked += 1;
+
+        let checked = storage
+            .open(&table_id)
+            .map_err(crate::Error::from)
+            .and_then(|blob| SsTable::check(&blob));
+        if let Err(e) = checked {
+            report.problems.push(TableProblem {
+                table_id,
+                kind: ProblemKind::Damaged,
+                problem: e.to_string(),
+            });
+        }
+
+        // Tables are listed from the newest to the oldest.
+        let Some(timestamp) = id_timestamp(&table_id) else {
+            report.problems.push(TableProblem {
+                table_id,
+                kind: ProblemKind::IdOrder,
+                problem: format!(
+                    "id is a version {} uuid, it is not time ordered",
+                    table_id.get_version_num()
+                ),
+            });
+            continue;
+        };
+        if let Some((newer_id, newer_timestamp)) = newer {
+            if newer_timestamp < timestamp {
+                report.problems.push(TableProblem {
+                    table_id,
+                    kind: ProblemKind::IdOrder,
+                    problem: format!("id sorts before {} but was made later", newer_id),
+                });
+            }
+        }
+        newer = Some((table_id, timestamp));
+    }
+
+    Ok(report)
+}
+
+/// Moves the table to another storage, so that it is out of the way of the engine but can still
+/// be looked at or restored. Table is removed only once it is written to the quarantine.
+pub fn quarantine<S: Storage, Q: Storage>(
+    storage: &S,
+    quarantine: &Q,
+    table_id: &Uuid,
+) -> crate::Result<()> {
+    let blob = storage.open(table_id)?;
+    let mut data = vec![0; blob.size()? as usize];
+    if !data.is_empty() {
+        blob.read_at(&mut data, 0)?;
+    }
+
+    quarantine.write(table_id, &data)?;
+    storage.delete(table_id)?;
+
+    Ok(())
+}
+
+/// Milliseconds since epoch the id was made at, None if the id is not a UUID v7.
+fn id_timestamp(table_id: &Uuid) -> Option<u64> {
+    match table_id.get_version_num() {
+        7 => {
+            let (secs, nanos) = table_id.get_timestamp()?.to_unix();
+            Some(secs * 1000 + nanos as u64 / 1_000_000)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::memtable::{MemTable, SsTableSize};
+    use crate::storage::mem;
+    use bytes::Bytes;
+
+    fn table(key: &str) -> SsTable {
+        let mut mt = MemTable::new(SsTableSize::Default);
+        mt.insert(Bytes::from(key.to_string()), Bytes::from("value"), 1, None);
+        SsTable::build(mt)
+    }
+
+    #[test]
+    fn test_fsck() {
+        let stor = mem::new();
+        for key in ["a", "b", "c", "d"] {
+            let table = table(key);
+            stor.write(&table.id, &table.encode()).unwrap();
+        }
+        assert!(fsck(&stor).unwrap().is_ok());
+
+        let damaged = table("e");
+        let mut encoded = damaged.encode();
+        let len = encoded.len();
+        encoded[len - 100] ^= 1;
+        stor.write(&damaged.id, &encoded).unwrap();
+
+        let report = fsck(&stor).unwrap();
+        assert_eq!(report.checked, 5);
+        assert_eq!(report.problems.len(), 1, "{}", report);
+        assert_eq!(report.problems[0].table_id, damaged.id);
+        assert_eq!(report.problems[0].problem, "checksum mismatch in block #0");
+        assert_eq!(report.damaged(), vec![damaged.id]);
+
+        let quarantined = mem::new();
+        quarantine(&stor, &quarantined, &damaged.id).unwrap();
+        assert!(fsck(&stor).unwrap().is_ok());
+        assert_eq!(
+            Storage::list_entries(&quarantined).unwrap(),
+            vec![damaged.id]
+        );
+        assert_eq!(Storage::open(&quarantined, &damaged.id).unwrap(), encoded);
+    }
+
+    #[test]
+    fn test_fsck_ids() {
+        let stor = mem::new();
+        let table = table("a");
+        stor.write(&table.id, &table.encode()).unwrap();
+        // Sorts after every v7 id made today, but is not one.
+        let not_v7 = Uuid::from_u128(u128::MAX);
+        stor.write(&not_v7, &table.encode()).unwrap();
+
+        let report = fsck(&stor).unwrap();
+        assert_eq!(report.problems.len(), 1, "{}", report);
+        assert_eq!(report.problems[0].table_id, not_v7);
+        assert_eq!(report.problems[0].kind, ProblemKind::IdOrder);
+        assert!(report.damaged().is_empty(), "the table itself is fine");
+    }
+}
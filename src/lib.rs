@@ -1,11 +1,24 @@
+mod clock;
+mod config;
+mod db;
 pub mod engine;
+mod error;
+pub mod fsck;
+pub mod protocol;
+pub mod server;
 pub mod storage;
 
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use config::{Config, Keepalive, SketchSize, SocketConfig, SyncPolicy};
+pub use db::Db;
+pub use error::BureauError;
+
+use std::future::Future;
 use std::io;
 use tokio::sync::oneshot;
 use uuid::Uuid;
 
-pub type Error = Box<dyn std::error::Error + Send + Sync>;
+pub type Error = BureauError;
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
@@ -22,14 +35,167 @@ pub trait Storage: Clone + Send + 'static {
     /// makes it properly work since sorting it will also order them in table creation time.
     fn list_entries(&self) -> io::Result<Vec<Uuid>>;
 
-    /// It writes a new SsTable to storage.
+    /// It writes a new SsTable to storage. A table is never overwritten: writing an id that is
+    /// already there fails with io::ErrorKind::AlreadyExists.
     fn write(&self, table_id: &Uuid, data: &[u8]) -> io::Result<()>;
 
     /// Opens SsTable to sequentially read it later.
     fn open(&self, table_id: &Uuid) -> io::Result<Self::Entry>;
+
+    /// Removes SsTable from storage. Used by compaction to clean up merged tables.
+    fn delete(&self, table_id: &Uuid) -> io::Result<()>;
+
+    /// Puts everything written and removed so far to durable storage without closing anything,
+    /// a barrier for Sync and backups. Does nothing by default, which suits storages that make
+    /// every write durable before returning from it.
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Same as list_entries, along with the size of every table in bytes. By default every table
+    /// is opened to learn its size, storages that keep sizes around should override it.
+    fn list_entries_with_meta(&self) -> io::Result<Vec<(Uuid, u64)>> {
+        self.list_entries()?
+            .into_iter()
+            .map(|table_id| Ok((table_id, self.open(&table_id)?.size()?)))
+            .collect()
+    }
+}
+
+/// Tables storage that may live behind the network, like an object storage. Its calls don't hold
+/// the runtime while waiting for the data, so this is what the dispatcher works with.
+///
+/// Every Storage is an AsyncStorage as well: its calls are made right away on the runtime thread,
+/// which is fine for the file system (FsStorage) since local calls are short. Network backends (S3
+/// and alike) implement this trait directly and are awaited instead. Tables are still read
+/// synchronously once they are opened, and an entry may block the thread reading it for as long
+/// as a read takes, like S3Entry does with a ranged GET per read. The dispatcher only reads
+/// entries on the blocking pool (tokio::task::spawn_blocking), never on the runtime threads.
+pub trait AsyncStorage: Clone + Send + Sync + 'static {
+    type Entry: StorageEntry + Send + 'static;
+
+    /// See Storage::bootstrap.
+    fn bootstrap(&self) -> impl Future<Output = io::Result<()>> + Send;
+
+    /// See Storage::list_entries.
+    fn list_entries(&self) -> impl Future<Output = io::Result<Vec<Uuid>>> + Send;
+
+    /// See Storage::write.
+    fn write(&self, table_id: &Uuid, data: &[u8]) -> impl Future<Output = io::Result<()>> + Send;
+
+    /// See Storage::open.
+    fn open(&self, table_id: &Uuid) -> impl Future<Output = io::Result<Self::Entry>> + Send;
+
+    /// See Storage::delete.
+    fn delete(&self, table_id: &Uuid) -> impl Future<Output = io::Result<()>> + Send;
+
+    /// See Storage::sync.
+    fn sync(&self) -> impl Future<Output = io::Result<()>> + Send {
+        std::future::ready(Ok(()))
+    }
+
+    /// See Storage::list_entries_with_meta.
+    fn list_entries_with_meta(&self) -> impl Future<Output = io::Result<Vec<(Uuid, u64)>>> + Send {
+        async {
+            let mut entries = Vec::new();
+            for table_id in self.list_entries().await? {
+                entries.push((table_id, self.open(&table_id).await?.size()?));
+            }
+            Ok(entries)
+        }
+    }
+}
+
+impl<T: Storage + Sync> AsyncStorage for T
+where
+    T::Entry: Send + 'static,
+{
+    type Entry = T::Entry;
+
+    fn bootstrap(&self) -> impl Future<Output = io::Result<()>> + Send {
+        std::future::ready(Storage::bootstrap(self))
+    }
+
+    fn list_entries(&self) -> impl Future<Output = io::Result<Vec<Uuid>>> + Send {
+        std::future::ready(Storage::list_entries(self))
+    }
+
+    fn write(&self, table_id: &Uuid, data: &[u8]) -> impl Future<Output = io::Result<()>> + Send {
+        std::future::ready(Storage::write(self, table_id, data))
+    }
+
+    fn open(&self, table_id: &Uuid) -> impl Future<Output = io::Result<Self::Entry>> + Send {
+        std::future::ready(Storage::open(self, table_id))
+    }
+
+    fn delete(&self, table_id: &Uuid) -> impl Future<Output = io::Result<()>> + Send {
+        std::future::ready(Storage::delete(self, table_id))
+    }
+
+    fn sync(&self) -> impl Future<Output = io::Result<()>> + Send {
+        std::future::ready(Storage::sync(self))
+    }
+
+    fn list_entries_with_meta(&self) -> impl Future<Output = io::Result<Vec<(Uuid, u64)>>> + Send {
+        std::future::ready(Storage::list_entries_with_meta(self))
+    }
+}
+
+/// Storage for the write ahead log. Log is kept in generations, a new one is started every time
+/// memtable is swapped, so that the log does not keep data that already made it to tables.
+pub trait WalStorage: Send + 'static {
+    /// Includes any setup required by storage to function, continues the latest generation if
+    /// there is one.
+    fn init(&mut self) -> io::Result<()>;
+
+    /// Returns data of all the generations kept in storage, oldest first.
+    fn persisted_data(&self) -> io::Result<Vec<Vec<u8>>>;
+
+    /// Appends data to the current generation.
+    fn append(&mut self, data: &[u8]) -> io::Result<()>;
+
+    /// Starts a new generation. Generations before it are kept until they are discarded.
+    fn rotate(&mut self) -> io::Result<()>;
+
+    /// Removes the generations up to and including the given one, once every write they hold is
+    /// in a table on disk. The current generation is never removed.
+    fn discard_through(&mut self, generation: u64) -> io::Result<()>;
+
+    /// Puts everything appended to the current generation to disk.
+    fn sync(&mut self) -> io::Result<()>;
+
+    /// Bytes left for the log to grow into.
+    fn available_space(&self) -> io::Result<u64>;
+
+    /// Bytes taken by all the generations kept in storage.
+    fn size(&self) -> io::Result<u64>;
+
+    /// Number of the current generation. Grows by one with every rotation.
+    fn generation(&self) -> u64;
 }
 
 pub trait StorageEntry {
     /// Reads at exactly given position for the length of given vector.
     fn read_at(&self, data: &mut Vec<u8>, position: u64) -> io::Result<()>;
+
+    /// Size of the entry in bytes.
+    fn size(&self) -> io::Result<u64>;
+
+    /// Tells the storage how the whole entry is going to be read, so that the OS cache keeps
+    /// what is read often. Only a hint, it does nothing by default.
+    fn advise(&self, _advice: Advice) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// How a table is going to be read (see StorageEntry::advise). File system storage passes it to
+/// the OS with posix_fadvise.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Advice {
+    /// Table is looked up, its pages are worth reading ahead and keeping.
+    WillNeed,
+    /// Table is read through once, like by a scan.
+    Sequential,
+    /// Table was read through once and its pages are not needed any more.
+    DontNeed,
 }
@@ -0,0 +1,1348 @@
+use crate::engine;
+use base64::Engine as _;
+use bytes::Bytes;
+
+/*
+Text protocol. Every request is a single line, arguments are separated by a space.
+------------------------------------------------------
+| GET <key>                                          |
+| MGET <count> <key> [<key> ...]                     |
+| SET <key> <value>                                  |
+| SETEX <key> <ttl> <value>                          |
+| MSET <count> <key> <value> [<key> <value> ...]     |
+| ID <request id> SET <key> <value>                  |
+| NOFILL GET <key>                                   |
+| AFTER <seq> GET <key>                              |
+| APPEND <key> <suffix>                              |
+| DELETE <key>                                       |
+| CAS <key> <expected> <value>                       |
+| CASNEW <key> <value>                               |
+| KEYS <start> <end> <limit>                         |
+| SCANFROM <cursor> <limit>                          |
+| SCAN <start> <end> <limit>                         |
+| SCAN PREFIX <prefix> <limit>                       |
+| SYNC                                               |
+| STATS                                              |
+| FLAGS <flags> SET <key> <value>                    |
+| FLAGS GET <key>                                    |
+| TX <op> [<op> ...]                                 |
+------------------------------------------------------
+
+MGET reads count keys (at most MAX_MGET_KEYS) at once and responds with their values in the
+order the keys are given, `nil` for a key without a value. The count has to match the keys that
+follow it.
+MSET sets count pairs (at most MAX_MSET_PAIRS) in one request and responds with the sequence
+number of the last write. Pairs are written one by one, the ones before a pair that fails stay
+written and the error names the failed one (counting from 0). Values can't take the rest of the
+line, so the ones with spaces are quoted.
+SETEX sets a value that expires in ttl seconds (from 1 to 2^32 - 1), an expired key is read the
+same way as a deleted one. It responds the way SET does.
+NOFILL GET reads the key without letting the value into the server's cache, for clients reading
+many keys once that should not push hot values out of it.
+APPEND adds suffix to the end of the current value and responds with the length of the new one.
+DELETE removes the key and responds with the sequence number of the delete, a deleted key is read
+the same way as a key that was never set.
+CAS and CASNEW set the key the way the ops of TX do (see below), on their own. They respond with
+`swapped` or `not swapped` if the current value is not the expected one.
+KEYS lists up to limit (at most MAX_KEYS_LIMIT) first keys within [start, end) without values.
+If there are more keys in the range, or the server cut the list short (see Config::max_scan_keys),
+the list is followed by `cursor <cursor>`. SCANFROM lists up to limit more keys of the same range
+from where the previous list ended, and gives the next cursor the same way (see Cursor).
+SCAN lists up to limit (at most MAX_KEYS_LIMIT) first keys within [start, end) along with their
+values, an empty or reversed range lists nothing. The list is cut short and followed by a cursor
+the way KEYS does it, values count towards the bytes listed, and SCANFROM given that cursor lists
+more keys along with their values. SCAN PREFIX lists the same way the first keys starting with
+prefix, an empty prefix lists from the first key on. A range starting at the key PREFIX is given
+quoted.
+SYNC responds once all the writes acknowledged before it are on disk (see engine::Command::Sync).
+STATS responds with counters of the server's state as `<name> <number>` pairs separated by a
+comma: tables on disk, entries and estimated bytes of the memtable, reads the value cache
+answered and missed, cached values found corrupted (see Config::verify_on_read), and keys. Keys
+are counted through all the tables, so a key overwritten after it was flushed is counted more
+than once.
+
+SET responds with the sequence number of the write. A client that reads over another connection
+passes it with AFTER, the read then waits until the server sees that write (see
+Config::read_after_timeout), so the client reads its own writes whichever connection it uses.
+AFTER goes in front of NOFILL.
+
+Flags is a byte (0 to 255) the client sets a value with to tell what kind of content it holds,
+like JSON or protobuf. FLAGS GET responds with the value followed by `, flags <flags>`. Values set
+without flags (or appended to) have zero flags.
+
+TX applies all of its ops or none of them. An op is one of the following, keys of a transaction
+must be distinct and its values can't take the rest of the line, so the ones with spaces are quoted.
+------------------------------------------------------
+| SET <key> <value>                                  |
+| DEL <key>                                          |
+| CAS <key> <expected> <value>                       |
+| CASNEW <key> <value>                               |
+------------------------------------------------------
+DEL deletes the key the way DELETE does. CAS sets the key only if its current value is the
+expected one, CASNEW only if it has no value.
+TX responds with `committed, seq <seq>`, or with `aborted, op <n> failed` naming the first op
+(counting from 0) whose condition doesn't hold, nothing is written then.
+
+A request id is an unsigned 64 bit number chosen by the client. A request retried with the same id
+is not applied again if the server has recently applied it.
+
+Keys and values can be given as is, as long as they are valid UTF-8 without spaces (a SET value
+given as is takes the rest of the line, spaces included). To pass arbitrary bytes an argument can
+be given in one of the following forms.
+------------------------------------------------------
+| "two words"  | quoted, supports \" \\ \n \r \t \0 and \xNN escapes |
+| b64:Zm9vYmFy | base64 (standard alphabet, padded)                   |
+| hex:666f6f   | hex encoded bytes                                    |
+------------------------------------------------------
+*/
+
+/// The longest request that can carry keys and values of the given sizes, an MSET of
+/// MAX_MSET_PAIRS pairs or an MGET of MAX_MGET_KEYS keys. Every byte of a key or a value takes at
+/// most 4 bytes in a request (\xNN escape), along with quotes and a space around each of them. The
+/// rest is room for the command and the count.
+pub fn max_request_len(max_key_size: u32, max_value_size: u32) -> usize {
+    let arg_len = |size: u32| 4 * size as usize + 3;
+    let mset = MAX_MSET_PAIRS * (arg_len(max_key_size) + arg_len(max_value_size));
+    let mget = MAX_MGET_KEYS * arg_len(max_key_size);
+
+    64 + mset.max(mget)
+}
+
+/// The most keys a single KEYS request can list.
+pub const MAX_KEYS_LIMIT: usize = 1000;
+
+/// The most keys a single MGET request can read. The whole request has to fit into
+/// Config::max_request_len too.
+pub const MAX_MGET_KEYS: usize = 100;
+
+/// The most pairs a single MSET request can set, the same way as MAX_MGET_KEYS.
+pub const MAX_MSET_PAIRS: usize = 100;
+
+const QUOTE: char = '"';
+const BASE64_PREFIX: &str = "b64:";
+const HEX_PREFIX: &str = "hex:";
+
+#[derive(Debug, PartialEq)]
+pub enum Request {
+    Get {
+        key: Bytes,
+        fill_cache: bool,
+        /// Sequence number of a write the read has to see.
+        after: Option<u64>,
+    },
+    MultiGet {
+        keys: Vec<Bytes>,
+    },
+    Set {
+        key: Bytes,
+        value: Bytes,
+        request_id: Option<u64>,
+    },
+    SetBatch {
+        pairs: Vec<(Bytes, Bytes)>,
+    },
+    SetEx {
+        key: Bytes,
+        value: Bytes,
+        ttl_secs: u32,
+    },
+    Append {
+        key: Bytes,
+        suffix: Bytes,
+    },
+    Delete {
+        key: Bytes,
+    },
+    Cas {
+        key: Bytes,
+        expected: Option<Bytes>,
+        value: Bytes,
+    },
+    Keys {
+        start: Bytes,
+        end: Bytes,
+        limit: usize,
+    },
+    ScanFrom {
+        cursor: Cursor,
+        limit: usize,
+    },
+    Scan {
+        start: Bytes,
+        end: Bytes,
+        limit: usize,
+    },
+    ScanPrefix {
+        prefix: Bytes,
+        limit: usize,
+    },
+    Sync,
+    Stats,
+    SetTyped {
+        key: Bytes,
+        value: Bytes,
+        flags: u8,
+    },
+    GetTyped {
+        key: Bytes,
+    },
+    Transaction {
+        ops: Vec<engine::WriteOp>,
+    },
+}
+
+#[derive(Debug)]
+pub enum Response {
+    Get {
+        key: Bytes,
+        value: Bytes,
+    },
+    /// Values of MGET keys in the order they were asked for.
+    MultiValue {
+        values: Vec<Option<Bytes>>,
+    },
+    Set {
+        key: Bytes,
+        value: Bytes,
+        seq: u64,
+    },
+    /// Every pair of MSET is set, the last one at seq.
+    SetBatch {
+        count: usize,
+        seq: u64,
+    },
+    Append {
+        key: Bytes,
+        len: u64,
+    },
+    Delete {
+        key: Bytes,
+        seq: u64,
+    },
+    Cas {
+        swapped: bool,
+    },
+    OkValueTyped {
+        key: Bytes,
+        value: Bytes,
+        flags: u8,
+    },
+    /// Cursor is given if there are keys in the range that are not listed.
+    Keys {
+        keys: Vec<Bytes>,
+        cursor: Option<Cursor>,
+    },
+    /// Cursor is given the same way as for Keys.
+    Scan {
+        pairs: Vec<(Bytes, Bytes)>,
+        cursor: Option<Cursor>,
+    },
+    Sync,
+    /// Counters of the server's state, see STATS.
+    Stats {
+        tables: u64,
+        memtable_entries: u64,
+        memtable_bytes: u64,
+        cache_hits: u64,
+        cache_misses: u64,
+        cache_verify_failures: u64,
+        keys: u64,
+    },
+    Committed {
+        seq: u64,
+    },
+    /// Transaction is not applied, the condition of the op at the index doesn't hold.
+    Aborted {
+        op: usize,
+    },
+    Error {
+        msg: String,
+    },
+}
+
+impl Request {
+    /// Parses a request line. Whatever is wrong with it is reported as invalid input.
+    pub fn parse(input: &str) -> crate::Result<Request> {
+        Request::parse_line(input).map_err(|e| match e {
+            crate::Error::Other(e) => crate::Error::InvalidInput(e.to_string()),
+            e => e,
+        })
+    }
+
+    fn parse_line(input: &str) -> crate::Result<Request> {
+        let (cmd, rest) = match input.split_once(' ') {
+            Some((cmd, rest)) => (cmd, rest),
+            None => (input, ""),
+        };
+
+        match cmd {
+            "GET" => {
+                let (key, rest) = next_arg(rest)?.ok_or("GET must be followed by a key")?;
+                check_key(&key)?;
+                if !rest.is_empty() {
+                    Err("GET's key must not be followed by anything")?
+                }
+                Ok(Request::Get {
+                    key,
+                    fill_cache: true,
+                    after: None,
+                })
+            }
+            "MGET" => {
+                let (count, mut rest) = rest.split_once(' ').unwrap_or((rest, ""));
+                let count = parse_count("MGET", count, MAX_MGET_KEYS)?;
+                let mut keys = Vec::with_capacity(count);
+                while let Some((key, tail)) = next_arg(rest)? {
+                    check_key(&key)?;
+                    if keys.len() == count {
+                        Err(format!("MGET is given more than {} keys", count))?
+                    }
+                    keys.push(key);
+                    rest = tail;
+                }
+                if keys.len() != count {
+                    Err(format!("MGET is given {} keys, not {}", keys.len(), count))?
+                }
+                Ok(Request::MultiGet { keys })
+            }
+            "MSET" => {
+                let (count, mut rest) = rest.split_once(' ').unwrap_or((rest, ""));
+                let count = parse_count("MSET", count, MAX_MSET_PAIRS)?;
+                let mut pairs = Vec::with_capacity(count);
+                while let Some((key, tail)) = next_arg(rest)? {
+                    check_key(&key)?;
+                    if pairs.len() == count {
+                        Err(format!("MSET is given more than {} pairs", count))?
+                    }
+                    let (value, tail) =
+                        next_arg(tail)?.ok_or("MSET needs a value for every key")?;
+                    pairs.push((key, value));
+                    rest = tail;
+                }
+                if pairs.len() != count {
+                    Err(format!(
+                        "MSET is given {} pairs, not {}",
+                        pairs.len(),
+                        count
+                    ))?
+                }
+                Ok(Request::SetBatch { pairs })
+            }
+            "SET" => {
+                let (key, rest) = next_arg(rest)?.ok_or("SET must be followed by a key")?;
+                check_key(&key)?;
+                let value = last_arg(rest)?.ok_or("SET needs a value")?;
+                Ok(Request::Set {
+                    key,
+                    value,
+                    request_id: None,
+                })
+            }
+            "SETEX" => {
+                let (key, rest) = next_arg(rest)?.ok_or("SETEX must be followed by a key")?;
+                check_key(&key)?;
+                let (ttl, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+                let ttl_secs = match ttl.parse() {
+                    Ok(ttl) if ttl > 0 => ttl,
+                    _ => Err(format!(
+                        "SETEX ttl must be a number of seconds from 1 to {}",
+                        u32::MAX
+                    ))?,
+                };
+                let value = last_arg(rest)?.ok_or("SETEX needs a value")?;
+                Ok(Request::SetEx {
+                    key,
+                    value,
+                    ttl_secs,
+                })
+            }
+            "APPEND" => {
+                let (key, rest) = next_arg(rest)?.ok_or("APPEND must be followed by a key")?;
+                check_key(&key)?;
+                let suffix = last_arg(rest)?.ok_or("APPEND needs a suffix")?;
+                Ok(Request::Append { key, suffix })
+            }
+            "DELETE" => {
+                let (key, rest) = next_arg(rest)?.ok_or("DELETE must be followed by a key")?;
+                check_key(&key)?;
+                if !rest.is_empty() {
+                    Err("DELETE's key must not be followed by anything")?
+                }
+                Ok(Request::Delete { key })
+            }
+            "CAS" | "CASNEW" => match parse_op(input)? {
+                (
+                    engine::WriteOp::Cas {
+                        key,
+                        expected,
+                        value,
+                    },
+                    "",
+                ) => Ok(Request::Cas {
+                    key,
+                    expected,
+                    value,
+                }),
+                _ => Err(format!("{}'s value must not be followed by anything", cmd))?,
+            },
+            "KEYS" => {
+                let (start, rest) = next_arg(rest)?.ok_or("KEYS must be followed by a range")?;
+                let (end, rest) = next_arg(rest)?.ok_or("KEYS needs the end of the range")?;
+                let limit = parse_limit("KEYS", rest)?;
+                Ok(Request::Keys { start, end, limit })
+            }
+            "SCANFROM" => {
+                let (cursor, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+                let cursor = Cursor::decode(cursor)?;
+                let limit = parse_limit("SCANFROM", rest)?;
+                Ok(Request::ScanFrom { cursor, limit })
+            }
+            "SCAN" if rest.split(' ').next() == Some("PREFIX") => {
+                let rest = rest.split_once(' ').map_or("", |(_, rest)| rest);
+                let (prefix, rest) =
+                    next_arg(rest)?.ok_or("SCAN PREFIX must be followed by a prefix")?;
+                let limit = parse_limit("SCAN PREFIX", rest)?;
+                Ok(Request::ScanPrefix { prefix, limit })
+            }
+            "SCAN" => {
+                let (start, rest) = next_arg(rest)?.ok_or("SCAN must be followed by a range")?;
+                let (end, rest) = next_arg(rest)?.ok_or("SCAN needs the end of the range")?;
+                let limit = parse_limit("SCAN", rest)?;
+                Ok(Request::Scan { start, end, limit })
+            }
+            "SYNC" => {
+                if !rest.is_empty() {
+                    Err("SYNC must not be followed by anything")?
+                }
+                Ok(Request::Sync)
+            }
+            "STATS" => {
+                if !rest.is_empty() {
+                    Err("STATS must not be followed by anything")?
+                }
+                Ok(Request::Stats)
+            }
+            "NOFILL" => match Request::parse_line(rest)? {
+                Request::Get {
+                    key,
+                    fill_cache: true,
+                    after: None,
+                } => Ok(Request::Get {
+                    key,
+                    fill_cache: false,
+                    after: None,
+                }),
+                _ => Err("NOFILL must be followed by GET")?,
+            },
+            "AFTER" => {
+                let (seq, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+                let seq = seq
+                    .parse()
+                    .map_err(|_| format!("invalid sequence number: {}", seq))?;
+                match Request::parse_line(rest)? {
+                    Request::Get {
+                        key,
+                        fill_cache,
+                        after: None,
+                    } => Ok(Request::Get {
+                        key,
+                        fill_cache,
+                        after: Some(seq),
+                    }),
+                    _ => Err("AFTER must be followed by GET")?,
+                }
+            }
+            "ID" => {
+                let (id, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+                let id = id
+                    .parse()
+                    .map_err(|_| format!("invalid request id: {}", id))?;
+                match Request::parse_line(rest)? {
+                    Request::Set {
+                        key,
+                        value,
+                        request_id: None,
+                    } => Ok(Request::Set {
+                        key,
+                        value,
+                        request_id: Some(id),
+                    }),
+                    _ => Err("request id must be followed by SET")?,
+                }
+            }
+            "FLAGS" => match rest.split(' ').next() {
+                Some("GET") => match Request::parse_line(rest)? {
+                    Request::Get {
+                        key,
+                        fill_cache: true,
+                        after: None,
+                    } => Ok(Request::GetTyped { key }),
+                    _ => Err("FLAGS must be followed by GET")?,
+                },
+                _ => {
+                    let (flags, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+                    let flags = flags
+                        .parse()
+                        .map_err(|_| format!("flags must be a number from 0 to 255: {}", flags))?;
+                    match Request::parse_line(rest)? {
+                        Request::Set {
+                            key,
+                            value,
+                            request_id: None,
+                        } => Ok(Request::SetTyped { key, value, flags }),
+                        _ => Err("flags must be followed by SET")?,
+                    }
+                }
+            },
+            "TX" => {
+                let mut ops = Vec::new();
+                let mut rest = rest;
+                while !rest.is_empty() {
+                    let (op, tail) = parse_op(rest)?;
+                    ops.push(op);
+                    rest = tail;
+                }
+                if ops.is_empty() {
+                    Err("TX must be followed by at least one op")?
+                }
+                Ok(Request::Transaction { ops })
+            }
+            "" => Err("empty input")?,
+            cmd => Err(format!("unknown command: {}", cmd))?,
+        }
+    }
+}
+
+impl Response {
+    pub fn serialize(&self) -> String {
+        match *self {
+            Response::Get { ref key, ref value } => {
+                format!("{:?} = {:?}", String::from_utf8_lossy(key), value)
+            }
+            Response::MultiValue { ref values } => {
+                let values: Vec<_> = values
+                    .iter()
+                    .map(|value| match value {
+                        Some(value) => format!("{:?}", value),
+                        None => "nil".to_string(),
+                    })
+                    .collect();
+                format!("[{}]", values.join(", "))
+            }
+            Response::OkValueTyped {
+                ref key,
+                ref value,
+                flags,
+            } => {
+                format!(
+                    "{:?} = {:?}, flags {}",
+                    String::from_utf8_lossy(key),
+                    value,
+                    flags
+                )
+            }
+            Response::Set {
+                ref key,
+                ref value,
+                seq,
+            } => {
+                format!(
+                    "set {} = `{:?}`, seq {}",
+                    String::from_utf8_lossy(key),
+                    value,
+                    seq
+                )
+            }
+            Response::SetBatch { count, seq } => format!("set {} keys, seq {}", count, seq),
+            Response::Append { ref key, len } => {
+                format!(
+                    "appended to {}, {} bytes",
+                    String::from_utf8_lossy(key),
+                    len
+                )
+            }
+            Response::Delete { ref key, seq } => {
+                format!("deleted {}, seq {}", String::from_utf8_lossy(key), seq)
+            }
+            Response::Cas { swapped: true } => "swapped".to_string(),
+            Response::Cas { swapped: false } => "not swapped".to_string(),
+            Response::Keys {
+                ref keys,
+                ref cursor,
+            } => {
+                let keys: Vec<_> = keys
+                    .iter()
+                    .map(|key| String::from_utf8_lossy(key))
+                    .collect();
+                match cursor {
+                    Some(cursor) => format!("{:?} cursor {}", keys, cursor.encode()),
+                    None => format!("{:?}", keys),
+                }
+            }
+            Response::Scan {
+                ref pairs,
+                ref cursor,
+            } => {
+                let pairs: Vec<_> = pairs
+                    .iter()
+                    .map(|(key, value)| format!("{:?} = {:?}", String::from_utf8_lossy(key), value))
+                    .collect();
+                match cursor {
+                    Some(cursor) => format!("[{}] cursor {}", pairs.join(", "), cursor.encode()),
+                    None => format!("[{}]", pairs.join(", ")),
+                }
+            }
+            Response::Sync => "synced".to_string(),
+            Response::Stats {
+                tables,
+                memtable_entries,
+                memtable_bytes,
+                cache_hits,
+                cache_misses,
+                cache_verify_failures,
+                keys,
+            } => format!(
+                "tables {}, memtable_entries {}, memtable_bytes {}, cache_hits {}, cache_misses {}, \
+                 cache_verify_failures {}, keys {}",
+                tables,
+                memtable_entries,
+                memtable_bytes,
+                cache_hits,
+                cache_misses,
+                cache_verify_failures,
+                keys
+            ),
+            Response::Committed { seq } => format!("committed, seq {}", seq),
+            Response::Aborted { op } => format!("aborted, op {} failed", op),
+            Response::Error { ref msg } => format!("error: {}", msg),
+        }
+    }
+}
+
+/// Where a scan stopped: the first key of the range it did not list and the end of the range.
+/// Clients get it as an opaque token to continue the scan with. Scan goes on from the key rather
+/// than from a position in some table, so a cursor stays valid however tables change in between,
+/// compaction included. Pages are not a snapshot though: a key written after the scan started is
+/// listed if it falls after the cursor. A cursor of SCAN continues listing values, one of KEYS
+/// lists keys only.
+#[derive(Debug, PartialEq)]
+pub struct Cursor {
+    pub next: Bytes,
+    pub end: Bytes,
+    pub values: bool,
+}
+
+/// Keys are far shorter than 2^15 bytes, the top bit of the next key len tells a cursor of SCAN.
+const CURSOR_VALUES_BIT: u16 = 1 << 15;
+
+impl Cursor {
+    /// Base64 of the next key len (2B, with CURSOR_VALUES_BIT), the next key and the end.
+    pub fn encode(&self) -> String {
+        let mut raw = Vec::with_capacity(2 + self.next.len() + self.end.len());
+        let mut next_len = self.next.len() as u16;
+        if self.values {
+            next_len |= CURSOR_VALUES_BIT;
+        }
+        raw.extend(next_len.to_be_bytes());
+        raw.extend(&self.next);
+        raw.extend(&self.end);
+
+        base64::engine::general_purpose::STANDARD.encode(raw)
+    }
+
+    pub fn decode(token: &str) -> crate::Result<Cursor> {
+        let raw = base64::engine::general_purpose::STANDARD
+            .decode(token)
+            .map_err(|_| "invalid cursor")?;
+        let next_len = match raw.get(..2) {
+            Some(len) => u16::from_be_bytes([len[0], len[1]]),
+            None => Err("invalid cursor")?,
+        };
+        let values = next_len & CURSOR_VALUES_BIT != 0;
+        let next_len = (next_len & !CURSOR_VALUES_BIT) as usize;
+        if raw.len() < 2 + next_len {
+            Err("invalid cursor")?
+        }
+
+        Ok(Cursor {
+            next: Bytes::copy_from_slice(&raw[2..2 + next_len]),
+            end: Bytes::copy_from_slice(&raw[2 + next_len..]),
+            values,
+        })
+    }
+}
+
+/// Reads the number of items a batch request declares, from 1 to max.
+fn parse_count(cmd: &str, input: &str, max: usize) -> crate::Result<usize> {
+    match input.parse() {
+        Ok(count) if (1..=max).contains(&count) => Ok(count),
+        _ => Err(format!("{} count must be a number from 1 to {}", cmd, max))?,
+    }
+}
+
+/// Reads the limit of keys to list, the last argument of KEYS and SCANFROM.
+fn parse_limit(cmd: &str, input: &str) -> crate::Result<usize> {
+    match input.parse() {
+        Ok(limit) if (1..=MAX_KEYS_LIMIT).contains(&limit) => Ok(limit),
+        _ => Err(format!(
+            "{} limit must be a number from 1 to {}",
+            cmd, MAX_KEYS_LIMIT
+        ))?,
+    }
+}
+
+/// Reads a single op of a transaction and returns it along with the rest of the input.
+fn parse_op(input: &str) -> crate::Result<(engine::WriteOp, &str)> {
+    let (op, rest) = input.split_once(' ').unwrap_or((input, ""));
+    let (key, rest) = next_arg(rest)?.ok_or_else(|| format!("{} must be followed by a key", op))?;
+    check_key(&key)?;
+
+    let op = match op {
+        "SET" => {
+            let (value, rest) = next_arg(rest)?.ok_or("SET needs a value")?;
+            (engine::WriteOp::Set { key, value }, rest)
+        }
+        "DEL" => (engine::WriteOp::Delete { key }, rest),
+        "CAS" => {
+            let (expected, rest) = next_arg(rest)?.ok_or("CAS needs the expected value")?;
+            let (value, rest) = next_arg(rest)?.ok_or("CAS needs a value")?;
+            let op = engine::WriteOp::Cas {
+                key,
+                expected: Some(expected),
+                value,
+            };
+            (op, rest)
+        }
+        "CASNEW" => {
+            let (value, rest) = next_arg(rest)?.ok_or("CASNEW needs a value")?;
+            let op = engine::WriteOp::Cas {
+                key,
+                expected: None,
+                value,
+            };
+            (op, rest)
+        }
+        op => Err(format!("unknown transaction op: {}", op))?,
+    };
+
+    Ok(op)
+}
+
+/// Empty keys are never valid, so they are refused before the request gets to the engine.
+fn check_key(key: &Bytes) -> crate::Result<()> {
+    if key.is_empty() {
+        Err("key must not be empty")?
+    }
+    Ok(())
+}
+
+/// Reads a single argument from the beginning of input and returns it along with the rest
+/// of the input (leading separator stripped). Returns None if there is nothing to read.
+fn next_arg(input: &str) -> crate::Result<Option<(Bytes, &str)>> {
+    if input.is_empty() {
+        return Ok(None);
+    }
+
+    if !input.starts_with(QUOTE) {
+        let (token, rest) = input.split_once(' ').unwrap_or((input, ""));
+        return Ok(Some((decode_token(token)?, rest)));
+    }
+
+    let (arg, rest) = parse_quoted(input)?;
+
+    if let Some(stripped) = rest.strip_prefix(' ') {
+        return Ok(Some((arg, stripped)));
+    }
+
+    if !rest.is_empty() {
+        Err("arguments must be separated by a space")?
+    }
+
+    Ok(Some((arg, rest)))
+}
+
+/// Reads the last argument of a command. Unlike other arguments, an unquoted and unprefixed
+/// last argument takes the rest of the line as is, so the simple case keeps working for values
+/// with spaces.
+fn last_arg(input: &str) -> crate::Result<Option<Bytes>> {
+    if input.is_empty() {
+        return Ok(None);
+    }
+
+    if !input.starts_with(QUOTE) && !is_encoded(input) {
+        return Ok(Some(Bytes::copy_from_slice(input.as_bytes())));
+    }
+
+    match next_arg(input)? {
+        Some((arg, "")) => Ok(Some(arg)),
+        Some(_) => Err("last argument must not be followed by anything")?,
+        None => Ok(None),
+    }
+}
+
+fn is_encoded(token: &str) -> bool {
+    token.starts_with(BASE64_PREFIX) || token.starts_with(HEX_PREFIX)
+}
+
+fn decode_token(token: &str) -> crate::Result<Bytes> {
+    if let Some(encoded) = token.strip_prefix(BASE64_PREFIX) {
+        let decoded = base64::engine::general_purpose::STANDARD
+            .decode(encoded)
+            .map_err(|e| format!("invalid base64 argument: {}", e))?;
+        return Ok(Bytes::from(decoded));
+    }
+
+    if let Some(encoded) = token.strip_prefix(HEX_PREFIX) {
+        return Ok(Bytes::from(decode_hex(encoded)?));
+    }
+
+    Ok(Bytes::copy_from_slice(token.as_bytes()))
+}
+
+fn decode_hex(encoded: &str) -> crate::Result<Vec<u8>> {
+    if !encoded.len().is_multiple_of(2) {
+        Err("invalid hex argument: odd number of digits")?
+    }
+
+    encoded
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            let digits = std::str::from_utf8(pair).map_err(|_| "invalid hex argument")?;
+            u8::from_str_radix(digits, 16)
+                .map_err(|_| crate::Error::from(format!("invalid hex digits: {}", digits)))
+        })
+        .collect()
+}
+
+/// Parses a quoted argument starting at the very beginning of input. Returns the unescaped
+/// bytes and the input that follows the closing quote.
+fn parse_quoted(input: &str) -> crate::Result<(Bytes, &str)> {
+    let mut arg = Vec::new();
+    let mut chars = input.char_indices().skip(1); // Skip the opening quote.
+
+    while let Some((pos, ch)) = chars.next() {
+        match ch {
+            QUOTE => return Ok((Bytes::from(arg), &input[pos + 1..])),
+            '\\' => {
+                let escaped = match chars.next() {
+                    Some((_, esc)) => esc,
+                    None => Err("unterminated escape sequence")?,
+                };
+                match escaped {
+                    '"' => arg.push(b'"'),
+                    '\\' => arg.push(b'\\'),
+                    'n' => arg.push(b'\n'),
+                    'r' => arg.push(b'\r'),
+                    't' => arg.push(b'\t'),
+                    '0' => arg.push(0),
+                    'x' => {
+                        let mut digits = String::with_capacity(2);
+                        for _ in 0..2 {
+                            match chars.next() {
+                                Some((_, digit)) => digits.push(digit),
+                                None => Err("unterminated escape sequence")?,
+                            }
+                        }
+                        arg.extend(decode_hex(&digits)?);
+                    }
+                    other => Err(format!("unknown escape sequence: \\{}", other))?,
+                }
+            }
+            ch => {
+                let mut buf = [0; 4];
+                arg.extend_from_slice(ch.encode_utf8(&mut buf).as_bytes());
+            }
+        }
+    }
+
+    Err("unterminated quoted argument")?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_simple() {
+        assert_eq!(
+            Request::parse("GET foo").unwrap(),
+            Request::Get {
+                key: Bytes::from("foo"),
+                fill_cache: true,
+                after: None
+            }
+        );
+        assert_eq!(
+            Request::parse("SET foo bar").unwrap(),
+            Request::Set {
+                key: Bytes::from("foo"),
+                value: Bytes::from("bar"),
+                request_id: None
+            }
+        );
+        assert_eq!(
+            Request::parse("SET foo bar baz").unwrap(),
+            Request::Set {
+                key: Bytes::from("foo"),
+                value: Bytes::from("bar baz"),
+                request_id: None
+            },
+            "unquoted value takes the rest of the line"
+        );
+        assert_eq!(
+            Request::parse("APPEND foo bar baz").unwrap(),
+            Request::Append {
+                key: Bytes::from("foo"),
+                suffix: Bytes::from("bar baz")
+            }
+        );
+        assert_eq!(
+            Request::parse("NOFILL GET foo").unwrap(),
+            Request::Get {
+                key: Bytes::from("foo"),
+                fill_cache: false,
+                after: None
+            }
+        );
+        assert_eq!(
+            Request::parse(r#"KEYS "" foo 10"#).unwrap(),
+            Request::Keys {
+                start: Bytes::new(),
+                end: Bytes::from("foo"),
+                limit: 10
+            }
+        );
+        assert_eq!(
+            Request::parse("DELETE foo").unwrap(),
+            Request::Delete {
+                key: Bytes::from("foo"),
+            }
+        );
+        assert_eq!(
+            Request::parse("CAS foo bar baz").unwrap(),
+            Request::Cas {
+                key: Bytes::from("foo"),
+                expected: Some(Bytes::from("bar")),
+                value: Bytes::from("baz"),
+            }
+        );
+        assert_eq!(
+            Request::parse("CASNEW foo bar").unwrap(),
+            Request::Cas {
+                key: Bytes::from("foo"),
+                expected: None,
+                value: Bytes::from("bar"),
+            }
+        );
+        assert_eq!(
+            Request::parse("SCAN a z 10").unwrap(),
+            Request::Scan {
+                start: Bytes::from("a"),
+                end: Bytes::from("z"),
+                limit: 10
+            }
+        );
+        assert_eq!(
+            Request::parse("SCAN PREFIX user:1: 10").unwrap(),
+            Request::ScanPrefix {
+                prefix: Bytes::from("user:1:"),
+                limit: 10
+            }
+        );
+        assert_eq!(
+            Request::parse("SCAN PREFIX hex:ffff 10").unwrap(),
+            Request::ScanPrefix {
+                prefix: Bytes::from_static(&[0xFF, 0xFF]),
+                limit: 10
+            }
+        );
+        assert_eq!(
+            Request::parse("SCAN \"PREFIX\" z 10").unwrap(),
+            Request::Scan {
+                start: Bytes::from("PREFIX"),
+                end: Bytes::from("z"),
+                limit: 10
+            }
+        );
+        assert_eq!(Request::parse("SYNC").unwrap(), Request::Sync);
+        assert_eq!(Request::parse("STATS").unwrap(), Request::Stats);
+        assert_eq!(
+            Request::parse("SETEX foo 60 bar baz").unwrap(),
+            Request::SetEx {
+                key: Bytes::from("foo"),
+                value: Bytes::from("bar baz"),
+                ttl_secs: 60,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_multi_get() {
+        assert_eq!(
+            Request::parse(r#"MGET 3 foo "two words" hex:00"#).unwrap(),
+            Request::MultiGet {
+                keys: vec![
+                    Bytes::from("foo"),
+                    Bytes::from("two words"),
+                    Bytes::from(&b"\x00"[..]),
+                ]
+            }
+        );
+        assert_eq!(
+            Response::MultiValue {
+                values: vec![Some(Bytes::from("bar")), None]
+            }
+            .serialize(),
+            r#"[b"bar", nil]"#
+        );
+    }
+
+    #[test]
+    fn test_parse_set_batch() {
+        assert_eq!(
+            Request::parse(r#"MSET 2 foo bar baz "two words""#).unwrap(),
+            Request::SetBatch {
+                pairs: vec![
+                    (Bytes::from("foo"), Bytes::from("bar")),
+                    (Bytes::from("baz"), Bytes::from("two words")),
+                ]
+            }
+        );
+        assert_eq!(
+            Response::SetBatch { count: 2, seq: 42 }.serialize(),
+            "set 2 keys, seq 42"
+        );
+    }
+
+    #[test]
+    fn test_parse_quoted() {
+        assert_eq!(
+            Request::parse(r#"SET "two words" "three words here""#).unwrap(),
+            Request::Set {
+                key: Bytes::from("two words"),
+                value: Bytes::from("three words here"),
+                request_id: None
+            }
+        );
+        assert_eq!(
+            Request::parse(r#"GET "say \"hi\"""#).unwrap(),
+            Request::Get {
+                key: Bytes::from(r#"say "hi""#),
+                fill_cache: true,
+                after: None
+            }
+        );
+        assert_eq!(
+            Request::parse(r#"SET foo "line\nbreak\\""#).unwrap(),
+            Request::Set {
+                key: Bytes::from("foo"),
+                value: Bytes::from("line\nbreak\\"),
+                request_id: None
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_binary() {
+        assert_eq!(
+            Request::parse(r#"SET "\x00\xff" b64:3q2+7w=="#).unwrap(),
+            Request::Set {
+                key: Bytes::from_static(&[0x00, 0xff]),
+                value: Bytes::from_static(&[0xde, 0xad, 0xbe, 0xef]),
+                request_id: None
+            }
+        );
+        assert_eq!(
+            Request::parse("GET hex:00ff10").unwrap(),
+            Request::Get {
+                key: Bytes::from_static(&[0x00, 0xff, 0x10]),
+                fill_cache: true,
+                after: None
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_flags() {
+        assert_eq!(
+            Request::parse("FLAGS 7 SET foo {}").unwrap(),
+            Request::SetTyped {
+                key: Bytes::from("foo"),
+                value: Bytes::from("{}"),
+                flags: 7,
+            }
+        );
+        assert_eq!(
+            Request::parse("FLAGS GET foo").unwrap(),
+            Request::GetTyped {
+                key: Bytes::from("foo")
+            }
+        );
+        assert_eq!(
+            Response::OkValueTyped {
+                key: Bytes::from("foo"),
+                value: Bytes::from("{}"),
+                flags: 7,
+            }
+            .serialize(),
+            r#""foo" = b"{}", flags 7"#
+        );
+    }
+
+    #[test]
+    fn test_parse_transaction() {
+        assert_eq!(
+            Request::parse(r#"TX SET foo bar DEL quux CAS baz "old value" new CASNEW qux hex:00"#)
+                .unwrap(),
+            Request::Transaction {
+                ops: vec![
+                    engine::WriteOp::Set {
+                        key: Bytes::from("foo"),
+                        value: Bytes::from("bar"),
+                    },
+                    engine::WriteOp::Delete {
+                        key: Bytes::from("quux"),
+                    },
+                    engine::WriteOp::Cas {
+                        key: Bytes::from("baz"),
+                        expected: Some(Bytes::from("old value")),
+                        value: Bytes::from("new"),
+                    },
+                    engine::WriteOp::Cas {
+                        key: Bytes::from("qux"),
+                        expected: None,
+                        value: Bytes::from(&b"\x00"[..]),
+                    },
+                ],
+            }
+        );
+        for input in [
+            "TX",
+            "TX SET foo",
+            "TX CAS foo bar",
+            "TX DEL",
+            "TX DELETE foo",
+            "TX SET  bar",
+        ] {
+            assert!(Request::parse(input).is_err(), "{}", input);
+        }
+        assert_eq!(
+            Response::Aborted { op: 1 }.serialize(),
+            "aborted, op 1 failed"
+        );
+    }
+
+    #[test]
+    fn test_cursor() {
+        let cursor = Cursor {
+            next: Bytes::from_static(b"a b\xff"),
+            end: Bytes::from("z"),
+            values: false,
+        };
+        let token = cursor.encode();
+        assert_eq!(Cursor::decode(&token).unwrap(), cursor);
+        let values = Cursor {
+            values: true,
+            ..Cursor::decode(&token).unwrap()
+        };
+        assert_eq!(Cursor::decode(&values.encode()).unwrap(), values);
+        assert_eq!(
+            Request::parse(&format!("SCANFROM {} 10", token)).unwrap(),
+            Request::ScanFrom { cursor, limit: 10 }
+        );
+        assert_eq!(
+            Response::Keys {
+                keys: vec![Bytes::from("a")],
+                cursor: Some(Cursor {
+                    next: Bytes::from("b"),
+                    end: Bytes::new(),
+                    values: false,
+                })
+            }
+            .serialize(),
+            r#"["a"] cursor AAFi"#
+        );
+        assert_eq!(
+            Response::Scan {
+                pairs: vec![(Bytes::from("a"), Bytes::from("1"))],
+                cursor: Some(Cursor {
+                    next: Bytes::from("b"),
+                    end: Bytes::new(),
+                    values: true,
+                })
+            }
+            .serialize(),
+            r#"["a" = b"1"] cursor gAFi"#
+        );
+    }
+
+    #[test]
+    fn test_parse_after() {
+        assert_eq!(
+            Request::parse("AFTER 42 GET foo").unwrap(),
+            Request::Get {
+                key: Bytes::from("foo"),
+                fill_cache: true,
+                after: Some(42)
+            }
+        );
+        assert_eq!(
+            Request::parse("AFTER 42 NOFILL GET foo").unwrap(),
+            Request::Get {
+                key: Bytes::from("foo"),
+                fill_cache: false,
+                after: Some(42)
+            }
+        );
+    }
+
+    #[test]
+    fn test_max_request_len() {
+        // Every byte escaped, the longest way a key or a value can be given.
+        let escaped = |size: u32| format!("\"{}\"", "\\x00".repeat(size as usize));
+        for (key_size, value_size) in [(engine::MAX_KEY_SIZE, engine::MAX_VALUE_SIZE), (16, 64)] {
+            let pair = format!(" {} {}", escaped(key_size), escaped(value_size));
+            let mset = format!("MSET {}{}", MAX_MSET_PAIRS, pair.repeat(MAX_MSET_PAIRS));
+            let mget = format!(
+                "MGET {}{}",
+                MAX_MGET_KEYS,
+                format!(" {}", escaped(key_size)).repeat(MAX_MGET_KEYS)
+            );
+
+            let max_len = max_request_len(key_size, value_size);
+            assert!(mset.len() <= max_len, "{} > {}", mset.len(), max_len);
+            assert!(mget.len() <= max_len, "{} > {}", mget.len(), max_len);
+            assert!(Request::parse(&mset).is_ok());
+            assert!(Request::parse(&mget).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_parse_request_id() {
+        assert_eq!(
+            Request::parse("ID 42 SET foo bar").unwrap(),
+            Request::Set {
+                key: Bytes::from("foo"),
+                value: Bytes::from("bar"),
+                request_id: Some(42)
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        let cases = [
+            ("", "empty input"),
+            ("DEL foo", "unknown command: DEL"),
+            ("GET", "GET must be followed by a key"),
+            ("GET foo bar", "GET's key must not be followed by anything"),
+            ("SET foo", "SET needs a value"),
+            ("APPEND foo", "APPEND needs a suffix"),
+            ("SETEX", "SETEX must be followed by a key"),
+            (
+                "SETEX foo 0 bar",
+                "SETEX ttl must be a number of seconds from 1 to 4294967295",
+            ),
+            (
+                "SETEX foo bar",
+                "SETEX ttl must be a number of seconds from 1 to 4294967295",
+            ),
+            ("SETEX foo 60", "SETEX needs a value"),
+            ("DELETE", "DELETE must be followed by a key"),
+            (
+                "DELETE foo bar",
+                "DELETE's key must not be followed by anything",
+            ),
+            ("KEYS", "KEYS must be followed by a range"),
+            ("KEYS a", "KEYS needs the end of the range"),
+            ("KEYS a b", "KEYS limit must be a number from 1 to 1000"),
+            ("KEYS a b 0", "KEYS limit must be a number from 1 to 1000"),
+            (
+                "KEYS a b 1001",
+                "KEYS limit must be a number from 1 to 1000",
+            ),
+            ("SYNC foo", "SYNC must not be followed by anything"),
+            ("STATS all", "STATS must not be followed by anything"),
+            ("CAS foo bar", "CAS needs a value"),
+            (
+                "CASNEW foo bar baz",
+                "CASNEW's value must not be followed by anything",
+            ),
+            ("SCAN", "SCAN must be followed by a range"),
+            ("SCAN a", "SCAN needs the end of the range"),
+            ("SCAN a b 0", "SCAN limit must be a number from 1 to 1000"),
+            ("SCAN PREFIX", "SCAN PREFIX must be followed by a prefix"),
+            (
+                "SCAN PREFIX a 0",
+                "SCAN PREFIX limit must be a number from 1 to 1000",
+            ),
+            ("MGET", "MGET count must be a number from 1 to 100"),
+            ("MGET 0", "MGET count must be a number from 1 to 100"),
+            ("MGET 101 foo", "MGET count must be a number from 1 to 100"),
+            ("MGET 2 foo", "MGET is given 1 keys, not 2"),
+            ("MGET 1 foo bar", "MGET is given more than 1 keys"),
+            ("MGET 2 foo  bar", "key must not be empty"),
+            ("MSET", "MSET count must be a number from 1 to 100"),
+            ("MSET 1 foo", "MSET needs a value for every key"),
+            ("MSET 2 foo bar", "MSET is given 1 pairs, not 2"),
+            ("MSET 1 foo bar baz qux", "MSET is given more than 1 pairs"),
+            (
+                "FLAGS 256 SET foo bar",
+                "flags must be a number from 0 to 255: 256",
+            ),
+            ("FLAGS 1 GET foo", "flags must be followed by SET"),
+            ("FLAGS GET", "GET must be followed by a key"),
+            (
+                "FLAGS NOFILL GET foo",
+                "flags must be a number from 0 to 255: NOFILL",
+            ),
+            ("SCANFROM", "invalid cursor"),
+            ("SCANFROM !!! 10", "invalid cursor"),
+            ("SCANFROM AAU= 10", "invalid cursor"),
+            (
+                "SCANFROM AAA= 0",
+                "SCANFROM limit must be a number from 1 to 1000",
+            ),
+            (r#"SET "foo bar"#, "unterminated quoted argument"),
+            (r#"GET "foo"bar"#, "arguments must be separated by a space"),
+            (
+                r#"SET foo "bar" baz"#,
+                "last argument must not be followed by anything",
+            ),
+            (r#"GET "\q""#, "unknown escape sequence: \\q"),
+            ("GET hex:abc", "invalid hex argument: odd number of digits"),
+            ("GET hex:zz", "invalid hex digits: zz"),
+            (r#"GET """#, "key must not be empty"),
+            ("GET hex:", "key must not be empty"),
+            ("GET  foo", "key must not be empty"),
+            (r#"SET "" bar"#, "key must not be empty"),
+            ("SET b64: bar", "key must not be empty"),
+            ("ID 1 SET hex: bar", "key must not be empty"),
+            ("ID foo SET foo bar", "invalid request id: foo"),
+            ("ID 1 GET foo", "request id must be followed by SET"),
+            ("NOFILL SET foo bar", "NOFILL must be followed by GET"),
+            ("NOFILL NOFILL GET foo", "NOFILL must be followed by GET"),
+            ("NOFILL AFTER 1 GET foo", "NOFILL must be followed by GET"),
+            ("AFTER 1 SET foo bar", "AFTER must be followed by GET"),
+            ("AFTER foo GET foo", "invalid sequence number: foo"),
+            (
+                "ID 1 ID 2 SET foo bar",
+                "request id must be followed by SET",
+            ),
+        ];
+
+        for (input, msg) in cases {
+            let res = Request::parse(input);
+            assert!(res.is_err(), "input {:?} should not be parsed", input);
+            let err = res.err().unwrap();
+            assert_eq!(err.to_string(), msg);
+            assert!(matches!(err, crate::Error::InvalidInput(_)), "{:?}", err);
+        }
+
+        assert!(matches!(
+            Request::parse("SET foo b64:!!!"),
+            Err(crate::Error::InvalidInput(_))
+        ));
+    }
+}
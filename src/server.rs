@@ -0,0 +1,1321 @@
+use crate::engine::{self, Command, Engine, TransactionOutcome, MAX_KEY_SIZE};
+use crate::protocol::{Cursor, Request, Response};
+use crate::{Config, Responder, SocketConfig, WalStorage};
+use bytes::Bytes;
+use futures::SinkExt;
+use socket2::{SockRef, TcpKeepalive};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinSet;
+use tokio_stream::StreamExt;
+use tokio_util::codec::{Framed, LinesCodec, LinesCodecError};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{error, info, warn};
+
+/// How often a read made after a write checks if the engine sees the write yet.
+const READ_AFTER_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Assembles a server piece by piece and runs it, so that new options don't widen the signature
+/// of run. Storages are the only thing a server can't do without, everything else has defaults:
+/// config is Config::default() and the server runs until the engine is down unless a shutdown
+/// signal is given. At least one listener has to be added.
+pub struct ServerBuilder<T, W> {
+    storage: T,
+    wal_storage: W,
+    listeners: Vec<TcpListener>,
+    config: Config,
+    shutdown: Pin<Box<dyn Future<Output = ()> + Send>>,
+}
+
+impl<T: crate::AsyncStorage, W: WalStorage> ServerBuilder<T, W> {
+    pub fn new(storage: T, wal_storage: W) -> Self {
+        ServerBuilder {
+            storage,
+            wal_storage,
+            listeners: Vec::new(),
+            config: Config::default(),
+            shutdown: Box::pin(std::future::pending()),
+        }
+    }
+
+    /// Adds a listener to accept clients on. Can be called several times.
+    pub fn listener(mut self, listener: TcpListener) -> Self {
+        self.listeners.push(listener);
+        self
+    }
+
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Future that resolves when the server has to shut down (see run).
+    pub fn shutdown(mut self, signal: impl Future<Output = ()> + Send + 'static) -> Self {
+        self.shutdown = Box::pin(signal);
+        self
+    }
+
+    pub async fn run(self) -> crate::Result<()> {
+        run(
+            self.listeners,
+            self.storage,
+            self.wal_storage,
+            self.config,
+            self.shutdown,
+        )
+        .await
+    }
+}
+
+/// Runs the engine and serves clients accepted on all the given listeners, every connection
+/// talks to the same engine. Runs until shutdown future resolves, or the engine or any of the
+/// accept loops is down.
+///
+/// On shutdown no more connections are accepted, open connections are given
+/// Config::drain_timeout to finish their requests and are closed after that. Engine is stopped
+/// last, once memtable is on disk.
+pub async fn run<T: crate::AsyncStorage, W: WalStorage>(
+    listeners: Vec<TcpListener>,
+    storage: T,
+    wal_storage: W,
+    config: Config,
+    shutdown: impl Future<Output = ()>,
+) -> crate::Result<()> {
+    if listeners.is_empty() {
+        Err("no listeners to serve on")?
+    }
+
+    let drain_timeout = config.drain_timeout;
+    let conn_config = Arc::new(config.clone());
+    let (req_tx, req_rx) = mpsc::channel(config.engine_queue_len.max(1));
+    let engine = Engine::new(req_rx, config);
+    let mut engine_handle = tokio::spawn(engine.run(storage, wal_storage));
+
+    let connections = TaskTracker::new();
+    let closing = CancellationToken::new();
+    let mut accept_loops = JoinSet::new();
+    for listener in listeners {
+        if let Ok(addr) = listener.local_addr() {
+            info!("Listening on: {}", addr);
+        }
+        accept_loops.spawn(accept_loop(
+            listener,
+            conn_config.clone(),
+            req_tx.clone(),
+            connections.clone(),
+            closing.clone(),
+        ));
+    }
+    drop(req_tx);
+
+    tokio::select! {
+        res = &mut engine_handle => {
+            error!("engine exited");
+            res?;
+            return Ok(());
+        }
+        Some(res) = accept_loops.join_next() => {
+            error!("accept loop down: {:?}", res);
+        }
+        _ = shutdown => {
+            info!("shutting down");
+        }
+    }
+
+    // Dropping listeners, new connections are refused from now on.
+    accept_loops.shutdown().await;
+
+    connections.close();
+    if tokio::time::timeout(drain_timeout, connections.wait())
+        .await
+        .is_err()
+    {
+        warn!(
+            "closing {} connections that did not finish in time",
+            connections.len()
+        );
+        closing.cancel();
+        connections.wait().await;
+    }
+
+    // Engine stops once all the connections are gone, since they hold the request channel.
+    engine_handle.await?;
+
+    Ok(())
+}
+
+async fn accept_loop(
+    listener: TcpListener,
+    config: Arc<Config>,
+    req_tx: mpsc::Sender<Command>,
+    connections: TaskTracker,
+    closing: CancellationToken,
+) {
+    loop {
+        match listener.accept().await {
+            Ok((socket, _)) => {
+                if config
+                    .max_connections
+                    .is_some_and(|max| connections.len() >= max)
+                {
+                    warn!("refusing connection, {} are open", connections.len());
+                    tokio::spawn(refuse_connection(socket));
+                    continue;
+                }
+                if let Err(e) = apply_socket_options(&socket, &config.socket) {
+                    warn!("could not set socket options; error = {:?}", e);
+                }
+                let (config, req_tx) = (config.clone(), req_tx.clone());
+                let closing = closing.clone();
+                connections.spawn(async move {
+                    tokio::select! {
+                        _ = handle_connection(socket, &config, req_tx) => {}
+                        _ = closing.cancelled() => {}
+                    }
+                });
+            }
+            Err(e) => error!("error accepting socket; error = {:?}", e),
+        }
+    }
+}
+
+/// Tells the client that the server can't take more connections and closes the connection, so
+/// that it is not taken for a crash.
+async fn refuse_connection(socket: TcpStream) {
+    let mut lines = Framed::new(socket, LinesCodec::new());
+    let response = Response::Error {
+        msg: "server at capacity".to_string(),
+    };
+    if let Err(e) = lines.send(response.serialize()).await {
+        warn!("error on sending response; error = {:?}", e);
+    }
+}
+
+/// Sets nodelay and keepalive options of a client socket according to the config.
+fn apply_socket_options(socket: &TcpStream, config: &SocketConfig) -> std::io::Result<()> {
+    socket.set_nodelay(config.nodelay)?;
+
+    let sock = SockRef::from(socket);
+    match &config.keepalive {
+        Some(keepalive) => {
+            let params = TcpKeepalive::new()
+                .with_time(keepalive.time)
+                .with_interval(keepalive.interval)
+                .with_retries(keepalive.retries);
+            sock.set_tcp_keepalive(&params)?;
+        }
+        None => sock.set_keepalive(false)?,
+    }
+
+    Ok(())
+}
+
+async fn handle_connection(socket: TcpStream, config: &Config, req_tx: mpsc::Sender<Command>) {
+    let max_request_len = config.request_len_limit();
+    let mut lines = Framed::new(socket, LinesCodec::new_with_max_length(max_request_len));
+
+    if let Some(result) = lines.next().await {
+        let response = match result {
+            Ok(line) => match Request::parse(&line) {
+                Ok(request) => handle_request(request, req_tx, config).await,
+                Err(e) => Response::Error {
+                    msg: format!("could not parse command: {}", e),
+                },
+            },
+            Err(LinesCodecError::MaxLineLengthExceeded) => Response::Error {
+                msg: format!("request is longer than {} bytes", max_request_len),
+            },
+            Err(e) => {
+                error!("error on decoding from socket; error = {:?}", e);
+                return;
+            }
+        };
+
+        if let Err(e) = lines.send(response.serialize()).await {
+            warn!("error on sending response; error = {:?}", e);
+        }
+    }
+}
+
+async fn handle_request(
+    request: Request,
+    req_tx: mpsc::Sender<Command>,
+    config: &Config,
+) -> Response {
+    match request {
+        Request::Get {
+            key,
+            fill_cache,
+            after,
+        } => {
+            if let Some(seq) = after {
+                match wait_for_write(&req_tx, seq, config.read_after_timeout).await {
+                    Some(Ok(())) => {}
+                    Some(Err(e)) => return error_response(e),
+                    None => return shutting_down(),
+                }
+            }
+
+            let resp = call(&req_tx, |responder| Command::Get {
+                key: key.clone(),
+                fill_cache,
+                responder,
+            })
+            .await;
+
+            match resp {
+                Some(Ok(Some(value))) => Response::Get { key, value },
+                Some(Ok(None)) => Response::Error {
+                    msg: "no value for given key".to_string(),
+                },
+                Some(Err(e)) => error_response(e),
+                None => shutting_down(),
+            }
+        }
+        Request::MultiGet { keys } => {
+            // Lookups are queued to the engine all at once, the values come back in key order.
+            let lookups = keys.into_iter().map(|key| {
+                call(&req_tx, |responder| Command::Get {
+                    key,
+                    fill_cache: true,
+                    responder,
+                })
+            });
+
+            let mut values = Vec::new();
+            for resp in futures::future::join_all(lookups).await {
+                match resp {
+                    Some(Ok(value)) => values.push(value),
+                    Some(Err(e)) => return error_response(e),
+                    None => return shutting_down(),
+                }
+            }
+            Response::MultiValue { values }
+        }
+        Request::Set {
+            key,
+            value,
+            request_id,
+        } => {
+            let resp = call(&req_tx, |responder| Command::Set {
+                key: key.clone(),
+                value: value.clone(),
+                request_id,
+                responder: Some(responder),
+            })
+            .await;
+
+            match resp {
+                Some(Ok(seq)) => Response::Set { key, value, seq },
+                Some(Err(e)) => error_response(e),
+                None => shutting_down(),
+            }
+        }
+        Request::SetBatch { pairs } => {
+            let count = pairs.len();
+            let resp = call(&req_tx, |responder| Command::SetBatch { pairs, responder }).await;
+
+            match resp {
+                Some(Ok(seq)) => Response::SetBatch { count, seq },
+                Some(Err(e)) => error_response(e),
+                None => shutting_down(),
+            }
+        }
+        Request::SetEx {
+            key,
+            value,
+            ttl_secs,
+        } => {
+            let resp = call(&req_tx, |responder| Command::SetEx {
+                key: key.clone(),
+                value: value.clone(),
+                ttl: Duration::from_secs(ttl_secs as u64),
+                responder,
+            })
+            .await;
+
+            match resp {
+                Some(Ok(seq)) => Response::Set { key, value, seq },
+                Some(Err(e)) => error_response(e),
+                None => shutting_down(),
+            }
+        }
+        Request::SetTyped { key, value, flags } => {
+            let resp = call(&req_tx, |responder| Command::SetTyped {
+                key: key.clone(),
+                value: value.clone(),
+                flags,
+                responder,
+            })
+            .await;
+
+            match resp {
+                Some(Ok(seq)) => Response::Set { key, value, seq },
+                Some(Err(e)) => error_response(e),
+                None => shutting_down(),
+            }
+        }
+        Request::Transaction { ops } => {
+            let resp = call(&req_tx, |responder| Command::Transaction { ops, responder }).await;
+
+            match resp {
+                Some(Ok(TransactionOutcome::Committed { seq })) => Response::Committed { seq },
+                Some(Ok(TransactionOutcome::Aborted { op })) => Response::Aborted { op },
+                Some(Err(e)) => error_response(e),
+                None => shutting_down(),
+            }
+        }
+        Request::GetTyped { key } => {
+            let resp = call(&req_tx, |responder| Command::GetTyped {
+                key: key.clone(),
+                responder,
+            })
+            .await;
+
+            match resp {
+                Some(Ok(Some((value, flags)))) => Response::OkValueTyped { key, value, flags },
+                Some(Ok(None)) => Response::Error {
+                    msg: "no value for given key".to_string(),
+                },
+                Some(Err(e)) => error_response(e),
+                None => shutting_down(),
+            }
+        }
+        Request::Append { key, suffix } => {
+            let resp = call(&req_tx, |responder| Command::Append {
+                key: key.clone(),
+                suffix,
+                responder,
+            })
+            .await;
+
+            match resp {
+                Some(Ok(len)) => Response::Append { key, len },
+                Some(Err(e)) => error_response(e),
+                None => shutting_down(),
+            }
+        }
+        Request::Delete { key } => {
+            let resp = call(&req_tx, |responder| Command::Delete {
+                key: key.clone(),
+                responder,
+            })
+            .await;
+
+            match resp {
+                Some(Ok(seq)) => Response::Delete { key, seq },
+                Some(Err(e)) => error_response(e),
+                None => shutting_down(),
+            }
+        }
+        Request::Cas {
+            key,
+            expected,
+            value,
+        } => {
+            let resp = call(&req_tx, |responder| Command::Cas {
+                key,
+                expected,
+                new: value,
+                responder,
+            })
+            .await;
+
+            match resp {
+                Some(Ok(swapped)) => Response::Cas { swapped },
+                Some(Err(e)) => error_response(e),
+                None => shutting_down(),
+            }
+        }
+        Request::Keys { start, end, limit } => scan(&req_tx, start, end, limit, config).await,
+        Request::ScanFrom { cursor, limit } if cursor.values => {
+            scan_values(&req_tx, cursor.next, cursor.end, limit, config).await
+        }
+        Request::ScanFrom { cursor, limit } => {
+            scan(&req_tx, cursor.next, cursor.end, limit, config).await
+        }
+        Request::Scan { start, end, limit } => {
+            scan_values(&req_tx, start, end, limit, config).await
+        }
+        Request::ScanPrefix { prefix, limit } => {
+            // A prefix of 0xFF bytes only is followed by no key that doesn't start with it.
+            let end = engine::prefix_end(&prefix).unwrap_or_else(keyspace_end);
+            scan_values(&req_tx, prefix, end, limit, config).await
+        }
+        Request::Sync => match call(&req_tx, |responder| Command::Sync { responder }).await {
+            Some(Ok(())) => Response::Sync,
+            Some(Err(e)) => error_response(e),
+            None => shutting_down(),
+        },
+        Request::Stats => stats(&req_tx).await,
+    }
+}
+
+/// A key above every key there can be, the longest key made of 0xFF bytes is below it.
+fn keyspace_end() -> Bytes {
+    Bytes::from(vec![0xFF; MAX_KEY_SIZE as usize + 1])
+}
+
+/// Gathers counters of the engine along with the number of keys. Empty key is below every key
+/// and the count goes up to keyspace_end, so it takes all of them.
+async fn stats(req_tx: &mpsc::Sender<Command>) -> Response {
+    let stats = match call(req_tx, |responder| Command::Stats { responder }).await {
+        Some(Ok(stats)) => stats,
+        Some(Err(e)) => return error_response(e),
+        None => return shutting_down(),
+    };
+
+    let resp = call(req_tx, |responder| Command::CountRange {
+        start: Bytes::new(),
+        end: keyspace_end(),
+        responder,
+    })
+    .await;
+
+    match resp {
+        Some(Ok(keys)) => Response::Stats {
+            tables: stats.tables as u64,
+            memtable_entries: stats.memtable_entries as u64,
+            memtable_bytes: stats.memtable_size,
+            cache_hits: stats.cache.hits,
+            cache_misses: stats.cache.misses,
+            cache_verify_failures: stats.cache.verify_failures,
+            keys: keys.count,
+        },
+        Some(Err(e)) => error_response(e),
+        None => shutting_down(),
+    }
+}
+
+/// Lists keys within [start, end) along with their values the way scan lists keys, values count
+/// towards the bytes of a page.
+async fn scan_values(
+    req_tx: &mpsc::Sender<Command>,
+    start: Bytes,
+    end: Bytes,
+    limit: usize,
+    config: &Config,
+) -> Response {
+    let limit = limit.min(config.max_scan_keys).max(1);
+    let resp = call(req_tx, |responder| Command::Scan {
+        start,
+        end: end.clone(),
+        limit: limit + 1,
+        responder,
+    })
+    .await;
+
+    match resp {
+        Some(Ok(pairs)) => {
+            let (pairs, next) = page(pairs, limit, config.max_scan_bytes, |(key, value)| {
+                key.len() + value.len()
+            });
+            Response::Scan {
+                pairs,
+                cursor: next.map(|(next, _)| Cursor {
+                    next,
+                    end,
+                    values: true,
+                }),
+            }
+        }
+        Some(Err(e)) => error_response(e),
+        None => shutting_down(),
+    }
+}
+
+/// Lists keys within [start, end), gives a cursor to continue from if some are left out.
+async fn scan(
+    req_tx: &mpsc::Sender<Command>,
+    start: Bytes,
+    end: Bytes,
+    limit: usize,
+    config: &Config,
+) -> Response {
+    // One more key tells if there is anything left to continue from.
+    let limit = limit.min(config.max_scan_keys).max(1);
+    let resp = call(req_tx, |responder| Command::ScanKeys {
+        start,
+        end: end.clone(),
+        limit: limit + 1,
+        responder,
+    })
+    .await;
+
+    match resp {
+        Some(Ok(keys)) => {
+            let (keys, next) = page(keys, limit, config.max_scan_bytes, Bytes::len);
+            Response::Keys {
+                keys,
+                cursor: next.map(|next| Cursor {
+                    next,
+                    end,
+                    values: false,
+                }),
+            }
+        }
+        Some(Err(e)) => error_response(e),
+        None => shutting_down(),
+    }
+}
+
+/// Cuts the items found by a scan down to limit and max_bytes (as counted by size), returns the
+/// ones left along with the first item cut off. At least one item is kept, so that a scan
+/// continued from the next key always gets further.
+fn page<T: Clone>(
+    mut items: Vec<T>,
+    limit: usize,
+    max_bytes: usize,
+    size: impl Fn(&T) -> usize,
+) -> (Vec<T>, Option<T>) {
+    let mut bytes = 0;
+    let fit = items
+        .iter()
+        .take(limit)
+        .take_while(|item| {
+            bytes += size(item);
+            bytes <= max_bytes
+        })
+        .count()
+        .max(1);
+
+    let next = items.get(fit).cloned();
+    items.truncate(fit);
+
+    (items, next)
+}
+
+/// Waits until the engine sees the write with the given sequence number, polling its position.
+/// A single engine sees every write it acknowledged, so it only waits for a view that lags
+/// behind the writes. Gives up with an error after timeout, None means the engine is gone.
+async fn wait_for_write(
+    req_tx: &mpsc::Sender<Command>,
+    seq: u64,
+    timeout: Duration,
+) -> Option<crate::Result<()>> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        match call(req_tx, |responder| Command::Position { responder }).await? {
+            Ok(position) if position.last_seq >= seq => return Some(Ok(())),
+            Ok(_) if tokio::time::Instant::now() >= deadline => {
+                return Some(Err(crate::Error::Overloaded(format!(
+                    "write {} is not visible after {:?}",
+                    seq, timeout
+                ))))
+            }
+            Ok(_) => tokio::time::sleep(READ_AFTER_POLL_INTERVAL).await,
+            Err(e) => return Some(Err(e)),
+        }
+    }
+}
+
+/// Sends a command to the engine and waits for the result. None means the engine is gone, which
+/// happens when the server shuts down while the request is in flight: either the command can't be
+/// sent or the engine drops it without responding.
+async fn call<T>(
+    req_tx: &mpsc::Sender<Command>,
+    cmd: impl FnOnce(Responder<T>) -> Command,
+) -> Option<crate::Result<T>> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+    req_tx.send(cmd(resp_tx)).await.ok()?;
+
+    resp_rx.await.ok()
+}
+
+fn shutting_down() -> Response {
+    Response::Error {
+        msg: "server shutting down".to_string(),
+    }
+}
+
+/// Turns an error of the engine into a response. Errors that are not the client's fault are
+/// logged, since all the client gets is the message.
+fn error_response(e: crate::Error) -> Response {
+    match e {
+        crate::Error::InvalidInput(_) | crate::Error::Overloaded(_) => {}
+        _ => error!("request failed; error = {}", e),
+    }
+
+    Response::Error { msg: e.to_string() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::mem;
+    use crate::{Keepalive, Storage};
+    use bytes::Bytes;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+    /// Log storage that takes its time to append, so that requests stay in flight for a while.
+    #[derive(Clone)]
+    struct SlowWalStorage {
+        inner: mem::MemWalStorage,
+        append_delay: Duration,
+    }
+
+    impl WalStorage for SlowWalStorage {
+        fn init(&mut self) -> std::io::Result<()> {
+            self.inner.init()
+        }
+
+        fn persisted_data(&self) -> std::io::Result<Vec<Vec<u8>>> {
+            self.inner.persisted_data()
+        }
+
+        fn append(&mut self, data: &[u8]) -> std::io::Result<()> {
+            std::thread::sleep(self.append_delay);
+            self.inner.append(data)
+        }
+
+        fn rotate(&mut self) -> std::io::Result<()> {
+            self.inner.rotate()
+        }
+
+        fn discard_through(&mut self, generation: u64) -> std::io::Result<()> {
+            self.inner.discard_through(generation)
+        }
+
+        fn sync(&mut self) -> std::io::Result<()> {
+            self.inner.sync()
+        }
+
+        fn available_space(&self) -> std::io::Result<u64> {
+            self.inner.available_space()
+        }
+
+        fn size(&self) -> std::io::Result<u64> {
+            self.inner.size()
+        }
+
+        fn generation(&self) -> u64 {
+            self.inner.generation()
+        }
+    }
+
+    async fn request(addr: SocketAddr, line: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(format!("{}\n", line).as_bytes())
+            .await
+            .unwrap();
+
+        let mut response = String::new();
+        BufReader::new(stream)
+            .read_line(&mut response)
+            .await
+            .unwrap();
+
+        response.trim_end().to_string()
+    }
+
+    /// Splits the sequence number off a SET response.
+    fn split_seq(response: &str) -> (&str, u64) {
+        let (ack, seq) = response
+            .rsplit_once(", seq ")
+            .expect("SET response has a sequence number");
+        (ack, seq.parse().unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_multiple_listeners() {
+        let first = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let second = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (first_addr, second_addr) = (first.local_addr().unwrap(), second.local_addr().unwrap());
+
+        tokio::spawn(run(
+            vec![first, second],
+            mem::new(),
+            mem::new_wal(),
+            Config::default(),
+            std::future::pending(),
+        ));
+
+        assert_eq!(
+            split_seq(&request(first_addr, "SET foo bar").await).0,
+            "set foo = `b\"bar\"`"
+        );
+        assert_eq!(request(second_addr, "GET foo").await, "\"foo\" = b\"bar\"");
+        assert_eq!(
+            split_seq(&request(second_addr, "SET baz qux").await).0,
+            "set baz = `b\"qux\"`"
+        );
+        assert_eq!(request(first_addr, "GET baz").await, "\"baz\" = b\"qux\"");
+    }
+
+    #[tokio::test]
+    async fn test_request_too_long() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = Config {
+            max_request_len: Some(16),
+            ..Default::default()
+        };
+
+        tokio::spawn(run(
+            vec![listener],
+            mem::new(),
+            mem::new_wal(),
+            config,
+            std::future::pending(),
+        ));
+
+        assert_eq!(
+            split_seq(&request(addr, "SET foo bar").await).0,
+            "set foo = `b\"bar\"`"
+        );
+        assert_eq!(
+            request(addr, "SET foo barbazquxquux").await,
+            "error: request is longer than 16 bytes"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_builder() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+
+        let server = tokio::spawn(
+            ServerBuilder::new(mem::new(), mem::new_wal())
+                .listener(listener)
+                .config(Config {
+                    dedupe_window: 0,
+                    ..Default::default()
+                })
+                .shutdown(async {
+                    shutdown_rx.await.ok();
+                })
+                .run(),
+        );
+
+        assert_eq!(
+            split_seq(&request(addr, "SET foo bar").await).0,
+            "set foo = `b\"bar\"`"
+        );
+        assert_eq!(request(addr, "GET foo").await, "\"foo\" = b\"bar\"");
+        assert_eq!(
+            request(addr, "APPEND foo baz").await,
+            "appended to foo, 6 bytes"
+        );
+        assert_eq!(
+            split_seq(&request(addr, "MSET 2 bar 1 qux 2").await).0,
+            "set 2 keys"
+        );
+        assert_eq!(
+            request(addr, "MSET 2 bar 1 qux \"\"").await,
+            "error: pair 1: value is empty"
+        );
+        assert_eq!(request(addr, "CAS bar 2 3").await, "not swapped");
+        assert_eq!(request(addr, "CASNEW zzz 1").await, "swapped");
+        assert_eq!(
+            request(addr, "MGET 3 foo bar foo").await,
+            r#"[b"barbaz", b"1", b"barbaz"]"#
+        );
+        assert_eq!(
+            request(addr, "KEYS a z 10").await,
+            r#"["bar", "foo", "qux"]"#
+        );
+        assert_eq!(request(addr, "SYNC").await, "synced");
+
+        shutdown_tx.send(()).unwrap();
+        assert!(server.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_max_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = Config {
+            max_connections: Some(1),
+            ..Default::default()
+        };
+
+        tokio::spawn(run(
+            vec![listener],
+            mem::new(),
+            mem::new_wal(),
+            config,
+            std::future::pending(),
+        ));
+
+        // Stays open without sending a request.
+        let idle = TcpStream::connect(addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let mut refused = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        let mut response = String::new();
+        refused.read_line(&mut response).await.unwrap();
+        assert_eq!(response, "error: server at capacity\n");
+        assert_eq!(
+            refused.read(&mut [0; 1]).await.unwrap(),
+            0,
+            "connection is closed"
+        );
+
+        drop(idle);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(
+            split_seq(&request(addr, "SET foo bar").await).0,
+            "set foo = `b\"bar\"`"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_no_listeners() {
+        let res = run(
+            vec![],
+            mem::new(),
+            mem::new_wal(),
+            Config::default(),
+            std::future::pending(),
+        )
+        .await;
+        assert!(res.is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_drain_on_shutdown() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stor = mem::new();
+        let wal_stor = SlowWalStorage {
+            inner: mem::new_wal(),
+            append_delay: Duration::from_millis(300),
+        };
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+
+        let server = tokio::spawn(run(
+            vec![listener],
+            stor.clone(),
+            wal_stor,
+            Config::default(),
+            async {
+                shutdown_rx.await.ok();
+            },
+        ));
+
+        let in_flight = tokio::spawn(request(addr, "SET foo bar"));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        shutdown_tx.send(()).unwrap();
+
+        assert_eq!(
+            split_seq(&in_flight.await.unwrap()).0,
+            "set foo = `b\"bar\"`"
+        );
+        assert!(server.await.unwrap().is_ok());
+        assert!(
+            TcpStream::connect(addr).await.is_err(),
+            "no connections are accepted after shutdown"
+        );
+        assert_eq!(
+            stor.list_entries().unwrap().len(),
+            1,
+            "memtable is flushed on shutdown"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_drain_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = Config {
+            drain_timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+
+        let server = tokio::spawn(run(
+            vec![listener],
+            mem::new(),
+            mem::new_wal(),
+            config,
+            async {
+                shutdown_rx.await.ok();
+            },
+        ));
+
+        // Client that never sends its request.
+        let mut idle = TcpStream::connect(addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        shutdown_tx.send(()).unwrap();
+
+        let stopped = tokio::time::timeout(Duration::from_secs(2), server).await;
+        assert!(stopped.is_ok(), "server must not wait for idle clients");
+
+        let mut buf = [0; 1];
+        assert_eq!(
+            idle.read(&mut buf).await.unwrap(),
+            0,
+            "connection is closed"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_engine_gone_mid_request() {
+        let (req_tx, mut req_rx) = mpsc::channel(1);
+        // Engine that stops after taking the first command, without responding to it.
+        let engine = tokio::spawn(async move {
+            let cmd = req_rx.recv().await;
+            drop(cmd);
+        });
+
+        let set = Request::Set {
+            key: Bytes::from("foo"),
+            value: Bytes::from("bar"),
+            request_id: None,
+        };
+        let resp = handle_request(set, req_tx.clone(), &Config::default()).await;
+        assert_eq!(resp.serialize(), "error: server shutting down");
+
+        engine.await.unwrap();
+        let get = Request::Get {
+            key: Bytes::from("foo"),
+            fill_cache: true,
+            after: None,
+        };
+        let resp = handle_request(get, req_tx, &Config::default()).await;
+        assert_eq!(
+            resp.serialize(),
+            "error: server shutting down",
+            "command can't be sent once the engine is gone"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_after() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(run(
+            vec![listener],
+            mem::new(),
+            mem::new_wal(),
+            Config::default(),
+            std::future::pending(),
+        ));
+
+        let (_, seq) = split_seq(&request(addr, "SET foo bar").await);
+        assert_eq!(
+            request(addr, &format!("AFTER {} GET foo", seq)).await,
+            "\"foo\" = b\"bar\""
+        );
+    }
+
+    #[tokio::test]
+    async fn test_scan_caps() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = Config {
+            max_scan_keys: 3,
+            max_scan_bytes: 10,
+            ..Default::default()
+        };
+        tokio::spawn(run(
+            vec![listener],
+            mem::new(),
+            mem::new_wal(),
+            config,
+            std::future::pending(),
+        ));
+
+        let keys = ["a", "b", "c", "dddddddd", "eeee", "f"];
+        for key in keys {
+            request(addr, &format!("SET {} 1", key)).await;
+        }
+
+        let mut pages = Vec::new();
+        let mut line = "KEYS a z 100".to_string();
+        loop {
+            let response = request(addr, &line).await;
+            match response.split_once(" cursor ") {
+                Some((page, cursor)) => {
+                    pages.push(page.to_string());
+                    line = format!("SCANFROM {} 100", cursor);
+                }
+                None => {
+                    pages.push(response);
+                    break;
+                }
+            }
+        }
+        assert_eq!(
+            pages,
+            vec![r#"["a", "b", "c"]"#, r#"["dddddddd"]"#, r#"["eeee", "f"]"#],
+            "cut by the number of keys, then by their bytes"
+        );
+
+        assert_eq!(
+            request(addr, "KEYS a z 1").await,
+            r#"["a"] cursor AAFieg=="#
+        );
+
+        let mut pages = Vec::new();
+        let mut line = "SCAN a z 100".to_string();
+        loop {
+            let response = request(addr, &line).await;
+            match response.split_once(" cursor ") {
+                Some((page, cursor)) => {
+                    pages.push(page.to_string());
+                    line = format!("SCANFROM {} 100", cursor);
+                }
+                None => {
+                    pages.push(response);
+                    break;
+                }
+            }
+        }
+        assert_eq!(
+            pages,
+            vec![
+                r#"["a" = b"1", "b" = b"1", "c" = b"1"]"#,
+                r#"["dddddddd" = b"1"]"#,
+                r#"["eeee" = b"1", "f" = b"1"]"#,
+            ],
+            "values count towards the bytes"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_scan_from() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(run(
+            vec![listener],
+            mem::new(),
+            mem::new_wal(),
+            Config::default(),
+            std::future::pending(),
+        ));
+
+        for i in 0..95 {
+            request(addr, &format!("SET key{:03} {}", i, i)).await;
+        }
+
+        let full = request(addr, "KEYS key key~ 1000").await;
+        let full: Vec<&str> = full.trim_matches(['[', ']']).split(", ").collect();
+        assert_eq!(full.len(), 95);
+
+        let mut paged = Vec::new();
+        let mut line = "KEYS key key~ 10".to_string();
+        loop {
+            let response = request(addr, &line).await;
+            let (page, cursor) = match response.split_once(" cursor ") {
+                Some((page, cursor)) => (page.to_string(), Some(cursor.to_string())),
+                None => (response, None),
+            };
+            let page: Vec<String> = page
+                .trim_matches(['[', ']'])
+                .split(", ")
+                .map(String::from)
+                .collect();
+            assert!(page.len() <= 10);
+            paged.extend(page);
+            match cursor {
+                Some(cursor) => line = format!("SCANFROM {} 10", cursor),
+                None => break,
+            }
+        }
+        assert_eq!(paged, full, "no gaps, no duplicates");
+    }
+
+    #[tokio::test]
+    async fn test_scan_prefix() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(run(
+            vec![listener],
+            mem::new(),
+            mem::new_wal(),
+            Config::default(),
+            std::future::pending(),
+        ));
+
+        for key in ["user:1", "user:1:a", "user:1:b", "user:1:c", "user:2:a"] {
+            request(addr, &format!("SET {} v", key)).await;
+        }
+        request(addr, "DELETE user:1:b").await;
+        for key in ["hex:ff", "hex:ffff", "hex:ffff00", "hex:feff"] {
+            request(addr, &format!("SET {} v", key)).await;
+        }
+
+        assert_eq!(
+            request(addr, "SCAN PREFIX user:1: 10").await,
+            r#"["user:1:a" = b"v", "user:1:c" = b"v"]"#
+        );
+
+        // A page cut short goes on from its cursor and stops at the end of the prefix.
+        let mut pages = Vec::new();
+        let mut line = "SCAN PREFIX user:1 1".to_string();
+        loop {
+            let response = request(addr, &line).await;
+            match response.split_once(" cursor ") {
+                Some((page, cursor)) => {
+                    pages.push(page.to_string());
+                    line = format!("SCANFROM {} 1", cursor);
+                }
+                None => {
+                    pages.push(response);
+                    break;
+                }
+            }
+        }
+        assert_eq!(
+            pages,
+            vec![
+                r#"["user:1" = b"v"]"#,
+                r#"["user:1:a" = b"v"]"#,
+                r#"["user:1:c" = b"v"]"#,
+            ]
+        );
+        assert_eq!(request(addr, "SCAN PREFIX user:3: 10").await, "[]");
+
+        // Keys starting with 0xFF bytes go on to the end of the keyspace.
+        let all_ff = request(addr, "SCAN PREFIX hex:ffff 10").await;
+        assert_eq!(all_ff.matches(" = ").count(), 2);
+        let ff = request(addr, "SCAN PREFIX hex:ff 10").await;
+        assert_eq!(ff.matches(" = ").count(), 3);
+        let all = request(addr, "SCAN PREFIX \"\" 100").await;
+        assert_eq!(all.matches(" = ").count(), 8);
+    }
+
+    #[tokio::test]
+    async fn test_stats() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let storage = mem::new();
+        tokio::spawn(run(
+            vec![listener],
+            storage.clone(),
+            mem::new_wal(),
+            Config::default(),
+            std::future::pending(),
+        ));
+
+        let stats = |response: String| -> Vec<(String, u64)> {
+            response
+                .split(", ")
+                .map(|pair| {
+                    let (name, value) = pair.split_once(' ').unwrap();
+                    (name.to_string(), value.parse().unwrap())
+                })
+                .collect()
+        };
+        let stat =
+            |stats: &[(String, u64)], name: &str| stats.iter().find(|(n, _)| n == name).unwrap().1;
+
+        let empty = stats(request(addr, "STATS").await);
+        let names: Vec<&str> = empty.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "tables",
+                "memtable_entries",
+                "memtable_bytes",
+                "cache_hits",
+                "cache_misses",
+                "cache_verify_failures",
+                "keys"
+            ]
+        );
+        assert_eq!(stat(&empty, "keys"), 0);
+
+        for key in ["a", "b", "c"] {
+            request(addr, &format!("SET {} 1", key)).await;
+        }
+        request(addr, "DELETE b").await;
+        let stats = stats(request(addr, "STATS").await);
+        assert_eq!(stat(&stats, "memtable_entries"), 3, "tombstone included");
+        assert!(stat(&stats, "memtable_bytes") > stat(&empty, "memtable_bytes"));
+        assert_eq!(stat(&stats, "keys"), 2);
+        assert_eq!(stat(&stats, "tables"), 0);
+        assert!(storage.list_entries().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_read_after_lagging_view() {
+        // Engine whose view of writes catches up by one write every time it is asked.
+        let (req_tx, mut req_rx) = mpsc::channel(1);
+        tokio::spawn(async move {
+            let mut last_seq = 0;
+            while let Some(cmd) = req_rx.recv().await {
+                match cmd {
+                    Command::Position { responder } => {
+                        last_seq += 1;
+                        let position = crate::engine::Position {
+                            newest_table: None,
+                            wal_generation: 0,
+                            last_seq,
+                        };
+                        responder.send(Ok(position)).ok();
+                    }
+                    Command::Get { responder, .. } => {
+                        responder.send(Ok(Some(Bytes::from("bar")))).ok();
+                    }
+                    _ => unreachable!(),
+                }
+            }
+        });
+
+        let get = |after| Request::Get {
+            key: Bytes::from("foo"),
+            fill_cache: true,
+            after: Some(after),
+        };
+        let config = |timeout| Config {
+            read_after_timeout: timeout,
+            ..Default::default()
+        };
+        let resp = handle_request(get(5), req_tx.clone(), &config(Duration::from_secs(5))).await;
+        assert_eq!(resp.serialize(), "\"foo\" = b\"bar\"");
+
+        // Takes a poll per write, far longer than the timeout.
+        let resp = handle_request(get(1000), req_tx, &config(Duration::from_millis(20))).await;
+        assert!(
+            resp.serialize()
+                .starts_with("error: write 1000 is not visible"),
+            "{}",
+            resp.serialize()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_apply_socket_options() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _client = TcpStream::connect(addr).await.unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+
+        let config = SocketConfig {
+            nodelay: false,
+            keepalive: Some(Keepalive {
+                time: Duration::from_secs(10),
+                interval: Duration::from_secs(5),
+                retries: 7,
+            }),
+        };
+        apply_socket_options(&socket, &config).unwrap();
+
+        let sock = SockRef::from(&socket);
+        assert!(!socket.nodelay().unwrap());
+        assert!(sock.keepalive().unwrap());
+        assert_eq!(sock.keepalive_time().unwrap(), Duration::from_secs(10));
+        assert_eq!(sock.keepalive_interval().unwrap(), Duration::from_secs(5));
+        assert_eq!(sock.keepalive_retries().unwrap(), 7);
+
+        let config = SocketConfig {
+            nodelay: true,
+            keepalive: None,
+        };
+        apply_socket_options(&socket, &config).unwrap();
+
+        assert!(socket.nodelay().unwrap());
+        assert!(!sock.keepalive().unwrap());
+    }
+}
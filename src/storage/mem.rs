@@ -1,3 +1,4 @@
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::io;
 use std::sync::{Arc, Mutex};
@@ -31,12 +32,16 @@ impl crate::Storage for MemStorage {
     }
 
     fn write(&self, table_id: &Uuid, data: &[u8]) -> io::Result<()> {
-        self.entries
-            .lock()
-            .unwrap()
-            .insert(*table_id, Vec::from(data));
-
-        Ok(())
+        match self.entries.lock().unwrap().entry(*table_id) {
+            Entry::Occupied(_) => Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("table id {} already exists", table_id),
+            )),
+            Entry::Vacant(entry) => {
+                entry.insert(Vec::from(data));
+                Ok(())
+            }
+        }
     }
 
     fn open(&self, table_id: &Uuid) -> io::Result<Self::Entry> {
@@ -48,9 +53,124 @@ impl crate::Storage for MemStorage {
             )),
         }
     }
+
+    fn delete(&self, table_id: &Uuid) -> io::Result<()> {
+        match self.entries.lock().unwrap().remove(table_id) {
+            Some(_) => Ok(()),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("table id {} not found", table_id),
+            )),
+        }
+    }
+}
+
+/// A dummy write ahead log storage that keeps generations in memory. Exists for test.
+/// Clones share the data, so a clone can be used to look into the log or to recover from it.
+/// Storage keeps track of how much of the current generation was synced, so that a crash can be
+/// simulated with crash.
+#[derive(Clone, Debug)]
+pub struct MemWalStorage {
+    generations: Arc<Mutex<Vec<Vec<u8>>>>,
+    synced: Arc<Mutex<usize>>,
+    rotations: Arc<Mutex<u64>>,
+}
+
+pub fn new_wal() -> MemWalStorage {
+    MemWalStorage {
+        generations: Arc::new(Mutex::new(Vec::new())),
+        synced: Arc::new(Mutex::new(0)),
+        rotations: Arc::new(Mutex::new(0)),
+    }
+}
+
+impl MemWalStorage {
+    /// Drops whatever was appended to the current generation since it was last synced, like a
+    /// machine going down before the OS gets appends to disk does.
+    pub fn crash(&self) {
+        let synced = *self.synced.lock().unwrap();
+        if let Some(current) = self.generations.lock().unwrap().last_mut() {
+            current.truncate(synced);
+        }
+    }
+}
+
+impl crate::WalStorage for MemWalStorage {
+    fn init(&mut self) -> io::Result<()> {
+        let mut generations = self.generations.lock().unwrap();
+        if generations.is_empty() {
+            generations.push(Vec::new());
+        }
+        *self.synced.lock().unwrap() = generations.last().map_or(0, Vec::len);
+
+        Ok(())
+    }
+
+    fn persisted_data(&self) -> io::Result<Vec<Vec<u8>>> {
+        Ok(self.generations.lock().unwrap().clone())
+    }
+
+    fn append(&mut self, data: &[u8]) -> io::Result<()> {
+        match self.generations.lock().unwrap().last_mut() {
+            Some(current) => {
+                current.extend_from_slice(data);
+                Ok(())
+            }
+            None => Err(io::Error::other("wal storage is not initialized")),
+        }
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let mut generations = self.generations.lock().unwrap();
+        generations.push(Vec::new());
+        *self.synced.lock().unwrap() = 0;
+        *self.rotations.lock().unwrap() += 1;
+
+        Ok(())
+    }
+
+    fn discard_through(&mut self, generation: u64) -> io::Result<()> {
+        let mut generations = self.generations.lock().unwrap();
+        // The last generation is the current one, its number is the number of rotations.
+        let current = *self.rotations.lock().unwrap();
+        let kept = current.saturating_sub(generation).max(1) as usize;
+        let len = generations.len();
+        if len > kept {
+            generations.drain(..len - kept);
+        }
+
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        match self.generations.lock().unwrap().last() {
+            Some(current) => {
+                *self.synced.lock().unwrap() = current.len();
+                Ok(())
+            }
+            None => Err(io::Error::other("wal storage is not initialized")),
+        }
+    }
+
+    fn available_space(&self) -> io::Result<u64> {
+        Ok(u64::MAX)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        let generations = self.generations.lock().unwrap();
+        Ok(generations.iter().map(|data| data.len() as u64).sum())
+    }
+
+    fn generation(&self) -> u64 {
+        *self.rotations.lock().unwrap()
+    }
 }
 
 impl crate::StorageEntry for Vec<u8> {
+    fn size(&self) -> io::Result<u64> {
+        Ok(Vec::len(self) as u64)
+    }
+
     fn read_at(&self, data: &mut Vec<u8>, position: u64) -> io::Result<()> {
         let position = position as usize;
 
@@ -84,7 +204,7 @@ impl crate::StorageEntry for Vec<u8> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{Storage, StorageEntry};
+    use crate::{Storage, StorageEntry, WalStorage};
 
     #[test]
     fn test_memstore() {
@@ -108,6 +228,12 @@ mod tests {
         }
 
         let list = st.list_entries().unwrap();
+        let with_meta = st.list_entries_with_meta().unwrap();
+        assert_eq!(with_meta.len(), 5);
+        for (i, (id, size)) in with_meta.into_iter().enumerate() {
+            assert_eq!(id, list[i]);
+            assert_eq!(size, 5);
+        }
 
         assert_eq!(list[0].to_string(), "01923000-d486-705e-b6fe-f1dcf9cb01ae");
         assert_eq!(list[4].to_string(), "01922ffe-ff42-7a24-99af-69793801e519");
@@ -120,6 +246,17 @@ mod tests {
             .open(&Uuid::parse_str("01922ffe-ff42-7a24-99af-69793801e519").unwrap())
             .unwrap();
 
+        let deleted = Uuid::parse_str("01923000-1551-71d1-96b0-4063addc3fcd").unwrap();
+        assert!(st.delete(&deleted).is_ok());
+        assert!(st.open(&deleted).is_err(), "deleted entry should be gone");
+        assert!(st.delete(&deleted).is_err());
+        assert_eq!(st.list_entries().unwrap().len(), 4);
+
+        let existing = list[0];
+        let err = st.write(&existing, b"xyz").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(st.open(&existing).unwrap(), b"abcde", "not overwritten");
+
         let mut data = vec![0; 3];
         assert!(entry.read_at(&mut data, 0).is_ok());
         assert_eq!(
@@ -143,4 +280,38 @@ mod tests {
             "target vec capacity exceeds source vec length"
         );
     }
+
+    #[test]
+    fn test_mem_wal() {
+        let mut st = new_wal();
+        assert!(st.append(b"foo").is_err(), "storage is not initialized");
+
+        st.init().unwrap();
+        st.append(b"foo").unwrap();
+        st.rotate().unwrap();
+        st.append(b"bar").unwrap();
+        assert_eq!(st.size().unwrap(), 6);
+        st.rotate().unwrap();
+        st.append(b"baz").unwrap();
+        assert_eq!(st.size().unwrap(), 9);
+        st.discard_through(0).unwrap();
+        assert_eq!(st.size().unwrap(), 6, "the oldest generation is removed");
+
+        let mut reopened = st.clone();
+        reopened.init().unwrap();
+        assert_eq!(
+            reopened.persisted_data().unwrap(),
+            vec![b"bar".to_vec(), b"baz".to_vec()]
+        );
+
+        reopened.append(b"qux").unwrap();
+        reopened.sync().unwrap();
+        reopened.append(b"quux").unwrap();
+        reopened.crash();
+        assert_eq!(
+            reopened.persisted_data().unwrap(),
+            vec![b"bar".to_vec(), b"bazqux".to_vec()],
+            "appends after the last sync are lost"
+        );
+    }
 }
@@ -0,0 +1,155 @@
+use memmap2::Mmap;
+use std::fs;
+use std::io;
+use std::sync::{Arc, RwLock};
+
+/// A table file mapped to memory. Reads copy from the mapping instead of making a syscall each,
+/// pages are brought in by the OS as they are touched, so a lookup costs no more than the pages
+/// of the bloom filter, the index and a block. Compaction reading whole tables block by block
+/// goes through the same mapping.
+///
+/// The file is mapped once on open. Tables are never changed once written, but an entry is not
+/// trusted to have stayed the same size: a read past the end of the mapping looks at the file
+/// again and maps it anew if it grew. Empty files are not mapped at all (zero length mappings
+/// are refused by the OS) until they grow.
+#[derive(Debug)]
+pub struct MmapEntry {
+    file: fs::File,
+    map: RwLock<Option<Arc<Mmap>>>,
+}
+
+impl MmapEntry {
+    pub fn new(file: fs::File) -> io::Result<Self> {
+        let map = map(&file)?;
+
+        Ok(MmapEntry {
+            file,
+            map: RwLock::new(map),
+        })
+    }
+
+    fn mapped(&self) -> Option<Arc<Mmap>> {
+        self.map.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Maps the file again if it is larger than the current mapping. Readers holding the old
+    /// mapping keep it until they are done.
+    fn remap(&self) -> io::Result<Option<Arc<Mmap>>> {
+        let mut current = self.map.write().unwrap_or_else(|e| e.into_inner());
+        let mapped_len = current.as_ref().map_or(0, |map| map.len() as u64);
+        if self.file.metadata()?.len() > mapped_len {
+            *current = map(&self.file)?;
+        }
+
+        Ok(current.clone())
+    }
+}
+
+fn map(file: &fs::File) -> io::Result<Option<Arc<Mmap>>> {
+    if file.metadata()?.len() == 0 {
+        return Ok(None);
+    }
+
+    // Safety: the mapping is read only and tables are never modified in place once written.
+    // A table truncated behind the storage's back would fault reads of the cut off pages, the
+    // same way it would corrupt any other reader.
+    let map = unsafe { Mmap::map(file)? };
+
+    Ok(Some(Arc::new(map)))
+}
+
+impl crate::StorageEntry for MmapEntry {
+    fn read_at(&self, data: &mut Vec<u8>, position: u64) -> io::Result<()> {
+        let end = position.saturating_add(data.len() as u64);
+
+        let mut map = self.mapped();
+        if map.as_ref().map_or(0, |map| map.len() as u64) < end {
+            map = self.remap()?;
+        }
+
+        let mapped: &[u8] = map.as_deref().map_or(&[], |map| map);
+        let range = usize::try_from(position)
+            .ok()
+            .zip(usize::try_from(end).ok());
+        match range.and_then(|(start, end)| mapped.get(start..end)) {
+            Some(read) => {
+                data.copy_from_slice(read);
+                Ok(())
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "read of {} bytes at {} exceeds table size {}",
+                    data.len(),
+                    position,
+                    mapped.len()
+                ),
+            )),
+        }
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    /// Mapped pages are the pages of the OS cache, advice on the file covers them.
+    fn advise(&self, advice: crate::Advice) -> io::Result<()> {
+        crate::StorageEntry::advise(&self.file, advice)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StorageEntry;
+    use std::io::Write;
+
+    #[test]
+    fn test_read_at() {
+        let path = std::env::temp_dir().join(format!("bureau-mmap-{}", std::process::id()));
+        let data: Vec<u8> = (0..64 * 1024).map(|i| i as u8).collect();
+        fs::write(&path, &data).unwrap();
+
+        let entry = MmapEntry::new(fs::File::open(&path).unwrap()).unwrap();
+        assert_eq!(entry.size().unwrap(), data.len() as u64);
+
+        let mut read = vec![0; 100];
+        entry.read_at(&mut read, 1000).unwrap();
+        assert_eq!(read, data[1000..1100]);
+        entry.read_at(&mut read, data.len() as u64 - 100).unwrap();
+        assert_eq!(read, data[data.len() - 100..]);
+
+        let err = entry
+            .read_at(&mut read, data.len() as u64 - 50)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        let err = entry.read_at(&mut read, u64::MAX).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_empty_and_growing() {
+        let path = std::env::temp_dir().join(format!("bureau-mmap-grow-{}", std::process::id()));
+        let mut file = fs::File::create(&path).unwrap();
+
+        let entry = MmapEntry::new(fs::File::open(&path).unwrap()).unwrap();
+        assert_eq!(entry.size().unwrap(), 0);
+        entry.read_at(&mut Vec::new(), 0).unwrap();
+        let err = entry.read_at(&mut vec![0; 1], 0).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        file.write_all(b"first").unwrap();
+        let mut read = vec![0; 5];
+        entry.read_at(&mut read, 0).unwrap();
+        assert_eq!(read, b"first");
+
+        file.write_all(b" and second").unwrap();
+        let mut read = vec![0; 6];
+        entry.read_at(&mut read, 10).unwrap();
+        assert_eq!(read, b"second");
+
+        fs::remove_file(&path).unwrap();
+    }
+}
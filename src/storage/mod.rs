@@ -1,9 +1,14 @@
 mod cache;
 pub mod mem;
+#[cfg(feature = "mmap")]
+pub mod mmap;
+#[cfg(feature = "s3")]
+pub mod s3;
+pub mod wal;
 
 use crate::engine::DATA_PATH;
 use std::fs;
-use std::io;
+use std::io::{self, Write};
 use std::os::unix::fs::FileExt;
 use std::path::{Path, PathBuf};
 use uuid::Uuid;
@@ -30,7 +35,11 @@ pub fn new(path: DataPath) -> FsStorage {
 }
 
 impl crate::Storage for FsStorage {
+    #[cfg(not(feature = "mmap"))]
     type Entry = fs::File;
+    /// Tables are mapped to memory on open (see MmapEntry).
+    #[cfg(feature = "mmap")]
+    type Entry = mmap::MmapEntry;
 
     fn bootstrap(&self) -> io::Result<()> {
         if !self.data_path.exists() {
@@ -65,16 +74,61 @@ impl crate::Storage for FsStorage {
         Ok(uuids)
     }
 
+    fn list_entries_with_meta(&self) -> io::Result<Vec<(Uuid, u64)>> {
+        self.list_entries()?
+            .into_iter()
+            .map(|table_id| {
+                let path = sstable_path(self.data_path.as_path(), &table_id);
+                Ok((table_id, fs::metadata(path)?.len()))
+            })
+            .collect()
+    }
+
+    /// Refuses to start writing a table that won't fit the disk. A table is written to a part
+    /// file first and only linked under its id once it is synced, so a failed write leaves no
+    /// half written table behind, and a part file left by an earlier attempt is written over.
+    /// Unlike rename the link never replaces a table already there.
     fn write(&self, table_id: &Uuid, data: &[u8]) -> io::Result<()> {
-        fs::write(sstable_path(self.data_path.as_path(), table_id), data)?;
+        if fs2::available_space(self.data_path.as_path())? < data.len() as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::StorageFull,
+                format!("no space left for table {}", table_id),
+            ));
+        }
+
+        let part = part_path(self.data_path.as_path(), table_id);
+        let written = fs::File::create(&part)
+            .and_then(|mut file| {
+                file.write_all(data)?;
+                file.sync_all()
+            })
+            .and_then(|_| fs::hard_link(&part, sstable_path(self.data_path.as_path(), table_id)));
+        let removed = fs::remove_file(&part);
+        written?;
+        removed?;
         fs::File::open(self.data_path.as_path())?.sync_all()
     }
 
     fn open(&self, table_id: &Uuid) -> io::Result<Self::Entry> {
-        fs::File::options()
+        let file = fs::File::options()
             .read(true)
             .write(false)
-            .open(sstable_path(self.data_path.as_path(), table_id))
+            .open(sstable_path(self.data_path.as_path(), table_id))?;
+
+        #[cfg(feature = "mmap")]
+        let file = mmap::MmapEntry::new(file)?;
+
+        Ok(file)
+    }
+
+    fn delete(&self, table_id: &Uuid) -> io::Result<()> {
+        fs::remove_file(sstable_path(self.data_path.as_path(), table_id))?;
+        fs::File::open(self.data_path.as_path())?.sync_all()
+    }
+
+    /// Tables are synced as they are written, so only the directory is left to sync.
+    fn sync(&self) -> io::Result<()> {
+        fs::File::open(self.data_path.as_path())?.sync_all()
     }
 }
 
@@ -84,8 +138,119 @@ impl crate::StorageEntry for fs::File {
 
         Ok(())
     }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+    fn advise(&self, advice: crate::Advice) -> io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        let advice = match advice {
+            crate::Advice::WillNeed => libc::POSIX_FADV_WILLNEED,
+            crate::Advice::Sequential => libc::POSIX_FADV_SEQUENTIAL,
+            crate::Advice::DontNeed => libc::POSIX_FADV_DONTNEED,
+        };
+        // Zero length goes to the end of the file. Unlike most calls it returns the error
+        // instead of setting errno.
+        match unsafe { libc::posix_fadvise(self.as_raw_fd(), 0, 0, advice) } {
+            0 => Ok(()),
+            errno => Err(io::Error::from_raw_os_error(errno)),
+        }
+    }
 }
 
 fn sstable_path(data_path: &Path, table_id: &Uuid) -> PathBuf {
     data_path.join(table_id.to_string())
 }
+
+/// Where a table is written before it is linked under its id. The name doesn't parse as an id,
+/// so list_entries never takes it for a table.
+fn part_path(data_path: &Path, table_id: &Uuid) -> PathBuf {
+    data_path.join(format!(".{}.part", table_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Advice, Storage, StorageEntry};
+
+    #[test]
+    fn test_sync() {
+        let path = std::env::temp_dir().join(format!("bureau-sync-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        let stor = new(DataPath::Is(path.to_string_lossy().into_owned()));
+        stor.bootstrap().unwrap();
+
+        let table_id = Uuid::now_v7();
+        stor.write(&table_id, b"table").unwrap();
+        stor.sync().unwrap();
+        stor.delete(&table_id).unwrap();
+        stor.sync().unwrap();
+
+        fs::remove_dir_all(&path).unwrap();
+        assert!(stor.sync().is_err(), "data dir is gone");
+    }
+
+    #[test]
+    fn test_write_existing() {
+        let path = std::env::temp_dir().join(format!("bureau-existing-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        let stor = new(DataPath::Is(path.to_string_lossy().into_owned()));
+        stor.bootstrap().unwrap();
+
+        let table_id = Uuid::now_v7();
+        stor.write(&table_id, b"table").unwrap();
+        let err = stor.write(&table_id, b"other table").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(stor.open(&table_id).unwrap().size().unwrap(), 5);
+        assert!(!part_path(&path, &table_id).exists());
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_write_after_partial() {
+        let path = std::env::temp_dir().join(format!("bureau-partial-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        let stor = new(DataPath::Is(path.to_string_lossy().into_owned()));
+        stor.bootstrap().unwrap();
+
+        // A write that failed halfway leaves its part file behind, never the table.
+        let table_id = Uuid::now_v7();
+        fs::write(part_path(&path, &table_id), b"tab").unwrap();
+        assert!(stor.list_entries().unwrap().is_empty());
+
+        // The retry goes under the same id.
+        stor.write(&table_id, b"table").unwrap();
+        assert_eq!(stor.list_entries().unwrap(), vec![table_id]);
+        assert_eq!(stor.open(&table_id).unwrap().size().unwrap(), 5);
+        assert!(!part_path(&path, &table_id).exists());
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_advise() {
+        let path = std::env::temp_dir().join(format!("bureau-advise-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        let stor = new(DataPath::Is(path.to_string_lossy().into_owned()));
+        stor.bootstrap().unwrap();
+
+        let table_id = Uuid::now_v7();
+        let data: Vec<u8> = (0..64 * 1024).map(|i| i as u8).collect();
+        stor.write(&table_id, &data).unwrap();
+
+        let blob = stor.open(&table_id).unwrap();
+        for advice in [Advice::WillNeed, Advice::Sequential, Advice::DontNeed] {
+            blob.advise(advice).unwrap();
+            let mut read = vec![0; 100];
+            StorageEntry::read_at(&blob, &mut read, 1000).unwrap();
+            assert_eq!(read, data[1000..1100], "{:?}", advice);
+        }
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -0,0 +1,272 @@
+use aws_sdk_s3::error::{DisplayErrorContext, SdkError};
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::Client;
+use std::io;
+use tokio::runtime::Handle;
+use uuid::Uuid;
+
+/// Keeps tables as objects of an S3 bucket (or of any storage speaking the S3 API), one object per
+/// table named after the table id under the given prefix. Objects are never changed once written,
+/// which is all tables need, so there is nothing to sync: a write is durable once it returns.
+///
+/// Tables are not fetched on open. Every read of an entry is a ranged GET of just the bytes asked
+/// for, and lookups only read the bloom filter, an index page and a block. Reads block the calling
+/// thread on the runtime the storage was opened on, so entries must be read off the runtime
+/// threads, the way the dispatcher does (see StorageEntry for S3Entry).
+#[derive(Clone, Debug)]
+pub struct S3Storage {
+    client: Client,
+    bucket: String,
+    prefix: String,
+}
+
+/// Storage of the bucket with the client configured from the environment: credentials, region and
+/// endpoint are taken the way AWS tools take them. Prefix is put in front of table ids as is, so
+/// it usually ends with a slash.
+pub async fn new(bucket: &str, prefix: &str) -> S3Storage {
+    let config = aws_config::load_from_env().await;
+
+    with_client(Client::new(&config), bucket, prefix)
+}
+
+/// Storage of the bucket with the given client, for a client configured by hand, like one that
+/// talks to a local S3 compatible server.
+pub fn with_client(client: Client, bucket: &str, prefix: &str) -> S3Storage {
+    S3Storage {
+        client,
+        bucket: bucket.to_string(),
+        prefix: prefix.to_string(),
+    }
+}
+
+impl S3Storage {
+    fn table_key(&self, table_id: &Uuid) -> String {
+        format!("{}{}", self.prefix, table_id)
+    }
+
+    /// Table id of the object key, None for objects under the prefix that are not tables.
+    fn table_id(&self, key: &str) -> Option<Uuid> {
+        Uuid::parse_str(key.strip_prefix(&self.prefix)?).ok()
+    }
+}
+
+impl crate::AsyncStorage for S3Storage {
+    type Entry = S3Entry;
+
+    /// Creates the bucket if there is none. Prefixes are just a part of object keys, there is
+    /// nothing to create for them.
+    async fn bootstrap(&self) -> io::Result<()> {
+        let head = self.client.head_bucket().bucket(&self.bucket).send().await;
+        match head {
+            Ok(_) => Ok(()),
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_not_found()) => {
+                self.client
+                    .create_bucket()
+                    .bucket(&self.bucket)
+                    .send()
+                    .await
+                    .map_err(io_error)?;
+                Ok(())
+            }
+            Err(e) => Err(io_error(e)),
+        }
+    }
+
+    async fn list_entries(&self) -> io::Result<Vec<Uuid>> {
+        let entries = self.list_entries_with_meta().await?;
+
+        Ok(entries.into_iter().map(|(table_id, _)| table_id).collect())
+    }
+
+    async fn write(&self, table_id: &Uuid, data: &[u8]) -> io::Result<()> {
+        let put = self
+            .client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(self.table_key(table_id))
+            .body(ByteStream::from(data.to_vec()))
+            .if_none_match("*")
+            .send()
+            .await;
+
+        match put {
+            Ok(_) => Ok(()),
+            Err(e) if e.raw_response().is_some_and(|r| r.status().as_u16() == 412) => {
+                Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("table {} already exists", table_id),
+                ))
+            }
+            Err(e) => Err(io_error(e)),
+        }
+    }
+
+    async fn open(&self, table_id: &Uuid) -> io::Result<S3Entry> {
+        let key = self.table_key(table_id);
+        let head = self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(&key)
+            .send()
+            .await;
+
+        let size = match head {
+            Ok(head) => head.content_length().unwrap_or(0) as u64,
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_not_found()) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("table id {} not found", table_id),
+                ))
+            }
+            Err(e) => return Err(io_error(e)),
+        };
+
+        Ok(S3Entry {
+            client: self.client.clone(),
+            bucket: self.bucket.clone(),
+            key,
+            size,
+            runtime: Handle::current(),
+        })
+    }
+
+    async fn delete(&self, table_id: &Uuid) -> io::Result<()> {
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(self.table_key(table_id))
+            .send()
+            .await
+            .map_err(io_error)?;
+
+        Ok(())
+    }
+
+    /// Listing carries object sizes, so tables are not opened to learn them.
+    async fn list_entries_with_meta(&self) -> io::Result<Vec<(Uuid, u64)>> {
+        let mut pages = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.bucket)
+            .prefix(&self.prefix)
+            .into_paginator()
+            .send();
+
+        let mut entries = Vec::new();
+        while let Some(page) = pages.next().await {
+            for object in page.map_err(io_error)?.contents() {
+                let Some(table_id) = object.key().and_then(|key| self.table_id(key)) else {
+                    continue;
+                };
+                entries.push((table_id, object.size().unwrap_or(0) as u64));
+            }
+        }
+
+        // Newest first, the same as the file system storage lists them.
+        entries.sort();
+        entries.reverse();
+
+        Ok(entries)
+    }
+}
+
+/// A single table in the bucket. Every read is a ranged GET issued on the runtime the table was
+/// opened on, the calling thread is blocked until it is done. Reading on a runtime thread panics,
+/// entries are meant to be read in tokio::task::spawn_blocking.
+#[derive(Debug)]
+pub struct S3Entry {
+    client: Client,
+    bucket: String,
+    key: String,
+    size: u64,
+    runtime: Handle,
+}
+
+impl crate::StorageEntry for S3Entry {
+    fn read_at(&self, data: &mut Vec<u8>, position: u64) -> io::Result<()> {
+        let len = data.len() as u64;
+        if len == 0 {
+            data.clear();
+            return Ok(());
+        }
+        if position + len > self.size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "read of {} bytes at {} exceeds table size {}",
+                    len, position, self.size
+                ),
+            ));
+        }
+
+        let read = self.runtime.block_on(async {
+            let object = self
+                .client
+                .get_object()
+                .bucket(&self.bucket)
+                .key(&self.key)
+                .range(format!("bytes={}-{}", position, position + len - 1))
+                .send()
+                .await
+                .map_err(io_error)?;
+
+            object
+                .body
+                .collect()
+                .await
+                .map(|body| body.into_bytes())
+                .map_err(io::Error::other)
+        })?;
+
+        if read.len() as u64 != len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "read {} bytes at {}, {} expected",
+                    read.len(),
+                    position,
+                    len
+                ),
+            ));
+        }
+
+        data.clear();
+        data.extend_from_slice(&read);
+
+        Ok(())
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.size)
+    }
+}
+
+fn io_error<E, R>(e: SdkError<E, R>) -> io::Error
+where
+    E: std::error::Error + Send + Sync + 'static,
+    R: std::fmt::Debug + Send + Sync + 'static,
+{
+    io::Error::other(DisplayErrorContext(e).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table_keys() {
+        let config = aws_sdk_s3::Config::builder()
+            .behavior_version(aws_sdk_s3::config::BehaviorVersion::latest())
+            .region(aws_sdk_s3::config::Region::new("us-east-1"))
+            .build();
+        let storage = with_client(Client::from_conf(config), "bucket", "tables/");
+
+        let table_id = Uuid::now_v7();
+        let key = storage.table_key(&table_id);
+        assert_eq!(key, format!("tables/{}", table_id));
+        assert_eq!(storage.table_id(&key), Some(table_id));
+        assert_eq!(storage.table_id(&table_id.to_string()), None);
+        assert_eq!(storage.table_id("tables/notes.txt"), None);
+    }
+}
@@ -0,0 +1,225 @@
+use super::DataPath;
+use crate::engine::DATA_PATH;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+const WAL_DIR: &str = "wal";
+
+const WAL_EXTENSION: &str = "wal";
+
+/// Keeps each generation of the log in its own file named after the generation number.
+/// Appends are not synced to disk one by one, a write is only lost if the machine goes down
+/// before the OS gets it to disk, unless the log is synced explicitly after it.
+#[derive(Debug)]
+pub struct FsWalStorage {
+    wal_path: PathBuf,
+    generation: u64,
+    file: Option<fs::File>,
+}
+
+pub fn new(path: DataPath) -> FsWalStorage {
+    let data_path = match path {
+        DataPath::Default => PathBuf::from(DATA_PATH),
+        DataPath::Is(path_str) => PathBuf::from(path_str),
+    };
+
+    FsWalStorage {
+        wal_path: data_path.join(WAL_DIR),
+        generation: 0,
+        file: None,
+    }
+}
+
+impl FsWalStorage {
+    /// Lists generations present in storage in ascending order.
+    fn generations(&self) -> io::Result<Vec<u64>> {
+        let mut generations: Vec<u64> = fs::read_dir(self.wal_path.as_path())?
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                if path.extension()? != WAL_EXTENSION {
+                    return None;
+                }
+                path.file_stem()?.to_str()?.parse().ok()
+            })
+            .collect();
+
+        generations.sort();
+
+        Ok(generations)
+    }
+
+    fn open_generation(&mut self, generation: u64) -> io::Result<()> {
+        let file = fs::File::options()
+            .create(true)
+            .append(true)
+            .open(generation_path(self.wal_path.as_path(), generation))?;
+        fs::File::open(self.wal_path.as_path())?.sync_all()?;
+
+        self.generation = generation;
+        self.file = Some(file);
+
+        Ok(())
+    }
+}
+
+impl crate::WalStorage for FsWalStorage {
+    fn init(&mut self) -> io::Result<()> {
+        fs::create_dir_all(self.wal_path.as_path())?;
+
+        let latest = self.generations()?.last().copied().unwrap_or(0);
+        self.open_generation(latest)
+    }
+
+    fn persisted_data(&self) -> io::Result<Vec<Vec<u8>>> {
+        self.generations()?
+            .into_iter()
+            .map(|generation| {
+                let mut data = Vec::new();
+                fs::File::open(generation_path(self.wal_path.as_path(), generation))?
+                    .read_to_end(&mut data)?;
+                Ok(data)
+            })
+            .collect()
+    }
+
+    fn append(&mut self, data: &[u8]) -> io::Result<()> {
+        match self.file.as_mut() {
+            Some(file) => file.write_all(data),
+            None => Err(io::Error::other("wal storage is not initialized")),
+        }
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if let Some(file) = &self.file {
+            file.sync_all()?;
+        }
+        self.open_generation(self.generation + 1)
+    }
+
+    fn discard_through(&mut self, generation: u64) -> io::Result<()> {
+        for discarded in self.generations()? {
+            if discarded <= generation && discarded != self.generation {
+                fs::remove_file(generation_path(self.wal_path.as_path(), discarded))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        match &self.file {
+            Some(file) => file.sync_data(),
+            None => Err(io::Error::other("wal storage is not initialized")),
+        }
+    }
+
+    fn available_space(&self) -> io::Result<u64> {
+        fs2::available_space(self.wal_path.as_path())
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self.generations()?
+            .into_iter()
+            .map(|generation| {
+                Ok(fs::metadata(generation_path(self.wal_path.as_path(), generation))?.len())
+            })
+            .sum()
+    }
+
+    fn generation(&self) -> u64 {
+        self.generation
+    }
+}
+
+fn generation_path(wal_path: &Path, generation: u64) -> PathBuf {
+    wal_path.join(format!("{:020}.{}", generation, WAL_EXTENSION))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::WalStorage;
+
+    fn temp_storage(name: &str) -> FsWalStorage {
+        let path = std::env::temp_dir().join(format!("bureau-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+
+        new(DataPath::Is(path.to_string_lossy().into_owned()))
+    }
+
+    #[test]
+    fn test_init() {
+        let mut storage = temp_storage("wal-init");
+        assert!(storage.init().is_ok());
+        assert_eq!(storage.persisted_data().unwrap(), vec![Vec::<u8>::new()]);
+
+        storage.append(b"foo").unwrap();
+        storage.rotate().unwrap();
+        storage.append(b"bar").unwrap();
+
+        let mut reopened = new(DataPath::Is(
+            storage
+                .wal_path
+                .parent()
+                .unwrap()
+                .to_string_lossy()
+                .into_owned(),
+        ));
+        reopened.init().unwrap();
+        reopened.append(b"baz").unwrap();
+        assert_eq!(
+            reopened.persisted_data().unwrap(),
+            vec![b"foo".to_vec(), b"barbaz".to_vec()],
+            "reopened storage continues the latest generation"
+        );
+
+        fs::remove_dir_all(storage.wal_path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_append() {
+        let mut storage = temp_storage("wal-append");
+        assert!(
+            storage.append(b"foo").is_err(),
+            "storage is not initialized"
+        );
+
+        storage.init().unwrap();
+        storage.append(b"foo").unwrap();
+        storage.append(b"bar").unwrap();
+        assert_eq!(storage.persisted_data().unwrap(), vec![b"foobar".to_vec()]);
+
+        fs::remove_dir_all(storage.wal_path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_rotate() {
+        let mut storage = temp_storage("wal-rotate");
+        storage.init().unwrap();
+
+        for data in [b"foo", b"bar", b"baz"] {
+            storage.append(data).unwrap();
+            storage.rotate().unwrap();
+        }
+        storage.append(b"qux").unwrap();
+        assert_eq!(storage.generations().unwrap(), vec![0, 1, 2, 3]);
+
+        storage.discard_through(1).unwrap();
+        assert_eq!(storage.generations().unwrap(), vec![2, 3]);
+        assert_eq!(
+            storage.persisted_data().unwrap(),
+            vec![b"baz".to_vec(), b"qux".to_vec()]
+        );
+        assert_eq!(storage.size().unwrap(), 6);
+
+        storage.discard_through(10).unwrap();
+        assert_eq!(
+            storage.generations().unwrap(),
+            vec![3],
+            "current generation is kept"
+        );
+
+        fs::remove_dir_all(storage.wal_path.parent().unwrap()).unwrap();
+    }
+}